@@ -0,0 +1,66 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "post.glsl"
+
+vec3 sampleInput(vec2 offset) {
+    return texture(inputImage, fragTexCoord + offset * pcs.target.xy).rgb;
+}
+
+float luma(vec3 color) {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// Weighting by inverse luma keeps single very bright pixels from flickering through the whole chain
+vec3 karisAverage(vec3 a, vec3 b, vec3 c, vec3 d) {
+    vec4 sum = vec4(0.0);
+    for (int i = 0; i < 4; i++) {
+        vec3 color = i == 0 ? a : i == 1 ? b : i == 2 ? c : d;
+        float weight = 1.0 / (1.0 + luma(color));
+        sum += vec4(color * weight, weight);
+    }
+    return sum.rgb / sum.w;
+}
+
+// Quadratic soft knee around the threshold so that bloom fades in instead of popping
+vec3 brightPass(vec3 color, float threshold, float knee) {
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee + 0.0001);
+    float contribution = max(soft, brightness - threshold) / max(brightness, 0.0001);
+    return color * contribution;
+}
+
+// 13 tap filter from Jimenez, "Next Generation Post Processing in Call of Duty: Advanced Warfare"
+void main() {
+    vec3 a = sampleInput(vec2(-2.0, -2.0));
+    vec3 b = sampleInput(vec2(0.0, -2.0));
+    vec3 c = sampleInput(vec2(2.0, -2.0));
+    vec3 d = sampleInput(vec2(-2.0, 0.0));
+    vec3 e = sampleInput(vec2(0.0, 0.0));
+    vec3 f = sampleInput(vec2(2.0, 0.0));
+    vec3 g = sampleInput(vec2(-2.0, 2.0));
+    vec3 h = sampleInput(vec2(0.0, 2.0));
+    vec3 i = sampleInput(vec2(2.0, 2.0));
+    vec3 j = sampleInput(vec2(-1.0, -1.0));
+    vec3 k = sampleInput(vec2(1.0, -1.0));
+    vec3 l = sampleInput(vec2(-1.0, 1.0));
+    vec3 m = sampleInput(vec2(1.0, 1.0));
+
+    vec3 color;
+    if (pcs.parameters.z > 0.0) {
+        color = karisAverage(a, b, d, e) * 0.125
+              + karisAverage(b, c, e, f) * 0.125
+              + karisAverage(d, e, g, h) * 0.125
+              + karisAverage(e, f, h, i) * 0.125
+              + karisAverage(j, k, l, m) * 0.5;
+        color = brightPass(color, pcs.parameters.x, pcs.parameters.y);
+    } else {
+        color = e * 0.125
+              + (a + c + g + i) * 0.03125
+              + (b + d + f + h) * 0.0625
+              + (j + k + l + m) * 0.125;
+    }
+
+    outColor = vec4(max(color, vec3(0.0)), 1.0);
+}
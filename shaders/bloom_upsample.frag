@@ -0,0 +1,24 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "post.glsl"
+
+// 3x3 tent filter, the result is added onto the larger level by the blend state
+void main() {
+    vec2 offset = pcs.target.xy * pcs.parameters.x;
+    float intensity = pcs.parameters.y;
+
+    vec3 color = texture(inputImage, fragTexCoord).rgb * 4.0;
+
+    color += texture(inputImage, fragTexCoord + vec2(-offset.x, 0.0)).rgb * 2.0;
+    color += texture(inputImage, fragTexCoord + vec2(offset.x, 0.0)).rgb * 2.0;
+    color += texture(inputImage, fragTexCoord + vec2(0.0, -offset.y)).rgb * 2.0;
+    color += texture(inputImage, fragTexCoord + vec2(0.0, offset.y)).rgb * 2.0;
+
+    color += texture(inputImage, fragTexCoord + vec2(-offset.x, -offset.y)).rgb;
+    color += texture(inputImage, fragTexCoord + vec2(offset.x, -offset.y)).rgb;
+    color += texture(inputImage, fragTexCoord + vec2(-offset.x, offset.y)).rgb;
+    color += texture(inputImage, fragTexCoord + vec2(offset.x, offset.y)).rgb;
+
+    outColor = vec4(color / 16.0 * intensity, 1.0);
+}
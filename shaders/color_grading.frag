@@ -0,0 +1,30 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "post.glsl"
+
+// The LUT is a horizontal strip of `size` blue slices, each holding red along x and green along y
+vec3 sampleSlice(vec3 color, float slice, float size) {
+    vec2 uv = vec2(
+        (slice * size + color.r * (size - 1.0) + 0.5) / (size * size),
+        (color.g * (size - 1.0) + 0.5) / size
+    );
+    return textureLod(auxiliaryImage, uv, 0.0).rgb;
+}
+
+void main() {
+    float strength = pcs.parameters.x;
+    float size = pcs.parameters.y;
+
+    vec3 color = clamp(texture(inputImage, fragTexCoord).rgb, 0.0, 1.0);
+
+    float blue = color.b * (size - 1.0);
+    float slice = floor(blue);
+    vec3 graded = mix(
+        sampleSlice(color, slice, size),
+        sampleSlice(color, min(slice + 1.0, size - 1.0), size),
+        blue - slice
+    );
+
+    outColor = encodeOutput(mix(color, graded, strength));
+}
@@ -1,4 +1,20 @@
 C:\VulkanSDK\1.3.296.0\Bin\glslc.exe shader.vert -o vert.spv
 C:\VulkanSDK\1.3.296.0\Bin\glslc.exe shader.frag -o frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe pbr.frag -o pbr_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe shadow.vert -o shadow_vert.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe skybox.vert -o skybox_vert.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe skybox.frag -o skybox_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe fullscreen.vert -o fullscreen_vert.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe tonemap.frag -o tonemap_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe fxaa.frag -o fxaa_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe vignette.frag -o vignette_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe color_grading.frag -o color_grading_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe bloom_downsample.frag -o bloom_downsample_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe bloom_upsample.frag -o bloom_upsample_frag.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe cull.comp -o cull_comp.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe particle_simulate.comp -o particle_simulate_comp.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe particle_sort.comp -o particle_sort_comp.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe particle.vert -o particle_vert.spv
+C:\VulkanSDK\1.3.296.0\Bin\glslc.exe particle.frag -o particle_frag.spv
 
 pause
@@ -0,0 +1,55 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "post.glsl"
+
+const float FXAA_SPAN_MAX = 8.0;
+const float FXAA_REDUCE_MUL = 1.0 / 8.0;
+const float FXAA_REDUCE_MIN = 1.0 / 128.0;
+
+float luma(vec3 color) {
+    return dot(color, vec3(0.299, 0.587, 0.114));
+}
+
+// Blurs along the local edge direction estimated from the luma of the four diagonal neighbours
+void main() {
+    vec2 texel = pcs.target.xy;
+
+    vec3 rgbNW = texture(inputImage, fragTexCoord + vec2(-1.0, -1.0) * texel).rgb;
+    vec3 rgbNE = texture(inputImage, fragTexCoord + vec2(1.0, -1.0) * texel).rgb;
+    vec3 rgbSW = texture(inputImage, fragTexCoord + vec2(-1.0, 1.0) * texel).rgb;
+    vec3 rgbSE = texture(inputImage, fragTexCoord + vec2(1.0, 1.0) * texel).rgb;
+    vec3 rgbM = texture(inputImage, fragTexCoord).rgb;
+
+    float lumaNW = luma(rgbNW);
+    float lumaNE = luma(rgbNE);
+    float lumaSW = luma(rgbSW);
+    float lumaSE = luma(rgbSE);
+    float lumaM = luma(rgbM);
+
+    float lumaMin = min(lumaM, min(min(lumaNW, lumaNE), min(lumaSW, lumaSE)));
+    float lumaMax = max(lumaM, max(max(lumaNW, lumaNE), max(lumaSW, lumaSE)));
+
+    vec2 direction = vec2(
+        -((lumaNW + lumaNE) - (lumaSW + lumaSE)),
+        (lumaNW + lumaSW) - (lumaNE + lumaSE)
+    );
+
+    float directionReduce = max((lumaNW + lumaNE + lumaSW + lumaSE) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    float inverseDirectionMin = 1.0 / (min(abs(direction.x), abs(direction.y)) + directionReduce);
+    direction = clamp(direction * inverseDirectionMin, vec2(-FXAA_SPAN_MAX), vec2(FXAA_SPAN_MAX)) * texel;
+
+    vec3 rgbA = 0.5 * (
+        texture(inputImage, fragTexCoord + direction * (1.0 / 3.0 - 0.5)).rgb +
+        texture(inputImage, fragTexCoord + direction * (2.0 / 3.0 - 0.5)).rgb
+    );
+    vec3 rgbB = rgbA * 0.5 + 0.25 * (
+        texture(inputImage, fragTexCoord + direction * -0.5).rgb +
+        texture(inputImage, fragTexCoord + direction * 0.5).rgb
+    );
+
+    float lumaB = luma(rgbB);
+    vec3 color = (lumaB < lumaMin || lumaB > lumaMax) ? rgbA : rgbB;
+
+    outColor = encodeOutput(color);
+}
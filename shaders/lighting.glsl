@@ -0,0 +1,98 @@
+// Light and shadow bindings shared by the scene fragment shaders, set 0
+
+#define MAX_LIGHTS 16
+#define MAX_SHADOW_MAPS 8
+
+#define LIGHT_DIRECTIONAL 0
+#define LIGHT_POINT 1
+#define LIGHT_SPOT 2
+
+struct Light {
+    vec4 position;  // xyz = position, w = kind
+    vec4 direction; // xyz = direction, w = range
+    vec4 color;     // xyz = color, w = intensity
+    vec4 cone;      // x = cos(inner), y = cos(outer), z = first shadow map layer or -1
+};
+
+layout(set = 0, binding = 0) uniform UniformBufferObject{
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPosition;
+    vec4 environment; // x = lighting enabled, y = intensity, z = highest mip level
+} ubo;
+
+layout(set = 0, binding = 1) uniform LightBufferObject {
+    uvec4 count;
+    Light lights[MAX_LIGHTS];
+} lbo;
+
+layout(set = 0, binding = 2) uniform ShadowBufferObject {
+    mat4 matrices[MAX_SHADOW_MAPS];
+    vec4 cascadeSplits;
+    vec4 parameters; // x = cascade count, y = pcf radius, z = normal bias, w = texel size
+} sbo;
+
+layout(set = 0, binding = 3) uniform sampler2DArrayShadow shadowMap;
+
+layout(set = 0, binding = 4) uniform samplerCube environmentMap;
+
+// The world is Z-up while cubemaps are sampled Y-up, keep in sync with `cubemap_to_world` in environment.rs
+vec3 toCubemap(vec3 direction) {
+    return vec3(direction.x, direction.z, -direction.y);
+}
+
+// Blurred lookup used as a cheap stand-in for a convolved irradiance or prefiltered map
+vec3 sampleEnvironment(vec3 direction, float lod) {
+    return textureLod(environmentMap, toCubemap(direction), lod).rgb * ubo.environment.y;
+}
+
+// Smooth window so that the light reaches exactly zero at its range.
+float attenuation(float distance, float range) {
+    float falloff = clamp(1.0 - pow(distance / range, 4.0), 0.0, 1.0);
+    return (falloff * falloff) / max(distance * distance, 0.0001);
+}
+
+// Returns 1.0 when the fragment is fully lit and 0.0 when fully in shadow.
+float shadowFactor(Light light, vec3 position, vec3 normal) {
+    int layer = int(light.cone.z);
+    if (layer < 0) {
+        return 1.0;
+    }
+
+    if (int(light.position.w) == LIGHT_DIRECTIONAL) {
+        float depth = -(ubo.view * vec4(position, 1.0)).z;
+        int cascadeCount = int(sbo.parameters.x);
+        int cascade = cascadeCount - 1;
+        for (int i = 0; i < cascadeCount; i++) {
+            if (depth < sbo.cascadeSplits[i]) {
+                cascade = i;
+                break;
+            }
+        }
+        layer += cascade;
+    }
+
+    // Offsetting along the normal removes acne on surfaces at grazing angles
+    vec3 offsetPosition = position + normal * sbo.parameters.z;
+    vec4 clip = sbo.matrices[layer] * vec4(offsetPosition, 1.0);
+    vec3 ndc = clip.xyz / clip.w;
+
+    if (ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    vec2 uv = ndc.xy * 0.5 + 0.5;
+    int radius = int(sbo.parameters.y);
+    float texelSize = sbo.parameters.w;
+
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; x++) {
+        for (int y = -radius; y <= radius; y++) {
+            vec2 offset = vec2(x, y) * texelSize;
+            lit += texture(shadowMap, vec4(uv + offset, float(layer), ndc.z));
+        }
+    }
+
+    float samples = float((2 * radius + 1) * (2 * radius + 1));
+    return lit / samples;
+}
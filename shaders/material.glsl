@@ -0,0 +1,30 @@
+// Matches MaterialBufferObject
+struct Material {
+    vec4 baseColorFactor;
+    vec4 emissiveFactor;
+    vec4 parameters; // x = metallic, y = roughness, z = normal scale, w = occlusion strength
+    uint textures[8]; // slots in materialTextures, in MaterialTexture order
+};
+
+const uint BASE_COLOR_MAP = 0;
+const uint METALLIC_ROUGHNESS_MAP = 1;
+const uint NORMAL_MAP = 2;
+const uint OCCLUSION_MAP = 3;
+const uint EMISSIVE_MAP = 4;
+
+layout(std430, set = 1, binding = 0) readonly buffer Materials {
+    Material materials[];
+};
+
+// Sized when the pipeline is created, large with descriptor indexing and small without
+layout(constant_id = 0) const uint TEXTURE_CAPACITY = 16;
+layout(set = 1, binding = 1) uniform sampler2D materialTextures[TEXTURE_CAPACITY];
+
+layout(push_constant) uniform PushConstants {
+    uint material;
+} pcs;
+
+// The index is the same for the whole draw, so no nonuniformEXT is needed
+vec4 sampleMap(Material material, uint map, vec2 uv) {
+    return texture(materialTextures[material.textures[map]], uv);
+}
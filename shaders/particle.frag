@@ -0,0 +1,17 @@
+#version 450
+
+layout(location = 0) in vec2 fragCorner;
+layout(location = 1) in vec4 fragColor;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // Round sprite fading out towards its edge
+    float alpha = fragColor.a * (1.0 - smoothstep(0.5, 1.0, length(fragCorner)));
+    if (alpha <= 0.0) {
+        discard;
+    }
+
+    // Premultiplied, so the same output works with additive and alpha blending
+    outColor = vec4(fragColor.rgb * alpha, alpha);
+}
@@ -0,0 +1,49 @@
+// Matches Particle in particle.rs, a zeroed particle is dead
+struct Particle {
+    vec4 position; // xyz = position, w = size
+    vec4 color;
+    vec4 velocity; // xyz = random velocity picked at spawn, w = remaining life in seconds
+};
+
+// Matches SortEntry, dead particles and padding have a negative key
+struct SortEntry {
+    float key;
+    uint index;
+};
+
+const uint CURVE_KEYS = 4;
+
+// Matches EmitterObject, curves are keyed evenly over the particles' life
+struct Emitter {
+    vec4 position; // xyz = position, w = lifetime in seconds
+    vec4 velocity[CURVE_KEYS];
+    vec4 color[CURVE_KEYS];
+    vec4 size; // one key per component
+    uint first; // first particle in the particle buffer
+    uint count;
+    uint sortOffset; // first entry in the sort buffer
+    uint sortCount; // count rounded up to a power of two for the bitonic sort
+    uint spawnCursor;
+    uint spawnCount;
+    float spread;
+    uint padding;
+};
+
+// Index of the key before t and how far t is towards the next one
+float curvePosition(float t, out uint key) {
+    float position = clamp(t, 0.0, 1.0) * float(CURVE_KEYS - 1);
+    key = min(uint(position), CURVE_KEYS - 2);
+    return position - float(key);
+}
+
+vec4 evaluateCurve(vec4 keys[CURVE_KEYS], float t) {
+    uint key;
+    float blend = curvePosition(t, key);
+    return mix(keys[key], keys[key + 1], blend);
+}
+
+float evaluateCurve(vec4 keys, float t) {
+    uint key;
+    float blend = curvePosition(t, key);
+    return mix(keys[key], keys[key + 1], blend);
+}
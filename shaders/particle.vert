@@ -0,0 +1,58 @@
+#version 450
+
+#extension GL_GOOGLE_include_directive : require
+
+#include "particle.glsl"
+
+layout(binding = 0) uniform UniformBufferObject{
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPosition;
+} ubo;
+
+layout(std430, set = 1, binding = 0) readonly buffer Particles {
+    Particle particles[];
+};
+
+layout(std430, set = 1, binding = 1) readonly buffer SortEntries {
+    SortEntry sorted[];
+};
+
+layout(std430, set = 1, binding = 2) readonly buffer Emitters {
+    Emitter emitters[];
+};
+
+layout(push_constant) uniform PushConstants {
+    uint emitter;
+} pcs;
+
+layout(location = 0) out vec2 fragCorner;
+layout(location = 1) out vec4 fragColor;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+void main() {
+    // Instances go through the sort entries, which are back to front for alpha blended emitters
+    Emitter emitter = emitters[pcs.emitter];
+    Particle particle = particles[sorted[emitter.sortOffset + gl_InstanceIndex].index];
+
+    vec2 corner = CORNERS[gl_VertexIndex];
+    fragCorner = corner;
+    fragColor = particle.color;
+
+    // Dead particles collapse into a point and produce no fragments
+    if (particle.velocity.w <= 0.0) {
+        gl_Position = vec4(0.0);
+        return;
+    }
+
+    // The rows of the view rotation are the camera's axes, so the quad always faces it
+    vec3 right = vec3(ubo.view[0][0], ubo.view[1][0], ubo.view[2][0]);
+    vec3 up = vec3(ubo.view[0][1], ubo.view[1][1], ubo.view[2][1]);
+    vec3 position = particle.position.xyz + (right * corner.x + up * corner.y) * particle.position.w;
+
+    gl_Position = ubo.proj * ubo.view * vec4(position, 1.0);
+}
@@ -0,0 +1,145 @@
+#version 450
+
+#extension GL_GOOGLE_include_directive : require
+
+#include "lighting.glsl"
+#include "material.glsl"
+
+const float PI = 3.14159265359;
+
+layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragTexCoord;
+layout(location = 2) in vec3 fragPosition;
+layout(location = 3) in vec3 fragNormal;
+layout(location = 4) in vec4 fragTangent;
+layout(location = 5) flat in float fragOpacity;
+
+layout(location = 0) out vec4 outColor;
+
+const vec3 AMBIENT = vec3(0.03);
+
+// Trowbridge-Reitz GGX normal distribution
+float distributionGGX(float NdotH, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float denominator = NdotH * NdotH * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denominator * denominator);
+}
+
+// Height-correlated Smith visibility term, already divided by 4 * NdotL * NdotV
+float visibilitySmithGGX(float NdotL, float NdotV, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float ggxV = NdotL * sqrt(NdotV * NdotV * (1.0 - a2) + a2);
+    float ggxL = NdotV * sqrt(NdotL * NdotL * (1.0 - a2) + a2);
+    return 0.5 / max(ggxV + ggxL, 0.0001);
+}
+
+vec3 fresnelSchlick(float cosTheta, vec3 F0) {
+    return F0 + (1.0 - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+vec3 fresnelSchlickRoughness(float cosTheta, vec3 F0, float roughness) {
+    return F0 + (max(vec3(1.0 - roughness), F0) - F0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+// Analytic fit of the split-sum environment BRDF, avoids a lookup texture
+vec2 environmentBRDF(float NdotV, float roughness) {
+    const vec4 c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+    const vec4 c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+    vec4 r = roughness * c0 + c1;
+    float a004 = min(r.x * r.x, exp2(-9.28 * NdotV)) * r.x + r.y;
+    return vec2(-1.04, 1.04) * a004 + r.zw;
+}
+
+vec3 getNormal(Material material) {
+    vec3 N = normalize(fragNormal);
+    vec3 T = normalize(fragTangent.xyz - N * dot(N, fragTangent.xyz));
+    vec3 B = cross(N, T) * fragTangent.w;
+
+    vec3 tangentNormal = sampleMap(material, NORMAL_MAP, fragTexCoord).xyz * 2.0 - 1.0;
+    tangentNormal.xy *= material.parameters.z;
+
+    return normalize(mat3(T, B, N) * tangentNormal);
+}
+
+void main() {
+    Material material = materials[pcs.material];
+
+    vec4 baseColor = sampleMap(material, BASE_COLOR_MAP, fragTexCoord) * material.baseColorFactor * vec4(fragColor, 1.0);
+    vec4 metallicRoughness = sampleMap(material, METALLIC_ROUGHNESS_MAP, fragTexCoord);
+
+    float metallic = clamp(metallicRoughness.b * material.parameters.x, 0.0, 1.0);
+    float roughness = clamp(metallicRoughness.g * material.parameters.y, 0.04, 1.0);
+    float occlusion = 1.0 + material.parameters.w * (sampleMap(material, OCCLUSION_MAP, fragTexCoord).r - 1.0);
+    vec3 emissive = sampleMap(material, EMISSIVE_MAP, fragTexCoord).rgb * material.emissiveFactor.rgb;
+
+    vec3 N = getNormal(material);
+    vec3 V = normalize(ubo.cameraPosition.xyz - fragPosition);
+    float NdotV = max(dot(N, V), 0.0001);
+
+    vec3 F0 = mix(vec3(0.04), baseColor.rgb, metallic);
+    vec3 diffuseColor = baseColor.rgb * (1.0 - metallic);
+
+    vec3 color = vec3(0.0);
+
+    for (uint i = 0; i < lbo.count.x; i++) {
+        Light light = lbo.lights[i];
+        int kind = int(light.position.w);
+
+        vec3 L;
+        float strength = light.color.w;
+
+        if (kind == LIGHT_DIRECTIONAL) {
+            L = normalize(-light.direction.xyz);
+        } else {
+            vec3 toLight = light.position.xyz - fragPosition;
+            float distance = length(toLight);
+            L = toLight / distance;
+            strength *= attenuation(distance, light.direction.w);
+
+            if (kind == LIGHT_SPOT) {
+                float theta = dot(L, normalize(-light.direction.xyz));
+                strength *= smoothstep(light.cone.y, light.cone.x, theta);
+            }
+        }
+
+        strength *= shadowFactor(light, fragPosition, normalize(fragNormal));
+
+        float NdotL = max(dot(N, L), 0.0);
+        if (NdotL <= 0.0) {
+            continue;
+        }
+
+        vec3 H = normalize(L + V);
+        float NdotH = max(dot(N, H), 0.0);
+        float VdotH = max(dot(V, H), 0.0);
+
+        vec3 F = fresnelSchlick(VdotH, F0);
+        float D = distributionGGX(NdotH, roughness);
+        float Vis = visibilitySmithGGX(NdotL, NdotV, roughness);
+
+        vec3 specular = F * D * Vis;
+        vec3 diffuse = (1.0 - F) * diffuseColor / PI;
+
+        color += (diffuse + specular) * light.color.rgb * strength * NdotL;
+    }
+
+    if (ubo.environment.x > 0.0) {
+        float maxLod = ubo.environment.z;
+        vec3 F = fresnelSchlickRoughness(NdotV, F0, roughness);
+        vec2 brdf = environmentBRDF(NdotV, roughness);
+
+        vec3 irradiance = sampleEnvironment(N, maxLod);
+        vec3 prefiltered = sampleEnvironment(reflect(-V, N), roughness * maxLod);
+
+        vec3 diffuse = (1.0 - F) * diffuseColor * irradiance;
+        vec3 specular = prefiltered * (F0 * brdf.x + brdf.y);
+        color += (diffuse + specular) * occlusion;
+    } else {
+        color += AMBIENT * diffuseColor * occlusion;
+    }
+    color += emissive;
+
+    outColor = vec4(color, baseColor.a * fragOpacity);
+}
@@ -0,0 +1,21 @@
+// Inputs shared by the full screen post-processing passes
+
+layout(set = 0, binding = 0) uniform sampler2D inputImage;
+layout(set = 0, binding = 1) uniform sampler2D auxiliaryImage;
+
+layout(push_constant) uniform PushConstants {
+    vec4 parameters; // Effect specific
+    vec4 target;     // xy = texel size, z = 1.0 when writing to an sRGB target, w = gamma
+} pcs;
+
+layout(location = 0) in vec2 fragTexCoord;
+
+layout(location = 0) out vec4 outColor;
+
+// sRGB targets encode on write, so undo the gamma applied by tonemapping to avoid encoding twice
+vec4 encodeOutput(vec3 color) {
+    if (pcs.target.z > 0.0) {
+        color = pow(max(color, vec3(0.0)), vec3(pcs.target.w));
+    }
+    return vec4(color, 1.0);
+}
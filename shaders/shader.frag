@@ -1,16 +1,62 @@
 #version 450
 
-layout(binding = 1) uniform sampler2D texSampler;
+#extension GL_GOOGLE_include_directive : require
 
-layout(push_constant) uniform PushConstants {
-    layout(offset = 64) float opacity;
-} pcs;
+#include "lighting.glsl"
+#include "material.glsl"
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec2 fragTexCoord;
+layout(location = 2) in vec3 fragPosition;
+layout(location = 3) in vec3 fragNormal;
+layout(location = 4) in vec4 fragTangent;
+layout(location = 5) flat in float fragOpacity;
 
 layout(location = 0) out vec4 outColor;
 
+const float AMBIENT = 0.05;
+const float SHININESS = 32.0;
+
 void main() {
-    outColor = vec4(texture(texSampler, fragTexCoord).rgb, pcs.opacity);
+    Material material = materials[pcs.material];
+
+    vec3 albedo = sampleMap(material, BASE_COLOR_MAP, fragTexCoord).rgb * material.baseColorFactor.rgb * fragColor;
+    vec3 normal = normalize(fragNormal);
+    vec3 viewDirection = normalize(ubo.cameraPosition.xyz - fragPosition);
+
+    vec3 ambient = ubo.environment.x > 0.0 ? sampleEnvironment(normal, ubo.environment.z) : vec3(AMBIENT);
+    vec3 color = albedo * ambient;
+
+    for (uint i = 0; i < lbo.count.x; i++) {
+        Light light = lbo.lights[i];
+        int kind = int(light.position.w);
+
+        vec3 lightDirection;
+        float strength = light.color.w;
+
+        if (kind == LIGHT_DIRECTIONAL) {
+            lightDirection = normalize(-light.direction.xyz);
+        } else {
+            vec3 toLight = light.position.xyz - fragPosition;
+            float distance = length(toLight);
+            lightDirection = toLight / distance;
+            strength *= attenuation(distance, light.direction.w);
+
+            if (kind == LIGHT_SPOT) {
+                float theta = dot(lightDirection, normalize(-light.direction.xyz));
+                strength *= smoothstep(light.cone.y, light.cone.x, theta);
+            }
+        }
+
+        strength *= shadowFactor(light, fragPosition, normalize(fragNormal));
+
+        float diffuse = max(dot(normal, lightDirection), 0.0);
+
+        vec3 halfway = normalize(lightDirection + viewDirection);
+        float specular = diffuse > 0.0 ? pow(max(dot(normal, halfway), 0.0), SHININESS) : 0.0;
+
+        color += (albedo * diffuse + vec3(specular)) * light.color.rgb * strength;
+    }
+
+    outColor = vec4(color, fragOpacity);
 }
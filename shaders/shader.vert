@@ -3,21 +3,33 @@
 layout(binding = 0) uniform UniformBufferObject{
     mat4 view;
     mat4 proj;
+    vec4 cameraPosition;
 } ubo;
 
-layout(push_constant) uniform PushConstants {
-    mat4 model;
-} pcs;
-
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inColor;
 layout(location = 2) in vec2 inTexCoord;
+layout(location = 3) in vec3 inNormal;
+layout(location = 4) in vec4 inTangent;
+
+// Per instance, the matrix takes up locations 5 to 8
+layout(location = 5) in mat4 inModel;
+layout(location = 9) in vec4 inParameters; // x = opacity
 
 layout(location = 0) out vec3 fragColor;
 layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec3 fragPosition;
+layout(location = 3) out vec3 fragNormal;
+layout(location = 4) out vec4 fragTangent;
+layout(location = 5) flat out float fragOpacity;
 
 void main() {
-    gl_Position = ubo.proj * ubo.view * pcs.model * vec4(inPosition, 1.0);
+    vec4 worldPosition = inModel * vec4(inPosition, 1.0);
+    gl_Position = ubo.proj * ubo.view * worldPosition;
     fragColor = inColor;
     fragTexCoord = inTexCoord;
+    fragPosition = worldPosition.xyz;
+    fragNormal = transpose(inverse(mat3(inModel))) * inNormal;
+    fragTangent = vec4(mat3(inModel) * inTangent.xyz, inTangent.w);
+    fragOpacity = inParameters.x;
 }
@@ -0,0 +1,14 @@
+#version 450
+
+layout(push_constant) uniform PushConstants {
+    mat4 lightMatrix;
+} pcs;
+
+layout(location = 0) in vec3 inPosition;
+
+// Per instance, shares the instance buffer and locations of the scene pass
+layout(location = 5) in mat4 inModel;
+
+void main() {
+    gl_Position = pcs.lightMatrix * inModel * vec4(inPosition, 1.0);
+}
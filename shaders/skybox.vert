@@ -0,0 +1,19 @@
+#version 450
+
+layout(binding = 0) uniform UniformBufferObject{
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPosition;
+} ubo;
+
+layout(location = 0) out vec3 fragDirection;
+
+void main() {
+    // Full screen triangle placed on the far plane
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+    gl_Position = vec4(position, 1.0, 1.0);
+
+    // Only the rotation of the view is applied so the sky stays infinitely far away
+    vec4 viewDirection = inverse(ubo.proj) * vec4(position, 1.0, 1.0);
+    fragDirection = transpose(mat3(ubo.view)) * (viewDirection.xyz / viewDirection.w);
+}
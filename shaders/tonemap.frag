@@ -0,0 +1,20 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "post.glsl"
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    float exposure = pcs.parameters.x;
+    float gamma = pcs.parameters.y;
+
+    vec3 color = texture(inputImage, fragTexCoord).rgb * exposure;
+    color = aces(color);
+    color = pow(color, vec3(1.0 / gamma));
+
+    outColor = encodeOutput(color);
+}
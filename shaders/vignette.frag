@@ -0,0 +1,17 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "post.glsl"
+
+void main() {
+    float strength = pcs.parameters.x;
+    float radius = pcs.parameters.y;
+
+    // 0.0 at the center and 1.0 in the corners
+    float distance = length(fragTexCoord - 0.5) * 1.41421356;
+    float vignette = 1.0 - strength * smoothstep(radius, 1.0, distance);
+
+    vec3 color = texture(inputImage, fragTexCoord).rgb * vignette;
+
+    outColor = encodeOutput(color);
+}
@@ -0,0 +1,218 @@
+use std::{env, fs, path::Path, time::Instant};
+
+use anyhow::{anyhow, Context, Result};
+
+use simple_rust_game::renderer::{ArchiveWriter, BundleWriter, Compression, OptimizeSettings, Vfs, BUNDLE_PATH};
+
+const DEFAULT_MANIFEST_PATH: &str = "resources/assets.manifest";
+
+const USAGE: &str = "Usage:
+    asset-bake [manifest] [output]
+    asset-bake pack <archive> [--compress] <path>...
+
+Bakes every asset listed in the manifest (default `resources/assets.manifest`) into a bundle
+(default `resources/assets.bundle`) that the game loads instead of the source files.
+
+Each manifest line names a source file relative to the working directory, followed by options:
+    resources/viking_room.obj [quantize]
+    resources/viking_room.gltf [quantize]
+    resources/viking_room.png [srgb | linear] [uncompressed]
+Blank lines and lines starting with `#` are ignored. Meshes are OBJ or glTF (`.gltf` or `.glb`),
+all meshes of a glTF file's default scene are merged into one. Quantized meshes store positions
+and texture coordinates in 16 bits within their ranges. Textures are sRGB unless marked
+linear, and get a mip chain and BC1 compression, or BC3 if they have transparent texels, unless
+marked uncompressed. KTX2 and DDS textures are stored with the levels and blocks they have.
+
+`pack` writes the given files, and everything below the given directories, into an archive
+the game mounts when it is named `assets.pak` and placed next to the executable or in the
+working directory. Paths inside the archive are the given ones, relative to the working
+directory. `--compress` deflates every file that gets smaller from it.";
+
+#[derive(Clone, Debug)]
+enum ManifestEntry
+{
+    Mesh {path: String, settings: OptimizeSettings},
+    Texture {path: String, srgb: bool, compress: bool},
+}
+
+fn main() -> Result<()> {
+    pretty_env_logger::init();
+
+    let arguments = env::args().skip(1).collect::<Vec<_>>();
+    if arguments.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    match arguments.first().map(|a| a.as_str()) {
+        Some("pack") => pack(&arguments[1..]),
+        _ if arguments.len() <= 2 => bake(&arguments),
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+    }
+}
+
+fn bake(arguments: &[String]) -> Result<()>
+{
+    let manifest_path = arguments.first().map_or(DEFAULT_MANIFEST_PATH, |a| a.as_str());
+    let output_path = arguments.get(1).map_or(BUNDLE_PATH, |a| a.as_str());
+
+    let manifest = fs::read_to_string(manifest_path)
+        .with_context(|| format!("Failed to read manifest `{}`", manifest_path))?;
+
+    let entries = parse_manifest(&manifest)
+        .with_context(|| format!("Invalid manifest `{}`", manifest_path))?;
+
+    // Sources are only looked up relative to the working directory, like the manifest's paths
+    let mut vfs = Vfs::default();
+    vfs.mount_directory(&env::current_dir()?, 0);
+
+    let start = Instant::now();
+    let mut writer = BundleWriter::new();
+
+    for entry in &entries {
+        let entry_start = Instant::now();
+
+        let path = match entry {
+            ManifestEntry::Mesh {path, settings} => {
+                writer.add_mesh(&vfs, path, settings).with_context(|| format!("Failed to bake `{}`", path))?;
+                path
+            },
+            ManifestEntry::Texture {path, srgb, compress} => {
+                writer.add_texture(&vfs, path, *srgb, *compress).with_context(|| format!("Failed to bake `{}`", path))?;
+                path
+            },
+        };
+
+        println!("Baked `{}` in {:.2?}.", path, entry_start.elapsed());
+    }
+
+    writer.write(Path::new(output_path))
+        .with_context(|| format!("Failed to write bundle `{}`", output_path))?;
+
+    println!("Wrote {} assets to `{}` in {:.2?}.", writer.len(), output_path, start.elapsed());
+
+    Ok(())
+}
+
+fn pack(arguments: &[String]) -> Result<()>
+{
+    let Some((archive_path, inputs)) = arguments.split_first() else {
+        return Err(anyhow!("No archive given.\n\n{}", USAGE));
+    };
+
+    let compression = match inputs.iter().any(|a| a == "--compress") {
+        true => Compression::Deflate,
+        false => Compression::None,
+    };
+
+    let mut files = vec![];
+    for input in inputs.iter().filter(|a| *a != "--compress") {
+        collect_files(Path::new(input), &mut files)?;
+    }
+
+    // The archive could be inside one of the packed directories
+    let archive = Path::new(archive_path);
+    files.retain(|f| !archive.exists() || !same_file(f, archive));
+
+    if files.is_empty() {
+        return Err(anyhow!("Nothing to pack.\n\n{}", USAGE));
+    }
+
+    let mut writer = ArchiveWriter::new();
+    for file in &files {
+        let bytes = fs::read(file).with_context(|| format!("Failed to read `{}`", file.display()))?;
+        writer.add_file(&file.to_string_lossy(), &bytes, compression)?;
+    }
+
+    writer.write(archive).with_context(|| format!("Failed to write archive `{}`", archive_path))?;
+
+    let (size, stored) = writer.sizes();
+    println!("Packed {} files into `{}`, {} bytes stored as {}.", writer.len(), archive_path, size, stored);
+
+    Ok(())
+}
+
+// Sorted so that packing the same files twice gives the same archive
+fn collect_files(path: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<()>
+{
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(path)
+        .with_context(|| format!("Failed to read `{}`", path.display()))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    entries.sort();
+    for entry in entries {
+        collect_files(&entry, files)?;
+    }
+
+    Ok(())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool
+{
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+fn parse_manifest(manifest: &str) -> Result<Vec<ManifestEntry>>
+{
+    let mut entries = vec![];
+
+    for (number, line) in manifest.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let path = words.next().unwrap().to_string();
+        let options = words.collect::<Vec<_>>();
+
+        let extension = Path::new(&path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let unknown_option = |option: &str| anyhow!("Line {}: unknown option `{}` for `{}`.", number + 1, option, path);
+
+        let entry = match extension.as_str() {
+            "obj" | "gltf" | "glb" => {
+                let mut settings = OptimizeSettings::default();
+                for option in options {
+                    match option {
+                        "quantize" => settings.quantize = true,
+                        _ => return Err(unknown_option(option)),
+                    }
+                }
+
+                ManifestEntry::Mesh {path, settings}
+            },
+            "png" | "ktx2" | "dds" => {
+                let (mut srgb, mut compress) = (true, true);
+                for option in options {
+                    match option {
+                        "srgb" => srgb = true,
+                        "linear" => srgb = false,
+                        "uncompressed" => compress = false,
+                        _ => return Err(unknown_option(option)),
+                    }
+                }
+
+                ManifestEntry::Texture {path, srgb, compress}
+            },
+            _ => return Err(anyhow!("Line {}: don't know how to bake `{}`.", number + 1, path)),
+        };
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
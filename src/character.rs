@@ -0,0 +1,12 @@
+use crate::math::euler::Euler;
+use crate::math::vector::Vector3;
+
+#[derive(Clone, Debug)]
+pub struct Character
+{
+    pub position : Vector3,
+    pub velocity: Vector3,
+    pub velocity_input: Vector3,
+    pub velocity_input_goal: Vector3,
+    pub view_angle: Euler,
+}
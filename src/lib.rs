@@ -0,0 +1,11 @@
+#![allow(
+    dead_code,
+    unused_variables,
+    clippy::too_many_arguments,
+    clippy::unnecessary_wraps,
+    clippy::missing_safety_doc
+)]
+
+pub mod character;
+pub mod math;
+pub mod renderer;
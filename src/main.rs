@@ -5,16 +5,14 @@
     clippy::unnecessary_wraps
 )]
 
-mod math;
-mod renderer;
-
 use anyhow::{Result};
 
-use math::approach;
-use math::euler::Euler;
-use math::vector::{cross_product, Vector3};
+use simple_rust_game::character::Character;
+use simple_rust_game::math::approach;
+use simple_rust_game::math::euler::Euler;
+use simple_rust_game::math::vector::{cross_product, Vector3, Vector4};
 
-use renderer::{Renderer, MAX_FRAMES_IN_FLIGHT};
+use simple_rust_game::renderer::{Curve, Emitter, EnvironmentDescription, EnvironmentSource, Light, ParticleBlend, PostEffect, Renderer, MAX_FRAMES_IN_FLIGHT};
 
 use std::result::Result::Ok;
 use std::time::Instant;
@@ -56,7 +54,7 @@ fn main() -> Result<()> {
                     game.resized = false;
                     game.frame = (game.frame + 1) % MAX_FRAMES_IN_FLIGHT;
 
-                    game.renderer.render(frame, resized, &game.character, &window)
+                    game.renderer.render(frame, resized, &game.character, &game.lights, &window)
 
                 }.unwrap(),
                 WindowEvent::Resized(size) => {
@@ -74,11 +72,11 @@ fn main() -> Result<()> {
                     game.handle_keyboard_event(event);
                     if game.shut_down_requested
                     {
-                        unsafe{ game.shut_down(&elwt);}
+                        unsafe{ game.shut_down(elwt);}
                     }
                 },
                 WindowEvent::CloseRequested => {
-                    unsafe{ game.shut_down(&elwt);}
+                    unsafe{ game.shut_down(elwt);}
                 }
                 _ => {}
             }
@@ -89,15 +87,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct Character
-{
-    position : Vector3,
-    velocity: Vector3,
-    velocity_input: Vector3,
-    velocity_input_goal: Vector3,
-    view_angle: Euler,
-}
+const ENVIRONMENT_PATH: &str = "resources/environment.hdr";
 
 #[derive(Debug)]
 struct Game{
@@ -108,13 +98,53 @@ struct Game{
     shut_down_requested: bool,
     start: Instant,
     character: Character,
+    lights: Vec<Light>,
     last_mouse: PhysicalPosition<f64>,
 }
 
 impl Game {
     unsafe fn create(window: &Window) -> Result<Self> {
+        let mut renderer = Renderer::create(window)?;
+
+        // Falls back to the procedural sky when no environment image has been added
+        if renderer.vfs().exists(ENVIRONMENT_PATH) {
+            renderer.set_environment(&EnvironmentDescription {
+                source: EnvironmentSource::Equirectangular(ENVIRONMENT_PATH.to_string()),
+                ..Default::default()
+            })?;
+        }
+
+        // Sparks rising from the point light and a plume of smoke drifting away behind them
+        renderer.set_emitters(vec![
+            Emitter {
+                position: Vector3{x: 0.0, y: 0.0, z: 0.4},
+                ..Default::default()
+            },
+            Emitter {
+                position: Vector3{x: 0.0, y: 0.0, z: 0.6},
+                spawn_rate: 8.0,
+                lifetime: 4.0,
+                spread: 0.1,
+                velocity: Curve::new([
+                    Vector3{x: 0.0, y: 0.0, z: 0.3},
+                    Vector3{x: 0.1, y: 0.0, z: 0.25},
+                    Vector3{x: 0.2, y: 0.05, z: 0.2},
+                    Vector3{x: 0.3, y: 0.1, z: 0.15},
+                ]),
+                color: Curve::new([
+                    Vector4::new(0.3, 0.3, 0.3, 0.0),
+                    Vector4::new(0.3, 0.3, 0.3, 0.5),
+                    Vector4::new(0.25, 0.25, 0.25, 0.3),
+                    Vector4::new(0.2, 0.2, 0.2, 0.0),
+                ]),
+                size: Curve::new([0.1, 0.2, 0.3, 0.4]),
+                blend: ParticleBlend::Alpha,
+                max_particles: 64,
+            },
+        ])?;
+
         Ok(Self{
-            renderer : Renderer::create(&window)?,
+            renderer,
             frame: 0,
             resized: false,
             minimized: false,
@@ -127,6 +157,28 @@ impl Game {
                 velocity_input_goal: Vector3{x:0.0, y:0.0,z:0.0},
                 view_angle: Euler{pitch: 0.0, yaw: 0.0, roll: 0.0},
             },
+            lights: vec![
+                Light::directional(
+                    Vector3{x: -0.3, y: -0.5, z: -1.0},
+                    Vector3{x: 1.0, y: 0.95, z: 0.85},
+                    0.6,
+                ).with_shadow(),
+                Light::point(
+                    Vector3{x: 0.0, y: 0.0, z: 0.4},
+                    Vector3{x: 1.0, y: 0.6, z: 0.3},
+                    1.5,
+                    3.0,
+                ),
+                Light::spot(
+                    Vector3{x: 1.5, y: 1.5, z: 1.5},
+                    Vector3{x: -1.0, y: -1.0, z: -1.0},
+                    Vector3{x: 0.6, y: 0.8, z: 1.0},
+                    4.0,
+                    6.0,
+                    0.3,
+                    0.5,
+                ).with_shadow(),
+            ],
             last_mouse: PhysicalPosition{x: 0.0, y: 0.0},
         })
     }
@@ -147,6 +199,8 @@ impl Game {
 
         self.character.velocity = forward * self.character.velocity_input.x + right * self.character.velocity_input.y;
         self.character.position = self.character.position + self.character.velocity_input * delta_time;
+
+        self.renderer.update_particles(delta_time);
     }
 
     fn handle_cursor_movement(&mut self, position: PhysicalPosition<f64>)
@@ -179,6 +233,14 @@ impl Game {
                 PhysicalKey::Code(KeyCode::KeyS) => {
                     self.character.velocity_input_goal.y = -10.0;
                 },
+                PhysicalKey::Code(KeyCode::KeyF) if !event.repeat => {
+                    self.toggle_post_effect(PostEffect::Fxaa);
+                },
+                PhysicalKey::Code(KeyCode::KeyB) if !event.repeat => {
+                    let mut settings = self.renderer.bloom_settings();
+                    settings.enabled = !settings.enabled;
+                    unsafe{ self.renderer.set_bloom_settings(settings) }.unwrap();
+                },
                 PhysicalKey::Code(KeyCode::Escape) => {
                     self.shut_down_requested = true;
                 },
@@ -204,6 +266,17 @@ impl Game {
         }
     }
 
+    fn toggle_post_effect(&mut self, effect: PostEffect)
+    {
+        let mut settings = self.renderer.post_settings().clone();
+        match settings.effects.iter().position(|e| *e == effect) {
+            Some(index) => {settings.effects.remove(index);},
+            None => settings.effects.push(effect),
+        }
+
+        unsafe{ self.renderer.set_post_settings(settings) }.unwrap();
+    }
+
     unsafe fn shut_down(&mut self, elwt: &EventLoopWindowTarget<()>)
     {
         elwt.exit();
@@ -13,9 +13,9 @@ pub fn approach(goal: f32, current: f32, delta_time: f32) -> f32
 
     if difference < -delta_time
     {
-        return current - delta_time;
+        current - delta_time
     }
     else {
-        return goal;
+        goal
     }
 }
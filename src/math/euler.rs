@@ -21,15 +21,7 @@ impl Euler {
 
     pub fn normalize(&mut self)
     {
-        if self.pitch > 89.0
-        {
-            self.pitch = 89.0;
-        }
-
-        if self.pitch < -89.0
-        {
-            self.pitch = -89.0;
-        }
+        self.pitch = self.pitch.clamp(-89.0, 89.0);
 
         while self.yaw < -180.0
         {
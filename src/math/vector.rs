@@ -45,6 +45,13 @@ pub struct Vector4
     pub w: f32,
 }
 
+impl Vector4
+{
+    pub const fn new(x: f32, y: f32, z: f32, w: f32) -> Self {
+        Self{x, y, z, w}
+    }
+}
+
 impl Vector3
 {
     pub const fn new(x: f32, y: f32, z:f32) -> Self {
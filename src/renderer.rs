@@ -1,16 +1,34 @@
+use allocator::{Allocation, Allocator};
 use anyhow::{anyhow, Ok, Result};
-use buffer::{create_index_buffer, create_vertex_buffer};
-use cgmath::{Deg, Point3};
+use assets::{destroy_asset_loader, get_placeholder_mesh, update_assets, AssetLoader, MODEL_PATH};
+use bloom::get_bloom_steps;
+use buffer::{create_index_buffer, create_vertex_buffer, destroy_index_buffer, destroy_vertex_buffer};
+use cgmath::{Deg, Point3, Rad};
 use command::{create_command_buffers, create_command_pools};
-use descriptor::{create_descriptor_pool, create_descriptor_set_layout, create_descriptor_sets, create_uniform_buffers, Mat4, UniformBufferObject};
+use culling::{create_cull_pipeline, cull_props, destroy_cull_pipeline, push_gpu_culling, read_cull_statistics, record_cull_dispatch, Bounds, CullDispatch, CullReadback, Frustum};
+use descriptor::{create_descriptor_pool, create_descriptor_set_layout, create_descriptor_sets, Mat4, UniformBufferObject};
 use device::{create_logical_device, pick_physical_device};
-use image::{create_color_objects, create_depth_objects, create_texture_image, create_texture_image_view, create_texture_sampler};
-use instance::{create_instance, create_sync_objects, load_model, VALIDATION_ENABLED};
-use pipeline::{create_pipeline, create_render_pass};
+use environment::create_environment;
+use frame::{create_frame_resources, destroy_frame_resources, FramePass};
+use graph::RenderGraph;
+use instancing::{push_instance_batches, InstanceBatches};
+use lod::{get_screen_size, select_lod, Lod};
+use image::{destroy_texture, Texture};
+use instance::{create_instance, create_sync_objects, VALIDATION_ENABLED};
+use light::{LightBufferObject, Vec4};
+use particle::{create_particle_system, destroy_particle_system, push_particles, record_particle_dispatch, ParticleDispatch, ParticleSystem};
+use material::{create_default_textures, create_material_descriptor_set, create_material_set_layout, create_materials, destroy_material, destroy_material_descriptor_set, Material};
+use post::{create_color_grading_lut, create_post_set_layout, PostPass, PostPushConstants};
+use ring::{create_frame_ring, destroy_frame_ring, FrameRing};
+use sampler::{create_sampler_cache, destroy_sampler_cache, get_sampler, SamplerCache};
+use scene::{is_mesh_ready, record_scene_command_buffers};
+use shadow::{get_projection_correction, ShadowBufferObject, ShadowFrame};
+use std::collections::HashSet;
 use std::mem::size_of;
-use std::ptr::copy_nonoverlapping as memcpy;
-use std::u64;
-use swapchain::{create_framebuffers, create_swapchain, create_swapchain_image_views};
+use std::sync::{Arc, Mutex};
+use swapchain::{create_swapchain, create_swapchain_image_views};
+use texture_table::TextureTable;
+use upload::{create_upload_manager, destroy_upload_manager, flush_uploads, poll_uploads, record_upload_acquires, wait_for_uploads, UploadId, UploadManager};
 use vertex::Vertex;
 use vk::ImageView;
 use vulkanalia::loader::{LibloadingLoader, LIBRARY};
@@ -21,38 +39,98 @@ use vulkanalia::vk::KhrSurfaceExtension;
 use vulkanalia::vk::KhrSwapchainExtension;
 use winit::window::Window;
 
-use crate::Character;
+use crate::character::Character;
 
+mod allocator;
+mod assets;
+mod barrier;
+mod bloom;
 mod buffer;
+mod bundle;
 mod command;
+mod culling;
 mod descriptor;
 mod device;
+mod environment;
+mod frame;
+mod gltf_import;
+mod graph;
 mod image;
 mod instance;
+mod instancing;
+mod jobs;
+mod light;
+mod lod;
+mod material;
+mod mesh_cache;
+mod optimize;
+mod particle;
 mod pipeline;
+mod post;
+mod ring;
+mod sampler;
+mod scene;
+mod shadow;
 mod swapchain;
+mod texture_compression;
+mod texture_container;
+mod texture_table;
+mod upload;
 mod vertex;
+mod vfs;
+
+pub use allocator::MemoryStatistics;
+pub use assets::AssetStatus;
+pub use bloom::BloomSettings;
+pub use bundle::{AssetKind, Bundle, BundleEntry, BundleWriter, BUNDLE_PATH};
+pub use culling::CullingStatistics;
+pub use environment::{EnvironmentDescription, EnvironmentSource};
+pub use instancing::Prop;
+pub use light::Light;
+pub use lod::LodSettings;
+pub use optimize::OptimizeSettings;
+pub use particle::{Curve, Emitter, ParticleBlend, CURVE_KEYS, MAX_EMITTERS, MAX_PARTICLES};
+pub use post::{PostEffect, PostSettings};
+pub use sampler::SamplerDescription;
+pub use shadow::ShadowSettings;
+pub use vfs::{Archive, ArchiveWriter, Compression, Vfs};
 
 type Vec3 = cgmath::Vector3<f32>;
 
 pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
 
+const MODEL_COUNT: usize = 2;
+
+const FIELD_OF_VIEW: Deg<f32> = Deg(90.0);
+const NEAR_PLANE: f32 = 0.1;
+const FAR_PLANE: f32 = 1000.0;
+
 #[derive(Debug)]
 pub struct Renderer {
     entry: Entry,
     data: RenderData,
     instance: Instance,
     device: Device,
+    assets: AssetLoader,
 }
 
 #[derive(Clone, Debug, Default)]
 struct RenderData {
     surface: vk::SurfaceKHR,
     messenger: vk::DebugUtilsMessengerEXT,
+    // Whether the instance can query extended device features and properties
+    properties2_supported: bool,
     physical_device: vk::PhysicalDevice,
     msaa_samples: vk::SampleCountFlags,
     graphics_queue: vk::Queue,
     present_queue: vk::Queue,
+    transfer_queue: vk::Queue,
+
+    // Every buffer and image is sub-allocated from here
+    allocator: Arc<Mutex<Allocator>>,
+
+    // Every asset file is read through here
+    vfs: Arc<Vfs>,
 
     swapchain_format: vk::Format,
     swapchain_extent: vk::Extent2D,
@@ -60,16 +138,21 @@ struct RenderData {
     swapchain_images: Vec<vk::Image>,
     swapchain_image_views: Vec<ImageView>,
 
+    // Frame Graph
+    graph: RenderGraph<FramePass>,
+
     // Pipeline
-    render_pass: vk::RenderPass,
     descriptor_set_layout: vk::DescriptorSetLayout,
     pipeline_layout: vk::PipelineLayout,
     pipeline: vk::Pipeline,
+    pbr_pipeline: vk::Pipeline,
+    skybox_pipeline: vk::Pipeline,
 
-    framebuffers : Vec<vk::Framebuffer>,
     command_pool: vk::CommandPool,
     command_pools: Vec<vk::CommandPool>,
     command_buffers: Vec<vk::CommandBuffer>,
+    // Indexed by frame in flight, then by recording thread
+    thread_command_pools: Vec<Vec<vk::CommandPool>>,
     secondary_command_buffers: Vec<Vec<vk::CommandBuffer>>,
 
     image_available_semaphores: Vec<vk::Semaphore>,
@@ -77,238 +160,549 @@ struct RenderData {
     in_flight_fences: Vec<vk::Fence>,
     images_in_flight: Vec<vk::Fence>,
 
+    // Uploads
+    uploads: UploadManager,
+
     // Vertex Buffers
     vertices: Vec<Vertex>,
     indices: Vec<u32>,
     vertex_buffer: vk::Buffer,
-    vertex_buffer_memory: vk::DeviceMemory,
+    vertex_buffer_memory: Allocation,
     index_buffer: vk::Buffer,
-    index_buffer_memory: vk::DeviceMemory,
+    index_buffer_memory: Allocation,
+    vertex_upload: UploadId,
+    index_upload: UploadId,
+    mesh_bounds: Bounds,
+    mesh_lods: Vec<Lod>,
 
     // Uniform Buffers
-    uniform_buffers: Vec<vk::Buffer>,
-    uniform_buffers_memory: Vec<vk::DeviceMemory>,
+    frame_ring: FrameRing,
     descriptor_pool : vk::DescriptorPool,
-    descriptor_sets : Vec<vk::DescriptorSet>,
+    descriptor_set : vk::DescriptorSet,
+
+    // Shadow Mapping
+    shadow_settings: ShadowSettings,
+    shadow_pipeline_layout: vk::PipelineLayout,
+    shadow_pipeline: vk::Pipeline,
+    shadow_sampler: vk::Sampler,
 
     // Texture Sampling
-    mip_levels: u32,
-    texture_image : vk::Image,
-    texture_image_memory: vk::DeviceMemory,
-    texture_image_view: vk::ImageView,
+    // Owns every sampler, the ones below are handles into it
+    samplers: SamplerCache,
     texture_sampler: vk::Sampler,
-
-    // Depth Buffering
-    depth_image: vk::Image,
-    depth_image_memory: vk::DeviceMemory,
-    depth_image_view: vk::ImageView,
-
-    // Multisampling
-    color_image : vk::Image,
-    color_image_memory : vk::DeviceMemory,
-    color_image_view: vk::ImageView,
+    // Block compressed formats the device can sample, textures in other ones are decompressed on the CPU
+    block_formats: HashSet<vk::Format>,
+    white_texture: Texture,
+    flat_normal_texture: Texture,
+
+    // Environment
+    environment: Texture,
+    environment_description: EnvironmentDescription,
+
+    // Materials
+    // Every material is in one buffer and every material texture in one array, bound once per frame
+    descriptor_indexing: bool,
+    texture_table: TextureTable,
+    material_set_layout: vk::DescriptorSetLayout,
+    material_descriptor_pool: vk::DescriptorPool,
+    material_descriptor_set: vk::DescriptorSet,
+    material_buffer: vk::Buffer,
+    material_buffer_memory: Allocation,
+    materials: Vec<Material>,
+
+    // Drawn every frame next to the character models
+    props: Vec<Prop>,
+    culling_statistics: CullingStatistics,
+
+    // Level of detail each of the frame's props was drawn with last, for the hysteresis
+    lod_settings: LodSettings,
+    prop_lods: Vec<usize>,
+
+    // GPU Culling
+    gpu_culling_supported: bool,
+    gpu_culling: bool,
+    cull_set_layout: vk::DescriptorSetLayout,
+    cull_pipeline_layout: vk::PipelineLayout,
+    cull_pipeline: vk::Pipeline,
+    cull_descriptor_pool: vk::DescriptorPool,
+    cull_descriptor_sets: Vec<vk::DescriptorSet>,
+    cull_readbacks: Vec<Option<CullReadback>>,
+
+    // Particles
+    particles: ParticleSystem,
+    particle_buffer: vk::Buffer,
+    particle_buffer_memory: Allocation,
+    particle_sort_buffer: vk::Buffer,
+    particle_sort_buffer_memory: Allocation,
+    particle_set_layout: vk::DescriptorSetLayout,
+    particle_compute_pipeline_layout: vk::PipelineLayout,
+    particle_simulate_pipeline: vk::Pipeline,
+    particle_sort_pipeline: vk::Pipeline,
+    particle_descriptor_pool: vk::DescriptorPool,
+    particle_descriptor_sets: Vec<vk::DescriptorSet>,
+    particle_pipeline_layout: vk::PipelineLayout,
+    particle_additive_pipeline: vk::Pipeline,
+    particle_alpha_pipeline: vk::Pipeline,
+
+    // Post Processing
+    post_settings: PostSettings,
+    post_set_layout: vk::DescriptorSetLayout,
+    post_pipeline_layout: vk::PipelineLayout,
+    post_descriptor_pool: vk::DescriptorPool,
+    post_sampler: vk::Sampler,
+    post_passes: Vec<PostPass>,
+    color_grading_lut: Texture,
+
+    // Bloom
+    bloom_settings: BloomSettings,
+    bloom_extents: Vec<vk::Extent2D>,
+    bloom_descriptor_pool: vk::DescriptorPool,
+    bloom_descriptor_sets: Vec<vk::DescriptorSet>,
+    bloom_downsample_pipeline: vk::Pipeline,
+    bloom_upsample_pipeline: vk::Pipeline,
 }
 
 impl Renderer {
+    // Finds assets next to the executable or the working directory, see `Vfs::with_default_mounts`
     pub unsafe fn create(window: &Window) -> Result<Self>
+    {
+        Self::create_with_vfs(window, Vfs::with_default_mounts())
+    }
+
+    pub unsafe fn create_with_vfs(window: &Window, vfs: Vfs) -> Result<Self>
     {
         let loader = LibloadingLoader::new(LIBRARY)?;
         let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
-        let mut data = RenderData::default();
+        let mut data = RenderData {vfs: Arc::new(vfs), ..Default::default()};
         let instance = create_instance(window, &entry, &mut data)?;
         data.surface = window::create_surface(&instance, &window, &window)?;
         pick_physical_device(&instance, &mut data)?;
 
         let device = create_logical_device(&entry, &instance, &mut data)?;
+
+        let limits = instance.get_physical_device_properties(data.physical_device).limits;
+        data.allocator = Arc::new(Mutex::new(Allocator::new(limits.buffer_image_granularity)));
+
         create_swapchain(window, &instance, &device, &mut data)?;
         create_swapchain_image_views(&device, &mut data)?;
 
-        create_render_pass(&instance, &device, &mut data)?;
         create_descriptor_set_layout(&device, &mut data)?;
-        create_pipeline(&device, &mut data)?;
+        create_material_set_layout(&device, &mut data)?;
 
-        create_color_objects(&instance, &device, &mut data)?;
-        create_depth_objects(&instance, &device, &mut data)?;
-        create_framebuffers(&device, &mut data)?;
         create_command_pools(&instance, &device, &mut data)?;
-        create_texture_image(&instance, &device, &mut data)?;
-        create_texture_image_view(&device, &mut data)?;
-        create_texture_sampler(&device, &mut data)?;
+        create_upload_manager(&instance, &device, &mut data)?;
+        create_sampler_cache(&instance, &mut data);
+        data.texture_sampler = get_sampler(&device, &mut data, SamplerDescription::TEXTURE)?;
+
+        create_post_set_layout(&device, &mut data)?;
+        create_color_grading_lut(&instance, &device, &mut data)?;
+        create_default_textures(&instance, &device, &mut data)?;
+        create_environment(&instance, &device, &mut data, &EnvironmentDescription::default())?;
+
+        // The frame's descriptor sets point at these textures, materials and meshes stream in afterwards
+        wait_for_uploads(&device, &mut data)?;
+
+        // Meshes and material textures are decoded in the background, placeholders are drawn until they are ready
+        let mut assets = AssetLoader::new(data.vfs.clone(), data.block_formats.clone())?;
+
+        create_material_descriptor_set(&instance, &device, &mut data)?;
+        create_materials(&device, &mut data, &mut assets)?;
+        data.shadow_sampler = get_sampler(&device, &mut data, SamplerDescription::SHADOW)?;
+
+        let placeholder = get_placeholder_mesh();
+        data.vertices = placeholder.vertices;
+        data.indices = placeholder.indices;
+        data.mesh_bounds = placeholder.bounds;
+        data.mesh_lods = placeholder.lods;
+        assets.load_mesh(MODEL_PATH);
 
-        load_model(&mut data)?;
         create_vertex_buffer(&instance, &device, &mut data)?;
         create_index_buffer(&instance, &device, &mut data)?;
-        create_uniform_buffers(&instance, &device, &mut data)?;
+        create_frame_ring(&instance, &device, &mut data)?;
+        create_cull_pipeline(&device, &mut data)?;
+        data.gpu_culling = data.gpu_culling_supported;
+        create_particle_system(&instance, &device, &mut data)?;
 
-        create_descriptor_pool(&device, &mut data)?;
-        create_descriptor_sets(&device, &mut data)?;
+        create_frame_resources(&instance, &device, &mut data)?;
 
         create_command_buffers(&device, &mut data)?;
         create_sync_objects(&device, &mut data)?;
 
-        Ok(Self{entry, data, instance, device})
+        Ok(Self{entry, data, instance, device, assets})
     }
 
-    unsafe fn update_command_buffer(&mut self, character: &Character, image_index: usize) -> Result<()>
+    unsafe fn update_command_buffer(&mut self, instances: &InstanceBatches, shadow_instances: &InstanceBatches, cull_dispatch: Option<&CullDispatch>, particle_dispatch: Option<&ParticleDispatch>, shadow_frame: &ShadowFrame, dynamic_offsets: &[u32], frame: usize, image_index: usize) -> Result<()>
     {
-        let command_pool = self.data.command_pools[image_index];
+        // The frame's fence was waited on, so nothing recorded from its pool is still executing
+        let command_pool = self.data.command_pools[frame];
         self.device.reset_command_pool(command_pool, vk::CommandPoolResetFlags::empty())?;
 
-        let command_buffer= self.data.command_buffers[image_index];
+        let command_buffer= self.data.command_buffers[frame];
 
         let info = vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
         self.device.begin_command_buffer(command_buffer, &info)?;
 
-        let render_area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::default())
-            .extent(self.data.swapchain_extent);
+        // Acquired first so that resources finished since the last frame can already be drawn
+        record_upload_acquires(&self.device, &mut self.data, command_buffer);
 
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0],
-            },
-        };
+        // Compute can't run inside the graph's render passes, so culling and particles go before all of them
+        if let Some(cull_dispatch) = cull_dispatch {
+            record_cull_dispatch(&self.device, &self.data, command_buffer, cull_dispatch);
+        }
+
+        if let Some(particle_dispatch) = particle_dispatch {
+            record_particle_dispatch(&self.device, &self.data, command_buffer, particle_dispatch);
+        }
+
+        // Secondary command buffers can't be recorded while the graph is borrowed
+        let scene_command_buffers = record_scene_command_buffers(&self.device, &self.data, instances, particle_dispatch, dynamic_offsets, frame, image_index)?;
+
+        let bloom_steps = get_bloom_steps(&self.data);
+
+        self.data.graph.execute(&self.device, command_buffer, image_index, |pass, command_buffer| {
+            match pass.payload {
+                FramePass::Shadow(layer) => self.record_shadow_pass(command_buffer, layer, shadow_instances, shadow_frame),
+                FramePass::Scene => self.device.cmd_execute_commands(command_buffer, &scene_command_buffers),
+                FramePass::Bloom(step) => {
+                    let step = &bloom_steps[step];
+                    self.record_fullscreen_pass(command_buffer, pass.extent, step.pipeline, step.descriptor_set, &step.push_constants);
+                },
+                FramePass::Post(index) => {
+                    let post_pass = &self.data.post_passes[index];
+                    let push_constants = post_pass.get_push_constants(&self.data);
+                    self.record_fullscreen_pass(command_buffer, pass.extent, post_pass.pipeline, post_pass.descriptor_set, &push_constants);
+                },
+            }
+
+            Ok(())
+        })?;
 
-        let depth_clear_value = vk::ClearValue {
-            depth_stencil: vk::ClearDepthStencilValue {
-                depth: 1.0,
-                stencil: 0,
-            },
+        self.device.end_command_buffer(command_buffer)?;
+
+        Ok(())
+    }
+
+    unsafe fn record_shadow_pass(&self, command_buffer: vk::CommandBuffer, layer: usize, instances: &InstanceBatches, shadow_frame: &ShadowFrame)
+    {
+        let settings = self.data.shadow_settings;
+
+        // Layers not assigned to a light are only cleared, as is every layer while the mesh is still uploading
+        let light_matrix = match shadow_frame.matrices.get(layer) {
+            Some(light_matrix) if self.is_mesh_ready() => light_matrix,
+            _ => return,
         };
 
-        let clear_values = &[color_clear_value, depth_clear_value];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.data.render_pass)
-            .framebuffer(self.data.framebuffers[image_index])
-            .render_area(render_area)
-            .clear_values(clear_values);
+        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.data.shadow_pipeline);
+        self.device.cmd_set_depth_bias(command_buffer, settings.depth_bias_constant, 0.0, settings.depth_bias_slope);
+        self.device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.data.vertex_buffer, self.data.frame_ring.buffer], &[0, instances.offset]);
+        self.device.cmd_bind_index_buffer(command_buffer, self.data.index_buffer, 0, vk::IndexType::UINT32);
 
-        self.device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::SECONDARY_COMMAND_BUFFERS);
+        let light_matrix_bytes = std::slice::from_raw_parts(
+            light_matrix as *const Mat4 as *const u8,
+            size_of::<Mat4>()
+        );
 
-        let secondary_command_buffers= (0..2)
-            .map(|i|
-                self.update_secondary_command_buffer(image_index, i, &character)
-            )
-            .collect::<Result<Vec<_>, _>>()?;
+        self.device.cmd_push_constants(command_buffer, self.data.shadow_pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, light_matrix_bytes);
 
-        self.device.cmd_execute_commands(command_buffer, &secondary_command_buffers[..]);
+        // Materials don't matter for depth, but batches are already split by them
+        for batch in &instances.batches {
+            let lod = self.data.mesh_lods[batch.lod];
+            self.device.cmd_draw_indexed(command_buffer, lod.index_count, batch.instance_count, lod.first_index, 0, batch.first_instance);
+        }
+    }
 
-        self.device.cmd_end_render_pass(command_buffer);
-        self.device.end_command_buffer(command_buffer)?;
+    unsafe fn set_viewport(&self, command_buffer: vk::CommandBuffer, extent: vk::Extent2D)
+    {
+        let viewport = vk::Viewport::builder()
+            .x(0.0)
+            .y(0.0)
+            .width(extent.width as f32)
+            .height(extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0);
+
+        let scissor = vk::Rect2D::builder()
+            .offset(vk::Offset2D::default())
+            .extent(extent);
+
+        self.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+        self.device.cmd_set_scissor(command_buffer, 0, &[scissor]);
+    }
+
+    // Bloom and post-processing steps draw a full screen triangle reading their input through a single descriptor set
+    unsafe fn record_fullscreen_pass(&self, command_buffer: vk::CommandBuffer, extent: vk::Extent2D, pipeline: vk::Pipeline, descriptor_set: vk::DescriptorSet, push_constants: &PostPushConstants)
+    {
+        let push_constant_bytes = std::slice::from_raw_parts(
+            push_constants as *const PostPushConstants as *const u8,
+            size_of::<PostPushConstants>()
+        );
+
+        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+        self.set_viewport(command_buffer, extent);
+        self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.data.post_pipeline_layout, 0, &[descriptor_set], &[]);
+        self.device.cmd_push_constants(command_buffer, self.data.post_pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0, push_constant_bytes);
+        self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+
+    fn is_mesh_ready(&self) -> bool
+    {
+        is_mesh_ready(&self.data)
+    }
+
+    fn get_aspect_ratio(&self) -> f32
+    {
+        self.data.swapchain_extent.width as f32 / self.data.swapchain_extent.height as f32
+    }
+
+    fn get_projection_matrix(&self) -> Mat4
+    {
+        get_projection_correction() * cgmath::perspective(
+            FIELD_OF_VIEW,
+            self.get_aspect_ratio(),
+            NEAR_PLANE,
+            FAR_PLANE)
+    }
+
+    // Each update pushes its data into the frame ring and returns the dynamic offset to bind it at
+    unsafe fn update_uniform_buffer(&mut self, character: &Character) -> Result<u32>
+    {
+        let view_angle = character.position - character.view_angle.to_vector() * 1.0;
+        let view = get_view_matrix(character);
+        let projection = self.get_projection_matrix();
+
+        let camera_position = Vec4::new(view_angle.x, view_angle.y, view_angle.z, 1.0);
+        let environment = &self.data.environment_description;
+        let environment = Vec4::new(
+            if environment.lighting {1.0} else {0.0},
+            environment.intensity,
+            (self.data.environment.mip_levels - 1) as f32,
+            0.0,
+        );
+
+        let ubo = UniformBufferObject{view, projection, camera_position, environment};
+
+        let offset = self.data.frame_ring.push(&ubo)?;
+
+        Ok(offset as u32)
+    }
+
+    unsafe fn update_light_buffer(&mut self, lights: &[Light], shadow_frame: &ShadowFrame) -> Result<u32>
+    {
+        let lbo = LightBufferObject::new(lights, &shadow_frame.light_layers);
+
+        let offset = self.data.frame_ring.push(&lbo)?;
+
+        Ok(offset as u32)
+    }
+
+    unsafe fn update_shadow_buffer(&mut self, shadow_frame: &ShadowFrame) -> Result<u32>
+    {
+        let sbo = ShadowBufferObject::new(shadow_frame, &self.data.shadow_settings);
+
+        let offset = self.data.frame_ring.push(&sbo)?;
+
+        Ok(offset as u32)
+    }
+
+    pub fn vfs(&self) -> &Vfs
+    {
+        &self.data.vfs
+    }
+
+    // None for paths that were never requested
+    pub fn asset_status(&self, path: &str) -> Option<AssetStatus>
+    {
+        self.assets.status(path).cloned()
+    }
+
+    pub fn assets_loading(&self) -> bool
+    {
+        self.assets.is_loading()
+    }
+
+    pub fn props(&self) -> &[Prop]
+    {
+        &self.data.props
+    }
+
+    pub fn set_props(&mut self, props: Vec<Prop>) -> Result<()>
+    {
+        if let Some(prop) = props.iter().find(|p| p.material >= self.data.materials.len()) {
+            return Err(anyhow!("Prop uses material {}, but only {} materials exist.", prop.material, self.data.materials.len()));
+        }
+
+        self.data.props = props;
+
+        // Levels are tracked by position, which no longer lines up with the new props
+        self.data.prop_lods.clear();
 
         Ok(())
     }
 
-    unsafe fn update_secondary_command_buffer(&mut self, image_index: usize, model_index: usize, character: &Character) -> Result<vk::CommandBuffer>
+    pub fn lod_settings(&self) -> LodSettings
+    {
+        self.data.lod_settings
+    }
+
+    pub fn set_lod_settings(&mut self, settings: LodSettings)
     {
-        let command_buffers = &mut self.data.secondary_command_buffers[image_index];
-        while model_index >= command_buffers.len() {
-            let allocate_info = vk::CommandBufferAllocateInfo::builder()
-                .command_pool(self.data.command_pools[image_index])
-                .level(vk::CommandBufferLevel::SECONDARY)
-                .command_buffer_count(1);
+        self.data.lod_settings = settings;
+    }
 
-            let command_buffer = self.device.allocate_command_buffers(&allocate_info)?[0];
-            command_buffers.push(command_buffer);
+    // Picks each prop's level of detail from its size on screen, starting from the level it had last frame
+    fn select_lods(&mut self, props: &[Prop], character: &Character) -> Vec<usize>
+    {
+        if self.data.prop_lods.len() != props.len() {
+            self.data.prop_lods = vec![0; props.len()];
         }
 
-        let mut model = Mat4::from_axis_angle(
-            Vec3{x: 0.0, y:0.0, z:1.0},
-            Deg(90.0)
-        );
+        let eye = get_eye_position(character);
+        let half_fov_tan = (Rad::from(FIELD_OF_VIEW).0 * 0.5).tan();
+        let lod_count = self.data.mesh_lods.len();
 
-        let mut translation = Vec3::new( character.position.x, character.position.y, character.position.z);
-        if model_index != 0
-        {
-            translation.x = 0.0;
-            translation.y = 0.0;
-            translation.z = 0.0;
+        for (prop, lod) in props.iter().zip(self.data.prop_lods.iter_mut()) {
+            let screen_size = get_screen_size(&self.data.mesh_bounds, &prop.transform, eye, half_fov_tan);
+            *lod = select_lod(*lod, screen_size, lod_count, &self.data.lod_settings);
         }
 
-        let transformation = Mat4::from_translation(translation);
-        model = model * transformation;
+        self.data.prop_lods.clone()
+    }
 
-        let model_bytes = std::slice::from_raw_parts(
-            &model as *const Mat4 as *const u8,
-            size_of::<Mat4>()
-        );
+    // Objects tested against the view frustum and how many of them were skipped, a few frames late when culling on the GPU
+    pub fn culling_statistics(&self) -> CullingStatistics
+    {
+        self.data.culling_statistics
+    }
 
-        let opacity = (model_index + 1) as f32 * 0.25;
-        let opacity_bytes = &opacity.to_ne_bytes()[..];
+    pub fn gpu_culling(&self) -> bool
+    {
+        self.data.gpu_culling
+    }
 
-        let command_buffer = command_buffers[model_index];
+    pub fn set_gpu_culling(&mut self, enabled: bool) -> Result<()>
+    {
+        if enabled && !self.data.gpu_culling_supported {
+            return Err(anyhow!("GPU culling needs indirect draws with a first instance, which the device doesn't support."));
+        }
 
-        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
-            .render_pass(self.data.render_pass)
-            .subpass(0)
-            .framebuffer(self.data.framebuffers[image_index]);
+        self.data.gpu_culling = enabled;
 
-        let info = vk::CommandBufferBeginInfo::builder()
-            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
-            .inheritance_info(&inheritance_info);
+        Ok(())
+    }
 
-        self.device.begin_command_buffer(command_buffer, &info)?;
+    // The character models followed by the props placed through `set_props`
+    fn get_frame_props(&self, character: &Character) -> Vec<Prop>
+    {
+        let models = (0..MODEL_COUNT).map(|i| Prop {
+            material: 0,
+            transform: get_model_matrix(i, character),
+            opacity: (i + 1) as f32 * 0.25,
+        });
 
-        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.data.pipeline);
-        self.device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.data.vertex_buffer], &[0]);
-        self.device.cmd_bind_index_buffer(command_buffer, self.data.index_buffer, 0, vk::IndexType::UINT32);
-        self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.data.pipeline_layout, 0, &[self.data.descriptor_sets[image_index]], &[]);
+        models.chain(self.data.props.iter().copied()).collect()
+    }
 
-        self.device.cmd_push_constants(command_buffer, self.data.pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, model_bytes,);
-        self.device.cmd_push_constants(command_buffer, self.data.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 64, opacity_bytes,);
-        self.device.cmd_draw_indexed(command_buffer, self.data.indices.len() as u32, 1, 0, 0, 0);
+    pub fn emitters(&self) -> &[Emitter]
+    {
+        self.data.particles.emitters()
+    }
 
-        self.device.end_command_buffer(command_buffer)?;
+    // Replaces every emitter and clears the particles spawned so far
+    pub fn set_emitters(&mut self, emitters: Vec<Emitter>) -> Result<()>
+    {
+        self.data.particles.set_emitters(emitters)
+    }
 
-        Ok(command_buffer)
+    // Time passed since the last call, the particles are simulated by it in the next frame
+    pub fn update_particles(&mut self, delta_time: f32)
+    {
+        self.data.particles.update(delta_time);
     }
 
-    unsafe fn update_uniform_buffer(&self, character: &Character, image_index: usize) -> Result<()>
+    // Usage and fragmentation of the device memory blocks, for deciding when a defragmentation pass would pay off
+    pub fn memory_statistics(&self) -> MemoryStatistics
     {
-        let view_angle = character.position - character.view_angle.to_vector() * 1.0;
-        let view = Mat4::look_at_rh(
-            Point3::new(view_angle.x, view_angle.y, view_angle.z),
-        Point3::new(character.position.x, character.position.y, character.position.z),
-            Vec3::new( 0.0, 0.0, 1.0),
-        );
+        self.data.allocator.lock().unwrap().statistics()
+    }
 
-        /*
-            cgmath uses OpenGL range of -1.0 - 1.0 while Vulkan uses 0.0 - 1.0
-            We also need to flip the Y-axis due to OpenGL coordinates
-            Lastly, this matrix is transposed because cgmath constructs in column-major order
-         */
-        let correction = Mat4::new(
-            1.0, 0.0, 0.0, 0.0,
-            0.0, -1.0, 0.0, 0.0,
-            0.0, 0.0, 1.0/2.0, 0.0,
-            0.0, 0.0, 1.0/2.0, 1.0,
-        );
+    pub fn shadow_settings(&self) -> ShadowSettings
+    {
+        self.data.shadow_settings
+    }
+
+    pub unsafe fn set_shadow_settings(&mut self, settings: ShadowSettings) -> Result<()>
+    {
+        self.device.device_wait_idle()?;
+
+        let resolution_changed = settings.resolution != self.data.shadow_settings.resolution;
+        self.data.shadow_settings = settings;
+
+        // Bias and filtering are read every frame, only a new resolution needs new shadow maps
+        if resolution_changed {
+            self.recreate_frame_resources()?;
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn set_environment(&mut self, description: &EnvironmentDescription) -> Result<()>
+    {
+        self.device.device_wait_idle()?;
+
+        destroy_texture(&self.device, &self.data, &self.data.environment);
+        create_environment(&self.instance, &self.device, &mut self.data, description)?;
+        wait_for_uploads(&self.device, &mut self.data)?;
+
+        self.device.destroy_descriptor_pool(self.data.descriptor_pool, None);
+        create_descriptor_pool(&self.device, &mut self.data)?;
+        create_descriptor_sets(&self.device, &mut self.data)?;
+
+        Ok(())
+    }
+
+    pub fn post_settings(&self) -> &PostSettings
+    {
+        &self.data.post_settings
+    }
 
-        let projection = correction * cgmath::perspective(
-            Deg(90.0),
-            self.data.swapchain_extent.width as f32 / self.data.swapchain_extent.height as f32,
-             0.1,
-              1000.0);
+    pub unsafe fn set_post_settings(&mut self, settings: PostSettings) -> Result<()>
+    {
+        self.device.device_wait_idle()?;
 
-        let ubo = UniformBufferObject{view, projection};
+        destroy_texture(&self.device, &self.data, &self.data.color_grading_lut);
 
-        let memory = self.device.map_memory(
-            self.data.uniform_buffers_memory[image_index],
-            0,
-            size_of::<UniformBufferObject>() as u64,
-            vk::MemoryMapFlags::empty()
-        )?;
+        self.data.post_settings = settings;
+        create_color_grading_lut(&self.instance, &self.device, &mut self.data)?;
+        wait_for_uploads(&self.device, &mut self.data)?;
 
-        memcpy(&ubo, memory.cast(), 1);
-        self.device.unmap_memory(self.data.uniform_buffers_memory[image_index]);
+        // The effect chain decides which passes and intermediate images the graph contains
+        self.recreate_frame_resources()
+    }
+
+    pub fn bloom_settings(&self) -> BloomSettings
+    {
+        self.data.bloom_settings
+    }
+
+    pub unsafe fn set_bloom_settings(&mut self, settings: BloomSettings) -> Result<()>
+    {
+        let passes_changed = settings.enabled != self.data.bloom_settings.enabled || settings.mip_count != self.data.bloom_settings.mip_count;
+        self.data.bloom_settings = settings;
+
+        // Everything else is read while recording, only the passes of the mip chain need rebuilding
+        if passes_changed {
+            self.recreate_frame_resources()?;
+        }
 
         Ok(())
     }
 
-    pub unsafe fn render(&mut self, frame: usize, resized : bool, character: &Character, window: &Window) -> Result<()>
+    pub unsafe fn render(&mut self, frame: usize, resized : bool, character: &Character, lights: &[Light], window: &Window) -> Result<()>
     {
+        update_assets(&self.instance, &self.device, &mut self.data, &mut self.assets)?;
+
         self.device.wait_for_fences(&[self.data.in_flight_fences[frame]], true, u64::MAX, )?;
 
         let result = self
@@ -335,12 +729,55 @@ impl Renderer {
 
         self.data.images_in_flight[image_index as usize] = self.data.in_flight_fences[frame];
 
-        self.update_command_buffer(character, image_index)?;
-        self.update_uniform_buffer(character, image_index)?;
+        // Uploads recorded since the last frame start copying, finished ones are acquired by this frame
+        flush_uploads(&self.device, &mut self.data)?;
+        poll_uploads(&self.device, &mut self.data)?;
+
+        let shadow_frame = ShadowFrame::new(
+            lights,
+            &self.data.shadow_settings,
+            get_view_matrix(character),
+            FIELD_OF_VIEW,
+            self.get_aspect_ratio(),
+            NEAR_PLANE,
+        );
+
+        // Counts written by the GPU the last time this frame ran, read before the ring overwrites them
+        if let Some(statistics) = read_cull_statistics(&mut self.data, frame) {
+            self.data.culling_statistics = statistics;
+        }
+
+        // The frame's fence was waited on above, so its part of the ring can be overwritten
+        self.data.frame_ring.begin_frame(frame);
+
+        let dynamic_offsets = [
+            self.update_uniform_buffer(character)?,
+            self.update_light_buffer(lights, &shadow_frame)?,
+            self.update_shadow_buffer(&shadow_frame)?,
+        ];
+
+        // Casters outside the view can still throw shadows into it, so only the scene pass is culled
+        let props = self.get_frame_props(character);
+        let frustum = Frustum::from_matrix(self.get_projection_matrix() * get_view_matrix(character));
+        let lods = self.select_lods(&props, character);
+        let shadow_instances = push_instance_batches(&mut self.data, &props, &lods)?;
+
+        let (instances, cull_dispatch) = if self.data.gpu_culling {
+            push_gpu_culling(&self.device, &mut self.data, frame, &props, &lods, &frustum)?
+        } else {
+            let (visible_props, visible_lods, culling_statistics) = cull_props(&props, &lods, &frustum, &self.data.mesh_bounds);
+            self.data.culling_statistics = culling_statistics;
+
+            (push_instance_batches(&mut self.data, &visible_props, &visible_lods)?, None)
+        };
+
+        let particle_dispatch = push_particles(&self.device, &mut self.data, frame, get_eye_position(character))?;
+
+        self.update_command_buffer(&instances, &shadow_instances, cull_dispatch.as_ref(), particle_dispatch.as_ref(), &shadow_frame, &dynamic_offsets, frame, image_index)?;
 
         let wait_semaphores = &[self.data.image_available_semaphores[frame]];
         let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers = &[self.data.command_buffers[image_index as usize]];
+        let command_buffers = &[self.data.command_buffers[frame]];
         let signal_semaphores = &[self.data.render_finished_semaphores[frame]];
         let submit_info = vk::SubmitInfo::builder()
             .wait_semaphores(wait_semaphores)
@@ -378,46 +815,25 @@ impl Renderer {
         create_swapchain(window, &self.instance, &self.device, &mut self.data)?;
         create_swapchain_image_views(&self.device, &mut self.data)?;
 
-        create_render_pass(&self.instance, &self.device, &mut self.data)?;
-        create_pipeline(&self.device, &mut self.data)?;
-        create_color_objects(&self.instance, &self.device, &mut self.data)?;
-        create_depth_objects(&self.instance, &self.device, &mut self.data)?;
-
-        create_framebuffers(&self.device, &mut self.data)?;
-        create_uniform_buffers(&self.instance, &self.device, &mut self.data)?;
-        create_descriptor_pool(&self.device, &mut self.data)?;
-        create_descriptor_sets(&self.device, &mut self.data)?;
-        create_command_buffers(&self.device, &mut self.data)?;
+        create_frame_resources(&self.instance, &self.device, &mut self.data)?;
 
         self.data.images_in_flight.resize(self.data.swapchain_images.len(), vk::Fence::null());
 
         Ok(())
     }
 
-    unsafe fn destroy_swapchain(&mut self) {
-        self.device.destroy_image_view(self.data.color_image_view, None);
-        self.device.free_memory(self.data.color_image_memory, None);
-        self.device.destroy_image(self.data.color_image, None);
+    // Rebuilds the frame graph and everything created against it after a settings change
+    unsafe fn recreate_frame_resources(&mut self) -> Result<()> {
+        self.device.device_wait_idle()?;
 
-        self.device.destroy_image_view(self.data.depth_image_view, None);
-        self.device.free_memory(self.data.depth_image_memory, None);
-        self.device.destroy_image(self.data.depth_image, None);
+        destroy_frame_resources(&self.device, &self.data);
+        create_frame_resources(&self.instance, &self.device, &mut self.data)?;
 
-        self.device.destroy_descriptor_pool(self.data.descriptor_pool, None);
-        self.data.uniform_buffers
-            .iter()
-            .for_each(|b| self.device.destroy_buffer(*b, None));
-        self.data.uniform_buffers_memory
-            .iter()
-            .for_each(|m| self.device.free_memory(*m, None));
-
-        self.data.framebuffers
-            .iter()
-            .for_each(|f| self.device.destroy_framebuffer(*f, None));
+        Ok(())
+    }
 
-        self.device.destroy_pipeline(self.data.pipeline, None);
-        self.device.destroy_pipeline_layout(self.data.pipeline_layout, None);
-        self.device.destroy_render_pass(self.data.render_pass, None);
+    unsafe fn destroy_swapchain(&mut self) {
+        destroy_frame_resources(&self.device, &self.data);
 
         self.data.swapchain_image_views
             .iter()
@@ -431,18 +847,31 @@ impl Renderer {
 
         self.destroy_swapchain();
 
-        self.device.destroy_sampler(self.data.texture_sampler, None);
-        self.device.destroy_image_view(self.data.texture_image_view, None);
-        self.device.destroy_image(self.data.texture_image, None);
-        self.device.free_memory(self.data.texture_image_memory, None);
+        destroy_texture(&self.device, &self.data, &self.data.color_grading_lut);
+        self.device.destroy_pipeline_layout(self.data.post_pipeline_layout, None);
+        self.device.destroy_descriptor_set_layout(self.data.post_set_layout, None);
+
+        destroy_asset_loader(&self.device, &self.data, &self.assets);
+
+        self.data.materials
+            .iter()
+            .for_each(|m| destroy_material(&self.device, &self.data, m));
+        destroy_material_descriptor_set(&self.device, &self.data);
+        self.device.destroy_descriptor_set_layout(self.data.material_set_layout, None);
+
+        destroy_texture(&self.device, &self.data, &self.data.white_texture);
+        destroy_texture(&self.device, &self.data, &self.data.flat_normal_texture);
+        destroy_texture(&self.device, &self.data, &self.data.environment);
+        destroy_sampler_cache(&self.device, &self.data);
 
         self.device.destroy_descriptor_set_layout(self.data.descriptor_set_layout, None);
 
-        self.device.destroy_buffer(self.data.index_buffer, None);
-        self.device.free_memory(self.data.index_buffer_memory, None);
+        destroy_cull_pipeline(&self.device, &self.data);
+        destroy_particle_system(&self.device, &self.data);
+        destroy_frame_ring(&self.device, &self.data);
 
-        self.device.destroy_buffer(self.data.vertex_buffer, None);
-        self.device.free_memory(self.data.vertex_buffer_memory, None);
+        destroy_index_buffer(&self.device, &self.data);
+        destroy_vertex_buffer(&self.device, &self.data);
 
         self.data.in_flight_fences
             .iter()
@@ -455,9 +884,12 @@ impl Renderer {
             .for_each(|s| self.device.destroy_semaphore(*s, None));
         self.data.command_pools
             .iter()
+            .chain(self.data.thread_command_pools.iter().flatten())
             .for_each(|p| self.device.destroy_command_pool(*p, None));
 
+        destroy_upload_manager(&self.device, &self.data);
         self.device.destroy_command_pool(self.data.command_pool, None);
+        self.data.allocator.lock().unwrap().destroy(&self.device);
         self.device.destroy_device(None);
         self.instance.destroy_surface_khr(self.data.surface, None);
 
@@ -469,3 +901,38 @@ impl Renderer {
         self.instance.destroy_instance(None);
     }
 }
+
+fn get_eye_position(character: &Character) -> Vec3
+{
+    let view_angle = character.position - character.view_angle.to_vector() * 1.0;
+    Vec3::new(view_angle.x, view_angle.y, view_angle.z)
+}
+
+fn get_view_matrix(character: &Character) -> Mat4
+{
+    let view_angle = character.position - character.view_angle.to_vector() * 1.0;
+    Mat4::look_at_rh(
+        Point3::new(view_angle.x, view_angle.y, view_angle.z),
+        Point3::new(character.position.x, character.position.y, character.position.z),
+        Vec3::new( 0.0, 0.0, 1.0),
+    )
+}
+
+fn get_model_matrix(model_index: usize, character: &Character) -> Mat4
+{
+    let model = Mat4::from_axis_angle(
+        Vec3{x: 0.0, y:0.0, z:1.0},
+        Deg(90.0)
+    );
+
+    let mut translation = Vec3::new( character.position.x, character.position.y, character.position.z);
+    if model_index != 0
+    {
+        translation.x = 0.0;
+        translation.y = 0.0;
+        translation.z = 0.0;
+    }
+
+    let transformation = Mat4::from_translation(translation);
+    model * transformation
+}
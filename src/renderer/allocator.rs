@@ -0,0 +1,599 @@
+use std::ptr;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device};
+
+// Size of the device memory blocks resources are sub-allocated from, larger resources get a block of their own size
+pub const BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+/*
+    Resources that may not share a page of `bufferImageGranularity`. Buffers and linear
+    images are Linear, optimal tiling images are Optimal.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AllocationKind
+{
+    #[default]
+    Linear,
+    Optimal,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Strategy
+{
+    // Best fit over every free region, freed memory is reused right away
+    #[default]
+    FreeList,
+    // Allocates only after the last allocation, freed memory is reused once everything after it is freed too
+    Linear,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Allocation
+{
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    memory_type_index: u32,
+    strategy: Strategy,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MemoryStatistics
+{
+    pub block_count: usize,
+    pub allocation_count: usize,
+    // Device memory allocated from the driver
+    pub block_bytes: vk::DeviceSize,
+    // Bytes handed out to resources, the rest is free or alignment padding
+    pub allocated_bytes: vk::DeviceSize,
+    pub free_region_count: usize,
+    pub largest_free_region: vk::DeviceSize,
+}
+
+impl MemoryStatistics {
+    fn add(&mut self, other: &MemoryStatistics)
+    {
+        self.block_count += other.block_count;
+        self.allocation_count += other.allocation_count;
+        self.block_bytes += other.block_bytes;
+        self.allocated_bytes += other.allocated_bytes;
+        self.free_region_count += other.free_region_count;
+        self.largest_free_region = self.largest_free_region.max(other.largest_free_region);
+    }
+
+    pub fn free_bytes(&self) -> vk::DeviceSize
+    {
+        self.block_bytes - self.allocated_bytes
+    }
+
+    // Share of the free memory outside of the largest free region, 0 when it is all in one piece
+    pub fn fragmentation(&self) -> f32
+    {
+        let free_bytes = self.free_bytes();
+        if free_bytes == 0 {
+            return 0.0;
+        }
+
+        1.0 - self.largest_free_region as f32 / free_bytes as f32
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Region
+{
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    // None for free regions
+    kind: Option<AllocationKind>,
+}
+
+impl Region {
+    fn end(&self) -> vk::DeviceSize
+    {
+        self.offset + self.size
+    }
+}
+
+/*
+    Bookkeeping of a single block, without any Vulkan objects. Regions are sorted by offset
+    and cover the whole block, neighbouring free regions are always merged.
+ */
+#[derive(Clone, Debug)]
+pub struct BlockMetadata
+{
+    size: vk::DeviceSize,
+    strategy: Strategy,
+    granularity: vk::DeviceSize,
+    regions: Vec<Region>,
+}
+
+impl BlockMetadata {
+    pub fn new(size: vk::DeviceSize, strategy: Strategy, granularity: vk::DeviceSize) -> Self
+    {
+        Self {
+            size,
+            strategy,
+            granularity: granularity.max(1),
+            regions: vec![Region {offset: 0, size, kind: None}],
+        }
+    }
+
+    // Returns the offset of the new allocation, or None if it doesn't fit anywhere
+    pub fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize, kind: AllocationKind) -> Option<vk::DeviceSize>
+    {
+        let size = size.max(1);
+
+        let candidates = match self.strategy {
+            Strategy::FreeList => 0..self.regions.len(),
+            Strategy::Linear => self.regions.len() - 1..self.regions.len(),
+        };
+
+        let mut best: Option<(usize, vk::DeviceSize)> = None;
+        for index in candidates {
+            if self.regions[index].kind.is_some() {
+                continue;
+            }
+
+            let Some(offset) = self.fit(index, size, alignment, kind) else {
+                continue;
+            };
+
+            if best.is_none_or(|(b, _)| self.regions[index].size < self.regions[b].size) {
+                best = Some((index, offset));
+            }
+        }
+
+        let (index, offset) = best?;
+        self.split(index, offset, size, kind);
+
+        Some(offset)
+    }
+
+    // Returns false if no allocation starts at `offset`
+    pub fn free(&mut self, offset: vk::DeviceSize) -> bool
+    {
+        let Ok(index) = self.regions.binary_search_by_key(&offset, |r| r.offset) else {
+            return false;
+        };
+
+        if self.regions[index].kind.is_none() {
+            return false;
+        }
+
+        self.regions[index].kind = None;
+
+        if self.regions.get(index + 1).is_some_and(|r| r.kind.is_none()) {
+            self.regions[index].size += self.regions[index + 1].size;
+            self.regions.remove(index + 1);
+        }
+
+        if index > 0 && self.regions[index - 1].kind.is_none() {
+            self.regions[index - 1].size += self.regions[index].size;
+            self.regions.remove(index);
+        }
+
+        true
+    }
+
+    // Whether no allocation before `offset` is still alive, linear blocks expect to be freed in this order
+    pub fn is_oldest(&self, offset: vk::DeviceSize) -> bool
+    {
+        self.regions
+            .iter()
+            .take_while(|r| r.offset < offset)
+            .all(|r| r.kind.is_none())
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.regions.iter().all(|r| r.kind.is_none())
+    }
+
+    pub fn statistics(&self) -> MemoryStatistics
+    {
+        let used = self.regions.iter().filter(|r| r.kind.is_some());
+        let free = self.regions.iter().filter(|r| r.kind.is_none());
+
+        MemoryStatistics {
+            block_count: 1,
+            allocation_count: used.clone().count(),
+            block_bytes: self.size,
+            allocated_bytes: used.map(|r| r.size).sum(),
+            free_region_count: free.clone().count(),
+            largest_free_region: free.map(|r| r.size).max().unwrap_or(0),
+        }
+    }
+
+    // Offset an allocation would get inside the free region at `index`
+    fn fit(&self, index: usize, size: vk::DeviceSize, alignment: vk::DeviceSize, kind: AllocationKind) -> Option<vk::DeviceSize>
+    {
+        let region = self.regions[index];
+        let mut offset = align_up(region.offset, alignment.max(1));
+
+        // Moves to the next page if a resource of the other kind ends on the page the allocation starts on
+        let conflicts_before = self.regions[..index]
+            .iter()
+            .rev()
+            .take_while(|r| self.same_page(r.end() - 1, offset))
+            .any(|r| r.kind.is_some_and(|k| k != kind));
+
+        if conflicts_before {
+            offset = align_up(offset, self.granularity);
+        }
+
+        let end = offset.checked_add(size)?;
+        if end > region.end() {
+            return None;
+        }
+
+        // Resources after the region can't move, so the allocation doesn't fit if it shares a page with one of them
+        let conflicts_after = self.regions[index + 1..]
+            .iter()
+            .take_while(|r| self.same_page(end - 1, r.offset))
+            .any(|r| r.kind.is_some_and(|k| k != kind));
+
+        if conflicts_after {
+            return None;
+        }
+
+        Some(offset)
+    }
+
+    fn split(&mut self, index: usize, offset: vk::DeviceSize, size: vk::DeviceSize, kind: AllocationKind)
+    {
+        let region = self.regions[index];
+        let end = offset + size;
+
+        let mut regions = vec![];
+        if offset > region.offset {
+            regions.push(Region {offset: region.offset, size: offset - region.offset, kind: None});
+        }
+
+        regions.push(Region {offset, size, kind: Some(kind)});
+
+        if end < region.end() {
+            regions.push(Region {offset: end, size: region.end() - end, kind: None});
+        }
+
+        self.regions.splice(index..index + 1, regions);
+    }
+
+    fn same_page(&self, a: vk::DeviceSize, b: vk::DeviceSize) -> bool
+    {
+        a / self.granularity == b / self.granularity
+    }
+}
+
+#[derive(Debug)]
+struct Block
+{
+    memory: vk::DeviceMemory,
+    metadata: BlockMetadata,
+    // Host visible blocks are mapped in full on first use and stay mapped until the block is freed
+    mapped: *mut u8,
+}
+
+// The mapped pointer is only handed out for allocations, which keep the block alive
+unsafe impl Send for Block {}
+
+#[derive(Debug)]
+struct Pool
+{
+    memory_type_index: u32,
+    strategy: Strategy,
+    blocks: Vec<Block>,
+}
+
+/*
+    Sub-allocates resources from large blocks of device memory, one pool of blocks per
+    memory type and strategy. Keeps the number of vkAllocateMemory calls far below
+    `maxMemoryAllocationCount` no matter how many buffers and images are created.
+ */
+#[derive(Debug, Default)]
+pub struct Allocator
+{
+    buffer_image_granularity: vk::DeviceSize,
+    pools: Vec<Pool>,
+}
+
+impl Allocator {
+    pub fn new(buffer_image_granularity: vk::DeviceSize) -> Self
+    {
+        Self {
+            buffer_image_granularity,
+            pools: vec![],
+        }
+    }
+
+    pub unsafe fn allocate(&mut self, device: &Device, memory_type_index: u32, requirements: vk::MemoryRequirements, kind: AllocationKind, strategy: Strategy) -> Result<Allocation>
+    {
+        let granularity = self.buffer_image_granularity;
+
+        let index = match self.pools.iter().position(|p| p.memory_type_index == memory_type_index && p.strategy == strategy) {
+            Some(index) => index,
+            None => {
+                self.pools.push(Pool {memory_type_index, strategy, blocks: vec![]});
+                self.pools.len() - 1
+            }
+        };
+
+        let pool = &mut self.pools[index];
+
+        for block in &mut pool.blocks {
+            if let Some(offset) = block.metadata.allocate(requirements.size, requirements.alignment, kind) {
+                return Ok(Allocation {memory: block.memory, offset, size: requirements.size, memory_type_index, strategy});
+            }
+        }
+
+        let size = requirements.size.max(BLOCK_SIZE);
+        let info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+
+        let memory = device.allocate_memory(&info, None)?;
+
+        let mut metadata = BlockMetadata::new(size, strategy, granularity);
+        let offset = metadata
+            .allocate(requirements.size, requirements.alignment, kind)
+            .ok_or_else(|| anyhow!("Allocation of {} bytes doesn't fit into a new block.", requirements.size))?;
+
+        pool.blocks.push(Block {memory, metadata, mapped: ptr::null_mut()});
+
+        Ok(Allocation {memory, offset, size: requirements.size, memory_type_index, strategy})
+    }
+
+    pub unsafe fn free(&mut self, device: &Device, allocation: &Allocation)
+    {
+        // Default allocations belong to resources that were never created
+        if allocation.memory.is_null() {
+            return;
+        }
+
+        let Some(pool) = self.pools
+            .iter_mut()
+            .find(|p| p.memory_type_index == allocation.memory_type_index && p.strategy == allocation.strategy)
+        else {
+            warn!("Freed an allocation from an unknown memory pool.");
+            return;
+        };
+
+        let Some(index) = pool.blocks.iter().position(|b| b.memory == allocation.memory) else {
+            warn!("Freed an allocation from an unknown memory block.");
+            return;
+        };
+
+        // Memory freed out of order is only reused once everything after it is freed too, which may be never
+        if pool.strategy == Strategy::Linear && !pool.blocks[index].metadata.is_oldest(allocation.offset) {
+            warn!("Linear allocation at offset {} was freed before older allocations in its block.", allocation.offset);
+        }
+
+        if !pool.blocks[index].metadata.free(allocation.offset) {
+            warn!("Freed an allocation at offset {} that doesn't exist.", allocation.offset);
+            return;
+        }
+
+        // The last block of a pool is kept so that alternating allocations and frees don't reallocate device memory
+        if pool.blocks[index].metadata.is_empty() && pool.blocks.len() > 1 {
+            let block = pool.blocks.remove(index);
+            free_block(device, &block);
+        }
+    }
+
+    // Pointer to the start of a host visible allocation
+    pub unsafe fn map(&mut self, device: &Device, allocation: &Allocation) -> Result<*mut u8>
+    {
+        let block = self.pools
+            .iter_mut()
+            .filter(|p| p.memory_type_index == allocation.memory_type_index && p.strategy == allocation.strategy)
+            .flat_map(|p| p.blocks.iter_mut())
+            .find(|b| b.memory == allocation.memory)
+            .ok_or_else(|| anyhow!("Mapped an allocation that doesn't belong to the allocator."))?;
+
+        if block.mapped.is_null() {
+            block.mapped = device
+                .map_memory(block.memory, 0, vk::WHOLE_SIZE as vk::DeviceSize, vk::MemoryMapFlags::empty())?
+                .cast();
+        }
+
+        Ok(block.mapped.add(allocation.offset as usize))
+    }
+
+    pub fn statistics(&self) -> MemoryStatistics
+    {
+        let mut statistics = MemoryStatistics::default();
+
+        self.pools
+            .iter()
+            .flat_map(|p| p.blocks.iter())
+            .for_each(|b| statistics.add(&b.metadata.statistics()));
+
+        statistics
+    }
+
+    pub unsafe fn destroy(&mut self, device: &Device)
+    {
+        let statistics = self.statistics();
+        if statistics.allocation_count > 0 {
+            warn!("{} allocations ({} bytes) were not freed before destroying the allocator.", statistics.allocation_count, statistics.allocated_bytes);
+        }
+
+        self.pools
+            .drain(..)
+            .flat_map(|p| p.blocks)
+            .for_each(|b| free_block(device, &b));
+    }
+}
+
+unsafe fn free_block(device: &Device, block: &Block)
+{
+    if !block.mapped.is_null() {
+        device.unmap_memory(block.memory);
+    }
+
+    device.free_memory(block.memory, None);
+}
+
+fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize
+{
+    offset.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRANULARITY: vk::DeviceSize = 1024;
+
+    fn free_list(size: vk::DeviceSize) -> BlockMetadata
+    {
+        BlockMetadata::new(size, Strategy::FreeList, GRANULARITY)
+    }
+
+    #[test]
+    fn allocations_are_aligned()
+    {
+        let mut block = free_list(4096);
+
+        assert_eq!(block.allocate(10, 4, AllocationKind::Linear), Some(0));
+        assert_eq!(block.allocate(10, 64, AllocationKind::Linear), Some(64));
+        assert_eq!(block.allocate(10, 1, AllocationKind::Linear), Some(10));
+    }
+
+    #[test]
+    fn allocation_fails_when_full()
+    {
+        let mut block = free_list(256);
+
+        assert_eq!(block.allocate(200, 1, AllocationKind::Linear), Some(0));
+        assert_eq!(block.allocate(100, 1, AllocationKind::Linear), None);
+        assert_eq!(block.allocate(56, 1, AllocationKind::Linear), Some(200));
+        assert_eq!(block.allocate(1, 1, AllocationKind::Linear), None);
+    }
+
+    #[test]
+    fn freed_regions_are_merged()
+    {
+        let mut block = free_list(300);
+
+        let a = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        let b = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        let c = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+
+        assert!(block.free(a));
+        assert!(block.free(c));
+        assert_eq!(block.statistics().free_region_count, 2);
+
+        assert!(block.free(b));
+        assert!(block.is_empty());
+        assert_eq!(block.statistics().free_region_count, 1);
+        assert_eq!(block.allocate(300, 1, AllocationKind::Linear), Some(0));
+    }
+
+    #[test]
+    fn freeing_twice_is_rejected()
+    {
+        let mut block = free_list(256);
+
+        let a = block.allocate(64, 1, AllocationKind::Linear).unwrap();
+        assert!(block.free(a));
+        assert!(!block.free(a));
+        assert!(!block.free(13));
+    }
+
+    #[test]
+    fn free_list_picks_the_smallest_fitting_region()
+    {
+        let mut block = free_list(1000);
+
+        let large = block.allocate(300, 1, AllocationKind::Linear).unwrap();
+        block.allocate(10, 1, AllocationKind::Linear).unwrap();
+        let small = block.allocate(50, 1, AllocationKind::Linear).unwrap();
+        block.allocate(10, 1, AllocationKind::Linear).unwrap();
+
+        block.free(large);
+        block.free(small);
+
+        // The 50 byte hole fits better than the 300 byte one or the rest of the block
+        assert_eq!(block.allocate(40, 1, AllocationKind::Linear), Some(small));
+        assert_eq!(block.allocate(200, 1, AllocationKind::Linear), Some(large));
+    }
+
+    #[test]
+    fn linear_strategy_only_allocates_at_the_end()
+    {
+        let mut block = BlockMetadata::new(300, Strategy::Linear, GRANULARITY);
+
+        let a = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        let b = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        let c = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+
+        // The hole left by `a` is not reused while later allocations are alive
+        assert!(block.is_oldest(a));
+        assert!(!block.is_oldest(c));
+        block.free(a);
+        assert_eq!(block.allocate(50, 1, AllocationKind::Linear), None);
+
+        block.free(c);
+        assert_eq!(block.allocate(50, 1, AllocationKind::Linear), Some(c));
+
+        block.free(c);
+        block.free(b);
+        assert!(block.is_empty());
+        assert_eq!(block.allocate(300, 1, AllocationKind::Linear), Some(0));
+    }
+
+    #[test]
+    fn different_kinds_are_kept_on_separate_pages()
+    {
+        let mut block = free_list(4 * GRANULARITY);
+
+        assert_eq!(block.allocate(100, 4, AllocationKind::Linear), Some(0));
+        assert_eq!(block.allocate(100, 4, AllocationKind::Optimal), Some(GRANULARITY));
+        assert_eq!(block.allocate(100, 4, AllocationKind::Optimal), Some(GRANULARITY + 100));
+
+        // Buffers can still use the padding left on the first page, or start after the images' page
+        assert_eq!(block.allocate(100, 4, AllocationKind::Linear), Some(100));
+        assert_eq!(block.allocate(GRANULARITY, 4, AllocationKind::Linear), Some(2 * GRANULARITY));
+    }
+
+    #[test]
+    fn allocations_sharing_a_page_with_a_later_resource_are_rejected()
+    {
+        let mut block = free_list(2 * GRANULARITY);
+
+        let hole = block.allocate(100, 1, AllocationKind::Optimal).unwrap();
+        let image = block.allocate(100, 1, AllocationKind::Optimal).unwrap();
+        assert_eq!(image, 100);
+
+        // A buffer in the hole would end on the page the image starts on
+        block.free(hole);
+        assert_eq!(block.allocate(50, 1, AllocationKind::Linear), Some(GRANULARITY));
+        assert_eq!(block.allocate(50, 1, AllocationKind::Optimal), Some(hole));
+    }
+
+    #[test]
+    fn statistics_report_fragmentation()
+    {
+        let mut block = free_list(400);
+
+        let a = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        let c = block.allocate(100, 1, AllocationKind::Linear).unwrap();
+        block.allocate(100, 1, AllocationKind::Linear).unwrap();
+
+        assert_eq!(block.statistics().fragmentation(), 0.0);
+
+        block.free(a);
+        block.free(c);
+
+        let statistics = block.statistics();
+        assert_eq!(statistics.allocation_count, 2);
+        assert_eq!(statistics.allocated_bytes, 200);
+        assert_eq!(statistics.free_bytes(), 200);
+        assert_eq!(statistics.free_region_count, 2);
+        assert_eq!(statistics.largest_free_region, 100);
+        assert_eq!(statistics.fragmentation(), 0.5);
+    }
+}
@@ -0,0 +1,417 @@
+use std::{collections::{HashMap, HashSet}, io::{BufReader, Cursor}, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use vulkanalia::{vk::{self, DeviceV1_0}, Device, Instance};
+
+use crate::math::vector::{Vector2, Vector3, Vector4};
+
+use super::{
+    buffer::{create_index_buffer, create_vertex_buffer, destroy_index_buffer, destroy_vertex_buffer},
+    bundle::{Bundle, BUNDLE_PATH},
+    culling::Bounds,
+    gltf_import::parse_gltf,
+    image::{create_texture_from_image, decode_image, destroy_texture, DecodedImage, Texture},
+    jobs::{JobHandle, JobPool},
+    lod::{generate_lods, Lod},
+    material::{set_material_texture, MaterialDescription, MaterialTexture},
+    mesh_cache::{get_mesh_cache_path, hash_file, read_mesh_cache, write_mesh_cache},
+    optimize::{optimize_mesh, OptimizeSettings, Quantization},
+    texture_compression::decompress_unsupported,
+    upload::wait_for_uploads,
+    vertex::{generate_tangents, Vertex},
+    vfs::Vfs,
+    RenderData,
+};
+
+pub const MODEL_PATH: &str = "resources/viking_room.obj";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssetStatus
+{
+    Loading,
+    Ready,
+    // The placeholder stays in use, the message says why loading failed
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Mesh
+{
+    pub vertices: Vec<Vertex>,
+    // Every level of detail after one another, see `lods`
+    pub indices: Vec<u32>,
+    pub lods: Vec<Lod>,
+    pub bounds: Bounds,
+    // Set when positions and texture coordinates were quantized, baked meshes then store them in 16 bits
+    pub quantization: Option<Quantization>,
+}
+
+#[derive(Debug)]
+enum PendingAsset
+{
+    Mesh {path: String, job: JobHandle<Mesh>},
+    Texture {path: String, material: usize, slot: MaterialTexture, job: JobHandle<DecodedImage>},
+    // Decoded and created, replaces the placeholder once the upload is complete
+    TextureUpload {path: String, material: usize, slot: MaterialTexture, texture: Texture},
+}
+
+/*
+    Decodes meshes and images on worker threads while placeholders are drawn in their place.
+    Results are picked up by `update_assets` at the start of a frame, which does the Vulkan
+    side on the render thread. Every asset is tracked by its path so that the game can poll
+    for it, a failed asset is reported and leaves its placeholder in place. Assets found in
+    the baked bundle are read from it instead of their source files. Block compressed textures
+    the device can't sample are decompressed on the worker as well.
+ */
+#[derive(Debug)]
+pub struct AssetLoader
+{
+    jobs: JobPool,
+    vfs: Arc<Vfs>,
+    bundle: Option<Arc<Bundle>>,
+    block_formats: Arc<HashSet<vk::Format>>,
+    pending: Vec<PendingAsset>,
+    statuses: HashMap<String, AssetStatus>,
+}
+
+impl AssetLoader {
+    pub fn new(vfs: Arc<Vfs>, block_formats: HashSet<vk::Format>) -> Result<Self>
+    {
+        Ok(Self {
+            jobs: JobPool::with_default_workers()?,
+            bundle: open_bundle(&vfs, BUNDLE_PATH).map(Arc::new),
+            vfs,
+            block_formats: Arc::new(block_formats),
+            pending: Vec::new(),
+            statuses: HashMap::new(),
+        })
+    }
+
+    // Replaces the scene mesh once the file has been parsed
+    pub fn load_mesh(&mut self, path: &str)
+    {
+        let owned_path = path.to_string();
+        let (vfs, bundle) = (self.vfs.clone(), self.bundle.clone());
+        let job = self.jobs.spawn(move || match bundle.as_ref().and_then(|b| b.mesh(&vfs, &owned_path)) {
+            Some(mesh) => mesh,
+            None => load_mesh(&vfs, &owned_path),
+        });
+
+        self.statuses.insert(path.to_string(), AssetStatus::Loading);
+        self.pending.push(PendingAsset::Mesh {path: path.to_string(), job});
+    }
+
+    pub fn load_material_textures(&mut self, material: usize, description: &MaterialDescription)
+    {
+        for slot in MaterialTexture::ALL {
+            let Some(path) = description.texture(slot) else {
+                continue;
+            };
+
+            let owned_path = path.to_string();
+            let (vfs, bundle, block_formats) = (self.vfs.clone(), self.bundle.clone(), self.block_formats.clone());
+            let job = self.jobs.spawn(move || {
+                let image = match bundle.as_ref().and_then(|b| b.texture(&vfs, &owned_path)) {
+                    Some(image) => image?,
+                    None => decode_image(&vfs, &owned_path)?,
+                };
+
+                Ok(decompress_unsupported(image, slot.format(), &block_formats))
+            });
+
+            self.statuses.insert(path.to_string(), AssetStatus::Loading);
+            self.pending.push(PendingAsset::Texture {path: path.to_string(), material, slot, job});
+        }
+    }
+
+    pub fn status(&self, path: &str) -> Option<&AssetStatus>
+    {
+        self.statuses.get(path)
+    }
+
+    pub fn is_loading(&self) -> bool
+    {
+        !self.pending.is_empty()
+    }
+
+    fn finish(&mut self, path: &str)
+    {
+        info!("Loaded `{}`.", path);
+        self.statuses.insert(path.to_string(), AssetStatus::Ready);
+    }
+
+    fn fail(&mut self, path: &str, error: anyhow::Error)
+    {
+        error!("Failed to load `{}`: {:#}", path, error);
+        self.statuses.insert(path.to_string(), AssetStatus::Failed(format!("{:#}", error)));
+    }
+}
+
+// A missing bundle is normal during development, everything is then loaded from the sources
+fn open_bundle(vfs: &Vfs, path: &str) -> Option<Bundle>
+{
+    if !vfs.exists(path) {
+        return None;
+    }
+
+    match Bundle::open(vfs, path) {
+        Ok(bundle) => {
+            info!("Loaded asset bundle `{}`.", path);
+            Some(bundle)
+        },
+        Err(e) => {
+            warn!("Ignoring asset bundle `{}`: {:#}", path, e);
+            None
+        },
+    }
+}
+
+// Hands finished jobs to the GPU and swaps uploaded assets in for their placeholders
+pub unsafe fn update_assets(instance: &Instance, device: &Device, data: &mut RenderData, assets: &mut AssetLoader) -> Result<()>
+{
+    // Descriptor sets and buffers being replaced are still read by frames in flight
+    let mut device_idle = false;
+
+    for asset in std::mem::take(&mut assets.pending) {
+        match asset {
+            PendingAsset::Mesh {path, job} => match job.poll() {
+                None => assets.pending.push(PendingAsset::Mesh {path, job}),
+                Some(Ok(mesh)) => {
+                    if !device_idle {
+                        device.device_wait_idle()?;
+                        device_idle = true;
+                    }
+
+                    replace_mesh(instance, device, data, mesh)?;
+                    assets.finish(&path);
+                },
+                Some(Err(e)) => assets.fail(&path, e),
+            },
+            PendingAsset::Texture {path, material, slot, job} => match job.poll() {
+                None => assets.pending.push(PendingAsset::Texture {path, material, slot, job}),
+                // A texture the device can't create fails on its own, the assets queued behind it still load
+                Some(Ok(image)) => match create_texture_from_image(instance, device, data, &image, slot.format()) {
+                    Ok(texture) => assets.pending.push(PendingAsset::TextureUpload {path, material, slot, texture}),
+                    Err(e) => assets.fail(&path, e),
+                },
+                Some(Err(e)) => assets.fail(&path, e),
+            },
+            PendingAsset::TextureUpload {path, material, slot, texture} => {
+                if !data.uploads.is_complete(texture.upload) {
+                    assets.pending.push(PendingAsset::TextureUpload {path, material, slot, texture});
+                    continue;
+                }
+
+                if !device_idle {
+                    device.device_wait_idle()?;
+                    device_idle = true;
+                }
+
+                // Fails when the texture array is full, the material keeps sampling the default
+                match set_material_texture(device, data, material, slot, texture) {
+                    Ok(()) => assets.finish(&path),
+                    Err(e) => assets.fail(&path, e),
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+// Expects the device to be idle
+pub unsafe fn destroy_asset_loader(device: &Device, data: &RenderData, assets: &AssetLoader)
+{
+    for asset in &assets.pending {
+        if let PendingAsset::TextureUpload {texture, ..} = asset {
+            destroy_texture(device, data, texture);
+        }
+    }
+}
+
+// Expects the device to be idle, the new mesh is uploaded before returning so that no frame is drawn without one
+unsafe fn replace_mesh(instance: &Instance, device: &Device, data: &mut RenderData, mesh: Mesh) -> Result<()>
+{
+    // The old buffers may still be waiting on their own upload
+    wait_for_uploads(device, data)?;
+
+    destroy_vertex_buffer(device, data);
+    destroy_index_buffer(device, data);
+
+    data.vertices = mesh.vertices;
+    data.indices = mesh.indices;
+    data.mesh_bounds = mesh.bounds;
+    data.mesh_lods = mesh.lods;
+
+    create_vertex_buffer(instance, device, data)?;
+    create_index_buffer(instance, device, data)?;
+    wait_for_uploads(device, data)?;
+
+    Ok(())
+}
+
+// Reads the baked mesh if it is up to date, otherwise parses the source and bakes it for the next launch
+pub fn load_mesh(vfs: &Vfs, path: &str) -> Result<Mesh>
+{
+    // Sources packed into an archive are never cached, they should be baked into the bundle instead
+    let Some(cache_path) = vfs.real_path(path).map(|p| get_mesh_cache_path(&p)) else {
+        return parse_mesh(vfs, path, &OptimizeSettings::default());
+    };
+
+    let source_hash = hash_file(vfs, path)?;
+
+    match read_mesh_cache(&cache_path, source_hash) {
+        Ok(Some(mesh)) => return Ok(mesh),
+        Ok(None) => {},
+        Err(e) => warn!("Ignoring mesh cache `{}`: {:#}", cache_path.display(), e),
+    }
+
+    let mesh = parse_mesh(vfs, path, &OptimizeSettings::default())?;
+
+    // Not being able to write the cache only costs the next launch some time
+    if let Err(e) = write_mesh_cache(&cache_path, &mesh, source_hash) {
+        warn!("Failed to write mesh cache `{}`: {:#}", cache_path.display(), e);
+    }
+
+    Ok(mesh)
+}
+
+// Parses and processes an OBJ or glTF file without looking at any cache, also used when baking bundles
+pub fn parse_mesh(vfs: &Vfs, path: &str, settings: &OptimizeSettings) -> Result<Mesh>
+{
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let mut mesh = match extension.as_str() {
+        "gltf" | "glb" => parse_gltf(vfs, path)?,
+        _ => parse_obj(vfs, path)?,
+    };
+
+    generate_tangents(&mut mesh.vertices, &mesh.indices);
+    mesh.bounds = Bounds::from_vertices(&mesh.vertices);
+    (mesh.indices, mesh.lods) = generate_lods(&mesh.vertices, &mesh.indices, &mesh.bounds);
+
+    let statistics = optimize_mesh(&mut mesh, settings);
+    for (lod, level) in statistics.levels.iter().enumerate() {
+        info!("`{}` LOD {}: ACMR {:.3} -> {:.3}.", path, lod, level.acmr_before, level.acmr_after);
+    }
+
+    if let Some(error) = statistics.quantization_error {
+        info!("`{}` quantized with an error of up to {}.", path, error);
+    }
+
+    Ok(mesh)
+}
+
+// Models are merged into one mesh, with duplicate vertices shared
+fn parse_obj(vfs: &Vfs, path: &str) -> Result<Mesh>
+{
+    let mut reader = BufReader::new(Cursor::new(vfs.read(path)?));
+
+    let (models, _) = tobj::load_obj_buf(
+        &mut reader,
+        &tobj::LoadOptions{
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        },
+        |_| Ok(Default::default()),
+    )?;
+
+    let mut mesh = Mesh::default();
+    let mut unique_vertices = HashMap::new();
+
+    for model in &models {
+        if model.mesh.texcoords.is_empty() {
+            return Err(anyhow!("Model `{}` in `{}` has no texture coordinates.", model.name, path));
+        }
+
+        for index in &model.mesh.indices {
+            let pos_offset = (3 * index) as usize;
+            let tex_coord_offset = (2 * index) as usize;
+            let vertex = Vertex {
+                position: Vector3::new(
+                    model.mesh.positions[pos_offset],
+                    model.mesh.positions[pos_offset + 1],
+                    model.mesh.positions[pos_offset + 2],
+                ),
+                color: Vector3::new(
+                    1.0,
+                    1.0,
+                     1.0
+                ),
+                tex_coord: Vector2::new(
+                    model.mesh.texcoords[tex_coord_offset],
+                    1.0 - model.mesh.texcoords[tex_coord_offset + 1],
+                ),
+                normal: if model.mesh.normals.is_empty() {
+                    Vector3::new(0.0, 0.0, 1.0)
+                } else {
+                    Vector3::new(
+                        model.mesh.normals[pos_offset],
+                        model.mesh.normals[pos_offset + 1],
+                        model.mesh.normals[pos_offset + 2],
+                    )
+                },
+                tangent: Vector4::new(0.0, 0.0, 0.0, 1.0),
+            };
+
+            if let Some(index) = unique_vertices.get(&vertex) {
+                mesh.indices.push(*index as u32);
+            } else {
+                let index = mesh.vertices.len();
+                unique_vertices.insert(vertex, index);
+
+                mesh.vertices.push(vertex);
+                mesh.indices.push(index as u32);
+            }
+        };
+    }
+
+    Ok(mesh)
+}
+
+// Unit cube drawn while the real mesh is loading, or in its place if loading failed
+pub fn get_placeholder_mesh() -> Mesh
+{
+    // Normal and the two axes spanning each face, ordered so that the corners wind counter-clockwise seen from outside
+    let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+        ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0], [1.0, 0.0, 0.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ];
+
+    let mut mesh = Mesh::default();
+
+    for (normal, u, v) in faces {
+        let first = mesh.vertices.len() as u32;
+
+        for (su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+            let corner = |axis: usize| 0.5 * (normal[axis] + su * u[axis] + sv * v[axis]);
+
+            mesh.vertices.push(Vertex::new(
+                Vector3::new(corner(0), corner(1), corner(2)),
+                Vector3::new(1.0, 1.0, 1.0),
+                Vector2::new((su + 1.0) * 0.5, 1.0 - (sv + 1.0) * 0.5),
+                Vector3::new(normal[0], normal[1], normal[2]),
+                Vector4::new(0.0, 0.0, 0.0, 1.0),
+            ));
+        }
+
+        mesh.indices.extend([first, first + 1, first + 2, first + 2, first + 3, first]);
+    }
+
+    generate_tangents(&mut mesh.vertices, &mesh.indices);
+    mesh.bounds = Bounds::from_vertices(&mesh.vertices);
+    (mesh.indices, mesh.lods) = generate_lods(&mesh.vertices, &mesh.indices, &mesh.bounds);
+    optimize_mesh(&mut mesh, &OptimizeSettings::default());
+
+    mesh
+}
@@ -0,0 +1,338 @@
+use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder}, Device};
+
+/*
+    Image memory barrier whose stage and access masks are derived from the layouts. The
+    defaults cover the whole image with the aspects of its format, every part can be
+    narrowed or overridden before recording.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct ImageBarrier
+{
+    pub image: vk::Image,
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+    pub subresource_range: vk::ImageSubresourceRange,
+    pub src_stage_mask: vk::PipelineStageFlags,
+    pub src_access_mask: vk::AccessFlags,
+    pub dst_stage_mask: vk::PipelineStageFlags,
+    pub dst_access_mask: vk::AccessFlags,
+    pub src_queue_family_index: u32,
+    pub dst_queue_family_index: u32,
+}
+
+impl ImageBarrier {
+    pub fn new(image: vk::Image, format: vk::Format, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout) -> Self
+    {
+        let (src_stage_mask, src_access_mask) = get_src_masks(old_layout);
+        let (dst_stage_mask, dst_access_mask) = get_dst_masks(new_layout);
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(get_barrier_aspects(format))
+            .base_mip_level(0)
+            .level_count(vk::REMAINING_MIP_LEVELS)
+            .base_array_layer(0)
+            .layer_count(vk::REMAINING_ARRAY_LAYERS)
+            .build();
+
+        Self {
+            image,
+            old_layout,
+            new_layout,
+            subresource_range,
+            src_stage_mask,
+            src_access_mask,
+            dst_stage_mask,
+            dst_access_mask,
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        }
+    }
+
+    pub fn with_mip_levels(mut self, base_mip_level: u32, level_count: u32) -> Self
+    {
+        self.subresource_range.base_mip_level = base_mip_level;
+        self.subresource_range.level_count = level_count;
+        self
+    }
+
+    pub fn with_layers(mut self, base_array_layer: u32, layer_count: u32) -> Self
+    {
+        self.subresource_range.base_array_layer = base_array_layer;
+        self.subresource_range.layer_count = layer_count;
+        self
+    }
+
+    pub fn with_aspects(mut self, aspect_mask: vk::ImageAspectFlags) -> Self
+    {
+        self.subresource_range.aspect_mask = aspect_mask;
+        self
+    }
+
+    // For accesses the layout alone doesn't describe, such as images sampled from a compute shader
+    pub fn with_src(mut self, stage_mask: vk::PipelineStageFlags, access_mask: vk::AccessFlags) -> Self
+    {
+        self.src_stage_mask = stage_mask;
+        self.src_access_mask = access_mask;
+        self
+    }
+
+    pub fn with_dst(mut self, stage_mask: vk::PipelineStageFlags, access_mask: vk::AccessFlags) -> Self
+    {
+        self.dst_stage_mask = stage_mask;
+        self.dst_access_mask = access_mask;
+        self
+    }
+
+    // Release or acquire half of a queue family ownership transfer
+    pub fn with_queue_families(mut self, src_queue_family_index: u32, dst_queue_family_index: u32) -> Self
+    {
+        self.src_queue_family_index = src_queue_family_index;
+        self.dst_queue_family_index = dst_queue_family_index;
+        self
+    }
+
+    fn build(&self) -> vk::ImageMemoryBarrier
+    {
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(self.old_layout)
+            .new_layout(self.new_layout)
+            .src_queue_family_index(self.src_queue_family_index)
+            .dst_queue_family_index(self.dst_queue_family_index)
+            .image(self.image)
+            .subresource_range(self.subresource_range)
+            .src_access_mask(self.src_access_mask)
+            .dst_access_mask(self.dst_access_mask)
+            .build()
+    }
+}
+
+// Records every barrier with a single command, waiting on the union of their stages
+pub unsafe fn record_image_barriers(device: &Device, command_buffer: vk::CommandBuffer, barriers: &[ImageBarrier])
+{
+    if barriers.is_empty() {
+        return;
+    }
+
+    let src_stage_mask = barriers
+        .iter()
+        .fold(vk::PipelineStageFlags::empty(), |mask, b| mask | b.src_stage_mask);
+
+    let dst_stage_mask = barriers
+        .iter()
+        .fold(vk::PipelineStageFlags::empty(), |mask, b| mask | b.dst_stage_mask);
+
+    let image_barriers = barriers
+        .iter()
+        .map(|b| b.build())
+        .collect::<Vec<_>>();
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src_stage_mask,
+        dst_stage_mask,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &image_barriers
+    );
+}
+
+/*
+    Stages and accesses that may have used an image in a layout. Shader reads assume the
+    graphics stages, unknown layouts fall back to a full barrier.
+ */
+fn get_layout_masks(layout: vk::ImageLayout) -> (vk::PipelineStageFlags, vk::AccessFlags)
+{
+    let shader_stages = vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER;
+    let depth_stages = vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+
+    match layout {
+        vk::ImageLayout::UNDEFINED => (
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::AccessFlags::empty(),
+        ),
+        vk::ImageLayout::PREINITIALIZED => (
+            vk::PipelineStageFlags::HOST,
+            vk::AccessFlags::HOST_WRITE,
+        ),
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => (
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_READ,
+        ),
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL => (
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+        ),
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => (
+            shader_stages,
+            vk::AccessFlags::SHADER_READ,
+        ),
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL => (
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        ),
+        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        | vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL
+        | vk::ImageLayout::STENCIL_ATTACHMENT_OPTIMAL
+        | vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL
+        | vk::ImageLayout::DEPTH_READ_ONLY_STENCIL_ATTACHMENT_OPTIMAL => (
+            depth_stages,
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        ),
+        // Read only depth can be tested against and sampled at the same time
+        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        | vk::ImageLayout::DEPTH_READ_ONLY_OPTIMAL
+        | vk::ImageLayout::STENCIL_READ_ONLY_OPTIMAL => (
+            depth_stages | shader_stages,
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::SHADER_READ,
+        ),
+        // Storage images and anything else, GENERAL allows every kind of access
+        _ => (
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+        ),
+    }
+}
+
+// Leaving the present layout chains with the acquire semaphore, which is waited on at COLOR_ATTACHMENT_OUTPUT
+pub fn get_src_masks(layout: vk::ImageLayout) -> (vk::PipelineStageFlags, vk::AccessFlags)
+{
+    match layout {
+        vk::ImageLayout::PRESENT_SRC_KHR => (vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags::empty()),
+        _ => get_layout_masks(layout),
+    }
+}
+
+// Presentation is synchronized by the semaphore signalled on submit, nothing inside the queue waits for it
+pub fn get_dst_masks(layout: vk::ImageLayout) -> (vk::PipelineStageFlags, vk::AccessFlags)
+{
+    match layout {
+        vk::ImageLayout::PRESENT_SRC_KHR => (vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::AccessFlags::empty()),
+        _ => get_layout_masks(layout),
+    }
+}
+
+pub fn is_depth_format(format: vk::Format) -> bool
+{
+    matches!(
+        format,
+        vk::Format::D16_UNORM
+            | vk::Format::X8_D24_UNORM_PACK32
+            | vk::Format::D32_SFLOAT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool
+{
+    matches!(
+        format,
+        vk::Format::S8_UINT | vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+// Sampled depth views only see the depth aspect
+pub fn get_view_aspects(format: vk::Format) -> vk::ImageAspectFlags
+{
+    if is_depth_format(format) {
+        vk::ImageAspectFlags::DEPTH
+    } else if has_stencil_component(format) {
+        vk::ImageAspectFlags::STENCIL
+    } else {
+        vk::ImageAspectFlags::COLOR
+    }
+}
+
+// Layout transitions of combined formats have to include both aspects
+pub fn get_barrier_aspects(format: vk::Format) -> vk::ImageAspectFlags
+{
+    let mut aspects = vk::ImageAspectFlags::empty();
+
+    if is_depth_format(format) {
+        aspects |= vk::ImageAspectFlags::DEPTH;
+    }
+
+    if has_stencil_component(format) {
+        aspects |= vk::ImageAspectFlags::STENCIL;
+    }
+
+    if aspects.is_empty() {
+        vk::ImageAspectFlags::COLOR
+    } else {
+        aspects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Stages = vk::PipelineStageFlags;
+    type Access = vk::AccessFlags;
+
+    #[test]
+    fn layouts_map_to_the_stages_and_accesses_that_use_them()
+    {
+        let depth_stages = Stages::EARLY_FRAGMENT_TESTS | Stages::LATE_FRAGMENT_TESTS;
+        let shader_stages = Stages::VERTEX_SHADER | Stages::FRAGMENT_SHADER;
+
+        let table = [
+            (vk::ImageLayout::UNDEFINED, Stages::TOP_OF_PIPE, Access::empty()),
+            (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, Stages::TRANSFER, Access::TRANSFER_READ),
+            (vk::ImageLayout::TRANSFER_DST_OPTIMAL, Stages::TRANSFER, Access::TRANSFER_WRITE),
+            (vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, shader_stages, Access::SHADER_READ),
+            (vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, Stages::COLOR_ATTACHMENT_OUTPUT, Access::COLOR_ATTACHMENT_READ | Access::COLOR_ATTACHMENT_WRITE),
+            (vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL, depth_stages, Access::DEPTH_STENCIL_ATTACHMENT_READ | Access::DEPTH_STENCIL_ATTACHMENT_WRITE),
+            (vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL, depth_stages, Access::DEPTH_STENCIL_ATTACHMENT_READ | Access::DEPTH_STENCIL_ATTACHMENT_WRITE),
+            (vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL, depth_stages | shader_stages, Access::DEPTH_STENCIL_ATTACHMENT_READ | Access::SHADER_READ),
+            (vk::ImageLayout::GENERAL, Stages::ALL_COMMANDS, Access::MEMORY_READ | Access::MEMORY_WRITE),
+        ];
+
+        for (layout, stages, access) in table {
+            assert_eq!(get_layout_masks(layout), (stages, access), "{:?}", layout);
+
+            // Only presenting differs between the two sides of a barrier
+            assert_eq!(get_src_masks(layout), get_dst_masks(layout), "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn presenting_waits_on_the_acquire_stage_and_nothing_waits_for_it()
+    {
+        assert_eq!(get_src_masks(vk::ImageLayout::PRESENT_SRC_KHR), (Stages::COLOR_ATTACHMENT_OUTPUT, Access::empty()));
+        assert_eq!(get_dst_masks(vk::ImageLayout::PRESENT_SRC_KHR), (Stages::BOTTOM_OF_PIPE, Access::empty()));
+    }
+
+    #[test]
+    fn formats_get_the_aspects_they_have()
+    {
+        let table = [
+            (vk::Format::R8G8B8A8_SRGB, false, vk::ImageAspectFlags::COLOR, vk::ImageAspectFlags::COLOR),
+            (vk::Format::D32_SFLOAT, true, vk::ImageAspectFlags::DEPTH, vk::ImageAspectFlags::DEPTH),
+            (vk::Format::D16_UNORM, true, vk::ImageAspectFlags::DEPTH, vk::ImageAspectFlags::DEPTH),
+            (vk::Format::D24_UNORM_S8_UINT, true, vk::ImageAspectFlags::DEPTH, vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL),
+            (vk::Format::D32_SFLOAT_S8_UINT, true, vk::ImageAspectFlags::DEPTH, vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL),
+            (vk::Format::S8_UINT, false, vk::ImageAspectFlags::STENCIL, vk::ImageAspectFlags::STENCIL),
+        ];
+
+        for (format, depth, view_aspects, barrier_aspects) in table {
+            assert_eq!(is_depth_format(format), depth, "{:?}", format);
+            assert_eq!(get_view_aspects(format), view_aspects, "{:?}", format);
+            assert_eq!(get_barrier_aspects(format), barrier_aspects, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn barriers_cover_the_whole_image_with_masks_from_the_layouts()
+    {
+        let barrier = ImageBarrier::new(vk::Image::default(), vk::Format::D24_UNORM_S8_UINT, vk::ImageLayout::UNDEFINED, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+        assert_eq!((barrier.src_stage_mask, barrier.src_access_mask), (Stages::TOP_OF_PIPE, Access::empty()));
+        assert_eq!(barrier.dst_stage_mask, Stages::EARLY_FRAGMENT_TESTS | Stages::LATE_FRAGMENT_TESTS);
+        assert_eq!(barrier.subresource_range.aspect_mask, vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL);
+        assert_eq!(barrier.subresource_range.level_count, vk::REMAINING_MIP_LEVELS);
+        assert_eq!(barrier.subresource_range.layer_count, vk::REMAINING_ARRAY_LAYERS);
+    }
+}
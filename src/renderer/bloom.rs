@@ -0,0 +1,219 @@
+use anyhow::Result;
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device};
+
+use super::{frame::{FramePass, HDR_IMAGE}, graph::{Attachment, ImageDescription, RenderGraph, ResourceId}, light::Vec4, post::{create_fullscreen_pipeline, PostPushConstants, HDR_FORMAT}, RenderData};
+
+pub const MAX_BLOOM_MIPS: u32 = 8;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BloomSettings
+{
+    pub enabled: bool,
+    // Luminance above which pixels start contributing to the bloom
+    pub threshold: f32,
+    // Width of the soft transition around the threshold, 0.0 gives a hard cut
+    pub knee: f32,
+    pub intensity: f32,
+    // Scale of the upsampling tent filter in source texels
+    pub radius: f32,
+    // Number of half resolution steps, clamped to MAX_BLOOM_MIPS and the swapchain size
+    pub mip_count: u32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self
+    {
+        Self {
+            enabled: true,
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.05,
+            radius: 1.0,
+            mip_count: 6,
+        }
+    }
+}
+
+// Every step reads one image and renders into another, see `add_bloom_passes` for the order
+#[derive(Copy, Clone, Debug)]
+pub struct BloomStep
+{
+    pub pipeline: vk::Pipeline,
+    pub descriptor_set: vk::DescriptorSet,
+    pub push_constants: PostPushConstants,
+}
+
+fn get_bloom_image_name(mip: usize) -> String
+{
+    format!("bloom {}", mip)
+}
+
+/*
+    The bright pass is folded into the first downsample, each following step halves the
+    resolution again. The chain is then walked back up, adding every level onto the next
+    larger one, and the largest level is finally added onto the HDR target.
+ */
+pub fn add_bloom_passes(graph: &mut RenderGraph<FramePass>, data: &mut RenderData, hdr: ResourceId)
+{
+    let max_mips = (data.swapchain_extent.width.min(data.swapchain_extent.height) as f32).log2().floor() as u32;
+    let mip_count = data.bloom_settings.mip_count.clamp(1, MAX_BLOOM_MIPS.min(max_mips.max(1)));
+
+    let mut extent = data.swapchain_extent;
+    let mut mips = vec![];
+    for mip in 0..mip_count as usize {
+        extent = vk::Extent2D {width: (extent.width / 2).max(1), height: (extent.height / 2).max(1)};
+
+        mips.push(graph.create_image(&get_bloom_image_name(mip), ImageDescription::new(HDR_FORMAT, extent)));
+        data.bloom_extents.push(extent);
+    }
+
+    let mut step = 0;
+    let mut input = hdr;
+    for mip in &mips {
+        graph.add_pass(FramePass::Bloom(step))
+            .sample(input)
+            .color(Attachment::discard(*mip));
+
+        input = *mip;
+        step += 1;
+    }
+
+    for i in (0..mips.len()).rev() {
+        let target = if i == 0 {hdr} else {mips[i - 1]};
+
+        graph.add_pass(FramePass::Bloom(step))
+            .sample(mips[i])
+            .color(Attachment::load(target));
+
+        step += 1;
+    }
+}
+
+// The descriptor set used for a step is the one of its input: index 0 is the HDR target and index i + 1 is bloom mip i
+pub unsafe fn create_bloom_objects(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    data.bloom_descriptor_sets.clear();
+
+    if data.bloom_extents.is_empty() {
+        data.bloom_descriptor_pool = vk::DescriptorPool::null();
+        data.bloom_downsample_pipeline = vk::Pipeline::null();
+        data.bloom_upsample_pipeline = vk::Pipeline::null();
+        return Ok(());
+    }
+
+    let mip_count = data.bloom_extents.len() as u32;
+    let set_count = mip_count + 1;
+    let pool_size = vk::DescriptorPoolSize::builder()
+        .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(set_count * 2);
+
+    let pool_sizes = &[pool_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(pool_sizes)
+        .max_sets(set_count);
+
+    data.bloom_descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+    let layouts = vec![data.post_set_layout; set_count as usize];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(data.bloom_descriptor_pool)
+        .set_layouts(&layouts);
+
+    data.bloom_descriptor_sets = device.allocate_descriptor_sets(&info)?;
+
+    let inputs = std::iter::once(data.graph.view(HDR_IMAGE))
+        .chain((0..mip_count as usize).map(|mip| data.graph.view(&get_bloom_image_name(mip))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (descriptor_set, view) in data.bloom_descriptor_sets.iter().zip(inputs) {
+        // The second binding of the post layout is unused by the bloom shaders
+        let image_infos = [view, view]
+            .iter()
+            .map(|v| {
+                [vk::DescriptorImageInfo::builder()
+                    .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image_view(*v)
+                    .sampler(data.post_sampler)
+                    .build()]
+            })
+            .collect::<Vec<_>>();
+
+        let writes = image_infos
+            .iter()
+            .enumerate()
+            .map(|(i, image_info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(i as u32)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(image_info)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+    }
+
+    let downsample = include_bytes!("../../shaders/bloom_downsample_frag.spv");
+    let upsample = include_bytes!("../../shaders/bloom_upsample_frag.spv");
+
+    // Render pass compatibility ignores load operations, so one pipeline works for every step in each direction
+    let downsample_render_pass = data.graph.render_pass(FramePass::Bloom(0))?;
+    let upsample_render_pass = data.graph.render_pass(FramePass::Bloom(mip_count as usize))?;
+
+    data.bloom_downsample_pipeline = create_fullscreen_pipeline(device, data, &downsample[..], downsample_render_pass, false)?;
+    data.bloom_upsample_pipeline = create_fullscreen_pipeline(device, data, &upsample[..], upsample_render_pass, true)?;
+
+    Ok(())
+}
+
+// In the order of the `FramePass::Bloom` passes
+pub fn get_bloom_steps(data: &RenderData) -> Vec<BloomStep>
+{
+    let settings = &data.bloom_settings;
+    let texel_size = |extent: vk::Extent2D| (1.0 / extent.width as f32, 1.0 / extent.height as f32);
+
+    let mut steps = vec![];
+    let mut source = data.swapchain_extent;
+
+    for (i, extent) in data.bloom_extents.iter().enumerate() {
+        let (x, y) = texel_size(source);
+        let prefilter = if i == 0 {1.0} else {0.0};
+
+        steps.push(BloomStep {
+            pipeline: data.bloom_downsample_pipeline,
+            descriptor_set: data.bloom_descriptor_sets[i],
+            push_constants: PostPushConstants {
+                parameters: Vec4::new(settings.threshold, settings.knee, prefilter, 0.0),
+                target: Vec4::new(x, y, 0.0, 0.0),
+            },
+        });
+
+        source = *extent;
+    }
+
+    for i in (0..data.bloom_extents.len()).rev() {
+        let (x, y) = texel_size(data.bloom_extents[i]);
+
+        let intensity = if i == 0 {settings.intensity} else {1.0};
+
+        steps.push(BloomStep {
+            pipeline: data.bloom_upsample_pipeline,
+            descriptor_set: data.bloom_descriptor_sets[i + 1],
+            push_constants: PostPushConstants {
+                parameters: Vec4::new(settings.radius, intensity, 0.0, 0.0),
+                target: Vec4::new(x, y, 0.0, 0.0),
+            },
+        });
+    }
+
+    steps
+}
+
+pub unsafe fn destroy_bloom_objects(device: &Device, data: &RenderData)
+{
+    device.destroy_pipeline(data.bloom_downsample_pipeline, None);
+    device.destroy_pipeline(data.bloom_upsample_pipeline, None);
+    device.destroy_descriptor_pool(data.bloom_descriptor_pool, None);
+}
@@ -1,10 +1,9 @@
 use anyhow::Result;
-use std::ptr::copy_nonoverlapping as memcpy;
 use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder}, Device, Instance};
 
-use super::{command::{begin_single_time_commands, end_single_time_commands}, device::get_memory_type_index, vertex::Vertex, RenderData};
+use super::{allocator::{Allocation, AllocationKind, Strategy}, device::get_memory_type_index, upload::upload_buffer, vertex::Vertex, RenderData};
 
-pub unsafe fn create_buffer(instance: &Instance, device: &Device, data: &RenderData, size: vk::DeviceSize, usage: vk::BufferUsageFlags, properties: vk::MemoryPropertyFlags) ->Result<(vk::Buffer, vk::DeviceMemory)>
+pub unsafe fn create_buffer(instance: &Instance, device: &Device, data: &RenderData, size: vk::DeviceSize, usage: vk::BufferUsageFlags, properties: vk::MemoryPropertyFlags, strategy: Strategy) ->Result<(vk::Buffer, Allocation)>
 {
     let buffer_info = vk::BufferCreateInfo::builder()
         .size(size)
@@ -15,50 +14,22 @@ pub unsafe fn create_buffer(instance: &Instance, device: &Device, data: &RenderD
     let buffer = device.create_buffer(&buffer_info, None)?;
 
     let requirements = device.get_buffer_memory_requirements(buffer);
-    let memory_info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(
-            instance,
-            data,
-            properties,
-            requirements
-        )?);
-
-    let buffer_memory = device.allocate_memory(&memory_info, None)?;
-    device.bind_buffer_memory(buffer, buffer_memory, 0)?;
+    let memory_type_index = get_memory_type_index(instance, data, properties, requirements)?;
 
-    Ok((buffer, buffer_memory))
-}
-
-pub unsafe fn copy_buffer(device: &Device, data: &RenderData, source: vk::Buffer, destination: vk::Buffer, size: vk::DeviceSize) ->Result<()>
-{
-    let command_buffer = begin_single_time_commands(device, data)?;
-
-    let regions = vk::BufferCopy::builder().size(size);
-    device.cmd_copy_buffer(command_buffer, source, destination, &[regions]);
+    let buffer_memory = data.allocator
+        .lock()
+        .unwrap()
+        .allocate(device, memory_type_index, requirements, AllocationKind::Linear, strategy)?;
 
-    end_single_time_commands(device, data, command_buffer)?;
+    device.bind_buffer_memory(buffer, buffer_memory.memory, buffer_memory.offset)?;
 
-    Ok(())
+    Ok((buffer, buffer_memory))
 }
 
 pub unsafe fn create_vertex_buffer(instance: &Instance, device: &Device, data: &mut RenderData) ->Result<()>
 {
     let size = (size_of::<Vertex>() * data.vertices.len()) as u64;
 
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
-
-    let memory = device.map_memory(staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty())?;
-    memcpy(data.vertices.as_ptr(), memory.cast(), data.vertices.len());
-    device.unmap_memory(staging_buffer_memory);
-
     let (vertex_buffer, vertex_buffer_memory) = create_buffer(
         instance,
         device,
@@ -66,15 +37,22 @@ pub unsafe fn create_vertex_buffer(instance: &Instance, device: &Device, data: &
         size,
         vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        Strategy::FreeList,
     )?;
 
     data.vertex_buffer = vertex_buffer;
     data.vertex_buffer_memory = vertex_buffer_memory;
 
-    copy_buffer(device, data, staging_buffer, vertex_buffer, size)?;
-
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
+    let vertices = data.vertices.clone();
+    data.vertex_upload = upload_buffer(
+        instance,
+        device,
+        data,
+        &vertices,
+        vertex_buffer,
+        vk::PipelineStageFlags::VERTEX_INPUT,
+        vk::AccessFlags::VERTEX_ATTRIBUTE_READ
+    )?;
 
     Ok(())
 }
@@ -83,19 +61,6 @@ pub unsafe fn create_index_buffer(instance: &Instance, device: &Device, data: &m
 {
     let size = (size_of::<u32>() * data.indices.len()) as u64;
 
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
-
-    let memory = device.map_memory(staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty())?;
-    memcpy(data.indices.as_ptr(), memory.cast(), data.indices.len());
-    device.unmap_memory(staging_buffer_memory);
-
     let (index_buffer, index_buffer_memory) = create_buffer(
         instance,
         device,
@@ -103,15 +68,34 @@ pub unsafe fn create_index_buffer(instance: &Instance, device: &Device, data: &m
         size,
         vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::INDEX_BUFFER,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        Strategy::FreeList,
     )?;
 
     data.index_buffer = index_buffer;
     data.index_buffer_memory = index_buffer_memory;
 
-    copy_buffer(device, data, staging_buffer, index_buffer, size)?;
-
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
+    let indices = data.indices.clone();
+    data.index_upload = upload_buffer(
+        instance,
+        device,
+        data,
+        &indices,
+        index_buffer,
+        vk::PipelineStageFlags::VERTEX_INPUT,
+        vk::AccessFlags::INDEX_READ
+    )?;
 
     Ok(())
 }
+
+pub unsafe fn destroy_vertex_buffer(device: &Device, data: &RenderData)
+{
+    device.destroy_buffer(data.vertex_buffer, None);
+    data.allocator.lock().unwrap().free(device, &data.vertex_buffer_memory);
+}
+
+pub unsafe fn destroy_index_buffer(device: &Device, data: &RenderData)
+{
+    device.destroy_buffer(data.index_buffer, None);
+    data.allocator.lock().unwrap().free(device, &data.index_buffer_memory);
+}
@@ -0,0 +1,296 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use super::{
+    assets::{parse_mesh, Mesh},
+    image::{decode_image, generate_mip_chain, get_mip_levels, DecodedImage},
+    mesh_cache::{decode_mesh, encode_mesh, hash_file, Reader},
+    optimize::OptimizeSettings,
+    texture_compression::{compress_image, BlockFormat},
+    vfs::{FileData, Vfs},
+};
+
+// Loaded in place of the source files when present, written by the `asset-bake` tool
+pub const BUNDLE_PATH: &str = "resources/assets.bundle";
+
+const BUNDLE_MAGIC: [u8; 4] = *b"SRGA";
+const BUNDLE_VERSION: u32 = 2;
+
+const TEXTURE_MAGIC: [u8; 4] = *b"SRGT";
+const TEXTURE_VERSION: u32 = 2;
+
+// Magic, version, entry count and the offset of the index
+const BUNDLE_HEADER_SIZE: usize = 4 + 4 + 4 + 8;
+
+// Every blob starts on this boundary so that it can be read straight from the mapping
+const BLOB_ALIGNMENT: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AssetKind
+{
+    Mesh = 1,
+    Texture = 2,
+}
+
+impl AssetKind {
+    fn from_raw(value: u32) -> Result<Self>
+    {
+        match value {
+            1 => Ok(Self::Mesh),
+            2 => Ok(Self::Texture),
+            _ => Err(anyhow!("Unknown asset kind {}.", value)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BundleEntry
+{
+    pub kind: AssetKind,
+    // Hash of the source file when it was baked, entries whose source changed since are skipped
+    pub source_hash: u64,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/*
+    Baked assets packed into one file. The header is followed by the blobs, then by an index
+    mapping each source path to its blob, so the writer doesn't need to know the sizes up front.
+    Meshes are stored in the mesh cache format, textures with their whole mip chain, block
+    compressed unless asked not to. Textures that come compressed keep the levels they have.
+ */
+#[derive(Debug)]
+pub struct Bundle
+{
+    map: FileData,
+    entries: HashMap<String, BundleEntry>,
+}
+
+impl Bundle {
+    pub fn open(vfs: &Vfs, path: &str) -> Result<Self>
+    {
+        let map = vfs.read(path)?;
+        let mut reader = Reader::new(&map);
+
+        if reader.take(4)? != BUNDLE_MAGIC {
+            return Err(anyhow!("`{}` is not an asset bundle.", path));
+        }
+
+        let version = reader.u32()?;
+        if version != BUNDLE_VERSION {
+            return Err(anyhow!("`{}` has version {}, expected {}.", path, version, BUNDLE_VERSION));
+        }
+
+        let entry_count = reader.u32()?;
+        let index_offset = reader.u64()? as usize;
+
+        let mut index = Reader::new(map.get(index_offset..).ok_or_else(|| anyhow!("Bundle index out of range."))?);
+        let mut entries = HashMap::new();
+
+        for _ in 0..entry_count {
+            let kind = AssetKind::from_raw(index.u32()?)?;
+            let path_length = index.u32()? as usize;
+            let source_hash = index.u64()?;
+            let offset = index.u64()? as usize;
+            let size = index.u64()? as usize;
+            let source = std::str::from_utf8(index.take(path_length)?)?.to_string();
+
+            if offset.checked_add(size).is_none_or(|end| end > index_offset) {
+                return Err(anyhow!("Blob of `{}` out of range.", source));
+            }
+
+            entries.insert(source, BundleEntry {kind, source_hash, offset, size});
+        }
+
+        Ok(Self {map, entries})
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &BundleEntry)>
+    {
+        self.entries.iter().map(|(path, entry)| (path.as_str(), entry))
+    }
+
+    // `None` if the bundle doesn't have an up to date version of the asset, it should be loaded from its source then
+    pub fn mesh(&self, vfs: &Vfs, path: &str) -> Option<Result<Mesh>>
+    {
+        let (entry, bytes) = self.find(vfs, path, AssetKind::Mesh)?;
+
+        match decode_mesh(bytes, entry.source_hash) {
+            Ok(Some(mesh)) => Some(Ok(mesh)),
+            Ok(None) => {
+                warn!("Baked `{}` uses another vertex layout, loading the source instead.", path);
+                None
+            },
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    pub fn texture(&self, vfs: &Vfs, path: &str) -> Option<Result<DecodedImage>>
+    {
+        let (_, bytes) = self.find(vfs, path, AssetKind::Texture)?;
+        Some(decode_texture(bytes))
+    }
+
+    fn find(&self, vfs: &Vfs, path: &str, kind: AssetKind) -> Option<(&BundleEntry, &[u8])>
+    {
+        let entry = self.entries.get(path).filter(|e| e.kind == kind)?;
+
+        // Shipped games don't have the sources, while artists expect their changes to show up before baking again
+        if let Ok(hash) = hash_file(vfs, path) {
+            if hash != entry.source_hash {
+                info!("`{}` changed since it was baked, loading the source instead.", path);
+                return None;
+            }
+        }
+
+        Some((entry, &self.map[entry.offset..entry.offset + entry.size]))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BundleWriter
+{
+    entries: Vec<(String, AssetKind, u64, Vec<u8>)>,
+}
+
+impl BundleWriter {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn add_mesh(&mut self, vfs: &Vfs, path: &str, settings: &OptimizeSettings) -> Result<()>
+    {
+        let source_hash = hash_file(vfs, path)?;
+        let mesh = parse_mesh(vfs, path, settings)?;
+
+        self.add(path, AssetKind::Mesh, source_hash, encode_mesh(&mesh, source_hash));
+        Ok(())
+    }
+
+    /*
+        Colour textures have their mip chain averaged in linear space, images that already have
+        one keep it. Compressed textures become BC1, or BC3 when any texel isn't opaque, while
+        block compressed files are stored as they are.
+     */
+    pub fn add_texture(&mut self, vfs: &Vfs, path: &str, srgb: bool, compress: bool) -> Result<()>
+    {
+        let source_hash = hash_file(vfs, path)?;
+        let mut image = decode_image(vfs, path)?;
+
+        if image.mip_levels == 1 && image.compression.is_none() {
+            image = generate_mip_chain(&image, srgb);
+        }
+
+        if compress && image.compression.is_none() {
+            let opaque = image.pixels.chunks_exact(4).all(|p| p[3] == 255);
+            image = compress_image(&image, if opaque {BlockFormat::Bc1Rgb} else {BlockFormat::Bc3})?;
+        }
+
+        self.add(path, AssetKind::Texture, source_hash, encode_texture(&image));
+        Ok(())
+    }
+
+    fn add(&mut self, path: &str, kind: AssetKind, source_hash: u64, bytes: Vec<u8>)
+    {
+        self.entries.retain(|(p, ..)| p != path);
+        self.entries.push((path.to_string(), kind, source_hash, bytes));
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+
+    // Written to a temporary file first so that a running game never maps a half written bundle
+    pub fn write(&self, path: &Path) -> Result<()>
+    {
+        let mut bytes = vec![0; BUNDLE_HEADER_SIZE];
+        let mut offsets = Vec::with_capacity(self.entries.len());
+
+        for (_, _, _, blob) in &self.entries {
+            bytes.resize(bytes.len().next_multiple_of(BLOB_ALIGNMENT), 0);
+            offsets.push(bytes.len());
+            bytes.extend(blob);
+        }
+
+        let index_offset = bytes.len();
+        for ((source, kind, source_hash, blob), offset) in self.entries.iter().zip(offsets) {
+            bytes.extend((*kind as u32).to_le_bytes());
+            bytes.extend((source.len() as u32).to_le_bytes());
+            bytes.extend(source_hash.to_le_bytes());
+            bytes.extend((offset as u64).to_le_bytes());
+            bytes.extend((blob.len() as u64).to_le_bytes());
+            bytes.extend(source.as_bytes());
+        }
+
+        bytes[0..4].copy_from_slice(&BUNDLE_MAGIC);
+        bytes[4..8].copy_from_slice(&BUNDLE_VERSION.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        bytes[12..20].copy_from_slice(&(index_offset as u64).to_le_bytes());
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+}
+
+// Magic, version, width, height, level count and block format or 0, followed by the tightly packed levels
+pub fn encode_texture(image: &DecodedImage) -> Vec<u8>
+{
+    let mut bytes = Vec::with_capacity(24 + image.pixels.len());
+    bytes.extend(TEXTURE_MAGIC);
+
+    let compression = image.compression.map_or(0, |c| c as u32);
+    for value in [TEXTURE_VERSION, image.width, image.height, image.mip_levels, compression] {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    bytes.extend(&image.pixels);
+    bytes
+}
+
+pub fn decode_texture(bytes: &[u8]) -> Result<DecodedImage>
+{
+    let mut reader = Reader::new(bytes);
+
+    if reader.take(4)? != TEXTURE_MAGIC {
+        return Err(anyhow!("Not a baked texture."));
+    }
+
+    let version = reader.u32()?;
+    if version != TEXTURE_VERSION {
+        return Err(anyhow!("Baked texture has version {}, expected {}.", version, TEXTURE_VERSION));
+    }
+
+    let width = reader.u32()?;
+    let height = reader.u32()?;
+    let mip_levels = reader.u32()?;
+    let compression = match reader.u32()? {
+        0 => None,
+        value => Some(BlockFormat::from_raw(value)?),
+    };
+
+    if width == 0 || height == 0 || mip_levels == 0 || mip_levels > get_mip_levels(width, height) {
+        return Err(anyhow!("Baked texture has an invalid size."));
+    }
+
+    let mut image = DecodedImage {width, height, mip_levels, compression, pixels: vec![]};
+    let size = (0..mip_levels).map(|level| image.level_size(level)).sum();
+
+    if reader.remaining() != size {
+        return Err(anyhow!("Baked texture has the wrong size."));
+    }
+
+    image.pixels = reader.take(size)?.to_vec();
+    Ok(image)
+}
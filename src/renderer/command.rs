@@ -1,7 +1,10 @@
 use anyhow::Result;
 use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
 
-use super::{device::QueueFamilyIndices, RenderData};
+use super::{device::QueueFamilyIndices, RenderData, MAX_FRAMES_IN_FLIGHT};
+
+// Upper bound on threads recording the scene, each one gets its own command pool per frame in flight
+pub const MAX_RECORDING_THREADS: usize = 4;
 
 pub unsafe fn begin_single_time_commands(device: &Device, data: &RenderData) -> Result<vk::CommandBuffer>
 {
@@ -39,10 +42,33 @@ pub unsafe fn create_command_pools(instance: &Instance, device: &Device, data: &
 {
     data.command_pool = create_command_pool(instance, device, data)?;
 
-    let num_images = data.swapchain_images.len();
-    for _ in 0..num_images {
+    let thread_count = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .clamp(1, MAX_RECORDING_THREADS);
+
+    // Keyed by frame in flight rather than swapchain image, a frame's pools are free to reset once its fence signaled
+    for _ in 0..MAX_FRAMES_IN_FLIGHT {
         let command_pool = create_command_pool(instance, device, data)?;
         data.command_pools.push(command_pool);
+
+        // Pools can only be used by one thread at a time, so every recording thread resets and records its own
+        let mut thread_command_pools = vec![];
+        let mut secondary_command_buffers = vec![];
+        for _ in 0..thread_count {
+            let command_pool = create_command_pool(instance, device, data)?;
+
+            let allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(command_pool)
+                .level(vk::CommandBufferLevel::SECONDARY)
+                .command_buffer_count(1);
+
+            secondary_command_buffers.push(device.allocate_command_buffers(&allocate_info)?[0]);
+            thread_command_pools.push(command_pool);
+        }
+
+        data.thread_command_pools.push(thread_command_pools);
+        data.secondary_command_buffers.push(secondary_command_buffers);
     }
 
     Ok(())
@@ -61,10 +87,9 @@ pub unsafe fn create_command_pool(instance: &Instance, device: &Device, data: &m
 
 pub unsafe fn create_command_buffers(device: &Device, data: &mut RenderData) ->Result<()>
 {
-    let num_images = data.swapchain_images.len();
-    for image_index in 0..num_images {
+    for frame in 0..MAX_FRAMES_IN_FLIGHT {
         let allocate_info = vk::CommandBufferAllocateInfo::builder()
-            .command_pool(data.command_pools[image_index])
+            .command_pool(data.command_pools[frame])
             .level(vk::CommandBufferLevel::PRIMARY)
             .command_buffer_count(1);
 
@@ -72,7 +97,5 @@ pub unsafe fn create_command_buffers(device: &Device, data: &mut RenderData) ->R
         data.command_buffers.push(command_buffer);
     }
 
-    data.secondary_command_buffers = vec![vec![]; data.swapchain_images.len()];
-
     Ok(())
 }
@@ -0,0 +1,419 @@
+use anyhow::Result;
+use cgmath::{InnerSpace, Matrix, Transform};
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device};
+
+use super::{descriptor::Mat4, instancing::{sort_into_batches, InstanceBatches, Prop}, light::Vec4, pipeline::create_shader_module, vertex::{InstanceData, Vertex}, RenderData, MAX_FRAMES_IN_FLIGHT};
+
+// Has to match `local_size_x` in cull.comp
+const CULL_WORKGROUP_SIZE: u32 = 64;
+
+type Vec3 = cgmath::Vector3<f32>;
+type Point3 = cgmath::Point3<f32>;
+
+// Object space bounds of a mesh, computed once when the mesh is loaded
+#[derive(Copy, Clone, Debug)]
+pub struct Bounds
+{
+    pub min: Vec3,
+    pub max: Vec3,
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Default for Bounds {
+    fn default() -> Self
+    {
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        Self {min: zero, max: zero, center: zero, radius: 0.0}
+    }
+}
+
+impl Bounds {
+    pub fn from_vertices(vertices: &[Vertex]) -> Self
+    {
+        let Some(first) = vertices.first() else {
+            return Self::default();
+        };
+
+        let position = |v: &Vertex| Vec3::new(v.position.x, v.position.y, v.position.z);
+
+        let (min, max) = vertices.iter().map(position).fold(
+            (position(first), position(first)),
+            |(min, max), p| (
+                Vec3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                Vec3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+            )
+        );
+
+        // Centered on the box, which is tighter than half its diagonal for most meshes
+        let center = (min + max) * 0.5;
+        let radius = vertices
+            .iter()
+            .map(|v| (position(v) - center).magnitude())
+            .fold(0.0, f32::max);
+
+        Self {min, max, center, radius}
+    }
+
+    // Box around the transformed box, following Arvo's method
+    fn transform_aabb(&self, model: &Mat4) -> (Vec3, Vec3)
+    {
+        let translation = model.w.truncate();
+        let (mut min, mut max) = (translation, translation);
+
+        for i in 0..3 {
+            for j in 0..3 {
+                let a = model[j][i] * self.min[j];
+                let b = model[j][i] * self.max[j];
+                min[i] += a.min(b);
+                max[i] += a.max(b);
+            }
+        }
+
+        (min, max)
+    }
+}
+
+/*
+    Planes of a view-projection, pointing inwards with xyz = normal and w = distance.
+    Extracted from the rows of the matrix (Gribb/Hartmann), using Vulkan's 0 to 1 depth range.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum
+{
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    pub fn planes(&self) -> [Vec4; 6]
+    {
+        self.planes
+    }
+
+    pub fn from_matrix(view_projection: Mat4) -> Self
+    {
+        let m = view_projection.transpose();
+        let (x, y, z, w) = (m.x, m.y, m.z, m.w);
+
+        let planes = [w + x, w - x, w + y, w - y, z, w - z]
+            .map(|p| p / p.truncate().magnitude());
+
+        Self {planes}
+    }
+
+    pub fn contains_sphere(&self, center: Vec3, radius: f32) -> bool
+    {
+        self.planes
+            .iter()
+            .all(|p| p.truncate().dot(center) + p.w >= -radius)
+    }
+
+    // Only the corner furthest along each plane's normal has to be tested
+    pub fn contains_aabb(&self, min: Vec3, max: Vec3) -> bool
+    {
+        self.planes.iter().all(|p| {
+            let corner = Vec3::new(
+                if p.x >= 0.0 {max.x} else {min.x},
+                if p.y >= 0.0 {max.y} else {min.y},
+                if p.z >= 0.0 {max.z} else {min.z},
+            );
+
+            p.truncate().dot(corner) + p.w >= 0.0
+        })
+    }
+
+    // The sphere rejects most objects cheaply, the box catches the ones it is too loose for
+    pub fn contains(&self, bounds: &Bounds, model: &Mat4) -> bool
+    {
+        let center = model.transform_point(Point3::new(bounds.center.x, bounds.center.y, bounds.center.z));
+        let scale = model.x.truncate().magnitude()
+            .max(model.y.truncate().magnitude())
+            .max(model.z.truncate().magnitude());
+
+        if !self.contains_sphere(Vec3::new(center.x, center.y, center.z), bounds.radius * scale) {
+            return false;
+        }
+
+        let (min, max) = bounds.transform_aabb(model);
+        self.contains_aabb(min, max)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CullingStatistics
+{
+    pub tested: usize,
+    pub culled: usize,
+}
+
+impl CullingStatistics {
+    pub fn visible(&self) -> usize
+    {
+        self.tested - self.culled
+    }
+}
+
+// Props whose bounds are at least partially inside the frustum, along with their levels of detail
+pub fn cull_props(props: &[Prop], lods: &[usize], frustum: &Frustum, bounds: &Bounds) -> (Vec<Prop>, Vec<usize>, CullingStatistics)
+{
+    let (visible, visible_lods): (Vec<_>, Vec<_>) = props
+        .iter()
+        .zip(lods)
+        .filter(|(p, _)| frustum.contains(bounds, &p.transform))
+        .map(|(p, lod)| (*p, *lod))
+        .unzip();
+
+    let statistics = CullingStatistics {
+        tested: props.len(),
+        culled: props.len() - visible.len(),
+    };
+
+    (visible, visible_lods, statistics)
+}
+
+// Input of the culling shader, laid out like `Object` in cull.comp
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct CullObject
+{
+    model: Mat4,
+    parameters: Vec4,
+    batch: u32,
+    _padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct CullPushConstants
+{
+    planes: [Vec4; 6],
+    // xyz = center, w = radius of the mesh's bounding sphere in object space
+    sphere: Vec4,
+    object_count: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CullDispatch
+{
+    descriptor_set: vk::DescriptorSet,
+    push_constants: CullPushConstants,
+}
+
+// Where a frame's draw commands are, their instance counts tell how many objects the GPU kept
+#[derive(Copy, Clone, Debug)]
+pub struct CullReadback
+{
+    commands: vk::DeviceSize,
+    batch_count: usize,
+    tested: usize,
+}
+
+/*
+    Compute pass testing every object's bounding sphere against the frustum. Visible objects are
+    compacted into their batch's instance range and counted in the batch's indexed indirect
+    command, which the scene pass then draws from. Everything lives in the frame ring, with one
+    descriptor set per frame in flight rewritten once the frame's fence has been waited on.
+ */
+pub unsafe fn create_cull_pipeline(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let bindings = (0..3)
+        .map(|binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let info = vk::DescriptorSetLayoutCreateInfo::builder()
+        .bindings(&bindings);
+
+    data.cull_set_layout = device.create_descriptor_set_layout(&info, None)?;
+
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        .offset(0)
+        .size(size_of::<CullPushConstants>() as u32);
+
+    let set_layouts = &[data.cull_set_layout];
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(push_constant_ranges);
+
+    data.cull_pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
+
+    let comp = include_bytes!("../../shaders/cull_comp.spv");
+    let comp_shader_module = create_shader_module(device, &comp[..])?;
+
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(comp_shader_module)
+        .name(b"main\0");
+
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage)
+        .layout(data.cull_pipeline_layout);
+
+    data.cull_pipeline = device.create_compute_pipelines(vk::PipelineCache::null(), &[info], None)?.0[0];
+
+    device.destroy_shader_module(comp_shader_module, None);
+
+    let pool_size = vk::DescriptorPoolSize::builder()
+        .type_(vk::DescriptorType::STORAGE_BUFFER)
+        .descriptor_count(3 * MAX_FRAMES_IN_FLIGHT as u32);
+
+    let pool_sizes = &[pool_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(pool_sizes)
+        .max_sets(MAX_FRAMES_IN_FLIGHT as u32);
+
+    data.cull_descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+    let layouts = vec![data.cull_set_layout; MAX_FRAMES_IN_FLIGHT];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(data.cull_descriptor_pool)
+        .set_layouts(&layouts);
+
+    data.cull_descriptor_sets = device.allocate_descriptor_sets(&info)?;
+    data.cull_readbacks = vec![None; MAX_FRAMES_IN_FLIGHT];
+
+    Ok(())
+}
+
+// Pushes the objects and zeroed draw commands into the frame ring, the instances are written by the dispatch
+pub unsafe fn push_gpu_culling(device: &Device, data: &mut RenderData, frame: usize, props: &[Prop], lods: &[usize], frustum: &Frustum) -> Result<(InstanceBatches, Option<CullDispatch>)>
+{
+    if props.is_empty() {
+        return Ok((InstanceBatches::default(), None));
+    }
+
+    let (order, batches) = sort_into_batches(props, lods);
+
+    let objects = batches
+        .iter()
+        .enumerate()
+        .flat_map(|(batch, b)| {
+            let range = b.first_instance as usize..(b.first_instance + b.instance_count) as usize;
+            order[range].iter().map(move |&i| CullObject {
+                model: props[i].transform,
+                parameters: InstanceData::new(&props[i]).parameters,
+                batch: batch as u32,
+                _padding: [0; 3],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let commands = batches
+        .iter()
+        .map(|b| vk::DrawIndexedIndirectCommand {
+            index_count: data.mesh_lods[b.lod].index_count,
+            instance_count: 0,
+            first_index: data.mesh_lods[b.lod].first_index,
+            vertex_offset: 0,
+            first_instance: b.first_instance,
+        })
+        .collect::<Vec<_>>();
+
+    let objects_offset = data.frame_ring.push_slice(&objects)?;
+    let commands_offset = data.frame_ring.push_slice(&commands)?;
+    let instances_offset = data.frame_ring.reserve::<InstanceData>(objects.len())?;
+
+    let descriptor_set = data.cull_descriptor_sets[frame];
+    let buffer_infos = [
+        (objects_offset, size_of_val(objects.as_slice())),
+        (commands_offset, size_of_val(commands.as_slice())),
+        (instances_offset, size_of::<InstanceData>() * objects.len()),
+    ]
+    .map(|(offset, range)| {
+        [vk::DescriptorBufferInfo::builder()
+            .buffer(data.frame_ring.buffer)
+            .offset(offset)
+            .range(range as vk::DeviceSize)
+            .build()]
+    });
+
+    let writes = buffer_infos
+        .iter()
+        .enumerate()
+        .map(|(binding, buffer_info)| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(binding as u32)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(buffer_info)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+
+    data.cull_readbacks[frame] = Some(CullReadback {commands: commands_offset, batch_count: commands.len(), tested: objects.len()});
+
+    let bounds = data.mesh_bounds;
+    let push_constants = CullPushConstants {
+        planes: frustum.planes(),
+        sphere: bounds.center.extend(bounds.radius),
+        object_count: objects.len() as u32,
+    };
+
+    let instances = InstanceBatches {offset: instances_offset, batches, commands: Some(commands_offset)};
+
+    Ok((instances, Some(CullDispatch {descriptor_set, push_constants})))
+}
+
+// Has to be recorded outside of a render pass, before the scene pass draws from the commands
+pub unsafe fn record_cull_dispatch(device: &Device, data: &RenderData, command_buffer: vk::CommandBuffer, dispatch: &CullDispatch)
+{
+    let push_constant_bytes = std::slice::from_raw_parts(
+        &dispatch.push_constants as *const CullPushConstants as *const u8,
+        size_of::<CullPushConstants>()
+    );
+
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, data.cull_pipeline);
+    device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, data.cull_pipeline_layout, 0, &[dispatch.descriptor_set], &[]);
+    device.cmd_push_constants(command_buffer, data.cull_pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, push_constant_bytes);
+    device.cmd_dispatch(command_buffer, dispatch.push_constants.object_count.div_ceil(CULL_WORKGROUP_SIZE), 1, 1);
+
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+        .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_INPUT,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+}
+
+// Counts of the culling pass the frame ran last time, its fence must have been waited on
+pub unsafe fn read_cull_statistics(data: &mut RenderData, frame: usize) -> Option<CullingStatistics>
+{
+    let readback = data.cull_readbacks.get_mut(frame)?.take()?;
+
+    let visible = data.frame_ring
+        .read::<vk::DrawIndexedIndirectCommand>(readback.commands, readback.batch_count)
+        .iter()
+        .map(|c| c.instance_count as usize)
+        .sum::<usize>();
+
+    Some(CullingStatistics {
+        tested: readback.tested,
+        culled: readback.tested.saturating_sub(visible),
+    })
+}
+
+pub unsafe fn destroy_cull_pipeline(device: &Device, data: &RenderData)
+{
+    device.destroy_descriptor_pool(data.cull_descriptor_pool, None);
+    device.destroy_pipeline(data.cull_pipeline, None);
+    device.destroy_pipeline_layout(data.cull_pipeline_layout, None);
+    device.destroy_descriptor_set_layout(data.cull_set_layout, None);
+}
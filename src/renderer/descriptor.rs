@@ -1,7 +1,7 @@
 use anyhow::Result;
-use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder}, Device, Instance};
+use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder}, Device};
 
-use super::{buffer::create_buffer, RenderData};
+use super::{light::{LightBufferObject, Vec4}, shadow::{ShadowBufferObject, SHADOW_MAP}, RenderData};
 
 pub type Mat4 = cgmath::Matrix4<f32>;
 
@@ -10,23 +10,45 @@ pub type Mat4 = cgmath::Matrix4<f32>;
 pub struct UniformBufferObject {
     pub view: Mat4,
     pub projection: Mat4,
+    pub camera_position: Vec4,
+    // x = environment lighting enabled, y = environment intensity, z = highest environment mip level
+    pub environment: Vec4,
 }
 
+// Per frame uniforms live in the frame ring, so the buffer bindings take dynamic offsets in binding order
 pub unsafe fn create_descriptor_set_layout(device: &Device, data: &mut RenderData) ->Result<()>
 {
     let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
         .binding(0)
-        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
         .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::VERTEX);
+        .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
 
-    let sampler_bidning = vk::DescriptorSetLayoutBinding::builder()
+    let light_binding = vk::DescriptorSetLayoutBinding::builder()
         .binding(1)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let shadow_binding = vk::DescriptorSetLayoutBinding::builder()
+        .binding(2)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let shadow_map_binding = vk::DescriptorSetLayoutBinding::builder()
+        .binding(3)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let environment_binding = vk::DescriptorSetLayoutBinding::builder()
+        .binding(4)
         .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
         .descriptor_count(1)
         .stage_flags(vk::ShaderStageFlags::FRAGMENT);
 
-    let bindings = &[ubo_binding, sampler_bidning];
+    let bindings = &[ubo_binding, light_binding, shadow_binding, shadow_map_binding, environment_binding];
     let info = vk::DescriptorSetLayoutCreateInfo::builder()
         .bindings(bindings);
 
@@ -35,91 +57,105 @@ pub unsafe fn create_descriptor_set_layout(device: &Device, data: &mut RenderDat
     Ok(())
 }
 
-pub unsafe fn create_uniform_buffers(instance: &Instance, device: &Device, data: &mut RenderData) ->Result<()>
-{
-    data.uniform_buffers.clear();
-    data.uniform_buffers_memory.clear();
-
-    for _ in 0..data.swapchain_images.len() {
-        let (uniform_buffer, uniform_buffer_memory) = create_buffer(
-            instance,
-            device,
-            data,
-            size_of::<UniformBufferObject>() as u64,
-            vk::BufferUsageFlags::UNIFORM_BUFFER,
-            vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
-        )?;
-
-        data.uniform_buffers.push(uniform_buffer);
-        data.uniform_buffers_memory.push(uniform_buffer_memory);
-    }
-
-    Ok(())
-}
-
 pub unsafe fn create_descriptor_pool(device: &Device, data: &mut RenderData) ->Result<()>
 {
     let ubo_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(data.swapchain_images.len() as u32);
+        .type_(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        .descriptor_count(3);
 
     let sampler_size = vk::DescriptorPoolSize::builder()
         .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(data.swapchain_images.len() as u32);
+        .descriptor_count(2);
 
     let pool_sizes = &[ubo_size, sampler_size];
     let info = vk::DescriptorPoolCreateInfo::builder()
         .pool_sizes(pool_sizes)
-        .max_sets(data.swapchain_images.len() as u32);
+        .max_sets(1);
 
     data.descriptor_pool = device.create_descriptor_pool(&info, None)?;
 
     Ok(())
 }
 
+// A single set serves every frame, the uniforms of a frame are selected with dynamic offsets when binding it
 pub unsafe fn create_descriptor_sets(device: &Device, data: &mut RenderData) ->Result<()>
 {
-    let layouts = vec![data.descriptor_set_layout; data.swapchain_images.len()];
+    let layouts = &[data.descriptor_set_layout];
     let info = vk::DescriptorSetAllocateInfo::builder()
         .descriptor_pool(data.descriptor_pool)
-        .set_layouts(&layouts);
-
-    data.descriptor_sets = device.allocate_descriptor_sets(&info)?;
-
-    for i in 0..data.swapchain_images.len()
-    {
-        let uniform_info = vk::DescriptorBufferInfo::builder()
-            .buffer(data.uniform_buffers[i])
-            .offset(0)
-            .range(size_of::<UniformBufferObject>() as u64);
-
-        let buffer_info = &[uniform_info];
-        let ubo_write = vk::WriteDescriptorSet::builder()
-            .dst_set(data.descriptor_sets[i])
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(buffer_info);
-
-        let sampler_info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(data.texture_image_view)
-            .sampler(data.texture_sampler);
-
-        let image_info = &[sampler_info];
-        let sampler_write = vk::WriteDescriptorSet::builder()
-            .dst_set(data.descriptor_sets[i])
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .image_info(image_info);
-
-        device.update_descriptor_sets(
-            &[ubo_write, sampler_write],
-            &[] as &[vk::CopyDescriptorSet]
-        );
-    }
+        .set_layouts(layouts);
+
+    data.descriptor_set = device.allocate_descriptor_sets(&info)?[0];
+
+    let uniform_info = vk::DescriptorBufferInfo::builder()
+        .buffer(data.frame_ring.buffer)
+        .offset(0)
+        .range(size_of::<UniformBufferObject>() as u64);
+
+    let buffer_info = &[uniform_info];
+    let ubo_write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.descriptor_set)
+        .dst_binding(0)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        .buffer_info(buffer_info);
+
+    let light_info = vk::DescriptorBufferInfo::builder()
+        .buffer(data.frame_ring.buffer)
+        .offset(0)
+        .range(size_of::<LightBufferObject>() as u64);
+
+    let light_buffer_info = &[light_info];
+    let light_write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.descriptor_set)
+        .dst_binding(1)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        .buffer_info(light_buffer_info);
+
+    let shadow_info = vk::DescriptorBufferInfo::builder()
+        .buffer(data.frame_ring.buffer)
+        .offset(0)
+        .range(size_of::<ShadowBufferObject>() as u64);
+
+    let shadow_buffer_info = &[shadow_info];
+    let shadow_write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.descriptor_set)
+        .dst_binding(2)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
+        .buffer_info(shadow_buffer_info);
+
+    let shadow_map_info = vk::DescriptorImageInfo::builder()
+        .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+        .image_view(data.graph.view(SHADOW_MAP)?)
+        .sampler(data.shadow_sampler);
+
+    let shadow_image_info = &[shadow_map_info];
+    let shadow_map_write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.descriptor_set)
+        .dst_binding(3)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(shadow_image_info);
+
+    let environment_info = vk::DescriptorImageInfo::builder()
+        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .image_view(data.environment.view)
+        .sampler(data.texture_sampler);
+
+    let environment_image_info = &[environment_info];
+    let environment_write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.descriptor_set)
+        .dst_binding(4)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(environment_image_info);
+
+    device.update_descriptor_sets(
+        &[ubo_write, light_write, shadow_write, shadow_map_write, environment_write],
+        &[] as &[vk::CopyDescriptorSet]
+    );
 
     Ok(())
 }
-
@@ -7,7 +7,13 @@ use vulkanalia::{vk::{self, DeviceQueueCreateInfo, DeviceV1_0, HasBuilder, Insta
 
 use crate::renderer::instance::VALIDATION_LAYER;
 
-use super::{instance::{PORTABILITY_MACOS_VERSION, VALIDATION_ENABLED}, swapchain::SwapchainSupport, RenderData};
+use super::{
+    instance::{PORTABILITY_MACOS_VERSION, VALIDATION_ENABLED},
+    swapchain::SwapchainSupport,
+    texture_compression::get_supported_block_formats,
+    texture_table::{get_descriptor_indexing_support, get_texture_capacity, TextureTable, DESCRIPTOR_INDEXING_EXTENSIONS},
+    RenderData,
+};
 
 const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
 
@@ -19,16 +25,19 @@ pub struct SuitabilityError(pub &'static str);
 pub struct QueueFamilyIndices
 {
     pub graphics: u32,
-    pub present: u32
+    pub present: u32,
+    // Dedicated transfer family if there is one, otherwise the graphics family
+    pub transfer: u32,
 }
 
 impl QueueFamilyIndices {
     pub unsafe fn get(instance: &Instance, data: & RenderData, physical_device : vk::PhysicalDevice) -> Result<Self>{
         let properties = instance.get_physical_device_queue_family_properties(physical_device);
 
+        // Culling is dispatched in the frame's command buffer, the spec guarantees a family that can do both
         let graphics = properties
             .iter()
-            .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+            .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE))
             .map(|i| i as u32);
 
         let mut present = None;
@@ -40,8 +49,19 @@ impl QueueFamilyIndices {
             }
         }
 
+        // Transfer only families run copies next to rendering, as long as they can copy single texels
+        let single_texel = vk::Extent3D {width: 1, height: 1, depth: 1};
+        let transfer = properties
+            .iter()
+            .position(|p| {
+                p.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                    && !p.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+                    && p.min_image_transfer_granularity == single_texel
+            })
+            .map(|i| i as u32);
+
         if let (Some(graphics), Some(present)) = (graphics, present) {
-            Ok(Self{graphics, present})
+            Ok(Self{graphics, present, transfer: transfer.unwrap_or(graphics)})
         }
         else {
             Err(anyhow!(SuitabilityError("Missing required queue families.")))
@@ -83,6 +103,11 @@ pub unsafe fn check_physical_device(instance: &Instance, data: & RenderData, phy
         return Err(anyhow!(SuitabilityError("No sampler anisotropy.")));
     }
 
+    // Materials pick their maps from a texture array with an index that is uniform across a draw
+    if features.shader_sampled_image_array_dynamic_indexing != vk::TRUE {
+        return Err(anyhow!(SuitabilityError("No dynamic indexing of sampled image arrays.")));
+    }
+
     Ok(())
 }
 
@@ -106,6 +131,7 @@ pub unsafe fn create_logical_device(entry: &Entry, instance: &Instance, data: &m
     let mut unique_indices = HashSet::new();
     unique_indices.insert(indices.graphics);
     unique_indices.insert(indices.present);
+    unique_indices.insert(indices.transfer);
 
     let queue_priorities = &[1.0];
     let queue_infos = unique_indices
@@ -133,19 +159,56 @@ pub unsafe fn create_logical_device(entry: &Entry, instance: &Instance, data: &m
         extensions.push(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name.as_ptr());
     }
 
+    // Indirect draws start at their batch's instances, without the feature the CPU culls instead
+    let supported = instance.get_physical_device_features(data.physical_device);
+    data.gpu_culling_supported = supported.draw_indirect_first_instance == vk::TRUE;
+
+    if !data.gpu_culling_supported {
+        warn!("No indirect first instance, culling on the CPU.");
+    }
+
+    // Formats can be supported one by one even without the feature
+    data.block_formats = get_supported_block_formats(instance, data);
+
+    if data.block_formats.is_empty() {
+        warn!("No block compressed formats, compressed textures are decompressed on the CPU.");
+    }
+
+    // Without descriptor indexing material textures go into a smaller array that is always fully written
+    data.descriptor_indexing = get_descriptor_indexing_support(instance, data);
+    data.texture_table = TextureTable::new(get_texture_capacity(instance, data));
+
+    if data.descriptor_indexing {
+        extensions.extend(DESCRIPTOR_INDEXING_EXTENSIONS.iter().map(|n| n.as_ptr()));
+    } else {
+        warn!("No descriptor indexing, material textures are limited to {}.", data.texture_table.capacity);
+    }
+
     let features  = vk::PhysicalDeviceFeatures::builder()
         .sampler_anisotropy(true)
-        .sample_rate_shading(true);
+        .sample_rate_shading(true)
+        .draw_indirect_first_instance(data.gpu_culling_supported)
+        .texture_compression_bc(supported.texture_compression_bc == vk::TRUE)
+        .shader_sampled_image_array_dynamic_indexing(true);
+
+    let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+        .descriptor_binding_partially_bound(true)
+        .descriptor_binding_sampled_image_update_after_bind(true);
 
-    let info = vk::DeviceCreateInfo::builder()
+    let mut info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
         .enabled_layer_names(&layers)
         .enabled_extension_names(&extensions)
         .enabled_features(&features);
 
+    if data.descriptor_indexing {
+        info = info.push_next(&mut indexing_features);
+    }
+
     let device = instance.create_device(data.physical_device, &info, None)?;
     data.graphics_queue = device.get_device_queue(indices.graphics, 0);
     data.present_queue = device.get_device_queue(indices.present, 0);
+    data.transfer_queue = device.get_device_queue(indices.transfer, 0);
 
     Ok(device)
 }
@@ -0,0 +1,475 @@
+use std::io::{BufRead, Cursor, Read};
+
+use anyhow::{anyhow, Result};
+use cgmath::InnerSpace;
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
+
+use super::{frame::FramePass, image::create_layered_texture, pipeline::create_shader_module, vfs::Vfs, RenderData};
+
+// Face size used when converting an equirectangular image or generating the procedural sky
+const MAX_CUBEMAP_SIZE: u32 = 1024;
+const PROCEDURAL_CUBEMAP_SIZE: u32 = 64;
+
+type Vec3 = cgmath::Vector3<f32>;
+
+#[derive(Clone, Debug, Default)]
+pub enum EnvironmentSource
+{
+    // Gradient sky generated on the CPU, used when no environment images are available
+    #[default]
+    Procedural,
+    // Six PNG images in the order +X, -X, +Y, -Y, +Z, -Z using the Y-up cubemap convention
+    Faces([String; 6]),
+    // Radiance `.hdr` image covering 360 degrees horizontally and 180 degrees vertically
+    Equirectangular(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct EnvironmentDescription
+{
+    pub source: EnvironmentSource,
+    pub intensity: f32,
+    // Use the environment for ambient and reflection lighting instead of the constant ambient term
+    pub lighting: bool,
+}
+
+impl Default for EnvironmentDescription {
+    fn default() -> Self
+    {
+        Self {
+            source: EnvironmentSource::Procedural,
+            intensity: 1.0,
+            lighting: true,
+        }
+    }
+}
+
+pub unsafe fn create_environment(instance: &Instance, device: &Device, data: &mut RenderData, description: &EnvironmentDescription) -> Result<()>
+{
+    let (size, faces) = match &description.source {
+        EnvironmentSource::Procedural => get_procedural_faces(PROCEDURAL_CUBEMAP_SIZE),
+        EnvironmentSource::Faces(paths) => load_faces(&data.vfs, paths)?,
+        EnvironmentSource::Equirectangular(path) => {
+            let (width, height, pixels) = load_hdr(&data.vfs, path)?;
+            let size = (width / 4).clamp(1, MAX_CUBEMAP_SIZE);
+            (size, get_equirectangular_faces(width, height, &pixels, size))
+        }
+    };
+
+    // Half floats keep the HDR range while still supporting linear filtering on every device
+    let pixels = faces
+        .iter()
+        .flat_map(|p| [p[0], p[1], p[2], 1.0])
+        .flat_map(|c| to_half(c).to_ne_bytes())
+        .collect::<Vec<_>>();
+
+    data.environment = create_layered_texture(
+        instance,
+        device,
+        data,
+        size,
+        size,
+        6,
+        &pixels,
+        vk::Format::R16G16B16A16_SFLOAT,
+        vk::ImageViewType::CUBE,
+        vk::ImageCreateFlags::CUBE_COMPATIBLE
+    )?;
+
+    data.environment_description = description.clone();
+
+    Ok(())
+}
+
+pub unsafe fn create_skybox_pipeline(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let vert = include_bytes!("../../shaders/skybox_vert.spv");
+    let frag = include_bytes!("../../shaders/skybox_frag.spv");
+
+    let vert_shader_module = create_shader_module(device, &vert[..])?;
+    let frag_shader_module = create_shader_module(device, &frag[..])?;
+
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    // The full screen triangle is generated from the vertex index
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder();
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(data.swapchain_extent.width as f32)
+        .height(data.swapchain_extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D{x:0, y:0})
+        .extent(data.swapchain_extent);
+
+    let viewports = &[viewport];
+    let scissors = &[scissor];
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewports(viewports)
+        .scissors(scissors);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(data.msaa_samples);
+
+    let attachment = vk::PipelineColorBlendAttachmentState::builder()
+        .color_write_mask(vk::ColorComponentFlags::all())
+        .blend_enable(false);
+
+    let attachments = &[attachment];
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(attachments)
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    // Drawn at the far plane after the scene so that only uncovered pixels are shaded
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(false)
+        .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
+        .depth_bounds_test_enable(false)
+        .min_depth_bounds(0.0)
+        .max_depth_bounds(1.0)
+        .stencil_test_enable(false);
+
+    let stages = &[vert_stage, frag_stage];
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .layout(data.pipeline_layout)
+        .render_pass(data.graph.render_pass(FramePass::Scene)?)
+        .subpass(0);
+
+    data.skybox_pipeline = device.create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?.0[0];
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    Ok(())
+}
+
+/*
+    The world is Z-up while cubemaps are sampled Y-up, so cubemap directions are
+    rotated -90 degrees around X. Keep in sync with `toCubemap` in lighting.glsl.
+ */
+fn cubemap_to_world(direction: Vec3) -> Vec3
+{
+    Vec3::new(direction.x, -direction.z, direction.y)
+}
+
+// Direction through the center of a texel, u and v are in the range -1.0 - 1.0
+fn get_face_direction(face: usize, u: f32, v: f32) -> Vec3
+{
+    match face {
+        0 => Vec3::new(1.0, -v, -u),
+        1 => Vec3::new(-1.0, -v, u),
+        2 => Vec3::new(u, 1.0, v),
+        3 => Vec3::new(u, -1.0, -v),
+        4 => Vec3::new(u, -v, 1.0),
+        _ => Vec3::new(-u, -v, -1.0),
+    }
+}
+
+// Calls `f` with the world space direction of every texel, faces and rows in upload order
+fn map_faces(size: u32, f: impl Fn(Vec3) -> [f32; 3]) -> Vec<[f32; 3]>
+{
+    let mut pixels = Vec::with_capacity((size * size * 6) as usize);
+    for face in 0..6 {
+        for y in 0..size {
+            for x in 0..size {
+                let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let direction = cubemap_to_world(get_face_direction(face, u, v)).normalize();
+                pixels.push(f(direction));
+            }
+        }
+    }
+
+    pixels
+}
+
+fn get_procedural_faces(size: u32) -> (u32, Vec<[f32; 3]>)
+{
+    let zenith = [0.15, 0.35, 0.75];
+    let horizon = [0.7, 0.8, 0.95];
+    let ground = [0.2, 0.18, 0.16];
+
+    let pixels = map_faces(size, |direction| {
+        let (from, to, t) = if direction.z >= 0.0 {
+            (horizon, zenith, direction.z.powf(0.5))
+        } else {
+            (horizon, ground, (-direction.z).powf(0.3))
+        };
+
+        [
+            from[0] + (to[0] - from[0]) * t,
+            from[1] + (to[1] - from[1]) * t,
+            from[2] + (to[2] - from[2]) * t,
+        ]
+    });
+
+    (size, pixels)
+}
+
+fn load_faces(vfs: &Vfs, paths: &[String; 6]) -> Result<(u32, Vec<[f32; 3]>)>
+{
+    let mut size = 0;
+    let mut pixels = vec![];
+
+    for path in paths {
+        let image = Cursor::new(vfs.read(path)?);
+
+        let mut decoder = png::Decoder::new(image);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+        buffer.truncate(info.buffer_size());
+
+        if info.width != info.height || (size != 0 && info.width != size) {
+            return Err(anyhow!("Cubemap face `{}` must be square and match the other faces.", path));
+        }
+
+        size = info.width;
+
+        let channels = info.color_type.samples();
+        pixels.extend(buffer.chunks_exact(channels).map(|p| {
+            let (r, g, b) = if channels >= 3 {(p[0], p[1], p[2])} else {(p[0], p[0], p[0])};
+            [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b)]
+        }));
+    }
+
+    Ok((size, pixels))
+}
+
+fn get_equirectangular_faces(width: u32, height: u32, pixels: &[[f32; 3]], size: u32) -> Vec<[f32; 3]>
+{
+    use std::f32::consts::PI;
+
+    let sample = |x: i64, y: i64| -> [f32; 3] {
+        let x = x.rem_euclid(width as i64) as usize;
+        let y = y.clamp(0, height as i64 - 1) as usize;
+        pixels[y * width as usize + x]
+    };
+
+    map_faces(size, |direction| {
+        // Longitude wraps around the Z axis, latitude runs from the top (+Z) to the bottom of the image
+        let longitude = direction.y.atan2(direction.x);
+        let latitude = direction.z.clamp(-1.0, 1.0).acos();
+
+        let x = (0.5 - longitude / (2.0 * PI)) * width as f32 - 0.5;
+        let y = (latitude / PI) * height as f32 - 0.5;
+
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+
+        let a = sample(x0 as i64, y0 as i64);
+        let b = sample(x0 as i64 + 1, y0 as i64);
+        let c = sample(x0 as i64, y0 as i64 + 1);
+        let d = sample(x0 as i64 + 1, y0 as i64 + 1);
+
+        let mut color = [0.0; 3];
+        for i in 0..3 {
+            let top = a[i] + (b[i] - a[i]) * tx;
+            let bottom = c[i] + (d[i] - c[i]) * tx;
+            color[i] = top + (bottom - top) * ty;
+        }
+
+        color
+    })
+}
+
+/*
+    Decodes a Radiance RGBE image, both flat and run length encoded scanlines are supported.
+    Only the standard `-Y height +X width` orientation is accepted.
+ */
+fn load_hdr(vfs: &Vfs, path: &str) -> Result<(u32, u32, Vec<[f32; 3]>)>
+{
+    let mut reader = Cursor::new(vfs.read(path)?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(anyhow!("`{}` is not a Radiance HDR image.", path));
+    }
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("Unexpected end of HDR header in `{}`.", path));
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            break;
+        }
+
+        if let Some(format) = trimmed.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(anyhow!("Unsupported HDR format `{}` in `{}`.", format, path));
+            }
+        }
+    }
+
+    line.clear();
+    reader.read_line(&mut line)?;
+    let resolution = line.split_whitespace().collect::<Vec<_>>();
+    let (height, width) = match resolution[..] {
+        ["-Y", height, "+X", width] => (height.parse::<u32>()?, width.parse::<u32>()?),
+        _ => return Err(anyhow!("Unsupported HDR orientation `{}` in `{}`.", line.trim(), path)),
+    };
+
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+
+    for _ in 0..height {
+        read_hdr_scanline(&mut reader, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|rgbe| rgbe_to_float(*rgbe)));
+    }
+
+    Ok((width, height, pixels))
+}
+
+fn read_hdr_scanline(reader: &mut impl Read, scanline: &mut [[u8; 4]]) -> Result<()>
+{
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header)?;
+
+    let width = scanline.len();
+    let encoded_width = ((header[2] as usize) << 8) | header[3] as usize;
+
+    // Scanlines outside 8 - 32767 pixels or without the marker are stored flat
+    if !(8..=0x7fff).contains(&width) || header[0] != 2 || header[1] != 2 || header[2] & 0x80 != 0 {
+        scanline[0] = header;
+        for pixel in scanline.iter_mut().skip(1) {
+            reader.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+
+    if encoded_width != width {
+        return Err(anyhow!("HDR scanline width does not match the image width."));
+    }
+
+    // Each channel is encoded separately as runs and literal spans
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            reader.read_exact(&mut count)?;
+
+            if count[0] > 128 {
+                let run = (count[0] - 128) as usize;
+                let mut value = [0u8; 1];
+                reader.read_exact(&mut value)?;
+
+                if x + run > width {
+                    return Err(anyhow!("HDR run overflows the scanline."));
+                }
+
+                for pixel in &mut scanline[x..x + run] {
+                    pixel[channel] = value[0];
+                }
+                x += run;
+            } else {
+                let span = count[0] as usize;
+                if span == 0 || x + span > width {
+                    return Err(anyhow!("Invalid HDR literal span."));
+                }
+
+                let mut values = vec![0u8; span];
+                reader.read_exact(&mut values)?;
+
+                for (pixel, value) in scanline[x..x + span].iter_mut().zip(values) {
+                    pixel[channel] = value;
+                }
+                x += span;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe_to_float(rgbe: [u8; 4]) -> [f32; 3]
+{
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+
+    let scale = 2.0_f32.powi(rgbe[3] as i32 - 136);
+    [rgbe[0] as f32 * scale, rgbe[1] as f32 * scale, rgbe[2] as f32 * scale]
+}
+
+fn srgb_to_linear(value: u8) -> f32
+{
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Rounds to nearest, values outside the half float range become infinity and denormals are flushed to zero
+fn to_half(value: f32) -> u16
+{
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        let nan = if mantissa != 0 {0x200} else {0};
+        return sign | 0x7c00 | nan;
+    }
+
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        return sign;
+    }
+
+    let half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    let round = (mantissa >> 12) & 1;
+
+    sign | (half + round) as u16
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use vulkanalia::{vk::{self, DeviceV1_0}, Device, Instance};
+
+use super::{
+    bloom::{add_bloom_passes, create_bloom_objects, destroy_bloom_objects},
+    descriptor::{create_descriptor_pool, create_descriptor_sets},
+    device::get_depth_format,
+    environment::create_skybox_pipeline,
+    graph::{Attachment, ImageDescription, RenderGraph},
+    particle::{create_particle_pipelines, destroy_particle_pipelines},
+    pipeline::create_pipeline,
+    post::{add_post_passes, create_post_processing, destroy_post_processing, HDR_FORMAT},
+    shadow::{add_shadow_passes, create_shadow_pipeline},
+    RenderData,
+};
+
+// Resolve target of the scene pass, read by bloom and the post-processing chain
+pub const HDR_IMAGE: &str = "hdr";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FramePass
+{
+    // Renders the shadow casters into one layer of the shadow map
+    Shadow(usize),
+    Scene,
+    // Index into `get_bloom_steps`
+    Bloom(usize),
+    // Index into `RenderData::post_passes`
+    Post(usize),
+}
+
+pub unsafe fn create_frame_graph(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let extent = data.swapchain_extent;
+    let mut graph = RenderGraph::new();
+
+    let swapchain = graph.import_image(
+        "swapchain",
+        &data.swapchain_images,
+        &data.swapchain_image_views,
+        ImageDescription::new(data.swapchain_format, extent),
+        vk::ImageLayout::PRESENT_SRC_KHR
+    );
+
+    let shadow_map = add_shadow_passes(instance, &mut graph, data)?;
+
+    let color = graph.create_image("scene color", ImageDescription::new(HDR_FORMAT, extent).with_samples(data.msaa_samples));
+    let depth_format = get_depth_format(instance, data)?;
+    let depth = graph.create_image("scene depth", ImageDescription::new(depth_format, extent).with_samples(data.msaa_samples));
+    let hdr = graph.create_image(HDR_IMAGE, ImageDescription::new(HDR_FORMAT, extent));
+
+    graph.add_pass(FramePass::Scene)
+        .color(Attachment::clear_color(color, [0.0, 0.0, 0.0, 1.0]).without_store())
+        .resolve(Attachment::discard(hdr))
+        .depth(Attachment::clear_depth(depth, 1.0).without_store())
+        .sample(shadow_map)
+        .secondary();
+
+    data.bloom_extents.clear();
+    if data.bloom_settings.enabled {
+        add_bloom_passes(&mut graph, data, hdr);
+    }
+
+    add_post_passes(&mut graph, data, hdr, swapchain);
+
+    graph.compile(instance, device, data)?;
+    data.graph = graph;
+
+    Ok(())
+}
+
+// Everything built against the graph's render passes or images, recreated whenever the graph changes
+pub unsafe fn create_frame_resources(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    create_frame_graph(instance, device, data)?;
+
+    create_pipeline(device, data)?;
+    create_skybox_pipeline(device, data)?;
+    create_particle_pipelines(device, data)?;
+    create_shadow_pipeline(device, data)?;
+    create_bloom_objects(device, data)?;
+    create_post_processing(device, data)?;
+
+    create_descriptor_pool(device, data)?;
+    create_descriptor_sets(device, data)?;
+
+    Ok(())
+}
+
+pub unsafe fn destroy_frame_resources(device: &Device, data: &RenderData)
+{
+    device.destroy_descriptor_pool(data.descriptor_pool, None);
+
+    destroy_post_processing(device, data);
+    destroy_bloom_objects(device, data);
+
+    destroy_particle_pipelines(device, data);
+
+    device.destroy_pipeline(data.shadow_pipeline, None);
+    device.destroy_pipeline_layout(data.shadow_pipeline_layout, None);
+
+    device.destroy_pipeline(data.pipeline, None);
+    device.destroy_pipeline(data.pbr_pipeline, None);
+    device.destroy_pipeline(data.skybox_pipeline, None);
+    device.destroy_pipeline_layout(data.pipeline_layout, None);
+
+    data.graph.destroy(device, &mut data.allocator.lock().unwrap());
+}
@@ -0,0 +1,308 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use cgmath::{InnerSpace, Matrix, SquareMatrix};
+use gltf::{buffer::Source, mesh::Mode, Gltf};
+
+use crate::math::vector::{Vector2, Vector3, Vector4};
+
+use super::{assets::Mesh, vertex::Vertex, vfs::{normalize_path, Vfs}};
+
+type Mat4 = cgmath::Matrix4<f32>;
+type Mat3 = cgmath::Matrix3<f32>;
+type Vec3 = cgmath::Vector3<f32>;
+type Vec4 = cgmath::Vector4<f32>;
+
+/*
+    Flattens every mesh of the default scene into one mesh with the node transforms applied,
+    like the models of an OBJ file end up in one. glTF is Y up while the game is Z up, so the
+    whole scene is rotated about X. Only triangle lists are read, vertex colours come from
+    COLOR_0 and are white without it. Tangents, LODs and the vertex order are left to the caller.
+ */
+pub fn parse_gltf(vfs: &Vfs, path: &str) -> Result<Mesh>
+{
+    let gltf = Gltf::from_slice(&vfs.read(path)?)?;
+    let buffers = read_buffers(vfs, path, &gltf)?;
+
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or_else(|| anyhow!("`{}` has no scene.", path))?;
+
+    // Y becomes Z and Z becomes -Y, exactly rather than through a rotation by an angle
+    let z_up = Mat4::from_cols(Vec4::unit_x(), Vec4::unit_z(), -Vec4::unit_y(), Vec4::unit_w());
+
+    let mut mesh = Mesh::default();
+    let mut nodes = scene.nodes().map(|n| (n, z_up)).collect::<Vec<_>>();
+
+    while let Some((node, parent)) = nodes.pop() {
+        let transform = parent * Mat4::from(node.transform().matrix());
+        nodes.extend(node.children().map(|c| (c, transform)));
+
+        for primitive in node.mesh().iter().flat_map(|m| m.primitives()) {
+            append_primitive(&mut mesh, &primitive, transform, &buffers, path)?;
+        }
+    }
+
+    if mesh.indices.is_empty() {
+        return Err(anyhow!("`{}` has no triangles.", path));
+    }
+
+    Ok(mesh)
+}
+
+fn append_primitive(mesh: &mut Mesh, primitive: &gltf::Primitive, transform: Mat4, buffers: &[Vec<u8>], path: &str) -> Result<()>
+{
+    if primitive.mode() != Mode::Triangles {
+        return Err(anyhow!("`{}` has a primitive that isn't a triangle list.", path));
+    }
+
+    let reader = primitive.reader(|b| buffers.get(b.index()).map(|b| b.as_slice()));
+
+    let positions = reader
+        .read_positions()
+        .ok_or_else(|| anyhow!("`{}` has a primitive without positions.", path))?
+        .collect::<Vec<_>>();
+
+    let tex_coords = reader
+        .read_tex_coords(0)
+        .ok_or_else(|| anyhow!("`{}` has a primitive without texture coordinates.", path))?
+        .into_f32()
+        .collect::<Vec<_>>();
+
+    let normals = reader.read_normals().map(|n| n.collect::<Vec<_>>());
+    let colors = reader.read_colors(0).map(|c| c.into_rgb_f32().collect::<Vec<_>>());
+
+    let count = positions.len();
+    let complete = tex_coords.len() == count
+        && normals.as_ref().is_none_or(|n| n.len() == count)
+        && colors.as_ref().is_none_or(|c| c.len() == count);
+
+    if !complete {
+        return Err(anyhow!("`{}` has a primitive whose attributes have different lengths.", path));
+    }
+
+    let indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..count as u32).collect(),
+    };
+
+    if indices.iter().any(|&i| i as usize >= count) {
+        return Err(anyhow!("`{}` has an index out of range.", path));
+    }
+
+    let linear = Mat3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+    let normal_matrix = linear.invert().map_or(linear, |m| m.transpose());
+
+    let first = mesh.vertices.len() as u32;
+    for (i, position) in positions.iter().enumerate() {
+        let position = transform * Vec3::from(*position).extend(1.0);
+        let normal = normals.as_ref().map_or(Vec3::unit_z(), |n| (normal_matrix * Vec3::from(n[i])).normalize());
+        let color = colors.as_ref().map_or([1.0; 3], |c| c[i]);
+
+        mesh.vertices.push(Vertex::new(
+            Vector3::new(position.x, position.y, position.z),
+            Vector3::new(color[0], color[1], color[2]),
+            Vector2::new(tex_coords[i][0], tex_coords[i][1]),
+            Vector3::new(normal.x, normal.y, normal.z),
+            Vector4::new(0.0, 0.0, 0.0, 1.0),
+        ));
+    }
+
+    // Mirroring transforms turn the triangles inside out
+    let mirrored = linear.determinant() < 0.0;
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| first + i);
+        mesh.indices.extend(if mirrored {[a, c, b]} else {[a, b, c]});
+    }
+
+    Ok(())
+}
+
+fn read_buffers(vfs: &Vfs, path: &str, gltf: &Gltf) -> Result<Vec<Vec<u8>>>
+{
+    let mut buffers = vec![];
+
+    for buffer in gltf.buffers() {
+        let bytes = match buffer.source() {
+            Source::Bin => gltf.blob.clone().ok_or_else(|| anyhow!("`{}` has no binary chunk.", path))?,
+            Source::Uri(uri) if uri.starts_with("data:") => decode_data_uri(uri)?,
+            Source::Uri(uri) => vfs.read(&get_uri_path(path, uri)?)?.to_vec(),
+        };
+
+        if bytes.len() < buffer.length() {
+            return Err(anyhow!("Buffer {} of `{}` is shorter than declared.", buffer.index(), path));
+        }
+
+        buffers.push(bytes);
+    }
+
+    // The reader slices buffers by their views without checking
+    for view in gltf.views() {
+        if view.offset() + view.length() > view.buffer().length() {
+            return Err(anyhow!("Buffer view {} of `{}` is out of range.", view.index(), path));
+        }
+    }
+
+    Ok(buffers)
+}
+
+// Files next to the glTF file its buffers are read from, so that changing them is noticed like changing the file
+pub fn get_buffer_paths(path: &str, bytes: &[u8]) -> Result<Vec<String>>
+{
+    let gltf = Gltf::from_slice(bytes)?;
+
+    gltf.buffers()
+        .filter_map(|buffer| match buffer.source() {
+            Source::Uri(uri) if !uri.starts_with("data:") => Some(get_uri_path(path, uri)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>>
+{
+    let (_, data) = uri
+        .split_once(";base64,")
+        .ok_or_else(|| anyhow!("Only base64 data URIs are supported."))?;
+
+    Ok(base64::engine::general_purpose::STANDARD.decode(data)?)
+}
+
+// URIs are percent-encoded and relative to the file that references them
+fn get_uri_path(path: &str, uri: &str) -> Result<String>
+{
+    let mut decoded = vec![];
+    let mut bytes = uri.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let digits = [bytes.next(), bytes.next()];
+        let value = digits
+            .iter()
+            .map(|d| d.and_then(|d| (d as char).to_digit(16)))
+            .try_fold(0, |value, digit| digit.map(|d| 16 * value + d as u8))
+            .ok_or_else(|| anyhow!("Invalid URI `{}`.", uri))?;
+
+        decoded.push(value);
+    }
+
+    let directory = Path::new(path).parent().and_then(|p| p.to_str()).unwrap_or_default();
+    normalize_path(&format!("{}/{}", directory, String::from_utf8(decoded)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One triangle in the XZ plane facing +Y, moved one unit along X by its node
+    fn get_triangle_gltf(uri: &str, scale: f32) -> String
+    {
+        format!(r#"{{
+            "asset": {{"version": "2.0"}},
+            "scene": 0,
+            "scenes": [{{"nodes": [0]}}],
+            "nodes": [{{"mesh": 0, "translation": [1, 0, 0], "scale": [{scale}, 1, 1]}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2}}, "indices": 3}}]}}],
+            "buffers": [{{"uri": "{uri}", "byteLength": 108}}],
+            "bufferViews": [
+                {{"buffer": 0, "byteOffset": 0, "byteLength": 36}},
+                {{"buffer": 0, "byteOffset": 36, "byteLength": 36}},
+                {{"buffer": 0, "byteOffset": 72, "byteLength": 24}},
+                {{"buffer": 0, "byteOffset": 96, "byteLength": 12}}
+            ],
+            "accessors": [
+                {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, -1], "max": [1, 0, 0]}},
+                {{"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3"}},
+                {{"bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC2"}},
+                {{"bufferView": 3, "componentType": 5125, "count": 3, "type": "SCALAR"}}
+            ]
+        }}"#)
+    }
+
+    fn get_triangle_buffer() -> Vec<u8>
+    {
+        let floats: [f32; 24] = [
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, -1.0,
+            0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 1.0, 0.0, 0.0, 1.0,
+        ];
+
+        let mut bytes = floats.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<_>>();
+        bytes.extend([0u32, 1, 2].iter().flat_map(|i| i.to_le_bytes()));
+        bytes
+    }
+
+    fn parse(name: &str, files: &[(&str, &[u8])]) -> Result<Mesh>
+    {
+        let directory = std::env::temp_dir().join(format!("gltf_import_test_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(directory.join("models")).unwrap();
+
+        for (path, bytes) in files {
+            std::fs::write(directory.join(path), bytes).unwrap();
+        }
+
+        let mut vfs = Vfs::default();
+        vfs.mount_directory(&directory, 0);
+        let mesh = parse_gltf(&vfs, "models/triangle.gltf");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+        mesh
+    }
+
+    #[test]
+    fn triangles_are_read_from_external_buffers_and_turned_z_up()
+    {
+        let gltf = get_triangle_gltf("triangle%20data.bin", 1.0);
+        let buffer = get_triangle_buffer();
+        let mesh = parse("external", &[("models/triangle.gltf", gltf.as_bytes()), ("models/triangle data.bin", &buffer)]).unwrap();
+
+        assert_eq!(mesh.indices, [0, 1, 2]);
+
+        // Y up becomes Z up, so -Z becomes +Y
+        let positions = mesh.vertices.iter().map(|v| [v.position.x, v.position.y, v.position.z]).collect::<Vec<_>>();
+        assert_eq!(positions, [[1.0, 0.0, 0.0], [2.0, 0.0, 0.0], [1.0, 1.0, 0.0]]);
+
+        for vertex in &mesh.vertices {
+            assert!((vertex.normal.z - 1.0).abs() < 1e-6);
+            assert_eq!([vertex.color.x, vertex.color.y, vertex.color.z], [1.0; 3]);
+        }
+
+        assert_eq!([mesh.vertices[2].tex_coord.x, mesh.vertices[2].tex_coord.y], [0.0, 1.0]);
+    }
+
+    #[test]
+    fn embedded_buffers_and_mirrored_nodes_are_supported()
+    {
+        let uri = format!("data:application/octet-stream;base64,{}", base64::engine::general_purpose::STANDARD.encode(get_triangle_buffer()));
+        let gltf = get_triangle_gltf(&uri, -1.0);
+        let mesh = parse("embedded", &[("models/triangle.gltf", gltf.as_bytes())]).unwrap();
+
+        // The winding flips so that the triangle still faces the way its normals do
+        assert_eq!(mesh.indices, [0, 2, 1]);
+        assert_eq!(mesh.vertices[1].position.x, 0.0);
+    }
+
+    #[test]
+    fn short_buffers_are_rejected()
+    {
+        let gltf = get_triangle_gltf("triangle.bin", 1.0);
+        let buffer = get_triangle_buffer();
+        let result = parse("short", &[("models/triangle.gltf", gltf.as_bytes()), ("models/triangle.bin", &buffer[..100])]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn buffer_paths_are_relative_to_the_file()
+    {
+        let gltf = get_triangle_gltf("buffers/triangle.bin", 1.0);
+        assert_eq!(get_buffer_paths("models/triangle.gltf", gltf.as_bytes()).unwrap(), ["models/buffers/triangle.bin"]);
+        assert!(get_buffer_paths("triangle.gltf", get_triangle_gltf("../triangle.bin", 1.0).as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,1032 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
+
+use super::{allocator::{Allocation, Allocator}, barrier::{get_view_aspects, is_depth_format, record_image_barriers, ImageBarrier}, image::{create_layered_image, create_layered_image_view}, RenderData};
+
+pub type ResourceId = usize;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ImageDescription
+{
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub samples: vk::SampleCountFlags,
+    pub layers: u32,
+}
+
+impl ImageDescription {
+    pub fn new(format: vk::Format, extent: vk::Extent2D) -> Self
+    {
+        Self {
+            format,
+            extent,
+            samples: vk::SampleCountFlags::_1,
+            layers: 1,
+        }
+    }
+
+    pub fn with_samples(mut self, samples: vk::SampleCountFlags) -> Self
+    {
+        self.samples = samples;
+        self
+    }
+
+    pub fn with_layers(mut self, layers: u32) -> Self
+    {
+        self.layers = layers;
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Resource
+{
+    name: String,
+    description: ImageDescription,
+    // Imported images are owned elsewhere, the presentation target has one image per swapchain image
+    imported: bool,
+    // Layout an imported image is left in at the end of the frame, UNDEFINED leaves it as is
+    final_layout: vk::ImageLayout,
+    images: Vec<vk::Image>,
+    views: Vec<vk::ImageView>,
+    // Single layer views of layered transient images, used as attachments
+    layer_views: Vec<vk::ImageView>,
+    memory: Allocation,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Attachment
+{
+    pub resource: ResourceId,
+    // Layer of a layered image to render into, None uses the whole view
+    pub layer: Option<u32>,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub clear_value: vk::ClearValue,
+}
+
+impl Attachment {
+    fn new(resource: ResourceId, load_op: vk::AttachmentLoadOp, clear_value: vk::ClearValue) -> Self
+    {
+        Self {
+            resource,
+            layer: None,
+            load_op,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear_value,
+        }
+    }
+
+    pub fn clear_color(resource: ResourceId, color: [f32; 4]) -> Self
+    {
+        let clear_value = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: color,
+            },
+        };
+
+        Self::new(resource, vk::AttachmentLoadOp::CLEAR, clear_value)
+    }
+
+    pub fn clear_depth(resource: ResourceId, depth: f32) -> Self
+    {
+        let clear_value = vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth,
+                stencil: 0,
+            },
+        };
+
+        Self::new(resource, vk::AttachmentLoadOp::CLEAR, clear_value)
+    }
+
+    // Keeps the previous contents, the pass then also depends on whoever wrote them
+    pub fn load(resource: ResourceId) -> Self
+    {
+        Self::new(resource, vk::AttachmentLoadOp::LOAD, vk::ClearValue::default())
+    }
+
+    // The pass overwrites every pixel so the previous contents are not needed
+    pub fn discard(resource: ResourceId) -> Self
+    {
+        Self::new(resource, vk::AttachmentLoadOp::DONT_CARE, vk::ClearValue::default())
+    }
+
+    pub fn with_layer(mut self, layer: u32) -> Self
+    {
+        self.layer = Some(layer);
+        self
+    }
+
+    // Contents are only needed inside the pass, such as multisampled targets that get resolved
+    pub fn without_store(mut self) -> Self
+    {
+        self.store_op = vk::AttachmentStoreOp::DONT_CARE;
+        self
+    }
+}
+
+// Layout and pending accesses of one image layer, reads in the same layout accumulate
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct LayerState
+{
+    layout: vk::ImageLayout,
+    stage_mask: vk::PipelineStageFlags,
+    access_mask: vk::AccessFlags,
+    write: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Barrier
+{
+    resource: ResourceId,
+    base_layer: u32,
+    layer_count: u32,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_stage_mask: vk::PipelineStageFlags,
+    src_access_mask: vk::AccessFlags,
+    dst_stage_mask: vk::PipelineStageFlags,
+    dst_access_mask: vk::AccessFlags,
+}
+
+impl Barrier {
+    fn extends(&self, other: &Barrier) -> bool
+    {
+        self.resource == other.resource
+            && self.base_layer + self.layer_count == other.base_layer
+            && self.old_layout == other.old_layout
+            && self.new_layout == other.new_layout
+            && self.src_stage_mask == other.src_stage_mask
+            && self.src_access_mask == other.src_access_mask
+            && self.dst_stage_mask == other.dst_stage_mask
+            && self.dst_access_mask == other.dst_access_mask
+    }
+}
+
+/*
+    A pass renders into its attachments with a single subpass and may sample other images
+    in its fragment shader. The payload identifies the pass when it is recorded.
+ */
+#[derive(Clone, Debug)]
+pub struct Pass<P>
+{
+    pub payload: P,
+    pub extent: vk::Extent2D,
+    color_attachments: Vec<Attachment>,
+    // One per color attachment when present
+    resolve_attachments: Vec<Attachment>,
+    depth_attachment: Option<Attachment>,
+    sampled: Vec<ResourceId>,
+    contents: vk::SubpassContents,
+    render_pass: vk::RenderPass,
+    framebuffers: Vec<vk::Framebuffer>,
+    barriers: Vec<Barrier>,
+}
+
+impl<P> Pass<P> {
+    pub fn color(&mut self, attachment: Attachment) -> &mut Self
+    {
+        self.color_attachments.push(attachment);
+        self
+    }
+
+    pub fn resolve(&mut self, attachment: Attachment) -> &mut Self
+    {
+        self.resolve_attachments.push(attachment);
+        self
+    }
+
+    pub fn depth(&mut self, attachment: Attachment) -> &mut Self
+    {
+        self.depth_attachment = Some(attachment);
+        self
+    }
+
+    // Reads every layer of the image from the fragment shader
+    pub fn sample(&mut self, resource: ResourceId) -> &mut Self
+    {
+        self.sampled.push(resource);
+        self
+    }
+
+    // The pass' draws are recorded into secondary command buffers
+    pub fn secondary(&mut self) -> &mut Self
+    {
+        self.contents = vk::SubpassContents::SECONDARY_COMMAND_BUFFERS;
+        self
+    }
+
+    // In render pass attachment order
+    fn attachments(&self) -> impl Iterator<Item = &Attachment>
+    {
+        self.color_attachments
+            .iter()
+            .chain(self.resolve_attachments.iter())
+            .chain(self.depth_attachment.iter())
+    }
+
+    fn get_reads(&self) -> Vec<ResourceId>
+    {
+        self.attachments()
+            .filter(|a| a.load_op == vk::AttachmentLoadOp::LOAD)
+            .map(|a| a.resource)
+            .chain(self.sampled.iter().cloned())
+            .collect()
+    }
+
+    fn get_writes(&self) -> Vec<ResourceId>
+    {
+        self.attachments()
+            .map(|a| a.resource)
+            .collect()
+    }
+
+    fn get_usages(&self, resources: &[Resource]) -> Vec<(ResourceId, Range<u32>, LayerState)>
+    {
+        let layers = |attachment: &Attachment| match attachment.layer {
+            Some(layer) => layer..layer + 1,
+            None => 0..resources[attachment.resource].description.layers,
+        };
+
+        let mut usages = vec![];
+
+        for attachment in self.color_attachments.iter().chain(self.resolve_attachments.iter()) {
+            let mut access_mask = vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+            if attachment.load_op == vk::AttachmentLoadOp::LOAD {
+                access_mask |= vk::AccessFlags::COLOR_ATTACHMENT_READ;
+            }
+
+            usages.push((attachment.resource, layers(attachment), LayerState {
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                access_mask,
+                write: true,
+            }));
+        }
+
+        if let Some(attachment) = &self.depth_attachment {
+            usages.push((attachment.resource, layers(attachment), LayerState {
+                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                write: true,
+            }));
+        }
+
+        for resource in &self.sampled {
+            let description = &resources[*resource].description;
+            let layout = if is_depth_format(description.format) {
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+            } else {
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            };
+
+            usages.push((*resource, 0..description.layers, LayerState {
+                layout,
+                stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                access_mask: vk::AccessFlags::SHADER_READ,
+                write: false,
+            }));
+        }
+
+        usages
+    }
+}
+
+/*
+    Passes are declared once per swapchain and compiled into render passes, framebuffers and
+    the barriers between them. Executing the graph records every pass for one frame, `variant`
+    selects which image of an imported resource with several images, the swapchain, is used.
+ */
+#[derive(Clone, Debug)]
+pub struct RenderGraph<P>
+{
+    resources: Vec<Resource>,
+    passes: Vec<Pass<P>>,
+    // Indices of the passes that survived culling, in execution order
+    order: Vec<usize>,
+    final_barriers: Vec<Barrier>,
+}
+
+impl<P> Default for RenderGraph<P> {
+    fn default() -> Self
+    {
+        Self {
+            resources: vec![],
+            passes: vec![],
+            order: vec![],
+            final_barriers: vec![],
+        }
+    }
+}
+
+impl<P: Copy + Debug + PartialEq> RenderGraph<P> {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    // Allocated by the graph on compile, contents don't survive from one frame to the next
+    pub fn create_image(&mut self, name: &str, description: ImageDescription) -> ResourceId
+    {
+        self.resources.push(Resource {
+            name: name.to_string(),
+            description,
+            ..Default::default()
+        });
+
+        self.resources.len() - 1
+    }
+
+    /*
+        Images owned outside the graph. Their contents are discarded at the start of every frame
+        and the first access waits on COLOR_ATTACHMENT_OUTPUT, the stage the acquire semaphore
+        is waited on. Passes writing to them are never culled.
+     */
+    pub fn import_image(&mut self, name: &str, images: &[vk::Image], views: &[vk::ImageView], description: ImageDescription, final_layout: vk::ImageLayout) -> ResourceId
+    {
+        self.resources.push(Resource {
+            name: name.to_string(),
+            description,
+            imported: true,
+            final_layout,
+            images: images.to_vec(),
+            views: views.to_vec(),
+            ..Default::default()
+        });
+
+        self.resources.len() - 1
+    }
+
+    pub fn add_pass(&mut self, payload: P) -> &mut Pass<P>
+    {
+        self.passes.push(Pass {
+            payload,
+            extent: vk::Extent2D::default(),
+            color_attachments: vec![],
+            resolve_attachments: vec![],
+            depth_attachment: None,
+            sampled: vec![],
+            contents: vk::SubpassContents::INLINE,
+            render_pass: vk::RenderPass::null(),
+            framebuffers: vec![],
+            barriers: vec![],
+        });
+
+        self.passes.last_mut().unwrap()
+    }
+
+    pub unsafe fn compile(&mut self, instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+    {
+        self.order = self.get_live_passes();
+        self.allocate_images(instance, device, data)?;
+
+        for index in self.order.clone() {
+            self.create_render_pass(device, index)?;
+        }
+
+        self.create_barriers();
+
+        Ok(())
+    }
+
+    /*
+        A pass only ever depends on passes declared before it, so declaration order is already a
+        valid execution order. Passes that don't contribute to an imported image are culled.
+     */
+    fn get_live_passes(&self) -> Vec<usize>
+    {
+        let mut dependencies = vec![vec![]; self.passes.len()];
+        let mut last_writers = HashMap::new();
+        let mut readers: HashMap<ResourceId, Vec<usize>> = HashMap::new();
+
+        for (index, pass) in self.passes.iter().enumerate() {
+            for resource in pass.get_reads() {
+                if let Some(writer) = last_writers.get(&resource) {
+                    dependencies[index].push(*writer);
+                }
+
+                readers.entry(resource).or_default().push(index);
+            }
+
+            // Writers wait for the previous writer and for everyone who read its contents
+            for resource in pass.get_writes() {
+                if let Some(writer) = last_writers.insert(resource, index) {
+                    dependencies[index].push(writer);
+                }
+
+                if let Some(previous) = readers.remove(&resource) {
+                    dependencies[index].extend(previous.into_iter().filter(|r| *r != index));
+                }
+            }
+        }
+
+        let mut live = vec![false; self.passes.len()];
+        let mut pending = self.passes
+            .iter()
+            .enumerate()
+            .filter(|(_, pass)| pass.get_writes().iter().any(|r| self.resources[*r].imported))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        while let Some(index) = pending.pop() {
+            if !live[index] {
+                live[index] = true;
+                pending.extend(dependencies[index].iter().cloned());
+            }
+        }
+
+        (0..self.passes.len())
+            .filter(|index| live[*index])
+            .collect()
+    }
+
+    unsafe fn allocate_images(&mut self, instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+    {
+        let mut usages = vec![vk::ImageUsageFlags::empty(); self.resources.len()];
+        let mut stored = vec![false; self.resources.len()];
+
+        for pass in self.order.iter().map(|index| &self.passes[*index]) {
+            for attachment in pass.attachments() {
+                usages[attachment.resource] |= if is_depth_format(self.resources[attachment.resource].description.format) {
+                    vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                } else {
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                };
+
+                stored[attachment.resource] |= attachment.store_op == vk::AttachmentStoreOp::STORE
+                    || attachment.load_op == vk::AttachmentLoadOp::LOAD;
+            }
+
+            for resource in &pass.sampled {
+                usages[*resource] |= vk::ImageUsageFlags::SAMPLED;
+            }
+        }
+
+        for (index, resource) in self.resources.iter_mut().enumerate() {
+            if resource.imported || usages[index].is_empty() {
+                continue;
+            }
+
+            // Contents that never leave a render pass don't need to be written back to memory
+            let mut usage = usages[index];
+            if !stored[index] && !usage.contains(vk::ImageUsageFlags::SAMPLED) {
+                usage |= vk::ImageUsageFlags::TRANSIENT_ATTACHMENT;
+            }
+
+            let description = resource.description;
+            let (image, memory) = create_layered_image(
+                instance,
+                device,
+                data,
+                description.extent.width,
+                description.extent.height,
+                1,
+                description.layers,
+                description.samples,
+                description.format,
+                vk::ImageTiling::OPTIMAL,
+                usage,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                vk::ImageCreateFlags::empty()
+            )?;
+
+            let aspects = get_view_aspects(description.format);
+            let view_type = if description.layers > 1 {vk::ImageViewType::_2D_ARRAY} else {vk::ImageViewType::_2D};
+            let view = create_layered_image_view(device, image, view_type, description.format, aspects, 1, 0, description.layers)?;
+
+            resource.layer_views = if description.layers > 1 {
+                (0..description.layers)
+                    .map(|layer| create_layered_image_view(device, image, vk::ImageViewType::_2D, description.format, aspects, 1, layer, 1))
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                vec![]
+            };
+
+            resource.images = vec![image];
+            resource.views = vec![view];
+            resource.memory = memory;
+        }
+
+        Ok(())
+    }
+
+    fn get_attachment_view(&self, attachment: &Attachment, variant: usize) -> vk::ImageView
+    {
+        let resource = &self.resources[attachment.resource];
+
+        match attachment.layer {
+            Some(layer) if !resource.layer_views.is_empty() => resource.layer_views[layer as usize],
+            _ => resource.views[variant % resource.views.len()],
+        }
+    }
+
+    /*
+        Attachments start and end the render pass in their attachment layout, every transition
+        happens in the barriers recorded between passes.
+     */
+    unsafe fn create_render_pass(&mut self, device: &Device, index: usize) -> Result<()>
+    {
+        let pass = &self.passes[index];
+        let first = match pass.attachments().next() {
+            Some(attachment) => *attachment,
+            None => return Ok(()),
+        };
+
+        if !pass.resolve_attachments.is_empty() && pass.resolve_attachments.len() != pass.color_attachments.len() {
+            return Err(anyhow!("Render graph pass {:?} must resolve every color attachment.", pass.payload));
+        }
+
+        let attachments = pass.attachments()
+            .map(|attachment| {
+                let description = &self.resources[attachment.resource].description;
+                let layout = get_attachment_layout(description.format);
+
+                vk::AttachmentDescription::builder()
+                    .format(description.format)
+                    .samples(description.samples)
+                    .load_op(attachment.load_op)
+                    .store_op(attachment.store_op)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(layout)
+                    .final_layout(layout)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        let reference = |attachment: usize, layout: vk::ImageLayout| {
+            vk::AttachmentReference::builder()
+                .attachment(attachment as u32)
+                .layout(layout)
+                .build()
+        };
+
+        let color_count = pass.color_attachments.len();
+        let resolve_count = pass.resolve_attachments.len();
+
+        let color_attachments = (0..color_count)
+            .map(|i| reference(i, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+            .collect::<Vec<_>>();
+
+        let resolve_attachments = (0..resolve_count)
+            .map(|i| reference(color_count + i, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+            .collect::<Vec<_>>();
+
+        let depth_stencil_attachment = reference(color_count + resolve_count, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+        let mut subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachments);
+
+        if resolve_count > 0 {
+            subpass = subpass.resolve_attachments(&resolve_attachments);
+        }
+
+        if pass.depth_attachment.is_some() {
+            subpass = subpass.depth_stencil_attachment(&depth_stencil_attachment);
+        }
+
+        let subpasses = &[subpass];
+        let info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(subpasses);
+
+        let render_pass = device.create_render_pass(&info, None)?;
+
+        let extent = self.resources[first.resource].description.extent;
+        let variants = pass.attachments()
+            .map(|a| self.resources[a.resource].views.len())
+            .max()
+            .unwrap_or(1);
+
+        let framebuffers = (0..variants)
+            .map(|variant| {
+                let views = pass.attachments()
+                    .map(|a| self.get_attachment_view(a, variant))
+                    .collect::<Vec<_>>();
+
+                let info = vk::FramebufferCreateInfo::builder()
+                    .render_pass(render_pass)
+                    .attachments(&views)
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1);
+
+                device.create_framebuffer(&info, None)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let pass = &mut self.passes[index];
+        pass.render_pass = render_pass;
+        pass.framebuffers = framebuffers;
+        pass.extent = extent;
+
+        Ok(())
+    }
+
+    /*
+        Walks the passes in order tracking the state of every image layer. The first walk finds
+        how each transient image is left at the end of a frame, the next frame has to wait for
+        those accesses before reusing the image.
+     */
+    fn create_barriers(&mut self)
+    {
+        let initial = self.resources
+            .iter()
+            .map(|resource| {
+                let state = if resource.imported {
+                    LayerState {
+                        stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                        ..Default::default()
+                    }
+                } else {
+                    LayerState::default()
+                };
+
+                vec![state; resource.description.layers as usize]
+            })
+            .collect::<Vec<_>>();
+
+        let (_, last) = self.simulate(initial.clone());
+
+        // Previous contents are never needed, so every frame starts from an undefined layout
+        let initial = self.resources
+            .iter()
+            .zip(initial)
+            .zip(last)
+            .map(|((resource, initial), last)| {
+                if resource.imported {
+                    return initial;
+                }
+
+                last.into_iter()
+                    .map(|state| LayerState {layout: vk::ImageLayout::UNDEFINED, ..state})
+                    .collect()
+            })
+            .collect::<Vec<_>>();
+
+        let (barriers, mut states) = self.simulate(initial);
+
+        for (index, barriers) in self.order.iter().zip(barriers) {
+            self.passes[*index].barriers = barriers;
+        }
+
+        self.final_barriers.clear();
+        for (index, resource) in self.resources.iter().enumerate() {
+            if resource.imported && resource.final_layout != vk::ImageLayout::UNDEFINED {
+                let usage = LayerState {
+                    layout: resource.final_layout,
+                    stage_mask: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    access_mask: vk::AccessFlags::empty(),
+                    write: false,
+                };
+
+                transition(&mut states[index], index, 0..resource.description.layers, usage, &mut self.final_barriers);
+            }
+        }
+    }
+
+    fn simulate(&self, mut states: Vec<Vec<LayerState>>) -> (Vec<Vec<Barrier>>, Vec<Vec<LayerState>>)
+    {
+        let barriers = self.order
+            .iter()
+            .map(|index| {
+                let mut barriers = vec![];
+                for (resource, layers, usage) in self.passes[*index].get_usages(&self.resources) {
+                    transition(&mut states[resource], resource, layers, usage, &mut barriers);
+                }
+
+                barriers
+            })
+            .collect();
+
+        (barriers, states)
+    }
+
+    pub unsafe fn execute<F>(&self, device: &Device, command_buffer: vk::CommandBuffer, variant: usize, mut record: F) -> Result<()>
+    where
+        F: FnMut(&Pass<P>, vk::CommandBuffer) -> Result<()>
+    {
+        for pass in self.order.iter().map(|index| &self.passes[*index]) {
+            self.record_barriers(device, command_buffer, &pass.barriers, variant);
+
+            if pass.render_pass.is_null() {
+                record(pass, command_buffer)?;
+                continue;
+            }
+
+            let render_area = vk::Rect2D::builder()
+                .offset(vk::Offset2D::default())
+                .extent(pass.extent);
+
+            let clear_values = pass.attachments()
+                .map(|a| a.clear_value)
+                .collect::<Vec<_>>();
+
+            let info = vk::RenderPassBeginInfo::builder()
+                .render_pass(pass.render_pass)
+                .framebuffer(pass.framebuffers[variant % pass.framebuffers.len()])
+                .render_area(render_area)
+                .clear_values(&clear_values);
+
+            device.cmd_begin_render_pass(command_buffer, &info, pass.contents);
+            record(pass, command_buffer)?;
+            device.cmd_end_render_pass(command_buffer);
+        }
+
+        self.record_barriers(device, command_buffer, &self.final_barriers, variant);
+
+        Ok(())
+    }
+
+    unsafe fn record_barriers(&self, device: &Device, command_buffer: vk::CommandBuffer, barriers: &[Barrier], variant: usize)
+    {
+        let image_barriers = barriers
+            .iter()
+            .map(|barrier| {
+                let resource = &self.resources[barrier.resource];
+                let image = resource.images[variant % resource.images.len()];
+
+                ImageBarrier::new(image, resource.description.format, barrier.old_layout, barrier.new_layout)
+                    .with_mip_levels(0, 1)
+                    .with_layers(barrier.base_layer, barrier.layer_count)
+                    .with_src(barrier.src_stage_mask, barrier.src_access_mask)
+                    .with_dst(barrier.dst_stage_mask, barrier.dst_access_mask)
+            })
+            .collect::<Vec<_>>();
+
+        record_image_barriers(device, command_buffer, &image_barriers);
+    }
+
+    fn get_pass(&self, payload: P) -> Result<&Pass<P>>
+    {
+        self.order
+            .iter()
+            .map(|index| &self.passes[*index])
+            .find(|pass| pass.payload == payload)
+            .ok_or_else(|| anyhow!("Render graph pass {:?} was culled or never added.", payload))
+    }
+
+    // Pipelines are created against this, passes with the same attachment formats are compatible
+    pub fn render_pass(&self, payload: P) -> Result<vk::RenderPass>
+    {
+        Ok(self.get_pass(payload)?.render_pass)
+    }
+
+    pub fn framebuffer(&self, payload: P, variant: usize) -> Result<vk::Framebuffer>
+    {
+        let pass = self.get_pass(payload)?;
+        Ok(pass.framebuffers[variant % pass.framebuffers.len()])
+    }
+
+    // View of every layer of an image, for descriptor sets sampling it
+    pub fn view(&self, name: &str) -> Result<vk::ImageView>
+    {
+        self.resources
+            .iter()
+            .find(|resource| resource.name == name)
+            .and_then(|resource| resource.views.first().cloned())
+            .ok_or_else(|| anyhow!("Render graph image \"{}\" was culled or never created.", name))
+    }
+
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut Allocator)
+    {
+        for pass in &self.passes {
+            pass.framebuffers
+                .iter()
+                .for_each(|f| device.destroy_framebuffer(*f, None));
+            device.destroy_render_pass(pass.render_pass, None);
+        }
+
+        for resource in self.resources.iter().filter(|r| !r.imported) {
+            resource.layer_views
+                .iter()
+                .chain(resource.views.iter())
+                .for_each(|v| device.destroy_image_view(*v, None));
+            resource.images
+                .iter()
+                .for_each(|i| device.destroy_image(*i, None));
+            allocator.free(device, &resource.memory);
+        }
+    }
+}
+
+// Reads in the same layout can overlap, anything else waits for the accesses before it
+fn transition(states: &mut [LayerState], resource: ResourceId, layers: Range<u32>, usage: LayerState, barriers: &mut Vec<Barrier>)
+{
+    for layer in layers {
+        let state = &mut states[layer as usize];
+
+        if state.layout == usage.layout && !state.write && !usage.write {
+            state.stage_mask |= usage.stage_mask;
+            state.access_mask |= usage.access_mask;
+            continue;
+        }
+
+        let src_stage_mask = if state.stage_mask.is_empty() {
+            vk::PipelineStageFlags::TOP_OF_PIPE
+        } else {
+            state.stage_mask
+        };
+
+        // Only writes need to be made available, reads just have to finish
+        let src_access_mask = if state.write {state.access_mask} else {vk::AccessFlags::empty()};
+
+        let barrier = Barrier {
+            resource,
+            base_layer: layer,
+            layer_count: 1,
+            old_layout: state.layout,
+            new_layout: usage.layout,
+            src_stage_mask,
+            src_access_mask,
+            dst_stage_mask: usage.stage_mask,
+            dst_access_mask: usage.access_mask,
+        };
+
+        *state = usage;
+
+        match barriers.last_mut() {
+            Some(last) if last.extends(&barrier) => last.layer_count += 1,
+            _ => barriers.push(barrier),
+        }
+    }
+}
+
+fn get_attachment_layout(format: vk::Format) -> vk::ImageLayout
+{
+    if is_depth_format(format) {
+        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+    } else {
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENT: vk::Extent2D = vk::Extent2D {width: 64, height: 64};
+
+    fn color() -> ImageDescription
+    {
+        ImageDescription::new(vk::Format::R8G8B8A8_UNORM, EXTENT)
+    }
+
+    fn depth(layers: u32) -> ImageDescription
+    {
+        ImageDescription::new(vk::Format::D32_SFLOAT, EXTENT).with_layers(layers)
+    }
+
+    // Culls and builds the barriers the way `compile` does, without creating anything on a device
+    fn compile(graph: &mut RenderGraph<&'static str>)
+    {
+        graph.order = graph.get_live_passes();
+        graph.create_barriers();
+    }
+
+    fn get_barriers<'a>(graph: &'a RenderGraph<&'static str>, payload: &'static str, resource: ResourceId) -> Vec<&'a Barrier>
+    {
+        graph.get_pass(payload).unwrap().barriers.iter().filter(|b| b.resource == resource).collect()
+    }
+
+    #[test]
+    fn passes_nobody_reads_are_culled()
+    {
+        let mut graph = RenderGraph::new();
+        let unused = graph.create_image("unused", color());
+        let scene = graph.create_image("scene", color());
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+
+        graph.add_pass("unused").color(Attachment::clear_color(unused, [0.0; 4]));
+        graph.add_pass("scene").color(Attachment::clear_color(scene, [0.0; 4]));
+        graph.add_pass("post").sample(scene).color(Attachment::discard(swapchain));
+
+        assert_eq!(graph.get_live_passes(), vec![1, 2]);
+    }
+
+    #[test]
+    fn loading_an_attachment_keeps_its_writer()
+    {
+        let mut graph = RenderGraph::new();
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+        let depth_buffer = graph.create_image("depth", depth(1));
+
+        graph.add_pass("prepass").depth(Attachment::clear_depth(depth_buffer, 1.0));
+        graph.add_pass("main").color(Attachment::discard(swapchain)).depth(Attachment::load(depth_buffer));
+        graph.add_pass("overlay").depth(Attachment::load(depth_buffer));
+
+        // Only passes writing to an imported image, and what they depend on, are kept
+        assert_eq!(graph.get_live_passes(), vec![0, 1]);
+    }
+
+    #[test]
+    fn reading_after_a_write_waits_with_one_barrier()
+    {
+        let mut graph = RenderGraph::new();
+        let scene = graph.create_image("scene", color());
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+
+        graph.add_pass("scene").color(Attachment::clear_color(scene, [0.0; 4]));
+        graph.add_pass("post").sample(scene).color(Attachment::discard(swapchain));
+        compile(&mut graph);
+
+        let barriers = get_barriers(&graph, "post", scene);
+        assert_eq!(barriers.len(), 1);
+
+        let barrier = barriers[0];
+        assert_eq!((barrier.old_layout, barrier.new_layout), (vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL));
+        assert_eq!((barrier.src_stage_mask, barrier.src_access_mask), (vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags::COLOR_ATTACHMENT_WRITE));
+        assert_eq!((barrier.dst_stage_mask, barrier.dst_access_mask), (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+    }
+
+    #[test]
+    fn reads_in_the_same_layout_share_a_barrier()
+    {
+        let mut graph = RenderGraph::new();
+        let scene = graph.create_image("scene", color());
+        let bloom = graph.create_image("bloom", color());
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+
+        graph.add_pass("scene").color(Attachment::clear_color(scene, [0.0; 4]));
+        graph.add_pass("bloom").sample(scene).color(Attachment::discard(bloom));
+        graph.add_pass("post").sample(scene).sample(bloom).color(Attachment::discard(swapchain));
+        compile(&mut graph);
+
+        assert_eq!(get_barriers(&graph, "bloom", scene).len(), 1);
+        assert!(get_barriers(&graph, "post", scene).is_empty());
+        assert_eq!(get_barriers(&graph, "post", bloom).len(), 1);
+    }
+
+    #[test]
+    fn barriers_for_neighbouring_layers_are_merged()
+    {
+        let mut graph = RenderGraph::new();
+        let shadows = graph.create_image("shadows", depth(4));
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+
+        for (layer, payload) in ["shadow 0", "shadow 1", "shadow 2", "shadow 3"].into_iter().enumerate() {
+            graph.add_pass(payload).depth(Attachment::clear_depth(shadows, 1.0).with_layer(layer as u32));
+        }
+
+        graph.add_pass("main").sample(shadows).color(Attachment::discard(swapchain));
+        compile(&mut graph);
+
+        // Each shadow pass only transitions its own layer
+        let barriers = get_barriers(&graph, "shadow 2", shadows);
+        assert_eq!(barriers.len(), 1);
+        assert_eq!((barriers[0].base_layer, barriers[0].layer_count), (2, 1));
+
+        let barriers = get_barriers(&graph, "main", shadows);
+        assert_eq!(barriers.len(), 1);
+        assert_eq!((barriers[0].base_layer, barriers[0].layer_count), (0, 4));
+        assert_eq!(barriers[0].new_layout, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+    }
+
+    #[test]
+    fn frames_wait_for_the_previous_frame()
+    {
+        let mut graph = RenderGraph::new();
+        let scene = graph.create_image("scene", color());
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+
+        graph.add_pass("scene").color(Attachment::clear_color(scene, [0.0; 4]));
+        graph.add_pass("post").sample(scene).color(Attachment::discard(swapchain));
+        compile(&mut graph);
+
+        // Transient images start undefined, after the last frame's reads of them
+        let barrier = get_barriers(&graph, "scene", scene)[0];
+        assert_eq!(barrier.old_layout, vk::ImageLayout::UNDEFINED);
+        assert_eq!((barrier.src_stage_mask, barrier.src_access_mask), (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::empty()));
+
+        // Imported images wait for the acquire semaphore's stage
+        let barrier = get_barriers(&graph, "post", swapchain)[0];
+        assert_eq!(barrier.old_layout, vk::ImageLayout::UNDEFINED);
+        assert_eq!(barrier.src_stage_mask, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT);
+    }
+
+    #[test]
+    fn imported_images_end_in_their_final_layout()
+    {
+        let mut graph = RenderGraph::new();
+        let swapchain = graph.import_image("swapchain", &[], &[], color(), vk::ImageLayout::PRESENT_SRC_KHR);
+        let kept = graph.import_image("kept", &[], &[], color(), vk::ImageLayout::UNDEFINED);
+
+        graph.add_pass("post").color(Attachment::discard(swapchain)).color(Attachment::discard(kept));
+        compile(&mut graph);
+
+        assert_eq!(graph.final_barriers.len(), 1);
+
+        let barrier = &graph.final_barriers[0];
+        assert_eq!(barrier.resource, swapchain);
+        assert_eq!((barrier.old_layout, barrier.new_layout), (vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, vk::ImageLayout::PRESENT_SRC_KHR));
+        assert_eq!((barrier.src_stage_mask, barrier.src_access_mask), (vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags::COLOR_ATTACHMENT_WRITE));
+        assert_eq!(barrier.dst_stage_mask, vk::PipelineStageFlags::BOTTOM_OF_PIPE);
+    }
+}
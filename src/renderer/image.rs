@@ -1,206 +1,341 @@
-use std::fs::File;
+use std::{io::Cursor, path::Path};
 
-use anyhow::{anyhow, Result};
-use std::ptr::copy_nonoverlapping as memcpy;
+use anyhow::{anyhow, Context, Result};
 use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0}, Device, Instance};
 
-use super::{buffer::create_buffer, command::{begin_single_time_commands, end_single_time_commands}, device::{get_depth_format, get_memory_type_index}, RenderData};
+use super::{
+    allocator::{Allocation, AllocationKind, Strategy},
+    barrier::{record_image_barriers, ImageBarrier},
+    device::get_memory_type_index,
+    texture_compression::{decompress_unsupported, get_image_format, get_level_size, BlockFormat},
+    texture_container::{decode_dds, decode_ktx2},
+    upload::{upload_image, upload_image_levels, UploadId},
+    vfs::Vfs,
+    RenderData,
+};
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Texture {
+    pub image: vk::Image,
+    pub memory: Allocation,
+    pub view: vk::ImageView,
+    pub format: vk::Format,
+    pub mip_levels: u32,
+    // Not to be sampled until the upload is complete
+    pub upload: UploadId,
+}
+
+// Pixels of a decoded image file, four channels with eight bits each unless they are block compressed
+#[derive(Clone, Debug)]
+pub struct DecodedImage
+{
+    pub width: u32,
+    pub height: u32,
+    // Levels are tightly packed one after another, with only one the rest of the chain is generated when uploading
+    pub mip_levels: u32,
+    // Blocks of 4x4 texels in this format, their chain can't be generated so it is uploaded as it is
+    pub compression: Option<BlockFormat>,
+    pub pixels: Vec<u8>,
+}
+
+impl DecodedImage {
+    pub fn level_size(&self, level: u32) -> usize
+    {
+        get_level_size(self.compression, (self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    // Where each level starts in `pixels`
+    pub fn level_offsets(&self) -> Vec<vk::DeviceSize>
+    {
+        let mut offset = 0;
+        (0..self.mip_levels)
+            .map(|level| {
+                let start = offset;
+                offset += self.level_size(level) as vk::DeviceSize;
+                start
+            })
+            .collect()
+    }
+}
+
+pub unsafe fn create_texture(instance: &Instance, device: &Device, data: &mut RenderData, path: &str, format: vk::Format) -> Result<Texture>
+{
+    let image = decompress_unsupported(decode_image(&data.vfs, path)?, format, &data.block_formats);
+    create_texture_from_image(instance, device, data, &image, format)
+}
+
+// Picks the decoder by extension, PNGs are the default
+pub fn decode_image(vfs: &Vfs, path: &str) -> Result<DecodedImage>
+{
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "ktx2" => decode_ktx2(&vfs.read(path)?).with_context(|| format!("Invalid KTX2 texture `{}`", path)),
+        "dds" => decode_dds(&vfs.read(path)?).with_context(|| format!("Invalid DDS texture `{}`", path)),
+        _ => decode_png(vfs, path),
+    }
+}
 
-pub unsafe fn create_texture_image(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+// Only touches the file system, so it can run on a worker thread
+pub fn decode_png(vfs: &Vfs, path: &str) -> Result<DecodedImage>
 {
-    let image = File::open("resources/viking_room.png")?;
+    let image = Cursor::new(vfs.read(path)?);
 
-    let decoder = png::Decoder::new(image);
+    let mut decoder = png::Decoder::new(image);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
     let mut reader = decoder.read_info()?;
 
-    if reader.info().color_type != png::ColorType::Rgba {
-        panic!("invalid texture image.")
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    buffer.truncate(info.buffer_size());
+
+    // Every texture is uploaded as four channels so that the same formats can be used for all of them
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buffer
+            .iter()
+            .flat_map(|p| [*p, *p, *p, 255])
+            .collect(),
+        png::ColorType::Indexed => return Err(anyhow!("Indexed texture image `{}` was not expanded.", path)),
+    };
+
+    Ok(DecodedImage {width: info.width, height: info.height, mip_levels: 1, compression: None, pixels})
+}
+
+/*
+    Averages every 2x2 block of the level above, down to 1x1. Colour textures are averaged in
+    linear space so that the smaller levels don't darken, odd edges repeat their last texel.
+    Only for uncompressed images, block compressed ones come with their chain.
+ */
+pub fn generate_mip_chain(image: &DecodedImage, srgb: bool) -> DecodedImage
+{
+    let mip_levels = get_mip_levels(image.width, image.height);
+    let (mut width, mut height) = (image.width, image.height);
+
+    let mut pixels = image.pixels[..(4 * width * height) as usize].to_vec();
+    let mut previous = pixels.clone();
+
+    let to_linear = |value: u8| {
+        let value = value as f32 / 255.0;
+        if srgb && value > 0.04045 {((value + 0.055) / 1.055).powf(2.4)} else if srgb {value / 12.92} else {value}
+    };
+
+    let to_byte = |value: f32| {
+        let value = if srgb && value > 0.0031308 {1.055 * value.powf(1.0 / 2.4) - 0.055} else if srgb {value * 12.92} else {value};
+        (value * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+
+    for _ in 1..mip_levels {
+        let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+        let mut level = Vec::with_capacity((4 * next_width * next_height) as usize);
+
+        for y in 0..next_height {
+            for x in 0..next_width {
+                let texel = |tx: u32, ty: u32, channel: u32| {
+                    let (tx, ty) = (tx.min(width - 1), ty.min(height - 1));
+                    previous[(4 * (ty * width + tx) + channel) as usize]
+                };
+
+                for channel in 0..4 {
+                    let corners = [
+                        texel(2 * x, 2 * y, channel),
+                        texel(2 * x + 1, 2 * y, channel),
+                        texel(2 * x, 2 * y + 1, channel),
+                        texel(2 * x + 1, 2 * y + 1, channel),
+                    ];
+
+                    // Alpha is coverage and always averaged as it is
+                    level.push(if channel == 3 {
+                        (corners.iter().map(|&c| c as f32).sum::<f32>() / 4.0).round() as u8
+                    } else {
+                        to_byte(corners.iter().map(|&c| to_linear(c)).sum::<f32>() / 4.0)
+                    });
+                }
+            }
+        }
+
+        pixels.extend(&level);
+        previous = level;
+        (width, height) = (next_width, next_height);
     }
 
-    let mut pixels = vec![0; reader.info().raw_bytes()];
-    reader.next_frame(&mut pixels)?;
+    DecodedImage {width: image.width, height: image.height, mip_levels, compression: None, pixels}
+}
 
-    let size = reader.info().raw_bytes() as u64;
-    let (width, height) = reader.info().size();
+// Mip level calculated by how many times max dimension came be divided by 2 (log2), floor handles case where dimension isn't power of 2, and add 1 so that original image has mip level
+pub fn get_mip_levels(width: u32, height: u32) -> u32
+{
+    (width.max(height) as f32).log2().floor() as u32 + 1
+}
+
+/*
+    Uploads the levels the image already has, or generates the chain if it only has one.
+    Block compressed images are created in the matching compressed format, which the device
+    has to support, see `decompress_unsupported`.
+ */
+pub unsafe fn create_texture_from_image(instance: &Instance, device: &Device, data: &mut RenderData, image: &DecodedImage, format: vk::Format) -> Result<Texture>
+{
+    if image.mip_levels <= 1 && image.compression.is_none() {
+        return create_texture_from_pixels(instance, device, data, image.width, image.height, &image.pixels, format);
+    }
 
-    // Mip level calculated by how many times max dimension came be divided by 2 (log2), floor handles case where dimension isn't power of 2, and add 1 so that original image has mip level
-    data.mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
+    let format = get_image_format(image, format);
 
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
+    let (texture_image, image_memory) = create_image(
         instance,
         device,
         data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE
+        image.width,
+        image.height,
+        image.mip_levels,
+        vk::SampleCountFlags::_1,
+        format,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
     )?;
 
-    let memory = device.map_memory(
-        staging_buffer_memory,
-        0,
-        size,
-        vk::MemoryMapFlags::empty()
-    )?;
+    let upload = upload_image_levels(instance, device, data, &image.pixels, &image.level_offsets(), texture_image, format, image.width, image.height)?;
+    let view = create_image_view(device, texture_image, format, vk::ImageAspectFlags::COLOR, image.mip_levels)?;
+
+    Ok(Texture {image: texture_image, memory: image_memory, view, format, mip_levels: image.mip_levels, upload})
+}
+
+pub unsafe fn create_texture_from_pixels(instance: &Instance, device: &Device, data: &mut RenderData, width: u32, height: u32, pixels: &[u8], format: vk::Format) -> Result<Texture>
+{
+    create_layered_texture(instance, device, data, width, height, 1, pixels, format, vk::ImageViewType::_2D, vk::ImageCreateFlags::empty())
+}
 
-    memcpy(pixels.as_ptr(), memory.cast(), pixels.len());
-    device.unmap_memory(staging_buffer_memory);
+// Layers are tightly packed one after another in `pixels`, cubemaps use the order +X, -X, +Y, -Y, +Z, -Z
+pub unsafe fn create_layered_texture(instance: &Instance, device: &Device, data: &mut RenderData, width: u32, height: u32, layer_count: u32, pixels: &[u8], format: vk::Format, view_type: vk::ImageViewType, flags: vk::ImageCreateFlags) -> Result<Texture>
+{
+    let mip_levels = get_mip_levels(width, height);
 
-    let (texture_image, texture_image_memory) = create_image(
+    let (image, image_memory) = create_layered_image(
         instance,
         device,
         data,
         width,
         height,
-        data.mip_levels,
+        mip_levels,
+        layer_count,
         vk::SampleCountFlags::_1,
-        vk::Format::R8G8B8A8_SRGB,
+        format,
         vk::ImageTiling::OPTIMAL,
         vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::DEVICE_LOCAL
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        flags
     )?;
 
-    data.texture_image = texture_image;
-    data.texture_image_memory = texture_image_memory;
+    let upload = upload_image(instance, device, data, pixels, image, format, width, height, mip_levels, layer_count)?;
 
-    transition_image_layout(
+    let view = create_layered_image_view(
         device,
-        data,
-        data.texture_image,
-        vk::Format::R8G8B8A8_SRGB,
-        vk::ImageLayout::UNDEFINED,
-        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        data.mip_levels,
+        image,
+        view_type,
+        format,
+        vk::ImageAspectFlags::COLOR,
+        mip_levels,
+        0,
+        layer_count
     )?;
 
-    copy_buffer_to_image(
-        device,
-        data,
-        staging_buffer,
-        data.texture_image,
-        width,
-        height
-    )?;
+    Ok(Texture {image, memory: image_memory, view, format, mip_levels, upload})
+}
 
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
+pub unsafe fn destroy_texture(device: &Device, data: &RenderData, texture: &Texture)
+{
+    device.destroy_image_view(texture.view, None);
+    device.destroy_image(texture.image, None);
+    data.allocator.lock().unwrap().free(device, &texture.memory);
+}
 
-    generate_mipmaps(
+pub unsafe fn create_image(instance: &Instance, device: &Device, data: &mut RenderData, width: u32, height: u32, mip_levels: u32, samples: vk::SampleCountFlags, format: vk::Format, tiling: vk::ImageTiling, usage: vk::ImageUsageFlags, properties: vk::MemoryPropertyFlags) -> Result<(vk::Image, Allocation)>
+{
+    create_layered_image(
         instance,
         device,
         data,
-        data.texture_image,
-        vk::Format::R8G8B8A8_SRGB,
         width,
         height,
-        data.mip_levels
-    )?;
-
-    Ok(())
+        mip_levels,
+        1,
+        samples,
+        format,
+        tiling,
+        usage,
+        properties,
+        vk::ImageCreateFlags::empty()
+    )
 }
 
-pub unsafe fn create_image(instance: &Instance, device: &Device, data: &mut RenderData, width: u32, height: u32, mip_levels: u32, samples: vk::SampleCountFlags, format: vk::Format, tiling: vk::ImageTiling, usage: vk::ImageUsageFlags, properties: vk::MemoryPropertyFlags) -> Result<(vk::Image, vk::DeviceMemory)>
+pub unsafe fn create_layered_image(instance: &Instance, device: &Device, data: &mut RenderData, width: u32, height: u32, mip_levels: u32, array_layers: u32, samples: vk::SampleCountFlags, format: vk::Format, tiling: vk::ImageTiling, usage: vk::ImageUsageFlags, properties: vk::MemoryPropertyFlags, flags: vk::ImageCreateFlags) -> Result<(vk::Image, Allocation)>
 {
     let info = vk::ImageCreateInfo::builder()
         .image_type(vk::ImageType::_2D)
         .extent(vk::Extent3D {width, height, depth: 1})
         .mip_levels(mip_levels)
-        .array_layers(1)
+        .array_layers(array_layers)
         .format(format)
         .tiling(tiling)
         .initial_layout(vk::ImageLayout::UNDEFINED)
         .usage(usage)
         .sharing_mode(vk::SharingMode::EXCLUSIVE)
         .samples(samples)
-        .flags(vk::ImageCreateFlags::empty());
+        .flags(flags);
 
     let image = device.create_image(&info, None)?;
     let requirements = device.get_image_memory_requirements(image);
 
-    let info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(instance, data, properties, requirements)?);
+    let memory_type_index = get_memory_type_index(instance, data, properties, requirements)?;
 
-    let image_memory = device.allocate_memory(&info, None)?;
+    let kind = if tiling == vk::ImageTiling::OPTIMAL {AllocationKind::Optimal} else {AllocationKind::Linear};
+    let image_memory = data.allocator
+        .lock()
+        .unwrap()
+        .allocate(device, memory_type_index, requirements, kind, Strategy::FreeList)?;
 
-    device.bind_image_memory(image, image_memory, 0)?;
+    device.bind_image_memory(image, image_memory.memory, image_memory.offset)?;
 
     Ok((image, image_memory))
 }
 
-pub unsafe fn transition_image_layout(device: &Device, data: &RenderData, image: vk::Image, format: vk::Format, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout, mip_levels: u32) -> Result<()>
+pub unsafe fn transition_image_layout(device: &Device, command_buffer: vk::CommandBuffer, image: vk::Image, format: vk::Format, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout, mip_levels: u32, layer_count: u32)
 {
-    let (
-        src_access_mask,
-        dst_access_mask,
-        src_stage_mask,
-        dst_stage_mask
-    ) = match(old_layout, new_layout) {
-        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL) => (
-            vk::AccessFlags::empty(),
-            vk::AccessFlags::TRANSFER_WRITE,
-            vk::PipelineStageFlags::TOP_OF_PIPE,
-            vk::PipelineStageFlags::TRANSFER,
-        ),
-        (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL) => (
-            vk::AccessFlags::TRANSFER_WRITE,
-            vk::AccessFlags::SHADER_READ,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-        ),
-        _ => return Err(anyhow!("Unsupported image layout transition!")),
-    };
-
-    let command_buffer = begin_single_time_commands(device, data)?;
-
-    let subresource = vk::ImageSubresourceRange::builder()
-        .aspect_mask(vk::ImageAspectFlags::COLOR)
-        .base_mip_level(0)
-        .level_count(mip_levels)
-        .base_array_layer(0)
-        .layer_count(1);
+    let barrier = ImageBarrier::new(image, format, old_layout, new_layout)
+        .with_mip_levels(0, mip_levels)
+        .with_layers(0, layer_count);
 
-    let barrier = vk::ImageMemoryBarrier::builder()
-        .old_layout(old_layout)
-        .new_layout(new_layout)
-        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-        .image(image)
-        .subresource_range(subresource)
-        .src_access_mask(src_access_mask)
-        .dst_access_mask(dst_access_mask);
-
-    device.cmd_pipeline_barrier(
-        command_buffer,
-        src_stage_mask,
-        dst_stage_mask,
-        vk::DependencyFlags::empty(),
-        &[] as &[vk::MemoryBarrier],
-        &[] as &[vk::BufferMemoryBarrier],
-        &[barrier]
-    );
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
+    record_image_barriers(device, command_buffer, &[barrier]);
 }
 
-pub unsafe fn copy_buffer_to_image(device: &Device, data: &RenderData, buffer: vk::Buffer, image: vk::Image, width: u32, height: u32) -> Result<()>
+pub unsafe fn copy_buffer_to_image(device: &Device, command_buffer: vk::CommandBuffer, buffer: vk::Buffer, image: vk::Image, width: u32, height: u32, layer_count: u32)
 {
-    let command_buffer = begin_single_time_commands(device, data)?;
-
     let subresource = vk::ImageSubresourceLayers::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .mip_level(0)
         .base_array_layer(0)
-        .layer_count(1);
+        .layer_count(layer_count);
 
-        let region = vk::BufferImageCopy::builder()
-            .buffer_offset(0)
-            .buffer_row_length(0)
-            .buffer_image_height(0)
-            .image_subresource(subresource)
-            .image_offset(vk::Offset3D{x:0, y:0, z:0})
-            .image_extent(vk::Extent3D{width, height, depth: 1});
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D{x:0, y:0, z:0})
+        .image_extent(vk::Extent3D{width, height, depth: 1});
 
     device.cmd_copy_buffer_to_image(
         command_buffer,
@@ -209,186 +344,71 @@ pub unsafe fn copy_buffer_to_image(device: &Device, data: &RenderData, buffer: v
         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
         &[region]
     );
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
 }
 
-pub unsafe fn create_texture_image_view(device: &Device, data: &mut RenderData) -> Result<()>
+pub unsafe fn create_image_view(device: &Device, image: vk::Image, format: vk::Format, aspects: vk::ImageAspectFlags, mip_levels: u32) -> Result<vk::ImageView>
 {
-    data.texture_image_view = create_image_view(
-        device,
-        data.texture_image,
-        vk::Format::R8G8B8A8_SRGB,
-        vk::ImageAspectFlags::COLOR,
-        data.mip_levels
-    )?;
-
-    Ok(())
-
+    create_layered_image_view(device, image, vk::ImageViewType::_2D, format, aspects, mip_levels, 0, 1)
 }
 
-pub unsafe fn create_image_view(device: &Device, image: vk::Image, format: vk::Format, aspects: vk::ImageAspectFlags, mip_levels: u32) -> Result<vk::ImageView>
+pub unsafe fn create_layered_image_view(device: &Device, image: vk::Image, view_type: vk::ImageViewType, format: vk::Format, aspects: vk::ImageAspectFlags, mip_levels: u32, base_array_layer: u32, layer_count: u32) -> Result<vk::ImageView>
 {
     let subresource_range = vk::ImageSubresourceRange::builder()
         .aspect_mask(aspects)
         .base_mip_level(0)
         .level_count(mip_levels)
-        .base_array_layer(0)
-        .layer_count(1);
+        .base_array_layer(base_array_layer)
+        .layer_count(layer_count);
 
     let info = vk::ImageViewCreateInfo::builder()
         .image(image)
-        .view_type(vk::ImageViewType::_2D)
+        .view_type(view_type)
         .format(format)
         .subresource_range(subresource_range);
 
     Ok(device.create_image_view(&info, None)?)
 }
 
-pub unsafe fn create_texture_sampler(device: &Device, data: &mut RenderData) -> Result<()>
-{
-    let info = vk::SamplerCreateInfo::builder()
-        .mag_filter(vk::Filter::LINEAR)
-        .min_filter(vk::Filter::LINEAR)
-        .address_mode_u(vk::SamplerAddressMode::REPEAT)
-        .address_mode_v(vk::SamplerAddressMode::REPEAT)
-        .address_mode_w(vk::SamplerAddressMode::REPEAT)
-        .anisotropy_enable(true)
-        .max_anisotropy(16.0)
-        .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
-        .unnormalized_coordinates(false)
-        .compare_enable(false)
-        .compare_op(vk::CompareOp::ALWAYS)
-        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-        .mip_lod_bias(0.0)
-        .min_lod(0.0)
-        .max_lod(data.mip_levels as f32);
-
-    data.texture_sampler = device.create_sampler(&info, None)?;
-
-    Ok(())
-}
-
-pub unsafe fn create_depth_objects(instance: &Instance, device: &Device, data: &mut RenderData) ->Result<()>
-{
-    let format = get_depth_format(instance, data)?;
-
-    let (depth_image, depth_image_memory) = create_image(
-        instance,
-        device,
-        data,
-        data.swapchain_extent.width,
-        data.swapchain_extent.height,
-        1,
-        data.msaa_samples,
-        format,
-        vk::ImageTiling::OPTIMAL,
-        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-         vk::MemoryPropertyFlags::DEVICE_LOCAL
-    )?;
-
-    data.depth_image = depth_image;
-    data.depth_image_memory = depth_image_memory;
-
-    data.depth_image_view = create_image_view(
-        device,
-        data.depth_image,
-        format,
-        vk::ImageAspectFlags::DEPTH,
-        1
-    )?;
-
-    Ok(())
-}
-
-pub unsafe fn create_color_objects(instance: &Instance, device: &Device, data: &mut RenderData) ->Result<()>
+// Blitting the mip chain filters linearly, which not every format supports
+pub unsafe fn supports_linear_blit(instance: &Instance, data: &RenderData, format: vk::Format) -> bool
 {
-    let (color_image, color_image_memory) = create_image(
-        instance,
-        device,
-        data,
-        data.swapchain_extent.width,
-        data.swapchain_extent.height,
-        1,
-        data.msaa_samples,
-        data.swapchain_format,
-         vk::ImageTiling::OPTIMAL,
-        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
-         vk::MemoryPropertyFlags::DEVICE_LOCAL
-    )?;
-
-    data.color_image = color_image;
-    data.color_image_memory = color_image_memory;
-
-    data.color_image_view = create_image_view(
-        device,
-        data.color_image,
-        data.swapchain_format,
-        vk::ImageAspectFlags::COLOR,
-        1
-    )?;
-
-    Ok(())
+    instance.get_physical_device_format_properties(data.physical_device, format)
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
 }
 
-
-
-unsafe fn generate_mipmaps(instance: &Instance, device: &Device, data: &RenderData, image: vk::Image, format: vk::Format, width: u32, height: u32, mip_levels: u32) ->Result<()>
+/*
+    Expects every level in TRANSFER_DST_OPTIMAL with level 0 filled, leaves every level in
+    SHADER_READ_ONLY_OPTIMAL. Only recorded for formats `supports_linear_blit` accepted when
+    the upload was queued, so it can't fail halfway through recording a frame.
+ */
+pub unsafe fn generate_mipmaps(device: &Device, command_buffer: vk::CommandBuffer, image: vk::Image, format: vk::Format, width: u32, height: u32, mip_levels: u32, layer_count: u32)
 {
-    if !instance.get_physical_device_format_properties(data.physical_device, format)
-    .optimal_tiling_features
-    .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
-    {
-        return Err(anyhow!("Texture image format does not support linear blitting!"));
-    }
-
-    let command_buffer = begin_single_time_commands(device, data)?;
-
-    let subresource = vk::ImageSubresourceRange::builder()
-        .aspect_mask(vk::ImageAspectFlags::COLOR)
-        .base_array_layer(0)
-        .layer_count(1)
-        .level_count(1);
-
-    let mut barrier = vk::ImageMemoryBarrier::builder()
-        .image(image)
-        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-        .subresource_range(subresource);
+    let level_barrier = |level: u32, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout| {
+        ImageBarrier::new(image, format, old_layout, new_layout)
+            .with_mip_levels(level, 1)
+            .with_layers(0, layer_count)
+    };
 
     let mut mip_width = width;
     let mut mip_height = height;
 
     for i in 1..mip_levels {
-        barrier.subresource_range.base_mip_level = i - 1;
-        barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
-        barrier.new_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
-        barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
-        barrier.dst_access_mask = vk::AccessFlags::TRANSFER_READ;
-
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::DependencyFlags::empty(),
-            &[] as &[vk::MemoryBarrier],
-            &[] as &[vk::BufferMemoryBarrier],
-            &[barrier]
-        );
+        record_image_barriers(device, command_buffer, &[
+            level_barrier(i - 1, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        ]);
 
         let src_subresource = vk::ImageSubresourceLayers::builder()
             .aspect_mask(vk::ImageAspectFlags::COLOR)
             .mip_level(i - 1)
             .base_array_layer(0)
-            .layer_count(1);
+            .layer_count(layer_count);
 
         let dst_subresource = vk::ImageSubresourceLayers::builder()
             .aspect_mask(vk::ImageAspectFlags::COLOR)
             .mip_level(i)
             .base_array_layer(0)
-            .layer_count(1);
+            .layer_count(layer_count);
 
         let blit = vk::ImageBlit::builder()
             .src_offsets([
@@ -420,20 +440,9 @@ unsafe fn generate_mipmaps(instance: &Instance, device: &Device, data: &RenderDa
             vk::Filter::LINEAR,
         );
 
-        barrier.old_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
-        barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
-        barrier.src_access_mask = vk::AccessFlags::TRANSFER_READ;
-        barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
-
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::DependencyFlags::empty(),
-            &[] as &[vk::MemoryBarrier],
-            &[] as &[vk::BufferMemoryBarrier],
-            &[barrier]
-        );
+        record_image_barriers(device, command_buffer, &[
+            level_barrier(i - 1, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        ]);
 
         if mip_width > 1 {
             mip_width /= 2;
@@ -444,23 +453,7 @@ unsafe fn generate_mipmaps(instance: &Instance, device: &Device, data: &RenderDa
         }
     }
 
-    barrier.subresource_range.base_mip_level = mip_levels - 1;
-    barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
-    barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
-    barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
-    barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
-
-    device.cmd_pipeline_barrier(
-        command_buffer,
-        vk::PipelineStageFlags::TRANSFER,
-        vk::PipelineStageFlags::FRAGMENT_SHADER,
-        vk::DependencyFlags::empty(),
-        &[] as &[vk::MemoryBarrier],
-        &[] as &[vk::BufferMemoryBarrier],
-        &[barrier]
-    );
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
+    record_image_barriers(device, command_buffer, &[
+        level_barrier(mip_levels - 1, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    ]);
 }
@@ -1,12 +1,10 @@
 use anyhow::{anyhow, Result};
 use log::{debug, error, info, trace, warn};
-use std::{collections::{HashMap, HashSet}, ffi::{c_void, CStr}, fs::File, io::BufReader};
+use std::{collections::HashSet, ffi::{c_void, CStr}};
 use vulkanalia::{vk::{self, DeviceV1_0, EntryV1_0, ExtDebugUtilsExtension, Handle, HasBuilder}, window, Device, Entry, Instance, Version};
 use winit::window::Window;
 
-use crate::math::vector::{Vector2, Vector3};
-
-use super::{vertex::Vertex, RenderData, MAX_FRAMES_IN_FLIGHT};
+use super::{RenderData, MAX_FRAMES_IN_FLIGHT};
 
 pub const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
 pub const VALIDATION_LAYER: vk::ExtensionName = vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
@@ -51,7 +49,6 @@ pub unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut RenderD
         entry.version()? >= PORTABILITY_MACOS_VERSION
     {
         info!("Enabling extension for macOS portability.");
-        extensions.push(vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name.as_ptr());
         extensions.push(vk::KHR_PORTABILITY_ENUMERATION_EXTENSION.name.as_ptr());
         vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR
 
@@ -59,6 +56,18 @@ pub unsafe fn create_instance(window: &Window, entry: &Entry, data: &mut RenderD
         vk::InstanceCreateFlags::empty()
     };
 
+    // Needed to query descriptor indexing support on a 1.0 instance, and by portability on macOS
+    let available_extensions = entry
+        .enumerate_instance_extension_properties(None)?
+        .iter()
+        .map(|e| e.extension_name)
+        .collect::<HashSet<_>>();
+
+    data.properties2_supported = available_extensions.contains(&vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name);
+    if data.properties2_supported {
+        extensions.push(vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_EXTENSION.name.as_ptr());
+    }
+
     let mut info = vk::InstanceCreateInfo::builder()
         .application_info(&application_info)
         .enabled_layer_names(&layers)
@@ -131,56 +140,3 @@ pub unsafe fn create_sync_objects(device: &Device, data: &mut RenderData) ->Resu
 
     Ok(())
 }
-
-pub fn load_model(data: &mut RenderData) -> Result<()>
-{
-    let mut reader = BufReader::new(File::open("resources/viking_room.obj")?);
-
-    let (models, _) = tobj::load_obj_buf(
-        &mut reader,
-        &tobj::LoadOptions{
-            triangulate: true,
-            ..Default::default()
-        },
-        |_| Ok(Default::default()),
-    )?;
-
-    let mut unique_vertices = HashMap::new();
-
-    for model in &models {
-        for index in &model.mesh.indices {
-            let pos_offset = (3 * index) as usize;
-            let tex_coord_offset = (2 * index) as usize;
-            let vertex = Vertex {
-                position: Vector3::new(
-                    model.mesh.positions[pos_offset],
-                    model.mesh.positions[pos_offset + 1],
-                    model.mesh.positions[pos_offset + 2],
-                ),
-                color: Vector3::new(
-                    1.0,
-                    1.0,
-                     1.0
-                ),
-                tex_coord: Vector2::new(
-                    model.mesh.texcoords[tex_coord_offset],
-                    1.0 - model.mesh.texcoords[tex_coord_offset + 1],
-                )
-            };
-
-            if let Some(index) = unique_vertices.get(&vertex) {
-                data.indices.push(*index as u32);
-            } else {
-                let index = data.vertices.len();
-                unique_vertices.insert(vertex, index);
-
-                data.vertices.push(vertex);
-                data.indices.push(index as u32);
-            }
-        };
-
-
-    }
-
-    Ok(())
-}
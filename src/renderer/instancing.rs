@@ -0,0 +1,75 @@
+use anyhow::Result;
+use vulkanalia::vk;
+
+use super::{descriptor::Mat4, vertex::InstanceData, RenderData};
+
+/*
+    Something placed in the world with the scene mesh. Props sharing a material and level of
+    detail end up in the same batch and are drawn with a single instanced draw.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Prop
+{
+    pub material: usize,
+    pub transform: Mat4,
+    pub opacity: f32,
+}
+
+impl Prop {
+    pub fn new(material: usize, transform: Mat4) -> Self
+    {
+        Self {material, transform, opacity: 1.0}
+    }
+}
+
+// Range of the frame's instance data drawn with one material and level of detail
+#[derive(Copy, Clone, Debug)]
+pub struct DrawBatch
+{
+    pub material: usize,
+    pub lod: usize,
+    pub first_instance: u32,
+    pub instance_count: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct InstanceBatches
+{
+    // Where the frame's instance data starts in the frame ring, bound as the instance vertex buffer
+    pub offset: vk::DeviceSize,
+    pub batches: Vec<DrawBatch>,
+    // One indexed indirect command per batch in the frame ring, set when the GPU culls the instances
+    pub commands: Option<vk::DeviceSize>,
+}
+
+// Order in which the props are laid out, grouped by material and level of detail, and the batches covering them
+pub fn sort_into_batches(props: &[Prop], lods: &[usize]) -> (Vec<usize>, Vec<DrawBatch>)
+{
+    let mut order = (0..props.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (props[i].material, lods[i]));
+
+    let mut batches: Vec<DrawBatch> = vec![];
+    for (instance, &i) in order.iter().enumerate() {
+        match batches.last_mut() {
+            Some(batch) if batch.material == props[i].material && batch.lod == lods[i] => batch.instance_count += 1,
+            _ => batches.push(DrawBatch {material: props[i].material, lod: lods[i], first_instance: instance as u32, instance_count: 1}),
+        }
+    }
+
+    (order, batches)
+}
+
+// Sorts the props into batches and pushes their instance data into the frame ring, `lods` holds the level of each prop
+pub unsafe fn push_instance_batches(data: &mut RenderData, props: &[Prop], lods: &[usize]) -> Result<InstanceBatches>
+{
+    let (order, batches) = sort_into_batches(props, lods);
+
+    let instances = order
+        .iter()
+        .map(|&i| InstanceData::new(&props[i]))
+        .collect::<Vec<_>>();
+
+    let offset = if instances.is_empty() {0} else {data.frame_ring.push_slice(&instances)?};
+
+    Ok(InstanceBatches {offset, batches, commands: None})
+}
@@ -0,0 +1,102 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+// Upper bound on worker threads, decoding is limited by disk and memory bandwidth long before that
+const MAX_WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/*
+    Fixed set of worker threads taking jobs from a shared queue. Jobs only do CPU work,
+    everything touching Vulkan stays on the thread that owns the renderer.
+ */
+#[derive(Debug)]
+pub struct JobPool
+{
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl JobPool {
+    pub fn new(worker_count: usize) -> Result<Self>
+    {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..worker_count.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("asset worker {}", i))
+                    .spawn(move || loop {
+                        // The lock is released before the job runs so that other workers can take the next one
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {sender: Some(sender), workers})
+    }
+
+    // One worker per core, leaving a core for the main thread
+    pub fn with_default_workers() -> Result<Self>
+    {
+        let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+        Self::new((cores - 1).clamp(1, MAX_WORKERS))
+    }
+
+    pub fn spawn<T, F>(&self, job: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        // A failed send means the handle was dropped, nobody is waiting for the result anymore
+        let job = Box::new(move || {
+            let _ = sender.send(job());
+        });
+
+        if let Some(jobs) = &self.sender {
+            let _ = jobs.send(job);
+        }
+
+        JobHandle {receiver}
+    }
+}
+
+impl Drop for JobPool {
+    fn drop(&mut self)
+    {
+        // Closing the queue lets the workers finish their current job and exit
+        self.sender.take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JobHandle<T>
+{
+    receiver: mpsc::Receiver<Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    // Returns the result once the job has finished, a handle only yields its result once
+    pub fn poll(&self) -> Option<Result<T>>
+    {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(anyhow!("Job panicked before returning a result."))),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+
+use crate::math::vector::Vector3;
+
+pub const MAX_LIGHTS: usize = 16;
+
+// The light buffer is rebuilt every frame, so dropping lights is only reported the first time
+static DROPPED_LIGHTS_REPORTED: AtomicBool = AtomicBool::new(false);
+
+pub type Vec4 = cgmath::Vector4<f32>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind
+{
+    Directional,
+    Point,
+    Spot,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Light
+{
+    pub kind: LightKind,
+    pub position: Vector3,
+    pub direction: Vector3,
+    pub color: Vector3,
+    pub intensity: f32,
+    pub range: f32,
+    // Cone angles are in radians, measured from the light direction
+    pub inner_cone: f32,
+    pub outer_cone: f32,
+    // Only directional and spot lights can cast shadows
+    pub casts_shadow: bool,
+}
+
+impl Light {
+    pub fn directional(direction: Vector3, color: Vector3, intensity: f32) -> Self
+    {
+        Self {
+            kind: LightKind::Directional,
+            position: Vector3::new(0.0, 0.0, 0.0),
+            direction: direction.normalized(),
+            color,
+            intensity,
+            range: 0.0,
+            inner_cone: 0.0,
+            outer_cone: 0.0,
+            casts_shadow: false,
+        }
+    }
+
+    pub fn point(position: Vector3, color: Vector3, intensity: f32, range: f32) -> Self
+    {
+        Self {
+            kind: LightKind::Point,
+            position,
+            direction: Vector3::new(0.0, 0.0, -1.0),
+            color,
+            intensity,
+            range,
+            inner_cone: 0.0,
+            outer_cone: 0.0,
+            casts_shadow: false,
+        }
+    }
+
+    pub fn spot(position: Vector3, direction: Vector3, color: Vector3, intensity: f32, range: f32, inner_cone: f32, outer_cone: f32) -> Self
+    {
+        Self {
+            kind: LightKind::Spot,
+            position,
+            direction: direction.normalized(),
+            color,
+            intensity,
+            range,
+            inner_cone,
+            outer_cone,
+            casts_shadow: false,
+        }
+    }
+
+    pub fn with_shadow(mut self) -> Self
+    {
+        self.casts_shadow = true;
+        self
+    }
+}
+
+/*
+    Matches the std140 layout of the `Light` struct in shader.frag.
+    The w components carry the scalar parameters so that each light is four vec4s.
+ */
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct LightData {
+    // xyz = position, w = kind
+    pub position: Vec4,
+    // xyz = direction, w = range
+    pub direction: Vec4,
+    // xyz = color, w = intensity
+    pub color: Vec4,
+    // x = cos(inner cone), y = cos(outer cone), z = first shadow map layer or -1
+    pub cone: Vec4,
+}
+
+impl LightData {
+    pub fn from_light(light: &Light, shadow_layer: i32) -> Self
+    {
+        let kind = match light.kind {
+            LightKind::Directional => 0.0,
+            LightKind::Point => 1.0,
+            LightKind::Spot => 2.0,
+        };
+
+        Self {
+            position: Vec4::new(light.position.x, light.position.y, light.position.z, kind),
+            direction: Vec4::new(light.direction.x, light.direction.y, light.direction.z, light.range),
+            color: Vec4::new(light.color.x, light.color.y, light.color.z, light.intensity),
+            cone: Vec4::new(light.inner_cone.cos(), light.outer_cone.cos(), shadow_layer as f32, 0.0),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct LightBufferObject {
+    // x = number of active lights
+    pub count: [u32; 4],
+    pub lights: [LightData; MAX_LIGHTS],
+}
+
+impl LightBufferObject {
+    pub fn new(lights: &[Light], shadow_layers: &[i32]) -> Self
+    {
+        let empty = LightData {
+            position: Vec4::new(0.0, 0.0, 0.0, 0.0),
+            direction: Vec4::new(0.0, 0.0, 0.0, 0.0),
+            color: Vec4::new(0.0, 0.0, 0.0, 0.0),
+            cone: Vec4::new(0.0, 0.0, 0.0, 0.0),
+        };
+
+        let mut light_data = [empty; MAX_LIGHTS];
+        let count = lights.len().min(MAX_LIGHTS);
+
+        if lights.len() > MAX_LIGHTS && !DROPPED_LIGHTS_REPORTED.swap(true, Ordering::Relaxed) {
+            warn!("{} lights given but only {} are supported, the rest are ignored.", lights.len(), MAX_LIGHTS);
+        }
+
+        for (i, light) in lights.iter().take(count).enumerate() {
+            light_data[i] = LightData::from_light(light, shadow_layers.get(i).cloned().unwrap_or(-1));
+        }
+
+        Self {
+            count: [count as u32, 0, 0, 0],
+            lights: light_data,
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+
+use cgmath::InnerSpace;
+
+use super::{culling::Bounds, descriptor::Mat4, vertex::Vertex};
+
+type Vec3 = cgmath::Vector3<f32>;
+
+pub const MAX_LODS: usize = 4;
+
+// Grid cells along the longest side of the mesh for the first generated level, halved for every further level
+const LOD_BASE_RESOLUTION: u32 = 64;
+
+// A generated level has to drop at least this share of the triangles of the level before it
+const LOD_MIN_REDUCTION: f32 = 0.1;
+
+// Range of the shared index buffer drawn for one level, all levels index into the same vertices
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Lod
+{
+    pub first_index: u32,
+    pub index_count: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LodSettings
+{
+    // Share of the screen height an object's bounding sphere has to fall below to use the next level
+    pub screen_sizes: [f32; MAX_LODS - 1],
+    // Relative band around each threshold in which the current level is kept, stops objects near it from popping
+    pub hysteresis: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self
+    {
+        Self {
+            screen_sizes: [0.3, 0.15, 0.075],
+            hysteresis: 0.1,
+        }
+    }
+}
+
+/*
+    Simplifies a mesh by vertex clustering. Vertices are snapped to a grid, every cell keeps the
+    vertex closest to the mean of the vertices in it, and triangles that collapse are dropped.
+    Returns the indices of every level after one another, starting with the original ones.
+ */
+pub fn generate_lods(vertices: &[Vertex], indices: &[u32], bounds: &Bounds) -> (Vec<u32>, Vec<Lod>)
+{
+    let mut all_indices = indices.to_vec();
+    let mut lods = vec![Lod {first_index: 0, index_count: indices.len() as u32}];
+
+    let size = bounds.max - bounds.min;
+    let extent = size.x.max(size.y).max(size.z);
+    if extent <= 0.0 {
+        return (all_indices, lods);
+    }
+
+    let mut resolution = LOD_BASE_RESOLUTION;
+    while lods.len() < MAX_LODS && resolution >= 1 {
+        let previous = lods.last().unwrap().index_count as f32;
+        let simplified = cluster_vertices(vertices, indices, bounds.min, extent / resolution as f32);
+
+        if simplified.is_empty() || simplified.len() as f32 > previous * (1.0 - LOD_MIN_REDUCTION) {
+            break;
+        }
+
+        lods.push(Lod {first_index: all_indices.len() as u32, index_count: simplified.len() as u32});
+        all_indices.extend(simplified);
+        resolution /= 2;
+    }
+
+    (all_indices, lods)
+}
+
+fn cluster_vertices(vertices: &[Vertex], indices: &[u32], origin: Vec3, cell_size: f32) -> Vec<u32>
+{
+    let position = |i: u32| {
+        let p = vertices[i as usize].position;
+        Vec3::new(p.x, p.y, p.z)
+    };
+
+    let cell = |i: u32| {
+        let p = (position(i) - origin) / cell_size;
+        (p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32)
+    };
+
+    // Summed in a fixed order, otherwise rounding could pick different representatives from run to run
+    let mut unique = indices.to_vec();
+    unique.sort_unstable();
+    unique.dedup();
+
+    let mut sums: HashMap<(i32, i32, i32), (Vec3, u32)> = HashMap::new();
+    for i in unique {
+        let sum = sums.entry(cell(i)).or_insert((Vec3::new(0.0, 0.0, 0.0), 0));
+        sum.0 += position(i);
+        sum.1 += 1;
+    }
+
+    // Keeping an existing vertex instead of the mean lets every level share the vertex buffer
+    let mut representatives: HashMap<(i32, i32, i32), (u32, f32)> = HashMap::new();
+    for &i in indices {
+        let key = cell(i);
+        let (sum, count) = sums[&key];
+        let distance = (position(i) - sum / count as f32).magnitude2();
+
+        let representative = representatives.entry(key).or_insert((i, distance));
+        if distance < representative.1 {
+            *representative = (i, distance);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut simplified = vec![];
+
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| representatives[&cell(i)].0);
+        if a == b || b == c || a == c {
+            continue;
+        }
+
+        // Rotated so that the same triangle is recognized no matter which corner comes first
+        let key = if a < b && a < c {(a, b, c)} else if b < c {(b, c, a)} else {(c, a, b)};
+        if seen.insert(key) {
+            simplified.extend([a, b, c]);
+        }
+    }
+
+    simplified
+}
+
+// Share of the screen height covered by the bounding sphere of the transformed mesh
+pub fn get_screen_size(bounds: &Bounds, model: &Mat4, eye: Vec3, half_fov_tan: f32) -> f32
+{
+    let center = (model * bounds.center.extend(1.0)).truncate();
+    let scale = model.x.truncate().magnitude()
+        .max(model.y.truncate().magnitude())
+        .max(model.z.truncate().magnitude());
+
+    let radius = bounds.radius * scale;
+    let distance = (center - eye).magnitude();
+
+    if distance <= radius {
+        return f32::INFINITY;
+    }
+
+    radius / (distance * half_fov_tan)
+}
+
+// Moves from the current level only once the screen size is past a threshold by more than the hysteresis band
+pub fn select_lod(current: usize, screen_size: f32, lod_count: usize, settings: &LodSettings) -> usize
+{
+    let thresholds = &settings.screen_sizes;
+    let mut lod = current.min(lod_count.max(1) - 1);
+
+    while lod + 1 < lod_count && screen_size < thresholds[lod] * (1.0 - settings.hysteresis) {
+        lod += 1;
+    }
+
+    while lod > 0 && screen_size > thresholds[lod - 1] * (1.0 + settings.hysteresis) {
+        lod -= 1;
+    }
+
+    lod
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{math::vector::Vector3, renderer::vertex::grid};
+
+    #[test]
+    fn levels_have_fewer_triangles()
+    {
+        // Twice as fine as the first clustering grid, so that every level merges vertices
+        let (vertices, indices) = grid(128);
+        let (all_indices, lods) = generate_lods(&vertices, &indices, &Bounds::from_vertices(&vertices));
+
+        assert!(lods.len() > 1);
+        assert_eq!(lods[0], Lod {first_index: 0, index_count: indices.len() as u32});
+
+        for pair in lods.windows(2) {
+            assert!(pair[1].index_count < pair[0].index_count);
+            assert_eq!(pair[1].first_index, pair[0].first_index + pair[0].index_count);
+        }
+
+        for lod in &lods {
+            assert_eq!(lod.index_count % 3, 0);
+        }
+
+        let end = lods.last().map(|l| l.first_index + l.index_count).unwrap();
+        assert_eq!(all_indices.len(), end as usize);
+        assert!(all_indices.iter().all(|&i| (i as usize) < vertices.len()));
+    }
+
+    #[test]
+    fn flat_meshes_keep_only_the_original_level()
+    {
+        let (mut vertices, indices) = grid(1);
+        vertices.iter_mut().for_each(|v| v.position = Vector3::new(0.0, 0.0, 0.0));
+
+        let (all_indices, lods) = generate_lods(&vertices, &indices, &Bounds::from_vertices(&vertices));
+
+        assert_eq!(all_indices, indices);
+        assert_eq!(lods.len(), 1);
+    }
+
+    #[test]
+    fn levels_stay_put_inside_the_hysteresis_band()
+    {
+        let settings = LodSettings::default();
+        let threshold = settings.screen_sizes[0];
+
+        // Wobbling around the threshold keeps whichever level was picked before
+        for screen_size in [threshold * 0.95, threshold * 1.05, threshold, threshold * 0.92] {
+            assert_eq!(select_lod(0, screen_size, MAX_LODS, &settings), 0);
+            assert_eq!(select_lod(1, screen_size, MAX_LODS, &settings), 1);
+        }
+
+        // Leaving the band switches in either direction
+        assert_eq!(select_lod(0, threshold * 0.85, MAX_LODS, &settings), 1);
+        assert_eq!(select_lod(1, threshold * 1.15, MAX_LODS, &settings), 0);
+    }
+
+    #[test]
+    fn selection_skips_levels_and_stays_in_range()
+    {
+        let settings = LodSettings::default();
+
+        assert_eq!(select_lod(0, 0.001, MAX_LODS, &settings), MAX_LODS - 1);
+        assert_eq!(select_lod(MAX_LODS - 1, 1.0, MAX_LODS, &settings), 0);
+        assert_eq!(select_lod(0, 0.001, 2, &settings), 1);
+        assert_eq!(select_lod(3, 0.001, 1, &settings), 0);
+    }
+}
@@ -0,0 +1,402 @@
+use anyhow::{anyhow, Result};
+use std::{mem::offset_of, ptr::copy_nonoverlapping as memcpy};
+use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder}, Device, Instance};
+
+use super::{
+    allocator::Strategy,
+    assets::AssetLoader,
+    buffer::create_buffer,
+    image::{create_texture_from_pixels, destroy_texture, Texture},
+    light::Vec4,
+    sampler::{get_sampler, SamplerDescription},
+    texture_table::{add_texture, create_texture_table, remove_texture, FLAT_NORMAL_TEXTURE, WHITE_TEXTURE},
+    upload::UploadManager,
+    RenderData,
+};
+
+// Entries in the material buffer, materials are no longer limited by descriptor sets
+pub const MAX_MATERIALS: u32 = 1024;
+
+// Base color, metallic-roughness, normal, occlusion and emissive maps
+const MATERIAL_TEXTURE_COUNT: usize = 5;
+
+type Vec3 = cgmath::Vector3<f32>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaterialTexture
+{
+    BaseColor,
+    MetallicRoughness,
+    Normal,
+    Occlusion,
+    Emissive,
+}
+
+impl MaterialTexture {
+    pub const ALL: [MaterialTexture; MATERIAL_TEXTURE_COUNT] = [
+        MaterialTexture::BaseColor,
+        MaterialTexture::MetallicRoughness,
+        MaterialTexture::Normal,
+        MaterialTexture::Occlusion,
+        MaterialTexture::Emissive,
+    ];
+
+    // Slot in the texture array sampled when the material has no map of this kind
+    pub fn default_texture(self) -> u32
+    {
+        match self {
+            MaterialTexture::Normal => FLAT_NORMAL_TEXTURE,
+            _ => WHITE_TEXTURE,
+        }
+    }
+
+    // Color data is authored in sRGB while the remaining maps store linear values
+    pub fn format(self) -> vk::Format
+    {
+        match self {
+            MaterialTexture::BaseColor | MaterialTexture::Emissive => vk::Format::R8G8B8A8_SRGB,
+            _ => vk::Format::R8G8B8A8_UNORM,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ShadingModel
+{
+    BlinnPhong,
+    #[default]
+    Pbr,
+}
+
+/*
+    Mirrors the glTF 2.0 metallic-roughness material.
+    Metallic is read from the blue channel and roughness from the green channel of the metallic-roughness map,
+    occlusion from the red channel of the occlusion map. Missing maps fall back to neutral defaults.
+    Every map of a material is read through the same sampler.
+ */
+#[derive(Clone, Debug)]
+pub struct MaterialDescription
+{
+    pub shading_model: ShadingModel,
+    pub base_color_texture: Option<String>,
+    pub metallic_roughness_texture: Option<String>,
+    pub normal_texture: Option<String>,
+    pub occlusion_texture: Option<String>,
+    pub emissive_texture: Option<String>,
+    pub base_color_factor: Vec4,
+    pub emissive_factor: Vec3,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    pub sampler: SamplerDescription,
+}
+
+impl MaterialDescription {
+    pub fn texture(&self, slot: MaterialTexture) -> Option<&str>
+    {
+        match slot {
+            MaterialTexture::BaseColor => self.base_color_texture.as_deref(),
+            MaterialTexture::MetallicRoughness => self.metallic_roughness_texture.as_deref(),
+            MaterialTexture::Normal => self.normal_texture.as_deref(),
+            MaterialTexture::Occlusion => self.occlusion_texture.as_deref(),
+            MaterialTexture::Emissive => self.emissive_texture.as_deref(),
+        }
+    }
+}
+
+impl Default for MaterialDescription {
+    fn default() -> Self
+    {
+        Self {
+            shading_model: ShadingModel::Pbr,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+            base_color_factor: Vec4::new(1.0, 1.0, 1.0, 1.0),
+            emissive_factor: Vec3::new(0.0, 0.0, 0.0),
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            sampler: SamplerDescription::default(),
+        }
+    }
+}
+
+// One entry of the material buffer, indexed by the material index pushed for every draw
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MaterialBufferObject {
+    pub base_color_factor: Vec4,
+    // xyz = emissive factor
+    pub emissive_factor: Vec4,
+    // x = metallic, y = roughness, z = normal scale, w = occlusion strength
+    pub parameters: Vec4,
+    // Slots in the texture array in `MaterialTexture::ALL` order, padded to keep the std430 stride
+    pub textures: [u32; 8],
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Material
+{
+    pub shading_model: ShadingModel,
+    // Textures owned by this material, the defaults in `RenderData` are used for missing maps
+    pub base_color: Option<Texture>,
+    pub metallic_roughness: Option<Texture>,
+    pub normal: Option<Texture>,
+    pub occlusion: Option<Texture>,
+    pub emissive: Option<Texture>,
+    // Slots in the texture array, either the textures above or the defaults
+    pub textures: [u32; MATERIAL_TEXTURE_COUNT],
+    // Owned by the sampler cache
+    pub sampler: vk::Sampler,
+}
+
+impl Material {
+    pub fn texture_mut(&mut self, slot: MaterialTexture) -> &mut Option<Texture>
+    {
+        match slot {
+            MaterialTexture::BaseColor => &mut self.base_color,
+            MaterialTexture::MetallicRoughness => &mut self.metallic_roughness,
+            MaterialTexture::Normal => &mut self.normal,
+            MaterialTexture::Occlusion => &mut self.occlusion,
+            MaterialTexture::Emissive => &mut self.emissive,
+        }
+    }
+
+    // Drawing has to wait until every texture of the material finished uploading
+    pub fn is_ready(&self, uploads: &UploadManager) -> bool
+    {
+        [
+            &self.base_color,
+            &self.metallic_roughness,
+            &self.normal,
+            &self.occlusion,
+            &self.emissive,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        .all(|t| uploads.is_complete(t.upload))
+    }
+}
+
+/*
+    A storage buffer with every material followed by the array all material textures are bound in.
+    With descriptor indexing the array may have unwritten elements and can be updated while bound.
+ */
+pub unsafe fn create_material_set_layout(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let materials_binding = vk::DescriptorSetLayoutBinding::builder()
+        .binding(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let textures_binding = vk::DescriptorSetLayoutBinding::builder()
+        .binding(1)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(data.texture_table.capacity)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let bindings = &[materials_binding, textures_binding];
+    let binding_flags = &[
+        vk::DescriptorBindingFlags::empty(),
+        vk::DescriptorBindingFlags::PARTIALLY_BOUND | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND,
+    ];
+
+    let mut flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+        .binding_flags(binding_flags);
+
+    let mut info = vk::DescriptorSetLayoutCreateInfo::builder()
+        .bindings(bindings);
+
+    if data.descriptor_indexing {
+        info = info
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .push_next(&mut flags_info);
+    }
+
+    data.material_set_layout = device.create_descriptor_set_layout(&info, None)?;
+
+    Ok(())
+}
+
+// Expects the default textures to exist, they take up the first slots of the texture array
+pub unsafe fn create_material_descriptor_set(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let buffer_size = vk::DescriptorPoolSize::builder()
+        .type_(vk::DescriptorType::STORAGE_BUFFER)
+        .descriptor_count(1);
+
+    let sampler_size = vk::DescriptorPoolSize::builder()
+        .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(data.texture_table.capacity);
+
+    let flags = if data.descriptor_indexing {
+        vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND
+    } else {
+        vk::DescriptorPoolCreateFlags::empty()
+    };
+
+    let pool_sizes = &[buffer_size, sampler_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(pool_sizes)
+        .max_sets(1)
+        .flags(flags);
+
+    data.material_descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+    let layouts = &[data.material_set_layout];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(data.material_descriptor_pool)
+        .set_layouts(layouts);
+
+    data.material_descriptor_set = device.allocate_descriptor_sets(&info)?[0];
+
+    let size = MAX_MATERIALS as u64 * size_of::<MaterialBufferObject>() as u64;
+    let (material_buffer, material_buffer_memory) = create_buffer(
+        instance,
+        device,
+        data,
+        size,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        Strategy::FreeList,
+    )?;
+
+    data.material_buffer = material_buffer;
+    data.material_buffer_memory = material_buffer_memory;
+
+    let buffer_info = vk::DescriptorBufferInfo::builder()
+        .buffer(data.material_buffer)
+        .offset(0)
+        .range(size);
+
+    let buffer_infos = &[buffer_info];
+    let buffer_write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.material_descriptor_set)
+        .dst_binding(0)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(buffer_infos);
+
+    device.update_descriptor_sets(&[buffer_write], &[] as &[vk::CopyDescriptorSet]);
+
+    create_texture_table(device, data);
+
+    Ok(())
+}
+
+pub unsafe fn create_default_textures(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    data.white_texture = create_texture_from_pixels(instance, device, data, 1, 1, &[255, 255, 255, 255], vk::Format::R8G8B8A8_UNORM)?;
+    data.flat_normal_texture = create_texture_from_pixels(instance, device, data, 1, 1, &[128, 128, 255, 255], vk::Format::R8G8B8A8_UNORM)?;
+
+    Ok(())
+}
+
+pub unsafe fn create_materials(device: &Device, data: &mut RenderData, assets: &mut AssetLoader) -> Result<()>
+{
+    let viking_room = MaterialDescription {
+        base_color_texture: Some("resources/viking_room.png".to_string()),
+        metallic_factor: 0.0,
+        roughness_factor: 0.8,
+        ..Default::default()
+    };
+
+    let material = create_material(device, data, &viking_room)?;
+    assets.load_material_textures(material, &viking_room);
+
+    Ok(())
+}
+
+// Returns the index of the material, which is what draws push to select it
+pub unsafe fn create_material(device: &Device, data: &mut RenderData, description: &MaterialDescription) -> Result<usize>
+{
+    let index = data.materials.len();
+    if index >= MAX_MATERIALS as usize {
+        return Err(anyhow!("Material buffer is full ({} materials).", MAX_MATERIALS));
+    }
+
+    // Textures are streamed in by the asset loader, the defaults are sampled until they arrive
+    let material = Material {
+        shading_model: description.shading_model,
+        textures: MaterialTexture::ALL.map(|t| t.default_texture()),
+        sampler: get_sampler(device, data, description.sampler)?,
+        ..Default::default()
+    };
+
+    let mut textures = [WHITE_TEXTURE; 8];
+    textures[..MATERIAL_TEXTURE_COUNT].copy_from_slice(&material.textures);
+
+    let mbo = MaterialBufferObject {
+        base_color_factor: description.base_color_factor,
+        emissive_factor: description.emissive_factor.extend(0.0),
+        parameters: Vec4::new(
+            description.metallic_factor,
+            description.roughness_factor,
+            description.normal_scale,
+            description.occlusion_strength,
+        ),
+        textures,
+    };
+
+    let memory = data.allocator.lock().unwrap().map(device, &data.material_buffer_memory)?;
+    memcpy(&mbo, memory.cast::<MaterialBufferObject>().add(index), 1);
+
+    data.materials.push(material);
+
+    Ok(index)
+}
+
+// Expects the device to be idle, the texture it replaces is destroyed and so is `texture` on failure
+pub unsafe fn set_material_texture(device: &Device, data: &mut RenderData, material: usize, slot: MaterialTexture, texture: Texture) -> Result<()>
+{
+    let index = match add_texture(device, data, &texture, data.materials[material].sampler) {
+        Ok(index) => index,
+        Err(e) => {
+            destroy_texture(device, data, &texture);
+            return Err(e);
+        },
+    };
+
+    let previous = std::mem::replace(&mut data.materials[material].textures[slot as usize], index);
+    remove_texture(device, data, previous);
+
+    if let Some(previous) = data.materials[material].texture_mut(slot).replace(texture) {
+        destroy_texture(device, data, &previous);
+    }
+
+    let textures = data.materials[material].textures;
+    let offset = material * size_of::<MaterialBufferObject>() + offset_of!(MaterialBufferObject, textures);
+
+    let memory = data.allocator.lock().unwrap().map(device, &data.material_buffer_memory)?;
+    memcpy(textures.as_ptr(), memory.add(offset).cast(), textures.len());
+
+    Ok(())
+}
+
+pub unsafe fn destroy_material(device: &Device, data: &RenderData, material: &Material)
+{
+    [
+        material.base_color,
+        material.metallic_roughness,
+        material.normal,
+        material.occlusion,
+        material.emissive,
+    ]
+    .iter()
+    .flatten()
+    .for_each(|t| destroy_texture(device, data, t));
+}
+
+pub unsafe fn destroy_material_descriptor_set(device: &Device, data: &RenderData)
+{
+    device.destroy_descriptor_pool(data.material_descriptor_pool, None);
+    device.destroy_buffer(data.material_buffer, None);
+    data.allocator.lock().unwrap().free(device, &data.material_buffer_memory);
+}
@@ -0,0 +1,489 @@
+use std::{fs::{self, File}, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, Result};
+use memmap2::Mmap;
+
+use crate::math::vector::{Vector2, Vector3, Vector4};
+
+use super::{
+    assets::Mesh,
+    culling::Bounds,
+    gltf_import::get_buffer_paths,
+    lod::Lod,
+    optimize::Quantization,
+    vertex::Vertex,
+    vfs::Vfs,
+};
+
+type Vec3 = cgmath::Vector3<f32>;
+
+const MESH_CACHE_MAGIC: [u8; 4] = *b"SRGM";
+
+// Bumped whenever the layout or the processing done before writing (tangents, LODs, optimization) changes
+const MESH_CACHE_VERSION: u32 = 2;
+
+const MESH_CACHE_EXTENSION: &str = "mesh";
+
+// Magic, version, source hash, vertex count, index count, attribute count, submesh count, vertex stride, quantized flag, bounds and quantization ranges
+const HEADER_SIZE: usize = 4 + 4 + 8 + 6 * 4 + 10 * 4 + 10 * 4;
+
+// Stored size of a quantized vertex, the position and texture coordinate take 16 bits per component
+const QUANTIZED_VERTEX_SIZE: usize = size_of::<Vertex>() - 5 * 4 + 5 * 2;
+
+// Location, format and offset of one attribute
+const ATTRIBUTE_SIZE: usize = 3 * 4;
+
+// First index and index count of one submesh
+const SUBMESH_SIZE: usize = 2 * 4;
+
+/*
+    Baked meshes, written next to the source file once it has been parsed and processed. The
+    file is a header followed by the vertex layout, the submesh table (one entry per level of
+    detail), the vertices and the indices, all little endian. Quantized meshes store positions
+    and texture coordinates as 16 bit integers within the header's ranges and are decoded back
+    to the vertex layout on load. A cache whose version, source hash or vertex layout doesn't
+    match is stale and simply rebuilt.
+ */
+pub fn get_mesh_cache_path(source: &Path) -> PathBuf
+{
+    source.with_extension(MESH_CACHE_EXTENSION)
+}
+
+// FNV-1a over the file contents, only used to notice that the source changed
+pub fn hash_file(vfs: &Vfs, path: &str) -> Result<u64>
+{
+    let bytes = vfs.read(path)?;
+    let mut hash = hash_bytes(&bytes);
+
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    // glTF buffers can be re-exported without the file that references them changing
+    if extension == "gltf" || extension == "glb" {
+        for buffer in get_buffer_paths(path, &bytes)? {
+            hash = hash_bytes_from(hash, &vfs.read(&buffer)?);
+        }
+    }
+
+    Ok(hash)
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64
+{
+    hash_bytes_from(0xcbf29ce484222325, bytes)
+}
+
+fn hash_bytes_from(hash: u64, bytes: &[u8]) -> u64
+{
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Returns `None` if there is no cache or it is stale
+pub fn read_mesh_cache(path: &Path, source_hash: u64) -> Result<Option<Mesh>>
+{
+    let Ok(file) = File::open(path) else {
+        return Ok(None);
+    };
+
+    // The file is only read while mapped and nothing else writes caches while loading
+    let bytes = unsafe { Mmap::map(&file)? };
+    decode_mesh(&bytes, source_hash)
+}
+
+// Written to a temporary file first so that an interrupted write never leaves a truncated cache behind
+pub fn write_mesh_cache(path: &Path, mesh: &Mesh, source_hash: u64) -> Result<()>
+{
+    let temporary = path.with_extension(format!("{}.tmp", MESH_CACHE_EXTENSION));
+    fs::write(&temporary, encode_mesh(mesh, source_hash))?;
+    fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+pub fn encode_mesh(mesh: &Mesh, source_hash: u64) -> Vec<u8>
+{
+    let attributes = Vertex::attribute_descriptions();
+    let stride = size_of::<Vertex>();
+    let vertex_size = if mesh.quantization.is_some() { QUANTIZED_VERTEX_SIZE } else { stride };
+
+    let mut bytes = Vec::with_capacity(
+        HEADER_SIZE
+            + attributes.len() * ATTRIBUTE_SIZE
+            + mesh.lods.len() * SUBMESH_SIZE
+            + mesh.vertices.len() * vertex_size
+            + mesh.indices.len() * 4
+    );
+
+    bytes.extend(MESH_CACHE_MAGIC);
+    bytes.extend(MESH_CACHE_VERSION.to_le_bytes());
+    bytes.extend(source_hash.to_le_bytes());
+
+    for count in [mesh.vertices.len(), mesh.indices.len(), attributes.len(), mesh.lods.len(), stride, mesh.quantization.is_some() as usize] {
+        bytes.extend((count as u32).to_le_bytes());
+    }
+
+    let bounds = &mesh.bounds;
+    for value in [bounds.min.x, bounds.min.y, bounds.min.z, bounds.max.x, bounds.max.y, bounds.max.z] {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    for value in [bounds.center.x, bounds.center.y, bounds.center.z, bounds.radius] {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    let quantization = mesh.quantization.unwrap_or_default();
+    for value in quantization.min.iter().chain(&quantization.max) {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    for attribute in &attributes {
+        for value in [attribute.location, attribute.format.as_raw() as u32, attribute.offset] {
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+
+    for lod in &mesh.lods {
+        bytes.extend(lod.first_index.to_le_bytes());
+        bytes.extend(lod.index_count.to_le_bytes());
+    }
+
+    // Field by field in declaration order, which is what the layout above describes
+    for vertex in &mesh.vertices {
+        let quantized = mesh.quantization.map(|q| q.encode(vertex));
+
+        match quantized {
+            Some(values) => bytes.extend(values[0..3].iter().flat_map(|v| v.to_le_bytes())),
+            None => bytes.extend([vertex.position.x, vertex.position.y, vertex.position.z].iter().flat_map(|v| v.to_le_bytes())),
+        }
+
+        for value in [vertex.color.x, vertex.color.y, vertex.color.z] {
+            bytes.extend(value.to_le_bytes());
+        }
+
+        match quantized {
+            Some(values) => bytes.extend(values[3..5].iter().flat_map(|v| v.to_le_bytes())),
+            None => bytes.extend([vertex.tex_coord.x, vertex.tex_coord.y].iter().flat_map(|v| v.to_le_bytes())),
+        }
+
+        let values = [
+            vertex.normal.x, vertex.normal.y, vertex.normal.z,
+            vertex.tangent.x, vertex.tangent.y, vertex.tangent.z, vertex.tangent.w,
+        ];
+
+        for value in values {
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+
+    for index in &mesh.indices {
+        bytes.extend(index.to_le_bytes());
+    }
+
+    bytes
+}
+
+// Returns `None` if the data was written for another version, source or vertex layout, and an error if it is damaged
+pub fn decode_mesh(bytes: &[u8], source_hash: u64) -> Result<Option<Mesh>>
+{
+    let mut reader = Reader::new(bytes);
+
+    if reader.take(4)? != MESH_CACHE_MAGIC {
+        return Err(anyhow!("Not a mesh cache."));
+    }
+
+    if reader.u32()? != MESH_CACHE_VERSION || reader.u64()? != source_hash {
+        return Ok(None);
+    }
+
+    let vertex_count = reader.u32()? as usize;
+    let index_count = reader.u32()? as usize;
+    let attribute_count = reader.u32()? as usize;
+    let submesh_count = reader.u32()? as usize;
+    let stride = reader.u32()? as usize;
+    let quantized = reader.u32()? != 0;
+
+    let min = reader.vec3()?;
+    let max = reader.vec3()?;
+    let center = reader.vec3()?;
+    let radius = reader.f32()?;
+    let bounds = Bounds {min, max, center, radius};
+
+    let mut quantization = Quantization::default();
+    for value in quantization.min.iter_mut().chain(&mut quantization.max) {
+        *value = reader.f32()?;
+    }
+
+    let quantization = quantized.then_some(quantization);
+    let vertex_size = if quantized { QUANTIZED_VERTEX_SIZE } else { stride };
+
+    let attributes = Vertex::attribute_descriptions();
+    if attribute_count != attributes.len() || stride != size_of::<Vertex>() {
+        return Ok(None);
+    }
+
+    for attribute in &attributes {
+        let layout = [reader.u32()?, reader.u32()?, reader.u32()?];
+        if layout != [attribute.location, attribute.format.as_raw() as u32, attribute.offset] {
+            return Ok(None);
+        }
+    }
+
+    let mut lods = Vec::with_capacity(submesh_count);
+    for _ in 0..submesh_count {
+        let lod = Lod {first_index: reader.u32()?, index_count: reader.u32()?};
+        if lod.first_index as usize + lod.index_count as usize > index_count {
+            return Err(anyhow!("Submesh indices out of range."));
+        }
+
+        lods.push(lod);
+    }
+
+    // Checked up front so that a damaged count can't make us allocate a huge vector
+    if reader.remaining() != vertex_count * vertex_size + index_count * 4 {
+        return Err(anyhow!("Mesh cache has the wrong size."));
+    }
+
+    let mut vertices = Vec::with_capacity(vertex_count);
+    for _ in 0..vertex_count {
+        let mut quantized = [0; 5];
+
+        let position = match quantization {
+            Some(_) => {
+                (quantized[0], quantized[1], quantized[2]) = (reader.u16()?, reader.u16()?, reader.u16()?);
+                Vec3::new(0.0, 0.0, 0.0)
+            },
+            None => reader.vec3()?,
+        };
+
+        let color = reader.vec3()?;
+
+        let tex_coord = match quantization {
+            Some(_) => {
+                (quantized[3], quantized[4]) = (reader.u16()?, reader.u16()?);
+                Vector2::new(0.0, 0.0)
+            },
+            None => Vector2::new(reader.f32()?, reader.f32()?),
+        };
+
+        let normal = reader.vec3()?;
+        let tangent = Vector4::new(reader.f32()?, reader.f32()?, reader.f32()?, reader.f32()?);
+
+        let mut vertex = Vertex::new(
+            Vector3::new(position.x, position.y, position.z),
+            Vector3::new(color.x, color.y, color.z),
+            tex_coord,
+            Vector3::new(normal.x, normal.y, normal.z),
+            tangent,
+        );
+
+        // Dequantized here so that the GPU keeps seeing the same vertex layout
+        if let Some(quantization) = &quantization {
+            quantization.decode(quantized, &mut vertex);
+        }
+
+        vertices.push(vertex);
+    }
+
+    let mut indices = Vec::with_capacity(index_count);
+    for _ in 0..index_count {
+        let index = reader.u32()?;
+        if index as usize >= vertex_count {
+            return Err(anyhow!("Mesh cache index out of range."));
+        }
+
+        indices.push(index);
+    }
+
+    Ok(Some(Mesh {vertices, indices, lods, bounds, quantization}))
+}
+
+// Little endian values read one after another, shared by the baked asset formats
+pub struct Reader<'a>
+{
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self
+    {
+        Self {bytes, offset: 0}
+    }
+
+    pub fn take(&mut self, size: usize) -> Result<&'a [u8]>
+    {
+        let bytes = self.bytes
+            .get(self.offset..self.offset + size)
+            .ok_or_else(|| anyhow!("Baked data is truncated."))?;
+
+        self.offset += size;
+        Ok(bytes)
+    }
+
+    pub fn remaining(&self) -> usize
+    {
+        self.bytes.len().saturating_sub(self.offset)
+    }
+
+    pub fn u16(&mut self) -> Result<u16>
+    {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32>
+    {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64>
+    {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32>
+    {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3>
+    {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{assets::get_placeholder_mesh, lod::Lod, optimize::{quantize, QUANTIZATION_STEPS}, vertex::grid};
+
+    const HASH: u64 = 0x1234_5678_9abc_def0;
+
+    fn assert_same_mesh(a: &Mesh, b: &Mesh)
+    {
+        assert_eq!(a.vertices, b.vertices);
+        assert_eq!(a.indices, b.indices);
+        assert_eq!(a.lods, b.lods);
+        assert_eq!(a.bounds.min, b.bounds.min);
+        assert_eq!(a.bounds.max, b.bounds.max);
+        assert_eq!(a.bounds.center, b.bounds.center);
+        assert_eq!(a.bounds.radius, b.bounds.radius);
+        assert_eq!(a.quantization, b.quantization);
+    }
+
+    #[test]
+    fn meshes_survive_a_round_trip()
+    {
+        let mesh = get_placeholder_mesh();
+        let decoded = decode_mesh(&encode_mesh(&mesh, HASH), HASH).unwrap().unwrap();
+
+        assert_same_mesh(&mesh, &decoded);
+    }
+
+    #[test]
+    fn empty_meshes_survive_a_round_trip()
+    {
+        let mesh = Mesh::default();
+        let decoded = decode_mesh(&encode_mesh(&mesh, HASH), HASH).unwrap().unwrap();
+
+        assert_same_mesh(&mesh, &decoded);
+    }
+
+    #[test]
+    fn meshes_survive_a_round_trip_through_a_file()
+    {
+        let path = std::env::temp_dir().join(format!("mesh_cache_test_{}.mesh", std::process::id()));
+        let mesh = get_placeholder_mesh();
+
+        write_mesh_cache(&path, &mesh, HASH).unwrap();
+        let decoded = read_mesh_cache(&path, HASH).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_same_mesh(&mesh, &decoded);
+    }
+
+    #[test]
+    fn quantized_meshes_are_smaller_and_stay_close()
+    {
+        // Odd scale and offset so that the grid's positions don't fall on quantization steps
+        let (mut vertices, indices) = grid(16);
+        vertices.iter_mut().for_each(|v| v.position = v.position * 0.37 + Vector3::new(-1.3, 2.9, 0.4));
+
+        let lods = vec![Lod {first_index: 0, index_count: indices.len() as u32}];
+        let bounds = Bounds::from_vertices(&vertices);
+        let original = Mesh {vertices, indices, lods, bounds, quantization: None};
+
+        let mut mesh = original.clone();
+        let error = quantize(&mut mesh);
+
+        let plain = encode_mesh(&original, HASH);
+        let quantized = encode_mesh(&mesh, HASH);
+        assert_eq!(plain.len() - quantized.len(), mesh.vertices.len() * (size_of::<Vertex>() - QUANTIZED_VERTEX_SIZE));
+
+        // Loads exactly as it was processed
+        let decoded = decode_mesh(&quantized, HASH).unwrap().unwrap();
+        assert_same_mesh(&mesh, &decoded);
+
+        // Each component is off by at most half a step of its range
+        let extent = 16.0 * 0.37;
+        let position_step = extent / QUANTIZATION_STEPS;
+        let uv_step = 1.0 / QUANTIZATION_STEPS;
+        assert!(error > 0.0 && error <= 3f32.sqrt() * position_step * 0.5 * 1.01, "error of {}", error);
+
+        for (before, after) in original.vertices.iter().zip(&decoded.vertices) {
+            assert!((before.position - after.position).length() <= error * 1.01);
+            assert!((before.tex_coord.x - after.tex_coord.x).abs() <= uv_step * 0.51);
+            assert!((before.tex_coord.y - after.tex_coord.y).abs() <= uv_step * 0.51);
+            assert_eq!(before.normal, after.normal);
+            assert_eq!(before.tangent, after.tangent);
+        }
+    }
+
+    #[test]
+    fn missing_caches_are_not_an_error()
+    {
+        let path = std::env::temp_dir().join("mesh_cache_test_missing.mesh");
+        assert!(read_mesh_cache(&path, HASH).unwrap().is_none());
+    }
+
+    #[test]
+    fn changed_sources_make_the_cache_stale()
+    {
+        let bytes = encode_mesh(&get_placeholder_mesh(), HASH);
+        assert!(decode_mesh(&bytes, HASH + 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn other_versions_make_the_cache_stale()
+    {
+        let mut bytes = encode_mesh(&get_placeholder_mesh(), HASH);
+        bytes[4..8].copy_from_slice(&(MESH_CACHE_VERSION + 1).to_le_bytes());
+
+        assert!(decode_mesh(&bytes, HASH).unwrap().is_none());
+    }
+
+    #[test]
+    fn damaged_caches_are_rejected()
+    {
+        let bytes = encode_mesh(&get_placeholder_mesh(), HASH);
+
+        assert!(decode_mesh(&bytes[..bytes.len() - 1], HASH).is_err());
+        assert!(decode_mesh(&bytes[..HEADER_SIZE / 2], HASH).is_err());
+        assert!(decode_mesh(b"not a mesh", HASH).is_err());
+
+        // Last index pointing past the vertices
+        let mut out_of_range = bytes.clone();
+        let last = out_of_range.len() - 4;
+        out_of_range[last..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_mesh(&out_of_range, HASH).is_err());
+    }
+
+    #[test]
+    fn file_hashes_follow_the_contents()
+    {
+        assert_eq!(hash_bytes(b"v 0 0 0"), hash_bytes(b"v 0 0 0"));
+        assert_ne!(hash_bytes(b"v 0 0 0"), hash_bytes(b"v 0 0 1"));
+    }
+}
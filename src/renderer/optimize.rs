@@ -0,0 +1,406 @@
+use super::{assets::Mesh, culling::Bounds, vertex::Vertex};
+
+// Entries of the LRU cache the triangle order is optimized for
+const OPTIMIZED_CACHE_SIZE: usize = 32;
+
+// FIFO cache ACMR is measured with, small enough to be a fair guess for most hardware
+const SIMULATED_CACHE_SIZE: usize = 16;
+
+// Scoring constants from Tom Forsyth's "Linear-Speed Vertex Cache Optimisation"
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+// Largest value of a quantized attribute component, they are stored as 16 bit integers
+pub const QUANTIZATION_STEPS: f32 = 65535.0;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OptimizeSettings
+{
+    // Snaps positions and texture coordinates to 16 bits within their ranges, baked meshes then store them as integers
+    pub quantize: bool,
+}
+
+/*
+    Ranges the quantized components were snapped to, in the order x, y and z of the position
+    followed by u and v of the texture coordinate. Zero encodes the minimum and
+    `QUANTIZATION_STEPS` the maximum of each range.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Quantization
+{
+    pub min: [f32; 5],
+    pub max: [f32; 5],
+}
+
+impl Quantization {
+    pub fn from_vertices(vertices: &[Vertex]) -> Self
+    {
+        let mut quantization = Self {min: [f32::MAX; 5], max: [f32::MIN; 5]};
+        for vertex in vertices {
+            for (i, value) in get_quantized_components(vertex).into_iter().enumerate() {
+                quantization.min[i] = quantization.min[i].min(value);
+                quantization.max[i] = quantization.max[i].max(value);
+            }
+        }
+
+        quantization
+    }
+
+    pub fn encode(&self, vertex: &Vertex) -> [u16; 5]
+    {
+        let values = get_quantized_components(vertex);
+        std::array::from_fn(|i| {
+            let extent = self.max[i] - self.min[i];
+            if extent <= 0.0 {
+                return 0;
+            }
+
+            ((values[i] - self.min[i]) / extent * QUANTIZATION_STEPS).round().clamp(0.0, QUANTIZATION_STEPS) as u16
+        })
+    }
+
+    // Overwrites the quantized components of `vertex`, the others are left alone
+    pub fn decode(&self, values: [u16; 5], vertex: &mut Vertex)
+    {
+        let values: [f32; 5] = std::array::from_fn(|i| {
+            self.min[i] + values[i] as f32 * (self.max[i] - self.min[i]) / QUANTIZATION_STEPS
+        });
+
+        vertex.position.x = values[0];
+        vertex.position.y = values[1];
+        vertex.position.z = values[2];
+        vertex.tex_coord.x = values[3];
+        vertex.tex_coord.y = values[4];
+    }
+}
+
+fn get_quantized_components(vertex: &Vertex) -> [f32; 5]
+{
+    [vertex.position.x, vertex.position.y, vertex.position.z, vertex.tex_coord.x, vertex.tex_coord.y]
+}
+
+// Average cache miss ratio (misses per triangle) of one level of detail, 0.5 is ideal and 3.0 the worst case
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LevelStatistics
+{
+    pub acmr_before: f32,
+    pub acmr_after: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OptimizeStatistics
+{
+    pub levels: Vec<LevelStatistics>,
+    // Largest distance a position moved while quantizing, in object space
+    pub quantization_error: Option<f32>,
+}
+
+/*
+    Reorders the triangles of every level of detail for the post-transform vertex cache, then
+    the vertices by first use so that fetching them walks through memory in order. Levels keep
+    their index ranges, only the order within them changes.
+ */
+pub fn optimize_mesh(mesh: &mut Mesh, settings: &OptimizeSettings) -> OptimizeStatistics
+{
+    let vertex_count = mesh.vertices.len();
+    let mut statistics = OptimizeStatistics::default();
+
+    for lod in &mesh.lods {
+        let range = lod.first_index as usize..(lod.first_index + lod.index_count) as usize;
+        let indices = &mut mesh.indices[range];
+
+        let acmr_before = get_acmr(indices, vertex_count, SIMULATED_CACHE_SIZE);
+        optimize_vertex_cache(indices, vertex_count);
+        let acmr_after = get_acmr(indices, vertex_count, SIMULATED_CACHE_SIZE);
+
+        statistics.levels.push(LevelStatistics {acmr_before, acmr_after});
+    }
+
+    optimize_vertex_fetch(&mut mesh.vertices, &mut mesh.indices);
+
+    if settings.quantize {
+        statistics.quantization_error = Some(quantize(mesh));
+    }
+
+    statistics
+}
+
+/*
+    Snaps the positions and texture coordinates to the values their 16 bit encoding decodes
+    to, so that a baked mesh loads exactly as it was processed. Returns the largest distance
+    a position moved.
+ */
+pub fn quantize(mesh: &mut Mesh) -> f32
+{
+    let quantization = Quantization::from_vertices(&mesh.vertices);
+    let mut error: f32 = 0.0;
+
+    for vertex in &mut mesh.vertices {
+        let before = vertex.position;
+        quantization.decode(quantization.encode(vertex), vertex);
+
+        error = error.max((vertex.position - before).length());
+    }
+
+    mesh.quantization = Some(quantization);
+    mesh.bounds = Bounds::from_vertices(&mesh.vertices);
+    error
+}
+
+// Simulates a FIFO cache of `cache_size` entries
+pub fn get_acmr(indices: &[u32], vertex_count: usize, cache_size: usize) -> f32
+{
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return 0.0;
+    }
+
+    // Time at which each vertex entered the cache, it is still cached while fewer than `cache_size` misses happened since
+    let mut entered = vec![None; vertex_count];
+    let mut misses: usize = 0;
+
+    for &index in indices {
+        let cached = entered[index as usize].is_some_and(|time| misses - time < cache_size);
+        if !cached {
+            entered[index as usize] = Some(misses);
+            misses += 1;
+        }
+    }
+
+    misses as f32 / triangle_count as f32
+}
+
+fn get_vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32
+{
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        // The last triangle's vertices get a fixed score so that its neighbours aren't always preferred
+        Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+        Some(position) => {
+            let scale = 1.0 / (OPTIMIZED_CACHE_SIZE - 3) as f32;
+            (1.0 - (position - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        },
+        None => 0.0,
+    };
+
+    // Vertices with few triangles left are finished early so that they don't linger
+    cache_score + VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+// Greedily emits the triangle whose vertices score highest, only rescoring what the cache touched
+fn optimize_vertex_cache(indices: &mut [u32], vertex_count: usize)
+{
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return;
+    }
+
+    let mut vertex_triangles = vec![vec![]; vertex_count];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &vertex in corners {
+            vertex_triangles[vertex as usize].push(triangle);
+        }
+    }
+
+    let mut cache_positions = vec![None; vertex_count];
+    let mut vertex_scores = vertex_triangles
+        .iter()
+        .map(|t| get_vertex_score(None, t.len()))
+        .collect::<Vec<_>>();
+
+    let triangle_vertices = |triangle: usize| [indices[3 * triangle], indices[3 * triangle + 1], indices[3 * triangle + 2]];
+    let mut triangle_scores = (0..triangle_count)
+        .map(|t| triangle_vertices(t).iter().map(|&v| vertex_scores[v as usize]).sum::<f32>())
+        .collect::<Vec<_>>();
+
+    let mut emitted = vec![false; triangle_count];
+    let mut order = Vec::with_capacity(triangle_count);
+    let mut cache: Vec<u32> = Vec::with_capacity(OPTIMIZED_CACHE_SIZE + 3);
+
+    // Only used when no cached vertex has triangles left, walks forward through the triangles once
+    let mut next_unemitted = 0;
+    let mut best = (0..triangle_count).max_by(|&a, &b| triangle_scores[a].total_cmp(&triangle_scores[b]));
+
+    while let Some(triangle) = best {
+        emitted[triangle] = true;
+        order.push(triangle);
+
+        let corners = triangle_vertices(triangle);
+        for &vertex in &corners {
+            vertex_triangles[vertex as usize].retain(|&t| t != triangle);
+        }
+
+        // The triangle's vertices move to the front, whatever falls off the back leaves the cache
+        let mut new_cache = corners.to_vec();
+        new_cache.extend(cache.iter().filter(|v| !corners.contains(v)));
+
+        for &vertex in new_cache.iter().skip(OPTIMIZED_CACHE_SIZE) {
+            cache_positions[vertex as usize] = None;
+        }
+
+        for (position, &vertex) in new_cache.iter().enumerate().take(OPTIMIZED_CACHE_SIZE) {
+            cache_positions[vertex as usize] = Some(position);
+        }
+
+        // Scores of every vertex whose position changed, and of the triangles using them
+        best = None;
+        let mut best_score = f32::MIN;
+        for &vertex in &new_cache {
+            let v = vertex as usize;
+            let score = get_vertex_score(cache_positions[v], vertex_triangles[v].len());
+            let delta = score - vertex_scores[v];
+            vertex_scores[v] = score;
+
+            for &t in &vertex_triangles[v] {
+                triangle_scores[t] += delta;
+            }
+        }
+
+        for &vertex in new_cache.iter().take(OPTIMIZED_CACHE_SIZE) {
+            for &t in &vertex_triangles[vertex as usize] {
+                if triangle_scores[t] > best_score {
+                    best_score = triangle_scores[t];
+                    best = Some(t);
+                }
+            }
+        }
+
+        new_cache.truncate(OPTIMIZED_CACHE_SIZE);
+        cache = new_cache;
+
+        if best.is_none() {
+            while next_unemitted < triangle_count && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+
+            best = (next_unemitted < triangle_count).then_some(next_unemitted);
+        }
+    }
+
+    let reordered = order
+        .iter()
+        .flat_map(|&t| triangle_vertices(t))
+        .collect::<Vec<_>>();
+
+    indices.copy_from_slice(&reordered);
+}
+
+// Lays the vertices out in the order the indices first use them, unused vertices are dropped
+fn optimize_vertex_fetch(vertices: &mut Vec<Vertex>, indices: &mut [u32])
+{
+    let mut remap = vec![None; vertices.len()];
+    let mut reordered = Vec::with_capacity(vertices.len());
+
+    for index in indices.iter_mut() {
+        let new_index = *remap[*index as usize].get_or_insert_with(|| {
+            reordered.push(vertices[*index as usize]);
+            reordered.len() as u32 - 1
+        });
+
+        *index = new_index;
+    }
+
+    *vertices = reordered;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::vertex::grid;
+
+    // Grid of `size` by `size` quads with the triangles in a scrambled order
+    fn scrambled_grid(size: u32) -> (Vec<Vertex>, Vec<u32>)
+    {
+        let (vertices, indices) = grid(size);
+        let mut triangles = indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect::<Vec<_>>();
+
+        // Fixed seed so that the test always sees the same order
+        let mut state: u32 = 12345;
+        for i in (1..triangles.len()).rev() {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            triangles.swap(i, (state >> 8) as usize % (i + 1));
+        }
+
+        (vertices, triangles.concat())
+    }
+
+    // Every triangle rotated to start at its smallest index, which keeps the winding
+    fn triangle_set(indices: &[u32]) -> Vec<[u32; 3]>
+    {
+        let mut triangles = indices
+            .chunks_exact(3)
+            .map(|t| {
+                let first = (0..3).min_by_key(|&i| t[i]).unwrap();
+                [t[first], t[(first + 1) % 3], t[(first + 2) % 3]]
+            })
+            .collect::<Vec<_>>();
+
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn acmr_of_a_strip_approaches_one()
+    {
+        assert_eq!(get_acmr(&[0, 1, 2], 3, SIMULATED_CACHE_SIZE), 3.0);
+
+        // Every triangle after the first adds one new vertex
+        let strip = (0..100u32).flat_map(|i| [i, i + 1, i + 2]).collect::<Vec<_>>();
+        let acmr = get_acmr(&strip, 102, SIMULATED_CACHE_SIZE);
+        assert_eq!(acmr, 102.0 / 100.0);
+
+        // Without a cache every index is a miss
+        assert_eq!(get_acmr(&strip, 102, 0), 3.0);
+        assert_eq!(get_acmr(&[], 0, SIMULATED_CACHE_SIZE), 0.0);
+    }
+
+    #[test]
+    fn vertex_cache_optimization_keeps_the_triangles()
+    {
+        let (vertices, indices) = scrambled_grid(16);
+
+        let mut optimized = indices.clone();
+        optimize_vertex_cache(&mut optimized, vertices.len());
+
+        assert_eq!(triangle_set(&optimized), triangle_set(&indices));
+
+        let before = get_acmr(&indices, vertices.len(), SIMULATED_CACHE_SIZE);
+        let after = get_acmr(&optimized, vertices.len(), SIMULATED_CACHE_SIZE);
+        assert!(after <= before, "ACMR went from {} to {}", before, after);
+        assert!(after < 1.0);
+    }
+
+    #[test]
+    fn vertex_fetch_optimization_keeps_what_indices_point_to()
+    {
+        let (mut vertices, mut indices) = scrambled_grid(8);
+
+        // One vertex no triangle uses, it should be dropped
+        let mut unused = vertices[0];
+        unused.position.z = 1.0;
+        vertices.push(unused);
+        let original = indices.iter().map(|&i| vertices[i as usize]).collect::<Vec<_>>();
+
+        optimize_vertex_fetch(&mut vertices, &mut indices);
+
+        assert_eq!(vertices.len(), 9 * 9);
+        assert_eq!(indices.iter().map(|&i| vertices[i as usize]).collect::<Vec<_>>(), original);
+
+        // Vertices come in the order they are first used
+        let mut next = 0;
+        for &index in &indices {
+            assert!(index <= next);
+            if index == next {
+                next += 1;
+            }
+        }
+    }
+}
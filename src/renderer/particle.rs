@@ -0,0 +1,782 @@
+use anyhow::{anyhow, Result};
+use cgmath::InnerSpace;
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
+
+use crate::math::vector::{Vector3, Vector4};
+
+use super::{allocator::Strategy, buffer::create_buffer, frame::FramePass, light::Vec4, pipeline::create_shader_module, RenderData, MAX_FRAMES_IN_FLIGHT};
+
+// Has to match `CURVE_KEYS` in particle.glsl
+pub const CURVE_KEYS: usize = 4;
+
+// Shared by every emitter, each one owns a contiguous range of it
+pub const MAX_PARTICLES: u32 = 65536;
+
+// Emitters are simulated as rows of workgroups in one dispatch
+pub const MAX_EMITTERS: usize = 256;
+
+// Has to match `local_size_x` in particle_simulate.comp and particle_sort.comp
+const PARTICLE_WORKGROUP_SIZE: u32 = 64;
+
+// Longer frames are simulated as if they took this long, so that a hitch doesn't fling particles away
+const MAX_DELTA_TIME: f32 = 0.1;
+
+// Quad drawn for each particle, generated from the vertex index
+const BILLBOARD_VERTICES: u32 = 6;
+
+type Vec3 = cgmath::Vector3<f32>;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ParticleBlend
+{
+    // Adds light, order doesn't matter so the particles aren't sorted
+    #[default]
+    Additive,
+    // Covers what is behind, drawn back to front
+    Alpha,
+}
+
+// Value over a particle's life, the keys are spread evenly from spawn to death and blended linearly
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Curve<T>
+{
+    pub keys: [T; CURVE_KEYS],
+}
+
+impl<T: Copy> Curve<T> {
+    pub fn new(keys: [T; CURVE_KEYS]) -> Self
+    {
+        Self {keys}
+    }
+
+    pub fn constant(value: T) -> Self
+    {
+        Self {keys: [value; CURVE_KEYS]}
+    }
+}
+
+/*
+    Spawns particles at its position at a steady rate. Every particle lives for the same time and
+    follows the curves, its velocity also gets a random offset within `spread` when it spawns.
+    Once `max_particles` are alive the oldest ones are respawned early, so the rate times the
+    lifetime should stay below it.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Emitter
+{
+    pub position: Vector3,
+    // Particles per second
+    pub spawn_rate: f32,
+    // Seconds
+    pub lifetime: f32,
+    pub spread: f32,
+    pub velocity: Curve<Vector3>,
+    pub color: Curve<Vector4>,
+    pub size: Curve<f32>,
+    pub blend: ParticleBlend,
+    pub max_particles: u32,
+}
+
+impl Default for Emitter {
+    fn default() -> Self
+    {
+        Self {
+            position: Vector3::new(0.0, 0.0, 0.0),
+            spawn_rate: 20.0,
+            lifetime: 2.0,
+            spread: 0.2,
+            velocity: Curve::constant(Vector3::new(0.0, 0.0, 0.5)),
+            color: Curve::new([
+                Vector4::new(1.0, 0.8, 0.4, 0.0),
+                Vector4::new(1.0, 0.6, 0.2, 1.0),
+                Vector4::new(0.8, 0.3, 0.1, 0.6),
+                Vector4::new(0.4, 0.1, 0.0, 0.0),
+            ]),
+            size: Curve::new([0.05, 0.1, 0.12, 0.15]),
+            blend: ParticleBlend::Additive,
+            max_particles: 64,
+        }
+    }
+}
+
+// Where an emitter's particles are and where its ring of spawn slots is at
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct EmitterState
+{
+    first: u32,
+    sort_offset: u32,
+    cursor: u32,
+    // Fraction of a particle carried over into the next frame
+    spawn_accumulator: f32,
+}
+
+impl EmitterState {
+    // Slots spawned into this frame as the cursor they start at and their count, whatever doesn't fit into the ring is dropped
+    fn advance(&mut self, spawn_rate: f32, delta_time: f32, count: u32) -> (u32, u32)
+    {
+        self.spawn_accumulator += spawn_rate * delta_time;
+        let spawn_count = (self.spawn_accumulator.floor() as u32).min(count);
+        self.spawn_accumulator = (self.spawn_accumulator - spawn_count as f32).fract();
+
+        let cursor = self.cursor;
+        self.cursor = (self.cursor + spawn_count) % count;
+
+        (cursor, spawn_count)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ParticleSystem
+{
+    emitters: Vec<Emitter>,
+    states: Vec<EmitterState>,
+    // Time passed since the particles were last simulated
+    delta_time: f32,
+    seed: u32,
+    // Particles are cleared before the next simulation after the emitters changed
+    reset: bool,
+}
+
+impl ParticleSystem {
+    pub fn emitters(&self) -> &[Emitter]
+    {
+        &self.emitters
+    }
+
+    // Particle ranges are packed in order, sort ranges are rounded up to powers of two for the bitonic sort
+    pub fn set_emitters(&mut self, emitters: Vec<Emitter>) -> Result<()>
+    {
+        if emitters.len() > MAX_EMITTERS {
+            return Err(anyhow!("Too many particle emitters ({}), at most {} are supported.", emitters.len(), MAX_EMITTERS));
+        }
+
+        if let Some(emitter) = emitters.iter().find(|e| e.max_particles == 0 || e.lifetime <= 0.0) {
+            return Err(anyhow!("Particle emitter at {:?} needs room for particles and a positive lifetime.", emitter.position));
+        }
+
+        let total = emitters.iter().map(|e| e.max_particles as u64).sum::<u64>();
+        if total > MAX_PARTICLES as u64 {
+            return Err(anyhow!("Particle emitters need {} particles, at most {} are supported.", total, MAX_PARTICLES));
+        }
+
+        let (mut first, mut sort_offset) = (0, 0);
+        self.states = emitters
+            .iter()
+            .map(|e| {
+                let state = EmitterState {first, sort_offset, ..Default::default()};
+                first += e.max_particles;
+                sort_offset += e.max_particles.next_power_of_two();
+                state
+            })
+            .collect();
+
+        self.emitters = emitters;
+        self.reset = true;
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, delta_time: f32)
+    {
+        self.delta_time += delta_time;
+    }
+}
+
+// Particle in the particle buffer, laid out like `Particle` in particle.glsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Particle
+{
+    position: Vec4,
+    color: Vec4,
+    velocity: Vec4,
+}
+
+// Laid out like `SortEntry` in particle.glsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct SortEntry
+{
+    key: f32,
+    index: u32,
+}
+
+// Laid out like `Emitter` in particle.glsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct EmitterObject
+{
+    position: Vec4,
+    velocity: [Vec4; CURVE_KEYS],
+    color: [Vec4; CURVE_KEYS],
+    size: [f32; CURVE_KEYS],
+    first: u32,
+    count: u32,
+    sort_offset: u32,
+    sort_count: u32,
+    spawn_cursor: u32,
+    spawn_count: u32,
+    spread: f32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct SimulatePushConstants
+{
+    camera_position: Vec4,
+    delta_time: f32,
+    seed: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct SortPushConstants
+{
+    offset: u32,
+    count: u32,
+    j: u32,
+    k: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct ParticleDraw
+{
+    emitter: u32,
+    count: u32,
+    blend: ParticleBlend,
+}
+
+#[derive(Clone, Debug)]
+pub struct ParticleDispatch
+{
+    descriptor_set: vk::DescriptorSet,
+    push_constants: SimulatePushConstants,
+    // Workgroups along x, enough for the largest sort range
+    group_count: u32,
+    // Sort ranges of the alpha blended emitters as offset and count
+    sorts: Vec<(u32, u32)>,
+    // Back to front by the emitters' distance to the camera
+    draws: Vec<ParticleDraw>,
+    reset: bool,
+}
+
+/*
+    Particles are simulated in a compute pass into a device local buffer that persists between
+    frames. The same pass writes a sort entry per particle, which alpha blended emitters then sort
+    back to front with a bitonic sort. The scene pass draws every particle as a camera facing quad
+    through its emitter's sort entries. The emitters are pushed into the frame ring, with one
+    descriptor set per frame in flight pointing at them.
+ */
+pub unsafe fn create_particle_system(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let (particle_buffer, particle_buffer_memory) = create_buffer(
+        instance,
+        device,
+        data,
+        (size_of::<Particle>() * MAX_PARTICLES as usize) as vk::DeviceSize,
+        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        Strategy::FreeList,
+    )?;
+
+    data.particle_buffer = particle_buffer;
+    data.particle_buffer_memory = particle_buffer_memory;
+
+    // Rounding every range up to a power of two at most doubles it
+    let (sort_buffer, sort_buffer_memory) = create_buffer(
+        instance,
+        device,
+        data,
+        (size_of::<SortEntry>() * 2 * MAX_PARTICLES as usize) as vk::DeviceSize,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        Strategy::FreeList,
+    )?;
+
+    data.particle_sort_buffer = sort_buffer;
+    data.particle_sort_buffer_memory = sort_buffer_memory;
+
+    let bindings = (0..3)
+        .map(|binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    let info = vk::DescriptorSetLayoutCreateInfo::builder()
+        .bindings(&bindings);
+
+    data.particle_set_layout = device.create_descriptor_set_layout(&info, None)?;
+
+    // The simulation and the sort share the layout, each pushing its own constants
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        .offset(0)
+        .size(size_of::<SimulatePushConstants>().max(size_of::<SortPushConstants>()) as u32);
+
+    let set_layouts = &[data.particle_set_layout];
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(push_constant_ranges);
+
+    data.particle_compute_pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
+
+    let simulate = include_bytes!("../../shaders/particle_simulate_comp.spv");
+    let sort = include_bytes!("../../shaders/particle_sort_comp.spv");
+
+    let simulate_shader_module = create_shader_module(device, &simulate[..])?;
+    let sort_shader_module = create_shader_module(device, &sort[..])?;
+
+    let infos = [simulate_shader_module, sort_shader_module].map(|module| {
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(module)
+            .name(b"main\0");
+
+        vk::ComputePipelineCreateInfo::builder()
+            .stage(stage)
+            .layout(data.particle_compute_pipeline_layout)
+            .build()
+    });
+
+    let pipelines = device.create_compute_pipelines(vk::PipelineCache::null(), &infos, None)?.0;
+    data.particle_simulate_pipeline = pipelines[0];
+    data.particle_sort_pipeline = pipelines[1];
+
+    device.destroy_shader_module(simulate_shader_module, None);
+    device.destroy_shader_module(sort_shader_module, None);
+
+    let pool_size = vk::DescriptorPoolSize::builder()
+        .type_(vk::DescriptorType::STORAGE_BUFFER)
+        .descriptor_count(3 * MAX_FRAMES_IN_FLIGHT as u32);
+
+    let pool_sizes = &[pool_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(pool_sizes)
+        .max_sets(MAX_FRAMES_IN_FLIGHT as u32);
+
+    data.particle_descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+    let layouts = vec![data.particle_set_layout; MAX_FRAMES_IN_FLIGHT];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(data.particle_descriptor_pool)
+        .set_layouts(&layouts);
+
+    data.particle_descriptor_sets = device.allocate_descriptor_sets(&info)?;
+
+    // Only the emitters move around in the frame ring, the other two bindings are written once
+    let buffer_infos = [data.particle_buffer, data.particle_sort_buffer].map(|buffer| {
+        [vk::DescriptorBufferInfo::builder()
+            .buffer(buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE as vk::DeviceSize)
+            .build()]
+    });
+
+    let writes = data.particle_descriptor_sets
+        .iter()
+        .flat_map(|set| {
+            buffer_infos.iter().enumerate().map(|(binding, buffer_info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(binding as u32)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(buffer_info)
+                    .build()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+
+    // Nothing has been simulated yet, so the buffer has to start out zeroed
+    data.particles.reset = true;
+
+    Ok(())
+}
+
+// Billboards are blended over the scene after the skybox, testing against its depth without writing to it
+pub unsafe fn create_particle_pipelines(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::VERTEX)
+        .offset(0)
+        .size(size_of::<u32>() as u32);
+
+    let set_layouts = &[data.descriptor_set_layout, data.particle_set_layout];
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(push_constant_ranges);
+
+    data.particle_pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
+
+    let vert = include_bytes!("../../shaders/particle_vert.spv");
+    let frag = include_bytes!("../../shaders/particle_frag.spv");
+
+    let vert_shader_module = create_shader_module(device, &vert[..])?;
+    let frag_shader_module = create_shader_module(device, &frag[..])?;
+
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    // Particles are read from the storage buffers
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder();
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(data.swapchain_extent.width as f32)
+        .height(data.swapchain_extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D{x:0, y:0})
+        .extent(data.swapchain_extent);
+
+    let viewports = &[viewport];
+    let scissors = &[scissor];
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewports(viewports)
+        .scissors(scissors);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(data.msaa_samples);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(false)
+        .depth_compare_op(vk::CompareOp::LESS)
+        .depth_bounds_test_enable(false)
+        .min_depth_bounds(0.0)
+        .max_depth_bounds(1.0)
+        .stencil_test_enable(false);
+
+    let stages = &[vert_stage, frag_stage];
+    let render_pass = data.graph.render_pass(FramePass::Scene)?;
+
+    // The fragment shader premultiplies, so the two modes only differ in how much of the destination is kept
+    let pipelines = [vk::BlendFactor::ONE, vk::BlendFactor::ONE_MINUS_SRC_ALPHA].map(|dst_color_blend_factor| {
+        let attachment = vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::all())
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::ONE)
+            .dst_color_blend_factor(dst_color_blend_factor)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD);
+
+        let attachments = &[attachment];
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+            .logic_op_enable(false)
+            .logic_op(vk::LogicOp::COPY)
+            .attachments(attachments)
+            .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .layout(data.particle_pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(0);
+
+        device.create_graphics_pipelines(vk::PipelineCache::null(), &[info], None).map(|p| p.0[0])
+    });
+
+    data.particle_additive_pipeline = pipelines[0]?;
+    data.particle_alpha_pipeline = pipelines[1]?;
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    Ok(())
+}
+
+// Advances the emitters by the time passed since the last frame and pushes them into the frame ring
+pub unsafe fn push_particles(device: &Device, data: &mut RenderData, frame: usize, camera_position: Vec3) -> Result<Option<ParticleDispatch>>
+{
+    let delta_time = data.particles.delta_time.min(MAX_DELTA_TIME);
+    data.particles.delta_time = 0.0;
+
+    if data.particles.emitters.is_empty() {
+        return Ok(None);
+    }
+
+    let system = &mut data.particles;
+    let emitters = system.emitters
+        .iter()
+        .zip(system.states.iter_mut())
+        .map(|(e, state)| {
+            let (spawn_cursor, spawn_count) = state.advance(e.spawn_rate, delta_time, e.max_particles);
+
+            EmitterObject {
+                position: Vec4::new(e.position.x, e.position.y, e.position.z, e.lifetime),
+                velocity: e.velocity.keys.map(|v| Vec4::new(v.x, v.y, v.z, 0.0)),
+                color: e.color.keys.map(|c| Vec4::new(c.x, c.y, c.z, c.w)),
+                size: e.size.keys,
+                first: state.first,
+                count: e.max_particles,
+                sort_offset: state.sort_offset,
+                sort_count: e.max_particles.next_power_of_two(),
+                spawn_cursor,
+                spawn_count,
+                spread: e.spread,
+                _padding: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    system.seed = system.seed.wrapping_add(1);
+    let seed = system.seed;
+    let reset = std::mem::take(&mut system.reset);
+
+    let sorts = emitters
+        .iter()
+        .zip(&system.emitters)
+        .filter(|(_, e)| e.blend == ParticleBlend::Alpha)
+        .map(|(o, _)| (o.sort_offset, o.sort_count))
+        .collect::<Vec<_>>();
+
+    // Emitters don't overlap much, so ordering them as a whole is enough for blending between them
+    let distance = |e: &Emitter| (Vec3::new(e.position.x, e.position.y, e.position.z) - camera_position).magnitude2();
+    let mut draws = system.emitters
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (distance(e), ParticleDraw {emitter: i as u32, count: e.max_particles, blend: e.blend}))
+        .collect::<Vec<_>>();
+    draws.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let group_count = emitters
+        .iter()
+        .map(|e| e.sort_count.div_ceil(PARTICLE_WORKGROUP_SIZE))
+        .max()
+        .unwrap_or(0);
+
+    let emitters_offset = data.frame_ring.push_slice(&emitters)?;
+
+    let descriptor_set = data.particle_descriptor_sets[frame];
+    let buffer_info = vk::DescriptorBufferInfo::builder()
+        .buffer(data.frame_ring.buffer)
+        .offset(emitters_offset)
+        .range(size_of_val(emitters.as_slice()) as vk::DeviceSize);
+
+    let buffer_info = &[buffer_info];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(2)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(buffer_info);
+
+    device.update_descriptor_sets(&[write], &[] as &[vk::CopyDescriptorSet]);
+
+    let push_constants = SimulatePushConstants {
+        camera_position: camera_position.extend(1.0),
+        delta_time,
+        seed,
+    };
+
+    Ok(Some(ParticleDispatch {
+        descriptor_set,
+        push_constants,
+        group_count,
+        sorts,
+        draws: draws.into_iter().map(|(_, d)| d).collect(),
+        reset,
+    }))
+}
+
+// Has to be recorded outside of a render pass, before the scene pass draws the particles
+pub unsafe fn record_particle_dispatch(device: &Device, data: &RenderData, command_buffer: vk::CommandBuffer, dispatch: &ParticleDispatch)
+{
+    // The previous frame may still be drawing or simulating the particles that are about to be overwritten
+    record_particle_barrier(
+        device,
+        command_buffer,
+        (vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::VERTEX_SHADER, vk::AccessFlags::SHADER_WRITE),
+        (vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::TRANSFER_WRITE),
+    );
+
+    if dispatch.reset {
+        device.cmd_fill_buffer(command_buffer, data.particle_buffer, 0, vk::WHOLE_SIZE as vk::DeviceSize, 0);
+
+        record_particle_barrier(
+            device,
+            command_buffer,
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+            (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+        );
+    }
+
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, data.particle_simulate_pipeline);
+    device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, data.particle_compute_pipeline_layout, 0, &[dispatch.descriptor_set], &[]);
+    device.cmd_push_constants(command_buffer, data.particle_compute_pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, as_bytes(&dispatch.push_constants));
+    device.cmd_dispatch(command_buffer, dispatch.group_count, dispatch.draws.len() as u32, 1);
+
+    let compute_to_compute = (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE);
+    let compute_read_write = (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+    // Every emitter's range goes through the same passes, ranges shorter than a pass skip it
+    if !dispatch.sorts.is_empty() {
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, data.particle_sort_pipeline);
+
+        for (j, k) in get_bitonic_passes(dispatch.sorts.iter().map(|s| s.1).max().unwrap_or(0)) {
+            record_particle_barrier(device, command_buffer, compute_to_compute, compute_read_write);
+
+            for &(offset, count) in dispatch.sorts.iter().filter(|s| k <= s.1) {
+                let push_constants = SortPushConstants {offset, count, j, k};
+                device.cmd_push_constants(command_buffer, data.particle_compute_pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, as_bytes(&push_constants));
+                device.cmd_dispatch(command_buffer, count.div_ceil(PARTICLE_WORKGROUP_SIZE), 1, 1);
+            }
+        }
+    }
+
+    record_particle_barrier(
+        device,
+        command_buffer,
+        compute_to_compute,
+        (vk::PipelineStageFlags::VERTEX_SHADER, vk::AccessFlags::SHADER_READ),
+    );
+}
+
+// Draws back to front into the scene pass, after everything opaque
+pub unsafe fn record_particle_draws(device: &Device, data: &RenderData, command_buffer: vk::CommandBuffer, dispatch: &ParticleDispatch, dynamic_offsets: &[u32])
+{
+    device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, data.particle_pipeline_layout, 0, &[data.descriptor_set, dispatch.descriptor_set], dynamic_offsets);
+
+    for draw in &dispatch.draws {
+        let pipeline = match draw.blend {
+            ParticleBlend::Additive => data.particle_additive_pipeline,
+            ParticleBlend::Alpha => data.particle_alpha_pipeline,
+        };
+
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+        device.cmd_push_constants(command_buffer, data.particle_pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, &draw.emitter.to_ne_bytes());
+        device.cmd_draw(command_buffer, BILLBOARD_VERTICES, draw.count, 0, 0);
+    }
+}
+
+// Sequence size k and compare distance j of every pass sorting `count` entries, `count` being a power of two
+fn get_bitonic_passes(count: u32) -> Vec<(u32, u32)>
+{
+    let mut passes = vec![];
+    let mut k = 2;
+    while k <= count {
+        let mut j = k / 2;
+        while j > 0 {
+            passes.push((j, k));
+            j /= 2;
+        }
+        k *= 2;
+    }
+
+    passes
+}
+
+unsafe fn record_particle_barrier(device: &Device, command_buffer: vk::CommandBuffer, src: (vk::PipelineStageFlags, vk::AccessFlags), dst: (vk::PipelineStageFlags, vk::AccessFlags))
+{
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src.1)
+        .dst_access_mask(dst.1);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src.0,
+        dst.0,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+}
+
+unsafe fn as_bytes<T>(value: &T) -> &[u8]
+{
+    std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+}
+
+pub unsafe fn destroy_particle_pipelines(device: &Device, data: &RenderData)
+{
+    device.destroy_pipeline(data.particle_additive_pipeline, None);
+    device.destroy_pipeline(data.particle_alpha_pipeline, None);
+    device.destroy_pipeline_layout(data.particle_pipeline_layout, None);
+}
+
+pub unsafe fn destroy_particle_system(device: &Device, data: &RenderData)
+{
+    device.destroy_descriptor_pool(data.particle_descriptor_pool, None);
+    device.destroy_pipeline(data.particle_simulate_pipeline, None);
+    device.destroy_pipeline(data.particle_sort_pipeline, None);
+    device.destroy_pipeline_layout(data.particle_compute_pipeline_layout, None);
+    device.destroy_descriptor_set_layout(data.particle_set_layout, None);
+
+    let mut allocator = data.allocator.lock().unwrap();
+    device.destroy_buffer(data.particle_buffer, None);
+    allocator.free(device, &data.particle_buffer_memory);
+    device.destroy_buffer(data.particle_sort_buffer, None);
+    allocator.free(device, &data.particle_sort_buffer_memory);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawning_wraps_around_the_ring()
+    {
+        let mut state = EmitterState::default();
+
+        // 2.5 particles, the half is carried over
+        assert_eq!(state.advance(10.0, 0.25, 4), (0, 2));
+        assert_eq!(state.advance(10.0, 0.25, 4), (2, 3));
+        assert_eq!(state.cursor, 1);
+
+        // Never more than the ring holds
+        assert_eq!(state.advance(10.0, 1.0, 4), (1, 4));
+        assert_eq!(state.cursor, 1);
+    }
+
+    #[test]
+    fn bitonic_passes_cover_every_stage()
+    {
+        assert_eq!(get_bitonic_passes(1), vec![]);
+        assert_eq!(get_bitonic_passes(4), vec![(1, 2), (2, 4), (1, 4)]);
+        assert_eq!(get_bitonic_passes(1024).len(), 55);
+    }
+}
@@ -0,0 +1,380 @@
+use anyhow::Result;
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
+
+use super::{frame::{FramePass, HDR_IMAGE}, graph::{Attachment, ImageDescription, RenderGraph, ResourceId}, image::{create_texture, create_texture_from_pixels}, light::Vec4, pipeline::create_shader_module, sampler::{get_sampler, SamplerDescription}, RenderData};
+
+// Format of the scene color target and of the intermediate post-processing images
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+// Edge length of the color grading cube, stored as LUT_SIZE slices of LUT_SIZE x LUT_SIZE side by side
+pub const LUT_SIZE: u32 = 16;
+
+/*
+    Full screen passes run in order after the scene. Effects after `Tonemap` operate on
+    display referred, gamma encoded values, so the chain should normally start with it.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PostEffect
+{
+    Tonemap,
+    Fxaa,
+    Vignette,
+    ColorGrading,
+}
+
+#[derive(Clone, Debug)]
+pub struct PostSettings
+{
+    // An empty chain falls back to tonemapping alone since the HDR target can't be presented
+    pub effects: Vec<PostEffect>,
+    pub exposure: f32,
+    pub gamma: f32,
+    pub vignette_strength: f32,
+    // Normalized distance from the center where darkening starts
+    pub vignette_radius: f32,
+    // PNG strip of LUT_SIZE * LUT_SIZE by LUT_SIZE pixels, an identity LUT is used when missing
+    pub color_grading_lut: Option<String>,
+    pub color_grading_strength: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self
+    {
+        Self {
+            effects: vec![PostEffect::Tonemap, PostEffect::Fxaa, PostEffect::Vignette],
+            exposure: 1.0,
+            gamma: 2.2,
+            vignette_strength: 0.35,
+            vignette_radius: 0.55,
+            color_grading_lut: None,
+            color_grading_strength: 1.0,
+        }
+    }
+}
+
+impl PostSettings {
+    fn get_effects(&self) -> Vec<PostEffect>
+    {
+        if self.effects.is_empty() {
+            vec![PostEffect::Tonemap]
+        } else {
+            self.effects.clone()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct PostPushConstants {
+    // Effect specific, see `PostPass::get_push_constants`
+    pub parameters: Vec4,
+    // xy = texel size, z = 1.0 when writing to an sRGB target, w = gamma
+    pub target: Vec4,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PostPass
+{
+    pub effect: PostEffect,
+    pub pipeline: vk::Pipeline,
+    pub descriptor_set: vk::DescriptorSet,
+    // The last pass writes to the swapchain image
+    pub presents: bool,
+}
+
+impl PostPass {
+    pub fn get_push_constants(&self, data: &RenderData) -> PostPushConstants
+    {
+        let settings = &data.post_settings;
+
+        let parameters = match self.effect {
+            PostEffect::Tonemap => Vec4::new(settings.exposure, settings.gamma, 0.0, 0.0),
+            PostEffect::Fxaa => Vec4::new(0.0, 0.0, 0.0, 0.0),
+            PostEffect::Vignette => Vec4::new(settings.vignette_strength, settings.vignette_radius, 0.0, 0.0),
+            PostEffect::ColorGrading => Vec4::new(settings.color_grading_strength, LUT_SIZE as f32, 0.0, 0.0),
+        };
+
+        let srgb = self.presents && is_srgb(data.swapchain_format);
+
+        PostPushConstants {
+            parameters,
+            target: Vec4::new(
+                1.0 / data.swapchain_extent.width as f32,
+                1.0 / data.swapchain_extent.height as f32,
+                if srgb {1.0} else {0.0},
+                settings.gamma,
+            ),
+        }
+    }
+}
+
+fn is_srgb(format: vk::Format) -> bool
+{
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32
+    )
+}
+
+pub unsafe fn create_post_set_layout(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    // Binding 0 is the previous pass' output, binding 1 an optional auxiliary input such as the color grading LUT
+    let bindings = (0..2)
+        .map(|binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        })
+        .collect::<Vec<_>>();
+
+    let info = vk::DescriptorSetLayoutCreateInfo::builder()
+        .bindings(&bindings);
+
+    data.post_set_layout = device.create_descriptor_set_layout(&info, None)?;
+
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        .offset(0)
+        .size(size_of::<PostPushConstants>() as u32);
+
+    let set_layouts = &[data.post_set_layout];
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(push_constant_ranges);
+
+    data.post_pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
+
+    data.post_sampler = get_sampler(device, data, SamplerDescription::CLAMPED)?;
+
+    Ok(())
+}
+
+pub unsafe fn create_color_grading_lut(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    if let Some(path) = data.post_settings.color_grading_lut.clone() {
+        data.color_grading_lut = create_texture(instance, device, data, &path, vk::Format::R8G8B8A8_UNORM)?;
+        return Ok(());
+    }
+
+    let max = (LUT_SIZE - 1) as f32;
+    let mut pixels = vec![];
+    for y in 0..LUT_SIZE {
+        for x in 0..LUT_SIZE * LUT_SIZE {
+            let r = (x % LUT_SIZE) as f32 / max;
+            let g = y as f32 / max;
+            let b = (x / LUT_SIZE) as f32 / max;
+            pixels.extend([(r * 255.0).round() as u8, (g * 255.0).round() as u8, (b * 255.0).round() as u8, 255]);
+        }
+    }
+
+    data.color_grading_lut = create_texture_from_pixels(instance, device, data, LUT_SIZE * LUT_SIZE, LUT_SIZE, &pixels, vk::Format::R8G8B8A8_UNORM)?;
+
+    Ok(())
+}
+
+fn get_post_image_name(pass: usize) -> String
+{
+    format!("post {}", pass)
+}
+
+// Each pass reads the previous pass' output, the last one writes to the swapchain image
+pub fn add_post_passes(graph: &mut RenderGraph<FramePass>, data: &RenderData, hdr: ResourceId, swapchain: ResourceId)
+{
+    let effects = data.post_settings.get_effects();
+
+    let mut input = hdr;
+    for i in 0..effects.len() {
+        let output = if i == effects.len() - 1 {
+            swapchain
+        } else {
+            graph.create_image(&get_post_image_name(i), ImageDescription::new(HDR_FORMAT, data.swapchain_extent))
+        };
+
+        graph.add_pass(FramePass::Post(i))
+            .sample(input)
+            .color(Attachment::discard(output));
+
+        input = output;
+    }
+}
+
+pub unsafe fn create_post_processing(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let effects = data.post_settings.get_effects();
+
+    let pool_size = vk::DescriptorPoolSize::builder()
+        .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(effects.len() as u32 * 2);
+
+    let pool_sizes = &[pool_size];
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(pool_sizes)
+        .max_sets(effects.len() as u32);
+
+    data.post_descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+    let layouts = vec![data.post_set_layout; effects.len()];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(data.post_descriptor_pool)
+        .set_layouts(&layouts);
+
+    let descriptor_sets = device.allocate_descriptor_sets(&info)?;
+
+    data.post_passes.clear();
+    for (i, effect) in effects.iter().enumerate() {
+        let input = if i == 0 {
+            data.graph.view(HDR_IMAGE)?
+        } else {
+            data.graph.view(&get_post_image_name(i - 1))?
+        };
+
+        write_post_descriptor_set(device, data, descriptor_sets[i], input);
+
+        let render_pass = data.graph.render_pass(FramePass::Post(i))?;
+        let pipeline = create_post_pipeline(device, data, *effect, render_pass)?;
+
+        data.post_passes.push(PostPass {effect: *effect, pipeline, descriptor_set: descriptor_sets[i], presents: i == effects.len() - 1});
+    }
+
+    Ok(())
+}
+
+unsafe fn write_post_descriptor_set(device: &Device, data: &RenderData, descriptor_set: vk::DescriptorSet, input: vk::ImageView)
+{
+    let image_infos = [input, data.color_grading_lut.view]
+        .iter()
+        .map(|view| {
+            [vk::DescriptorImageInfo::builder()
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(*view)
+                .sampler(data.post_sampler)
+                .build()]
+        })
+        .collect::<Vec<_>>();
+
+    let writes = image_infos
+        .iter()
+        .enumerate()
+        .map(|(i, image_info)| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(i as u32)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(image_info)
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+}
+
+unsafe fn create_post_pipeline(device: &Device, data: &RenderData, effect: PostEffect, render_pass: vk::RenderPass) -> Result<vk::Pipeline>
+{
+    let frag = match effect {
+        PostEffect::Tonemap => &include_bytes!("../../shaders/tonemap_frag.spv")[..],
+        PostEffect::Fxaa => &include_bytes!("../../shaders/fxaa_frag.spv")[..],
+        PostEffect::Vignette => &include_bytes!("../../shaders/vignette_frag.spv")[..],
+        PostEffect::ColorGrading => &include_bytes!("../../shaders/color_grading_frag.spv")[..],
+    };
+
+    create_fullscreen_pipeline(device, data, frag, render_pass, false)
+}
+
+/*
+    Full screen triangle using the post-processing layout. Viewport and scissor are dynamic
+    so that the same pipeline can render into targets of any size.
+ */
+pub unsafe fn create_fullscreen_pipeline(device: &Device, data: &RenderData, frag: &[u8], render_pass: vk::RenderPass, additive: bool) -> Result<vk::Pipeline>
+{
+    let vert = include_bytes!("../../shaders/fullscreen_vert.spv");
+
+    let vert_shader_module = create_shader_module(device, &vert[..])?;
+    let frag_shader_module = create_shader_module(device, frag)?;
+
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder();
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let dynamic_states = &[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(dynamic_states);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(vk::SampleCountFlags::_1);
+
+    let attachment = vk::PipelineColorBlendAttachmentState::builder()
+        .color_write_mask(vk::ColorComponentFlags::all())
+        .blend_enable(additive)
+        .src_color_blend_factor(vk::BlendFactor::ONE)
+        .dst_color_blend_factor(vk::BlendFactor::ONE)
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+        .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+        .alpha_blend_op(vk::BlendOp::ADD);
+
+    let attachments = &[attachment];
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(attachments)
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    let stages = &[vert_stage, frag_stage];
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(data.post_pipeline_layout)
+        .render_pass(render_pass)
+        .subpass(0);
+
+    let pipeline = device.create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?.0[0];
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    Ok(pipeline)
+}
+
+pub unsafe fn destroy_post_processing(device: &Device, data: &RenderData)
+{
+    data.post_passes
+        .iter()
+        .for_each(|p| device.destroy_pipeline(p.pipeline, None));
+    device.destroy_descriptor_pool(data.post_descriptor_pool, None);
+}
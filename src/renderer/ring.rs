@@ -0,0 +1,134 @@
+use std::ptr::{self, copy_nonoverlapping as memcpy};
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{vk::{self, DeviceV1_0, InstanceV1_0}, Device, Instance};
+
+use super::{allocator::{Allocation, Strategy}, buffer::create_buffer, RenderData, MAX_FRAMES_IN_FLIGHT};
+
+// Space each frame in flight has for its transient data
+pub const FRAME_RING_SIZE: vk::DeviceSize = 4 * 1024 * 1024;
+
+/*
+    Persistently mapped buffer split into one segment per frame in flight. Data pushed during
+    a frame stays valid until the same frame index comes around again, by which point its
+    fence has been waited on. Bound as dynamic uniform buffers, as vertex and index buffers,
+    or as storage and indirect buffers at the returned offsets.
+ */
+#[derive(Clone, Debug)]
+pub struct FrameRing
+{
+    pub buffer: vk::Buffer,
+    memory: Allocation,
+    mapped: *mut u8,
+    // Largest of the uniform and storage buffer offset alignments, every push starts on it so that any offset can be bound
+    alignment: vk::DeviceSize,
+    frame: usize,
+    head: vk::DeviceSize,
+}
+
+// The mapped memory is only written through `&mut self`, recording threads only read `buffer`
+unsafe impl Send for FrameRing {}
+unsafe impl Sync for FrameRing {}
+
+impl Default for FrameRing {
+    fn default() -> Self
+    {
+        Self {
+            buffer: vk::Buffer::default(),
+            memory: Allocation::default(),
+            mapped: ptr::null_mut(),
+            alignment: 1,
+            frame: 0,
+            head: 0,
+        }
+    }
+}
+
+impl FrameRing {
+    // Starts over at the beginning of the frame's segment, the frame's fence must have been waited on
+    pub fn begin_frame(&mut self, frame: usize)
+    {
+        self.frame = frame % MAX_FRAMES_IN_FLIGHT;
+        self.head = self.frame as vk::DeviceSize * FRAME_RING_SIZE;
+    }
+
+    pub unsafe fn push<T: Copy>(&mut self, value: &T) -> Result<vk::DeviceSize>
+    {
+        self.push_slice(std::slice::from_ref(value))
+    }
+
+    // Copies `values` into the current frame's segment and returns their offset into `buffer`
+    pub unsafe fn push_slice<T: Copy>(&mut self, values: &[T]) -> Result<vk::DeviceSize>
+    {
+        let offset = self.reserve::<T>(values.len())?;
+        memcpy(values.as_ptr(), self.mapped.add(offset as usize).cast(), values.len());
+
+        Ok(offset)
+    }
+
+    // Space for `count` values the GPU writes itself, the contents are left as they were
+    pub fn reserve<T>(&mut self, count: usize) -> Result<vk::DeviceSize>
+    {
+        let size = (size_of::<T>() * count) as vk::DeviceSize;
+        let alignment = self.alignment.max(align_of::<T>() as vk::DeviceSize);
+        let offset = self.head.div_ceil(alignment) * alignment;
+
+        let end = (self.frame as vk::DeviceSize + 1) * FRAME_RING_SIZE;
+        if offset + size > end {
+            return Err(anyhow!("Frame ring is out of space, {} bytes don't fit into frame {}.", size, self.frame));
+        }
+
+        self.head = offset + size;
+
+        Ok(offset)
+    }
+
+    // Reads back values the GPU wrote during an earlier use of the segment, its fence must have been waited on
+    pub unsafe fn read<T: Copy>(&self, offset: vk::DeviceSize, count: usize) -> Vec<T>
+    {
+        std::slice::from_raw_parts(self.mapped.add(offset as usize).cast::<T>(), count).to_vec()
+    }
+
+    // Bytes pushed so far this frame
+    pub fn used(&self) -> vk::DeviceSize
+    {
+        self.head - self.frame as vk::DeviceSize * FRAME_RING_SIZE
+    }
+}
+
+pub unsafe fn create_frame_ring(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let (buffer, memory) = create_buffer(
+        instance,
+        device,
+        data,
+        FRAME_RING_SIZE * MAX_FRAMES_IN_FLIGHT as vk::DeviceSize,
+        vk::BufferUsageFlags::UNIFORM_BUFFER
+            | vk::BufferUsageFlags::VERTEX_BUFFER
+            | vk::BufferUsageFlags::INDEX_BUFFER
+            | vk::BufferUsageFlags::STORAGE_BUFFER
+            | vk::BufferUsageFlags::INDIRECT_BUFFER,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        Strategy::FreeList,
+    )?;
+
+    let mapped = data.allocator.lock().unwrap().map(device, &memory)?;
+    let limits = instance.get_physical_device_properties(data.physical_device).limits;
+
+    data.frame_ring = FrameRing {
+        buffer,
+        memory,
+        mapped,
+        alignment: limits.min_uniform_buffer_offset_alignment.max(limits.min_storage_buffer_offset_alignment).max(1),
+        frame: 0,
+        head: 0,
+    };
+
+    Ok(())
+}
+
+pub unsafe fn destroy_frame_ring(device: &Device, data: &RenderData)
+{
+    device.destroy_buffer(data.frame_ring.buffer, None);
+    data.allocator.lock().unwrap().free(device, &data.frame_ring.memory);
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0}, Device, Instance};
+
+use super::RenderData;
+
+/*
+    Everything that tells samplers apart, the rest of the state follows from it. Mipmaps are
+    filtered like texels and every level can be used. Comparison samplers read white beyond the
+    border, so that whatever is outside a shadow map counts as lit, all others read black.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerDescription
+{
+    pub filter: vk::Filter,
+    pub address_mode: vk::SamplerAddressMode,
+    // 1 turns anisotropic filtering off, higher values are clamped to what the device supports
+    pub anisotropy: u32,
+    pub compare_op: Option<vk::CompareOp>,
+}
+
+impl SamplerDescription {
+    // Filtered and repeated with as much anisotropy as the device allows, for material textures
+    pub const TEXTURE: Self = Self {
+        filter: vk::Filter::LINEAR,
+        address_mode: vk::SamplerAddressMode::REPEAT,
+        anisotropy: 16,
+        compare_op: None,
+    };
+
+    // Unfiltered and repeated, for pixel art
+    pub const PIXEL_ART: Self = Self {
+        filter: vk::Filter::NEAREST,
+        address_mode: vk::SamplerAddressMode::REPEAT,
+        anisotropy: 1,
+        compare_op: None,
+    };
+
+    // Filtered and clamped to the edge, for UI and full screen passes
+    pub const CLAMPED: Self = Self {
+        filter: vk::Filter::LINEAR,
+        address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        anisotropy: 1,
+        compare_op: None,
+    };
+
+    // Depth comparison, linear filtering gives an extra 2x2 PCF tap for free
+    pub const SHADOW: Self = Self {
+        filter: vk::Filter::LINEAR,
+        address_mode: vk::SamplerAddressMode::CLAMP_TO_BORDER,
+        anisotropy: 1,
+        compare_op: Some(vk::CompareOp::LESS_OR_EQUAL),
+    };
+}
+
+impl Default for SamplerDescription {
+    fn default() -> Self
+    {
+        Self::TEXTURE
+    }
+}
+
+// Samplers are created the first time they are asked for and shared from then on
+#[derive(Clone, Debug, Default)]
+pub struct SamplerCache
+{
+    samplers: HashMap<SamplerDescription, vk::Sampler>,
+    max_anisotropy: u32,
+}
+
+pub unsafe fn create_sampler_cache(instance: &Instance, data: &mut RenderData)
+{
+    let limits = instance.get_physical_device_properties(data.physical_device).limits;
+
+    data.samplers = SamplerCache {
+        max_anisotropy: (limits.max_sampler_anisotropy as u32).max(1),
+        ..Default::default()
+    };
+}
+
+// Descriptions that only differ in anisotropy the device can't provide share one sampler
+pub unsafe fn get_sampler(device: &Device, data: &mut RenderData, description: SamplerDescription) -> Result<vk::Sampler>
+{
+    let description = SamplerDescription {
+        anisotropy: description.anisotropy.clamp(1, data.samplers.max_anisotropy),
+        ..description
+    };
+
+    if let Some(sampler) = data.samplers.samplers.get(&description) {
+        return Ok(*sampler);
+    }
+
+    let mipmap_mode = match description.filter {
+        vk::Filter::NEAREST => vk::SamplerMipmapMode::NEAREST,
+        _ => vk::SamplerMipmapMode::LINEAR,
+    };
+
+    let border_color = match description.compare_op {
+        Some(_) => vk::BorderColor::FLOAT_OPAQUE_WHITE,
+        None => vk::BorderColor::INT_OPAQUE_BLACK,
+    };
+
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(description.filter)
+        .min_filter(description.filter)
+        .address_mode_u(description.address_mode)
+        .address_mode_v(description.address_mode)
+        .address_mode_w(description.address_mode)
+        .anisotropy_enable(description.anisotropy > 1)
+        .max_anisotropy(description.anisotropy as f32)
+        .border_color(border_color)
+        .unnormalized_coordinates(false)
+        .compare_enable(description.compare_op.is_some())
+        .compare_op(description.compare_op.unwrap_or(vk::CompareOp::ALWAYS))
+        .mipmap_mode(mipmap_mode)
+        .mip_lod_bias(0.0)
+        .min_lod(0.0)
+        .max_lod(vk::LOD_CLAMP_NONE);
+
+    let sampler = device.create_sampler(&info, None)?;
+    data.samplers.samplers.insert(description, sampler);
+
+    Ok(sampler)
+}
+
+// Expects the device to be idle
+pub unsafe fn destroy_sampler_cache(device: &Device, data: &RenderData)
+{
+    data.samplers.samplers
+        .values()
+        .for_each(|s| device.destroy_sampler(*s, None));
+}
@@ -0,0 +1,134 @@
+use std::{ops::Range, thread};
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder}, Device};
+
+use super::{frame::FramePass, instancing::InstanceBatches, material::ShadingModel, particle::{record_particle_draws, ParticleDispatch}, RenderData};
+
+// Handing fewer draws than this to another thread costs more than recording them directly
+const MIN_DRAWS_PER_THREAD: usize = 8;
+
+/*
+    Records the scene pass into one secondary command buffer per recording thread. The instanced
+    draws are split into contiguous ranges so that the primary executes them in their original
+    order, each thread only touches its own command pool for the frame in flight.
+ */
+pub unsafe fn record_scene_command_buffers(device: &Device, data: &RenderData, instances: &InstanceBatches, particles: Option<&ParticleDispatch>, dynamic_offsets: &[u32], frame: usize, image_index: usize) -> Result<Vec<vk::CommandBuffer>>
+{
+    let draw_count = instances.batches.len();
+    let thread_count = draw_count
+        .div_ceil(MIN_DRAWS_PER_THREAD)
+        .clamp(1, data.thread_command_pools[frame].len());
+
+    let range_size = draw_count.div_ceil(thread_count);
+    let ranges = (0..thread_count)
+        .map(|i| (i * range_size).min(draw_count)..((i + 1) * range_size).min(draw_count))
+        .collect::<Vec<_>>();
+
+    // The skybox goes last so that it is only shaded where no geometry was drawn, particles blend over both
+    let record = |thread: usize, draws: Range<usize>| unsafe {
+        let last = thread + 1 == thread_count;
+        record_draws(device, data, instances, draws, last.then_some(particles).flatten(), dynamic_offsets, frame, image_index, thread, last)
+    };
+
+    thread::scope(|scope| {
+        let workers = ranges
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(thread, draws)| {
+                let draws = draws.clone();
+                scope.spawn(move || record(thread, draws))
+            })
+            .collect::<Vec<_>>();
+
+        // The first range is recorded on the calling thread instead of waiting idle
+        let mut command_buffers = vec![record(0, ranges[0].clone())?];
+        for worker in workers {
+            command_buffers.push(worker.join().map_err(|_| anyhow!("Scene recording thread panicked."))??);
+        }
+
+        Ok(command_buffers)
+    })
+}
+
+unsafe fn record_draws(device: &Device, data: &RenderData, instances: &InstanceBatches, draws: Range<usize>, particles: Option<&ParticleDispatch>, dynamic_offsets: &[u32], frame: usize, image_index: usize, thread: usize, skybox: bool) -> Result<vk::CommandBuffer>
+{
+    device.reset_command_pool(data.thread_command_pools[frame][thread], vk::CommandPoolResetFlags::empty())?;
+
+    let command_buffer = data.secondary_command_buffers[frame][thread];
+    begin_secondary_command_buffer(device, data, command_buffer, image_index)?;
+
+    // Nothing is drawn until the mesh finished uploading
+    if !draws.is_empty() && is_mesh_ready(data) {
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[data.vertex_buffer, data.frame_ring.buffer], &[0, instances.offset]);
+        device.cmd_bind_index_buffer(command_buffer, data.index_buffer, 0, vk::IndexType::UINT32);
+
+        // Every material is in the same set, draws only push the index of theirs
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipeline_layout, 0, &[data.descriptor_set, data.material_descriptor_set], dynamic_offsets);
+
+        for index in draws {
+            // Same for materials, their textures are swapped in once uploaded
+            let draw = &instances.batches[index];
+            let material = &data.materials[draw.material];
+            if !material.is_ready(&data.uploads) {
+                continue;
+            }
+
+            let pipeline = match material.shading_model {
+                ShadingModel::BlinnPhong => data.pipeline,
+                ShadingModel::Pbr => data.pbr_pipeline,
+            };
+
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+            device.cmd_push_constants(command_buffer, data.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0, &(draw.material as u32).to_ne_bytes());
+
+            // Culled on the GPU, the instance count is only known to the indirect command
+            match instances.commands {
+                Some(commands) => {
+                    let stride = size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+                    device.cmd_draw_indexed_indirect(command_buffer, data.frame_ring.buffer, commands + (index as u32 * stride) as vk::DeviceSize, 1, stride);
+                },
+                None => {
+                    let lod = data.mesh_lods[draw.lod];
+                    device.cmd_draw_indexed(command_buffer, lod.index_count, draw.instance_count, lod.first_index, 0, draw.first_instance);
+                },
+            }
+        }
+    }
+
+    if skybox {
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, data.skybox_pipeline);
+        device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipeline_layout, 0, &[data.descriptor_set], dynamic_offsets);
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+
+    if let Some(particles) = particles {
+        record_particle_draws(device, data, command_buffer, particles, dynamic_offsets);
+    }
+
+    device.end_command_buffer(command_buffer)?;
+
+    Ok(command_buffer)
+}
+
+unsafe fn begin_secondary_command_buffer(device: &Device, data: &RenderData, command_buffer: vk::CommandBuffer, image_index: usize) -> Result<()>
+{
+    let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+        .render_pass(data.graph.render_pass(FramePass::Scene)?)
+        .subpass(0)
+        .framebuffer(data.graph.framebuffer(FramePass::Scene, image_index)?);
+
+    let info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
+        .inheritance_info(&inheritance_info);
+
+    device.begin_command_buffer(command_buffer, &info)?;
+
+    Ok(())
+}
+
+pub fn is_mesh_ready(data: &RenderData) -> bool
+{
+    data.uploads.is_complete(data.vertex_upload) && data.uploads.is_complete(data.index_upload)
+}
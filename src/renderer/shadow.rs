@@ -0,0 +1,383 @@
+use anyhow::Result;
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Point3, Rad, SquareMatrix};
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
+
+use super::{descriptor::Mat4, device::get_supported_format, frame::FramePass, graph::{Attachment, ImageDescription, RenderGraph, ResourceId}, light::{Light, LightKind, Vec4, MAX_LIGHTS}, pipeline::create_shader_module, vertex::{InstanceData, Vertex}, RenderData};
+
+// Layers of the shadow map array shared by all shadow casting lights
+pub const MAX_SHADOW_MAPS: usize = 8;
+pub const MAX_SHADOW_CASCADES: usize = 4;
+
+pub const SHADOW_MAP: &str = "shadow map";
+
+// Distance the light cameras are pulled back so that casters outside the cascade still land in the map
+const SHADOW_CASTER_MARGIN: f32 = 20.0;
+
+type Vec3 = cgmath::Vector3<f32>;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowSettings
+{
+    pub resolution: u32,
+    // Number of cascades used by directional lights, up to MAX_SHADOW_CASCADES
+    pub cascade_count: u32,
+    // Blend between uniform (0.0) and logarithmic (1.0) cascade splits
+    pub cascade_split_lambda: f32,
+    // Distance from the camera covered by directional light cascades
+    pub max_distance: f32,
+    pub depth_bias_constant: f32,
+    pub depth_bias_slope: f32,
+    // World space offset along the surface normal applied before sampling
+    pub normal_bias: f32,
+    // Kernel radius in texels, 1 samples a 3x3 neighbourhood
+    pub pcf_radius: u32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self
+    {
+        Self {
+            resolution: 2048,
+            cascade_count: 4,
+            cascade_split_lambda: 0.75,
+            max_distance: 30.0,
+            depth_bias_constant: 1.25,
+            depth_bias_slope: 1.75,
+            normal_bias: 0.02,
+            pcf_radius: 1,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowBufferObject {
+    pub matrices: [Mat4; MAX_SHADOW_MAPS],
+    // View space distance to the far plane of each cascade
+    pub cascade_splits: Vec4,
+    // x = cascade count, y = pcf radius, z = normal bias, w = texel size
+    pub parameters: Vec4,
+}
+
+/*
+    Shadow map layers assigned to the lights for one frame.
+    Directional lights take one layer per cascade, spot lights take a single layer.
+ */
+#[derive(Clone, Debug)]
+pub struct ShadowFrame
+{
+    // First shadow map layer of each light, -1 if the light is unshadowed
+    pub light_layers: Vec<i32>,
+    pub matrices: Vec<Mat4>,
+    pub cascade_splits: [f32; MAX_SHADOW_CASCADES],
+}
+
+impl ShadowFrame {
+    pub fn new(lights: &[Light], settings: &ShadowSettings, view: Mat4, field_of_view: Deg<f32>, aspect: f32, near: f32) -> Self
+    {
+        let cascade_count = (settings.cascade_count as usize).clamp(1, MAX_SHADOW_CASCADES);
+        let cascade_splits = get_cascade_splits(cascade_count, settings.cascade_split_lambda, near, settings.max_distance);
+
+        let mut light_layers = vec![];
+        let mut matrices = vec![];
+
+        for light in lights.iter().take(MAX_LIGHTS) {
+            let layers = match light.kind {
+                LightKind::Directional => cascade_count,
+                LightKind::Spot => 1,
+                LightKind::Point => 0,
+            };
+
+            if !light.casts_shadow || layers == 0 || matrices.len() + layers > MAX_SHADOW_MAPS {
+                light_layers.push(-1);
+                continue;
+            }
+
+            light_layers.push(matrices.len() as i32);
+
+            match light.kind {
+                LightKind::Directional => {
+                    let mut cascade_near = near;
+                    for split in cascade_splits.iter().take(cascade_count) {
+                        matrices.push(get_cascade_matrix(light, settings, view, field_of_view, aspect, cascade_near, *split));
+                        cascade_near = *split;
+                    }
+                }
+                _ => matrices.push(get_spot_matrix(light)),
+            }
+        }
+
+        Self {light_layers, matrices, cascade_splits}
+    }
+}
+
+impl ShadowBufferObject {
+    pub fn new(frame: &ShadowFrame, settings: &ShadowSettings) -> Self
+    {
+        let mut matrices = [Mat4::identity(); MAX_SHADOW_MAPS];
+        matrices[..frame.matrices.len()].copy_from_slice(&frame.matrices);
+
+        let splits = frame.cascade_splits;
+
+        Self {
+            matrices,
+            cascade_splits: Vec4::new(splits[0], splits[1], splits[2], splits[3]),
+            parameters: Vec4::new(
+                (settings.cascade_count as usize).clamp(1, MAX_SHADOW_CASCADES) as f32,
+                settings.pcf_radius as f32,
+                settings.normal_bias,
+                1.0 / settings.resolution as f32,
+            ),
+        }
+    }
+}
+
+/*
+    cgmath uses OpenGL range of -1.0 - 1.0 while Vulkan uses 0.0 - 1.0
+    We also need to flip the Y-axis due to OpenGL coordinates
+    Lastly, this matrix is transposed because cgmath constructs in column-major order
+ */
+pub fn get_projection_correction() -> Mat4
+{
+    Mat4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, -1.0, 0.0, 0.0,
+        0.0, 0.0, 1.0/2.0, 0.0,
+        0.0, 0.0, 1.0/2.0, 1.0,
+    )
+}
+
+// Practical split scheme, blends logarithmic splits near the camera with uniform splits further away
+fn get_cascade_splits(cascade_count: usize, lambda: f32, near: f32, far: f32) -> [f32; MAX_SHADOW_CASCADES]
+{
+    let mut splits = [far; MAX_SHADOW_CASCADES];
+
+    for (i, split) in splits.iter_mut().enumerate().take(cascade_count) {
+        let p = (i + 1) as f32 / cascade_count as f32;
+        let logarithmic = near * (far / near).powf(p);
+        let uniform = near + (far - near) * p;
+        *split = lambda * logarithmic + (1.0 - lambda) * uniform;
+    }
+
+    splits
+}
+
+fn get_light_up(direction: Vec3) -> Vec3
+{
+    if direction.z.abs() > 0.99 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(0.0, 0.0, 1.0)
+    }
+}
+
+fn get_cascade_matrix(light: &Light, settings: &ShadowSettings, view: Mat4, field_of_view: Deg<f32>, aspect: f32, near: f32, far: f32) -> Mat4
+{
+    let direction = Vec3::new(light.direction.x, light.direction.y, light.direction.z).normalize();
+    let inverse_view = view.invert().unwrap_or(Mat4::identity());
+
+    // Corners of the cascade's slice of the camera frustum in world space
+    let tan_half_fov = Rad::from(field_of_view / 2.0).0.tan();
+    let mut corners = vec![];
+    for distance in [near, far] {
+        let half_height = distance * tan_half_fov;
+        let half_width = half_height * aspect;
+        for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+            let corner = inverse_view * Vec4::new(x * half_width, y * half_height, -distance, 1.0);
+            corners.push(corner.truncate());
+        }
+    }
+
+    // A bounding sphere keeps the projection size constant while the camera rotates, which avoids shimmering
+    let center = corners.iter().fold(Vec3::new(0.0, 0.0, 0.0), |sum, c| sum + c) / corners.len() as f32;
+    let radius = corners
+        .iter()
+        .map(|c| (c - center).magnitude())
+        .fold(0.0_f32, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let eye = center - direction * (radius + SHADOW_CASTER_MARGIN);
+    let light_view = Mat4::look_at_rh(
+        Point3::from_vec(eye),
+        Point3::from_vec(center),
+        get_light_up(direction),
+    );
+
+    let projection = cgmath::ortho(-radius, radius, -radius, radius, 0.0, 2.0 * radius + SHADOW_CASTER_MARGIN);
+    let mut matrix = get_projection_correction() * projection * light_view;
+
+    // Snap the projection to whole texels so that static geometry doesn't swim as the camera moves
+    let texels = settings.resolution as f32 / 2.0;
+    let origin = matrix * Vec4::new(0.0, 0.0, 0.0, 1.0);
+    let offset_x = ((origin.x * texels).round() - origin.x * texels) / texels;
+    let offset_y = ((origin.y * texels).round() - origin.y * texels) / texels;
+    matrix.w.x += offset_x;
+    matrix.w.y += offset_y;
+
+    matrix
+}
+
+fn get_spot_matrix(light: &Light) -> Mat4
+{
+    let position = Vec3::new(light.position.x, light.position.y, light.position.z);
+    let direction = Vec3::new(light.direction.x, light.direction.y, light.direction.z).normalize();
+
+    let light_view = Mat4::look_at_rh(
+        Point3::from_vec(position),
+        Point3::from_vec(position + direction),
+        get_light_up(direction),
+    );
+
+    let field_of_view = Rad((light.outer_cone * 2.0).clamp(0.01, 3.1));
+    let projection = cgmath::perspective(field_of_view, 1.0, 0.05, light.range.max(0.1));
+
+    get_projection_correction() * projection * light_view
+}
+
+unsafe fn get_shadow_format(instance: &Instance, data: &RenderData) -> Result<vk::Format>
+{
+    let candidates = &[vk::Format::D32_SFLOAT, vk::Format::D16_UNORM];
+    get_supported_format(
+        instance,
+        data,
+        candidates,
+        vk::ImageTiling::OPTIMAL,
+        vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE
+    )
+}
+
+/*
+    Every layer of the shadow map is cleared each frame so that layers not assigned to a light
+    still hold valid depth, only the assigned layers receive draws.
+ */
+pub unsafe fn add_shadow_passes(instance: &Instance, graph: &mut RenderGraph<FramePass>, data: &RenderData) -> Result<ResourceId>
+{
+    let resolution = data.shadow_settings.resolution;
+    let extent = vk::Extent2D{width: resolution, height: resolution};
+    let description = ImageDescription::new(get_shadow_format(instance, data)?, extent)
+        .with_layers(MAX_SHADOW_MAPS as u32);
+
+    let shadow_map = graph.create_image(SHADOW_MAP, description);
+
+    for layer in 0..MAX_SHADOW_MAPS {
+        graph.add_pass(FramePass::Shadow(layer))
+            .depth(Attachment::clear_depth(shadow_map, 1.0).with_layer(layer as u32));
+    }
+
+    Ok(shadow_map)
+}
+
+pub unsafe fn create_shadow_pipeline(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let vert = include_bytes!("../../shaders/shadow_vert.spv");
+    let vert_shader_module = create_shader_module(device, &vert[..])?;
+
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+
+    // Only the position and the model matrix columns are read
+    let binding_descriptions = &[Vertex::binding_description(), InstanceData::binding_description()];
+    let instance_attributes = InstanceData::attribute_descriptions();
+    let attribute_descriptions = &[
+        Vertex::attribute_descriptions()[0],
+        instance_attributes[0],
+        instance_attributes[1],
+        instance_attributes[2],
+        instance_attributes[3],
+    ];
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_binding_descriptions(binding_descriptions)
+        .vertex_attribute_descriptions(attribute_descriptions);
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+        .primitive_restart_enable(false);
+
+    let resolution = data.shadow_settings.resolution;
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(resolution as f32)
+        .height(resolution as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D{x:0, y:0})
+        .extent(vk::Extent2D{width: resolution, height: resolution});
+
+    let viewports = &[viewport];
+    let scissors = &[scissor];
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewports(viewports)
+        .scissors(scissors);
+
+    // Geometry is not guaranteed to be closed, so both faces cast shadows
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .line_width(1.0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(true);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(false)
+        .rasterization_samples(vk::SampleCountFlags::_1);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(true)
+        .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
+        .depth_bounds_test_enable(false)
+        .min_depth_bounds(0.0)
+        .max_depth_bounds(1.0)
+        .stencil_test_enable(false);
+
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(&[] as &[vk::PipelineColorBlendAttachmentState])
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    // Bias is dynamic so it can be tuned without rebuilding the pipeline
+    let dynamic_states = &[vk::DynamicState::DEPTH_BIAS];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(dynamic_states);
+
+    // Light view-projection, model matrices come from the instance buffer
+    let push_constant_range = vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::VERTEX)
+        .offset(0)
+        .size(64);
+
+    let push_constant_ranges = &[push_constant_range];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .push_constant_ranges(push_constant_ranges);
+
+    data.shadow_pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
+
+    let stages = &[vert_stage];
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(data.shadow_pipeline_layout)
+        .render_pass(data.graph.render_pass(FramePass::Shadow(0))?)
+        .subpass(0);
+
+    data.shadow_pipeline = device.create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?.0[0];
+
+    device.destroy_shader_module(vert_shader_module, None);
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder, KhrSurfaceExtension, KhrSwapchainExtension}, Device, Instance};
+use vulkanalia::{vk::{self, Handle, HasBuilder, KhrSurfaceExtension, KhrSwapchainExtension}, Device, Instance};
 use winit::window::Window;
 use super::{device::QueueFamilyIndices, image::create_image_view, RenderData};
 
@@ -24,13 +24,16 @@ impl SwapchainSupport {
     }
 }
 
+// Gamma is applied by the tonemapping pass, so a UNORM format is preferred over an sRGB one
 fn get_swapchain_surface_format(formats: &[vk::SurfaceFormatKHR]) -> vk::SurfaceFormatKHR
 {
-    formats
+    [vk::Format::B8G8R8A8_UNORM, vk::Format::B8G8R8A8_SRGB]
         .iter()
-        .cloned()
-        .find(|f|{
-            f.format == vk::Format::B8G8R8A8_SRGB && f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+        .find_map(|format| {
+            formats
+                .iter()
+                .cloned()
+                .find(|f| f.format == *format && f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR)
         })
         .unwrap_or_else(|| formats[0])
 }
@@ -130,25 +133,3 @@ pub unsafe fn create_swapchain_image_views(device: &Device, data: &mut RenderDat
     Ok(())
 }
 
-pub unsafe fn create_framebuffers(device: &Device, data: &mut RenderData) ->Result<()>
-{
-    data.framebuffers = data
-        .swapchain_image_views
-        .iter()
-        .map(|i| {
-            let attachments = &[data.color_image_view, data.depth_image_view, *i];
-            let create_info = vk::FramebufferCreateInfo::builder()
-                .render_pass(data.render_pass)
-                .attachments(attachments)
-                .width(data.swapchain_extent.width)
-                .height(data.swapchain_extent.height)
-                .layers(1);
-
-            device.create_framebuffer(&create_info, None)
-
-        })
-        .collect::<Result<Vec<_>, _>>()?;
-
-    Ok(())
-}
-
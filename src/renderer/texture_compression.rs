@@ -0,0 +1,1085 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{vk, Instance};
+
+use super::{device::get_supported_format, image::DecodedImage, RenderData};
+
+// Texels per block edge, every BC format encodes 4x4 texels
+const BLOCK_EDGE: u32 = 4;
+
+// Interpolation weights out of 64 for each index size
+const WEIGHTS_2: [i32; 4] = [0, 21, 43, 64];
+const WEIGHTS_3: [i32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const WEIGHTS_4: [i32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BlockFormat
+{
+    // Opaque, the fourth colour of the three colour mode is black
+    Bc1Rgb = 1,
+    // The fourth colour of the three colour mode is transparent
+    Bc1Rgba = 2,
+    Bc2 = 3,
+    Bc3 = 4,
+    Bc4 = 5,
+    Bc5 = 6,
+    Bc6h = 7,
+    Bc6hSigned = 8,
+    Bc7 = 9,
+}
+
+impl BlockFormat {
+    pub const ALL: [BlockFormat; 9] = [
+        BlockFormat::Bc1Rgb,
+        BlockFormat::Bc1Rgba,
+        BlockFormat::Bc2,
+        BlockFormat::Bc3,
+        BlockFormat::Bc4,
+        BlockFormat::Bc5,
+        BlockFormat::Bc6h,
+        BlockFormat::Bc6hSigned,
+        BlockFormat::Bc7,
+    ];
+
+    pub fn from_raw(value: u32) -> Result<Self>
+    {
+        Self::ALL
+            .into_iter()
+            .find(|f| *f as u32 == value)
+            .ok_or_else(|| anyhow!("Unknown block format {}.", value))
+    }
+
+    pub fn block_size(self) -> usize
+    {
+        match self {
+            BlockFormat::Bc1Rgb | BlockFormat::Bc1Rgba | BlockFormat::Bc4 => 8,
+            _ => 16,
+        }
+    }
+
+    // Formats without an sRGB variant hold linear data no matter how they are used
+    pub fn format(self, srgb: bool) -> vk::Format
+    {
+        match (self, srgb) {
+            (BlockFormat::Bc1Rgb, false) => vk::Format::BC1_RGB_UNORM_BLOCK,
+            (BlockFormat::Bc1Rgb, true) => vk::Format::BC1_RGB_SRGB_BLOCK,
+            (BlockFormat::Bc1Rgba, false) => vk::Format::BC1_RGBA_UNORM_BLOCK,
+            (BlockFormat::Bc1Rgba, true) => vk::Format::BC1_RGBA_SRGB_BLOCK,
+            (BlockFormat::Bc2, false) => vk::Format::BC2_UNORM_BLOCK,
+            (BlockFormat::Bc2, true) => vk::Format::BC2_SRGB_BLOCK,
+            (BlockFormat::Bc3, false) => vk::Format::BC3_UNORM_BLOCK,
+            (BlockFormat::Bc3, true) => vk::Format::BC3_SRGB_BLOCK,
+            (BlockFormat::Bc4, _) => vk::Format::BC4_UNORM_BLOCK,
+            (BlockFormat::Bc5, _) => vk::Format::BC5_UNORM_BLOCK,
+            (BlockFormat::Bc6h, _) => vk::Format::BC6H_UFLOAT_BLOCK,
+            (BlockFormat::Bc6hSigned, _) => vk::Format::BC6H_SFLOAT_BLOCK,
+            (BlockFormat::Bc7, false) => vk::Format::BC7_UNORM_BLOCK,
+            (BlockFormat::Bc7, true) => vk::Format::BC7_SRGB_BLOCK,
+        }
+    }
+}
+
+// Bytes taken by one level, partial blocks at the edges are stored whole
+pub fn get_level_size(compression: Option<BlockFormat>, width: u32, height: u32) -> usize
+{
+    match compression {
+        Some(format) => (width.div_ceil(BLOCK_EDGE) * height.div_ceil(BLOCK_EDGE)) as usize * format.block_size(),
+        None => 4 * (width * height) as usize,
+    }
+}
+
+// Block compressed formats the device can sample and filter, queried like `get_supported_format` does
+pub unsafe fn get_supported_block_formats(instance: &Instance, data: &RenderData) -> HashSet<vk::Format>
+{
+    BlockFormat::ALL
+        .iter()
+        .flat_map(|f| [f.format(false), f.format(true)])
+        .filter(|f| get_supported_format(
+            instance,
+            data,
+            &[*f],
+            vk::ImageTiling::OPTIMAL,
+            vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR
+        ).is_ok())
+        .collect()
+}
+
+// Format the image is created with when its texels are meant to be read as `format`
+pub fn get_image_format(image: &DecodedImage, format: vk::Format) -> vk::Format
+{
+    match image.compression {
+        Some(compression) => compression.format(format == vk::Format::R8G8B8A8_SRGB),
+        None => format,
+    }
+}
+
+// Leaves the image as it is unless the device can't sample its block format, only touches memory so it can run on a worker thread
+pub fn decompress_unsupported(image: DecodedImage, format: vk::Format, supported: &HashSet<vk::Format>) -> DecodedImage
+{
+    if image.compression.is_none() || supported.contains(&get_image_format(&image, format)) {
+        return image;
+    }
+
+    decompress_image(&image, format == vk::Format::R8G8B8A8_SRGB)
+}
+
+/*
+    Decodes every level into eight bit RGBA texels. BC6H is clamped to [0, 1] and encoded as sRGB
+    for colour textures, since the result is sampled from the same formats as decoded PNGs.
+ */
+pub fn decompress_image(image: &DecodedImage, srgb: bool) -> DecodedImage
+{
+    let Some(format) = image.compression else {
+        return image.clone();
+    };
+
+    let mut pixels = vec![];
+
+    for (level, offset) in image.level_offsets().into_iter().enumerate() {
+        let (width, height) = ((image.width >> level).max(1), (image.height >> level).max(1));
+        let blocks_wide = width.div_ceil(BLOCK_EDGE);
+        let start = pixels.len();
+        pixels.resize(start + 4 * (width * height) as usize, 0);
+
+        let size = get_level_size(Some(format), width, height);
+        let blocks = image.pixels[offset as usize..offset as usize + size].chunks_exact(format.block_size());
+
+        for (index, block) in blocks.enumerate() {
+            let (block_x, block_y) = (index as u32 % blocks_wide, index as u32 / blocks_wide);
+            let texels = decompress_block(format, block, srgb);
+
+            for (texel, value) in texels.iter().enumerate() {
+                let (x, y) = (BLOCK_EDGE * block_x + texel as u32 % 4, BLOCK_EDGE * block_y + texel as u32 / 4);
+                if x < width && y < height {
+                    let offset = start + 4 * (y * width + x) as usize;
+                    pixels[offset..offset + 4].copy_from_slice(value);
+                }
+            }
+        }
+    }
+
+    DecodedImage {width: image.width, height: image.height, mip_levels: image.mip_levels, compression: None, pixels}
+}
+
+// Texels in rows from the top left
+pub fn decompress_block(format: BlockFormat, block: &[u8], srgb: bool) -> [[u8; 4]; 16]
+{
+    match format {
+        BlockFormat::Bc1Rgb => decode_color_block(block, true, true),
+        BlockFormat::Bc1Rgba => decode_color_block(block, true, false),
+        BlockFormat::Bc2 => {
+            let mut texels = decode_color_block(&block[8..], false, true);
+            for (texel, value) in texels.iter_mut().enumerate() {
+                let alpha = (block[texel / 2] >> (4 * (texel % 2))) & 0xF;
+                value[3] = alpha * 17;
+            }
+
+            texels
+        },
+        BlockFormat::Bc3 => {
+            let mut texels = decode_color_block(&block[8..], false, true);
+            for (value, alpha) in texels.iter_mut().zip(decode_value_block(&block[..8])) {
+                value[3] = alpha;
+            }
+
+            texels
+        },
+        BlockFormat::Bc4 => decode_value_block(block).map(|r| [r, 0, 0, 255]),
+        BlockFormat::Bc5 => {
+            let (red, green) = (decode_value_block(&block[..8]), decode_value_block(&block[8..]));
+            std::array::from_fn(|texel| [red[texel], green[texel], 0, 255])
+        },
+        BlockFormat::Bc6h | BlockFormat::Bc6hSigned => {
+            decode_bc6h(block, format == BlockFormat::Bc6hSigned).map(|[r, g, b]| {
+                let to_byte = |value: f32| {
+                    let value = value.clamp(0.0, 1.0);
+                    let value = if !srgb {value} else if value > 0.0031308 {1.055 * value.powf(1.0 / 2.4) - 0.055} else {value * 12.92};
+                    (value * 255.0).round() as u8
+                };
+
+                [to_byte(r), to_byte(g), to_byte(b), 255]
+            })
+        },
+        BlockFormat::Bc7 => decode_bc7(block),
+    }
+}
+
+/*
+    Encodes every level of an uncompressed image into blocks of `format`, texels past the edge
+    of a level repeat its last row and column. Only BC1, BC3, BC4 and BC5 can be encoded, each
+    block's endpoints are the extremes of its texels, which is quick rather than optimal.
+ */
+pub fn compress_image(image: &DecodedImage, format: BlockFormat) -> Result<DecodedImage>
+{
+    if image.compression.is_some() {
+        return Err(anyhow!("Image is block compressed already."));
+    }
+
+    let mut pixels = vec![];
+
+    for (level, offset) in image.level_offsets().into_iter().enumerate() {
+        let (width, height) = ((image.width >> level).max(1), (image.height >> level).max(1));
+        let level_pixels = &image.pixels[offset as usize..];
+
+        for block_y in 0..height.div_ceil(BLOCK_EDGE) {
+            for block_x in 0..width.div_ceil(BLOCK_EDGE) {
+                let texels = std::array::from_fn(|texel| {
+                    let x = (BLOCK_EDGE * block_x + texel as u32 % 4).min(width - 1);
+                    let y = (BLOCK_EDGE * block_y + texel as u32 / 4).min(height - 1);
+                    let offset = 4 * (y * width + x) as usize;
+                    level_pixels[offset..offset + 4].try_into().unwrap()
+                });
+
+                pixels.extend(compress_block(format, &texels)?);
+            }
+        }
+    }
+
+    Ok(DecodedImage {width: image.width, height: image.height, mip_levels: image.mip_levels, compression: Some(format), pixels})
+}
+
+// Texels in rows from the top left, like `decompress_block` returns them
+pub fn compress_block(format: BlockFormat, texels: &[[u8; 4]; 16]) -> Result<Vec<u8>>
+{
+    let channel = |c: usize| texels.map(|t| t[c]);
+
+    match format {
+        BlockFormat::Bc1Rgb => Ok(encode_color_block(texels).to_vec()),
+        BlockFormat::Bc3 => Ok([encode_value_block(&channel(3)), encode_color_block(texels)].concat()),
+        BlockFormat::Bc4 => Ok(encode_value_block(&channel(0)).to_vec()),
+        BlockFormat::Bc5 => Ok([encode_value_block(&channel(0)), encode_value_block(&channel(1))].concat()),
+        _ => Err(anyhow!("Encoding {:?} is not supported.", format)),
+    }
+}
+
+// Endpoints at the extremes of the colours along their principal axis, always in four colour mode
+fn encode_color_block(texels: &[[u8; 4]; 16]) -> [u8; 8]
+{
+    let colors = texels.map(|t| [t[0] as f32, t[1] as f32, t[2] as f32]);
+    let mean: [f32; 3] = std::array::from_fn(|c| colors.iter().map(|t| t[c]).sum::<f32>() / 16.0);
+
+    let mut covariance = [[0.0; 3]; 3];
+    for color in &colors {
+        for (i, row) in covariance.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value += (color[i] - mean[i]) * (color[j] - mean[j]);
+            }
+        }
+    }
+
+    // A few rounds of power iteration are plenty to find the axis the colours spread along
+    let mut axis = [1.0, 1.0, 1.0];
+    for _ in 0..8 {
+        let next: [f32; 3] = std::array::from_fn(|i| (0..3).map(|j| covariance[i][j] * axis[j]).sum());
+        let length = next.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if length < 1e-6 {
+            break;
+        }
+
+        axis = next.map(|v| v / length);
+    }
+
+    let project = |color: &[f32; 3]| (0..3).map(|c| (color[c] - mean[c]) * axis[c]).sum::<f32>();
+    let (low, high) = colors.iter().map(project).fold((f32::MAX, f32::MIN), |(low, high), p| (low.min(p), high.max(p)));
+
+    let to_565 = |t: f32| {
+        let channel = |c: usize, max: f32| ((mean[c] + axis[c] * t).clamp(0.0, 255.0) * max / 255.0).round() as u16;
+        (channel(0, 31.0) << 11) | (channel(1, 63.0) << 5) | channel(2, 31.0)
+    };
+
+    let (mut color0, mut color1) = (to_565(high), to_565(low));
+    if color0 < color1 {
+        (color0, color1) = (color1, color0);
+    }
+
+    let mut block = [0; 8];
+    block[0..2].copy_from_slice(&color0.to_le_bytes());
+    block[2..4].copy_from_slice(&color1.to_le_bytes());
+
+    // The first four texels of a block indexing 0 to 3 decode to the palette exactly as the GPU builds it
+    block[4] = 0b11_10_01_00;
+    let palette = decode_color_block(&block, false, true);
+
+    let indices = texels.iter().enumerate().fold(0u32, |indices, (texel, value)| {
+        let distance = |color: &[u8; 4]| (0..3).map(|c| (color[c] as i32 - value[c] as i32).pow(2)).sum::<i32>();
+        let index = (0..4).min_by_key(|&i| distance(&palette[i])).unwrap();
+        indices | (index as u32) << (2 * texel)
+    });
+
+    block[4..8].copy_from_slice(&indices.to_le_bytes());
+    block
+}
+
+// Endpoints at the smallest and largest value, in the mode that interpolates six values between them
+fn encode_value_block(values: &[u8; 16]) -> [u8; 8]
+{
+    let (low, high) = (*values.iter().min().unwrap(), *values.iter().max().unwrap());
+
+    // The first eight values of a block indexing 0 to 7 decode to the palette, like for colour blocks
+    let mut block = [high, low, 0x88, 0xC6, 0xFA, 0, 0, 0];
+    let palette = decode_value_block(&block);
+
+    let indices = values.iter().enumerate().fold(0u64, |indices, (texel, value)| {
+        let index = (0..8).min_by_key(|&i| (palette[i] as i32 - *value as i32).abs()).unwrap();
+        indices | (index as u64) << (3 * texel)
+    });
+
+    block[2..8].copy_from_slice(&indices.to_le_bytes()[..6]);
+    block
+}
+
+// Reads fields from the least significant bit of a block on
+struct BitReader
+{
+    bits: u128,
+}
+
+impl BitReader {
+    fn new(block: &[u8]) -> Self
+    {
+        Self {bits: u128::from_le_bytes(block[..16].try_into().unwrap())}
+    }
+
+    fn read(&mut self, count: u32) -> u32
+    {
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        value
+    }
+}
+
+// Two RGB565 endpoints followed by two bit indices, shared by BC1, BC2 and BC3
+fn decode_color_block(block: &[u8], punch_through: bool, opaque: bool) -> [[u8; 4]; 16]
+{
+    let color0 = u16::from_le_bytes([block[0], block[1]]);
+    let color1 = u16::from_le_bytes([block[2], block[3]]);
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+
+    let expand = |color: u16| {
+        let (r, g, b) = ((color >> 11) as u32, ((color >> 5) & 0x3F) as u32, (color & 0x1F) as u32);
+        [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+    };
+
+    let (a, b) = (expand(color0), expand(color1));
+    let mix = |wa: u32, wb: u32| {
+        let [r, g, bl] = std::array::from_fn(|c| ((wa * a[c] + wb * b[c]) / (wa + wb)) as u8);
+        [r, g, bl, 255]
+    };
+
+    // BC2 and BC3 always use four colours, BC1 switches to three and a transparent black when the endpoints aren't ordered
+    let palette = if !punch_through || color0 > color1 {
+        [mix(1, 0), mix(0, 1), mix(2, 1), mix(1, 2)]
+    } else {
+        [mix(1, 0), mix(0, 1), mix(1, 1), [0, 0, 0, if opaque {255} else {0}]]
+    };
+
+    std::array::from_fn(|texel| palette[((indices >> (2 * texel)) & 3) as usize])
+}
+
+// Two eight bit endpoints followed by three bit indices, used by BC3 alpha, BC4 and BC5
+fn decode_value_block(block: &[u8]) -> [u8; 16]
+{
+    let (a, b) = (block[0] as u32, block[1] as u32);
+    let indices = block[2..8].iter().rev().fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+
+    let palette: [u8; 8] = if a > b {
+        std::array::from_fn(|i| match i {
+            0 => a as u8,
+            1 => b as u8,
+            _ => (((8 - i as u32) * a + (i as u32 - 1) * b) / 7) as u8,
+        })
+    } else {
+        std::array::from_fn(|i| match i {
+            0 => a as u8,
+            1 => b as u8,
+            6 => 0,
+            7 => 255,
+            _ => (((6 - i as u32) * a + (i as u32 - 1) * b) / 5) as u8,
+        })
+    };
+
+    std::array::from_fn(|texel| palette[((indices >> (3 * texel)) & 7) as usize])
+}
+
+fn interpolate(a: i32, b: i32, weight: i32) -> i32
+{
+    ((64 - weight) * a + weight * b + 32) >> 6
+}
+
+fn get_weights(index_bits: u32) -> &'static [i32]
+{
+    match index_bits {
+        2 => &WEIGHTS_2,
+        3 => &WEIGHTS_3,
+        _ => &WEIGHTS_4,
+    }
+}
+
+// Subset of each texel, one bit per texel for two subsets
+fn get_subset(subsets: u32, partition: usize, texel: usize) -> usize
+{
+    match subsets {
+        1 => 0,
+        2 => ((PARTITIONS_2[partition] >> texel) & 1) as usize,
+        _ => PARTITIONS_3[partition][texel] as usize,
+    }
+}
+
+// The first index of every subset has its top bit left out, it is always zero
+fn is_anchor(subsets: u32, partition: usize, texel: usize) -> bool
+{
+    texel == 0 || match subsets {
+        2 => texel == ANCHORS_2[partition] as usize,
+        3 => texel == ANCHORS_3_SECOND[partition] as usize || texel == ANCHORS_3_THIRD[partition] as usize,
+        _ => false,
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Bc7Mode
+{
+    subsets: u32,
+    partition_bits: u32,
+    rotation_bits: u32,
+    selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    // One bit below every endpoint, or one shared by both endpoints of a subset
+    endpoint_p_bits: bool,
+    shared_p_bits: bool,
+    index_bits: u32,
+    secondary_index_bits: u32,
+}
+
+const BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode {subsets: 3, partition_bits: 4, rotation_bits: 0, selection_bits: 0, color_bits: 4, alpha_bits: 0, endpoint_p_bits: true, shared_p_bits: false, index_bits: 3, secondary_index_bits: 0},
+    Bc7Mode {subsets: 2, partition_bits: 6, rotation_bits: 0, selection_bits: 0, color_bits: 6, alpha_bits: 0, endpoint_p_bits: false, shared_p_bits: true, index_bits: 3, secondary_index_bits: 0},
+    Bc7Mode {subsets: 3, partition_bits: 6, rotation_bits: 0, selection_bits: 0, color_bits: 5, alpha_bits: 0, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, secondary_index_bits: 0},
+    Bc7Mode {subsets: 2, partition_bits: 6, rotation_bits: 0, selection_bits: 0, color_bits: 7, alpha_bits: 0, endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, secondary_index_bits: 0},
+    Bc7Mode {subsets: 1, partition_bits: 0, rotation_bits: 2, selection_bits: 1, color_bits: 5, alpha_bits: 6, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, secondary_index_bits: 3},
+    Bc7Mode {subsets: 1, partition_bits: 0, rotation_bits: 2, selection_bits: 0, color_bits: 7, alpha_bits: 8, endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, secondary_index_bits: 2},
+    Bc7Mode {subsets: 1, partition_bits: 0, rotation_bits: 0, selection_bits: 0, color_bits: 7, alpha_bits: 7, endpoint_p_bits: true, shared_p_bits: false, index_bits: 4, secondary_index_bits: 0},
+    Bc7Mode {subsets: 2, partition_bits: 6, rotation_bits: 0, selection_bits: 0, color_bits: 5, alpha_bits: 5, endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, secondary_index_bits: 0},
+];
+
+/*
+    The mode is the position of the first set bit, it decides how many subsets the block is
+    split into, how precise the endpoints are and whether alpha has indices of its own.
+    Blocks without a mode bit are reserved and decode to transparent black.
+ */
+fn decode_bc7(block: &[u8]) -> [[u8; 4]; 16]
+{
+    let mut bits = BitReader::new(block);
+    let Some(mode) = (0..8).find(|_| bits.read(1) == 1) else {
+        return [[0; 4]; 16];
+    };
+
+    let mode = BC7_MODES[mode];
+    let partition = bits.read(mode.partition_bits) as usize;
+    let rotation = bits.read(mode.rotation_bits);
+    let selection = bits.read(mode.selection_bits);
+
+    // Subset, endpoint, channel
+    let mut endpoints = [[[0u32; 4]; 2]; 3];
+    let channels = if mode.alpha_bits > 0 {4} else {3};
+
+    let used = mode.subsets as usize;
+
+    for channel in 0..channels {
+        let channel_bits = if channel == 3 {mode.alpha_bits} else {mode.color_bits};
+        for endpoint in endpoints.iter_mut().take(used).flatten() {
+            endpoint[channel] = bits.read(channel_bits);
+        }
+    }
+
+    let mut precision = [mode.color_bits, mode.color_bits, mode.color_bits, mode.alpha_bits];
+    if mode.endpoint_p_bits || mode.shared_p_bits {
+        for subset in endpoints.iter_mut().take(used) {
+            let shared = if mode.shared_p_bits {Some(bits.read(1))} else {None};
+            for endpoint in subset.iter_mut() {
+                let p = shared.unwrap_or_else(|| bits.read(1));
+                endpoint.iter_mut().take(channels).for_each(|value| *value = (*value << 1) | p);
+            }
+        }
+
+        precision.iter_mut().take(channels).for_each(|p| *p += 1);
+    }
+
+    // Bits are repeated from the top into the low bits so that the extremes map to 0 and 255
+    for subset in endpoints.iter_mut() {
+        for endpoint in subset.iter_mut() {
+            for channel in 0..4 {
+                endpoint[channel] = if channel >= channels {
+                    255
+                } else {
+                    let value = endpoint[channel] << (8 - precision[channel]);
+                    value | (value >> precision[channel])
+                };
+            }
+        }
+    }
+
+    let subsets = mode.subsets;
+    let primary: [u32; 16] = std::array::from_fn(|texel| {
+        bits.read(mode.index_bits - is_anchor(subsets, partition, texel) as u32)
+    });
+
+    let secondary: [u32; 16] = std::array::from_fn(|texel| {
+        if mode.secondary_index_bits == 0 {0} else {bits.read(mode.secondary_index_bits - (texel == 0) as u32)}
+    });
+
+    // Mode 4 can swap which index set is used for colour, modes 4 and 5 read alpha from the second set
+    let ((color_indices, color_bits), (alpha_indices, alpha_bits)) = match (mode.secondary_index_bits, selection) {
+        (0, _) => ((primary, mode.index_bits), (primary, mode.index_bits)),
+        (_, 0) => ((primary, mode.index_bits), (secondary, mode.secondary_index_bits)),
+        _ => ((secondary, mode.secondary_index_bits), (primary, mode.index_bits)),
+    };
+
+    std::array::from_fn(|texel| {
+        let [a, b] = endpoints[get_subset(subsets, partition, texel)];
+        let color_weight = get_weights(color_bits)[color_indices[texel] as usize];
+        let alpha_weight = get_weights(alpha_bits)[alpha_indices[texel] as usize];
+
+        let mut value: [u8; 4] = std::array::from_fn(|channel| {
+            let weight = if channel == 3 {alpha_weight} else {color_weight};
+            interpolate(a[channel] as i32, b[channel] as i32, weight) as u8
+        });
+
+        if rotation > 0 {
+            value.swap(3, rotation as usize - 1);
+        }
+
+        value
+    })
+}
+
+// Fields of the BC6H endpoints, red, green then blue for each of the four endpoints, then the partition
+const RW: usize = 0;
+const RX: usize = 1;
+const RY: usize = 2;
+const RZ: usize = 3;
+const GW: usize = 4;
+const GX: usize = 5;
+const GY: usize = 6;
+const GZ: usize = 7;
+const BW: usize = 8;
+const BX: usize = 9;
+const BY: usize = 10;
+const BZ: usize = 11;
+const D: usize = 12;
+
+#[derive(Copy, Clone, Debug)]
+struct Bc6hMode
+{
+    code: u32,
+    // Endpoints other than the first are stored as differences from it
+    transformed: bool,
+    endpoint_bits: u32,
+    delta_bits: [u32; 3],
+    // Field and bit range in the order they are stored, `(field, a, b)` is read from bit `b` towards bit `a`
+    layout: &'static [(usize, u32, u32)],
+}
+
+const BC6H_MODES: [Bc6hMode; 14] = [
+    Bc6hMode {code: 0x00, transformed: true, endpoint_bits: 10, delta_bits: [5, 5, 5], layout: &[
+        (GY, 4, 4), (BY, 4, 4), (BZ, 4, 4), (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 4, 0), (GZ, 4, 4), (GY, 3, 0), (GX, 4, 0),
+        (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x01, transformed: true, endpoint_bits: 7, delta_bits: [6, 6, 6], layout: &[
+        (GY, 5, 5), (GZ, 4, 4), (GZ, 5, 5), (RW, 6, 0), (BZ, 0, 0), (BZ, 1, 1), (BY, 4, 4), (GW, 6, 0), (BY, 5, 5), (BZ, 2, 2),
+        (GY, 4, 4), (BW, 6, 0), (BZ, 3, 3), (BZ, 5, 5), (BZ, 4, 4), (RX, 5, 0), (GY, 3, 0), (GX, 5, 0), (GZ, 3, 0), (BX, 5, 0),
+        (BY, 3, 0), (RY, 5, 0), (RZ, 5, 0), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x02, transformed: true, endpoint_bits: 11, delta_bits: [5, 4, 4], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 4, 0), (RW, 10, 10), (GY, 3, 0), (GX, 3, 0), (GW, 10, 10), (BZ, 0, 0), (GZ, 3, 0),
+        (BX, 3, 0), (BW, 10, 10), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x06, transformed: true, endpoint_bits: 11, delta_bits: [4, 5, 4], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 3, 0), (RW, 10, 10), (GZ, 4, 4), (GY, 3, 0), (GX, 4, 0), (GW, 10, 10), (GZ, 3, 0),
+        (BX, 3, 0), (BW, 10, 10), (BZ, 1, 1), (BY, 3, 0), (RY, 3, 0), (BZ, 0, 0), (BZ, 2, 2), (RZ, 3, 0), (GY, 4, 4), (BZ, 3, 3),
+        (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x0A, transformed: true, endpoint_bits: 11, delta_bits: [4, 4, 5], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 3, 0), (RW, 10, 10), (BY, 4, 4), (GY, 3, 0), (GX, 3, 0), (GW, 10, 10), (BZ, 0, 0),
+        (GZ, 3, 0), (BX, 4, 0), (BW, 10, 10), (BY, 3, 0), (RY, 3, 0), (BZ, 1, 1), (BZ, 2, 2), (RZ, 3, 0), (BZ, 4, 4), (BZ, 3, 3),
+        (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x0E, transformed: true, endpoint_bits: 9, delta_bits: [5, 5, 5], layout: &[
+        (RW, 8, 0), (BY, 4, 4), (GW, 8, 0), (GY, 4, 4), (BW, 8, 0), (BZ, 4, 4), (RX, 4, 0), (GZ, 4, 4), (GY, 3, 0), (GX, 4, 0),
+        (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0), (BZ, 3, 3), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x12, transformed: true, endpoint_bits: 8, delta_bits: [6, 5, 5], layout: &[
+        (RW, 7, 0), (GZ, 4, 4), (BY, 4, 4), (GW, 7, 0), (BZ, 2, 2), (GY, 4, 4), (BW, 7, 0), (BZ, 3, 3), (BZ, 4, 4), (RX, 5, 0),
+        (GY, 3, 0), (GX, 4, 0), (BZ, 0, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 5, 0), (RZ, 5, 0), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x16, transformed: true, endpoint_bits: 8, delta_bits: [5, 6, 5], layout: &[
+        (RW, 7, 0), (BZ, 0, 0), (BY, 4, 4), (GW, 7, 0), (GY, 5, 5), (GY, 4, 4), (BW, 7, 0), (GZ, 5, 5), (BZ, 4, 4), (RX, 4, 0),
+        (GZ, 4, 4), (GY, 3, 0), (GX, 5, 0), (GZ, 3, 0), (BX, 4, 0), (BZ, 1, 1), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0),
+        (BZ, 3, 3), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x1A, transformed: true, endpoint_bits: 8, delta_bits: [5, 5, 6], layout: &[
+        (RW, 7, 0), (BZ, 1, 1), (BY, 4, 4), (GW, 7, 0), (BY, 5, 5), (GY, 4, 4), (BW, 7, 0), (BZ, 5, 5), (BZ, 4, 4), (RX, 4, 0),
+        (GZ, 4, 4), (GY, 3, 0), (GX, 4, 0), (BZ, 0, 0), (GZ, 3, 0), (BX, 5, 0), (BY, 3, 0), (RY, 4, 0), (BZ, 2, 2), (RZ, 4, 0),
+        (BZ, 3, 3), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x1E, transformed: false, endpoint_bits: 6, delta_bits: [6, 6, 6], layout: &[
+        (RW, 5, 0), (GZ, 4, 4), (BZ, 0, 0), (BZ, 1, 1), (BY, 4, 4), (GW, 5, 0), (GY, 5, 5), (BY, 5, 5), (BZ, 2, 2), (GY, 4, 4),
+        (BW, 5, 0), (GZ, 5, 5), (BZ, 3, 3), (BZ, 5, 5), (BZ, 4, 4), (RX, 5, 0), (GY, 3, 0), (GX, 5, 0), (GZ, 3, 0), (BX, 5, 0),
+        (BY, 3, 0), (RY, 5, 0), (RZ, 5, 0), (D, 4, 0),
+    ]},
+    Bc6hMode {code: 0x03, transformed: false, endpoint_bits: 10, delta_bits: [10, 10, 10], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 9, 0), (GX, 9, 0), (BX, 9, 0),
+    ]},
+    Bc6hMode {code: 0x07, transformed: true, endpoint_bits: 11, delta_bits: [9, 9, 9], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 8, 0), (RW, 10, 10), (GX, 8, 0), (GW, 10, 10), (BX, 8, 0), (BW, 10, 10),
+    ]},
+    Bc6hMode {code: 0x0B, transformed: true, endpoint_bits: 12, delta_bits: [8, 8, 8], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 7, 0), (RW, 10, 11), (GX, 7, 0), (GW, 10, 11), (BX, 7, 0), (BW, 10, 11),
+    ]},
+    Bc6hMode {code: 0x0F, transformed: true, endpoint_bits: 16, delta_bits: [4, 4, 4], layout: &[
+        (RW, 9, 0), (GW, 9, 0), (BW, 9, 0), (RX, 3, 0), (RW, 10, 15), (GX, 3, 0), (GW, 10, 15), (BX, 3, 0), (BW, 10, 15),
+    ]},
+];
+
+fn sign_extend(value: i32, bits: u32) -> i32
+{
+    (value << (32 - bits)) >> (32 - bits)
+}
+
+// Spreads an endpoint over the full 16 bit range before interpolating
+fn unquantize(value: i32, bits: u32, signed: bool) -> i32
+{
+    if !signed {
+        return match value {
+            _ if bits >= 15 => value,
+            0 => 0,
+            _ if value == (1 << bits) - 1 => 0xFFFF,
+            _ => ((value << 16) + 0x8000) >> bits,
+        };
+    }
+
+    if bits >= 16 {
+        return value;
+    }
+
+    let magnitude = value.abs();
+    let unquantized = if magnitude == 0 {
+        0
+    } else if magnitude >= (1 << (bits - 1)) - 1 {
+        0x7FFF
+    } else {
+        ((magnitude << 15) + 0x4000) >> (bits - 1)
+    };
+
+    if value < 0 {-unquantized} else {unquantized}
+}
+
+fn half_to_f32(half: u16) -> f32
+{
+    let sign = if half & 0x8000 != 0 {-1.0} else {1.0};
+    let exponent = ((half >> 10) & 0x1F) as i32;
+    let mantissa = (half & 0x3FF) as f32;
+
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/*
+    Half float RGB with one or two regions. Endpoint fields are scattered over the block in a
+    different order for every mode, see `BC6H_MODES`. Reserved modes decode to black.
+ */
+fn decode_bc6h(block: &[u8], signed: bool) -> [[f32; 3]; 16]
+{
+    let mut bits = BitReader::new(block);
+    let mut code = bits.read(2);
+    if code > 1 {
+        code |= bits.read(3) << 2;
+    }
+
+    let Some(mode) = BC6H_MODES.iter().find(|m| m.code == code) else {
+        return [[0.0; 3]; 16];
+    };
+
+    let mut fields = [0i32; 13];
+    for &(field, a, b) in mode.layout {
+        if a >= b {
+            fields[field] |= (bits.read(a - b + 1) as i32) << b;
+        } else {
+            for bit in (a..=b).rev() {
+                fields[field] |= (bits.read(1) as i32) << bit;
+            }
+        }
+    }
+
+    let regions = if mode.layout.iter().any(|(field, ..)| *field == D) {2} else {1};
+    let partition = fields[D] as usize;
+
+    // Region, endpoint, channel
+    let mut endpoints = [[[0i32; 3]; 2]; 2];
+
+    for channel in 0..3 {
+        let mut values = [0; 4];
+        values.copy_from_slice(&fields[4 * channel..4 * channel + 4]);
+
+        if signed {
+            values[0] = sign_extend(values[0], mode.endpoint_bits);
+        }
+
+        for value in values.iter_mut().take(2 * regions).skip(1) {
+            if signed || mode.transformed {
+                *value = sign_extend(*value, mode.delta_bits[channel]);
+            }
+        }
+
+        if mode.transformed {
+            for i in 1..2 * regions {
+                values[i] = (values[0] + values[i]) & ((1 << mode.endpoint_bits) - 1);
+                if signed {
+                    values[i] = sign_extend(values[i], mode.endpoint_bits);
+                }
+            }
+        }
+
+        for (i, value) in values.iter().take(2 * regions).enumerate() {
+            endpoints[i / 2][i % 2][channel] = unquantize(*value, mode.endpoint_bits, signed);
+        }
+    }
+
+    let index_bits = if regions == 2 {3} else {4};
+    let subsets = regions as u32;
+    let indices: [u32; 16] = std::array::from_fn(|texel| {
+        bits.read(index_bits - is_anchor(subsets, partition, texel) as u32)
+    });
+
+    std::array::from_fn(|texel| {
+        let [a, b] = endpoints[get_subset(subsets, partition, texel)];
+        let weight = get_weights(index_bits)[indices[texel] as usize];
+
+        std::array::from_fn(|channel| {
+            let value = interpolate(a[channel], b[channel], weight);
+
+            // Scaled back down to the largest finite half float, negative values keep their sign bit
+            let half = if !signed {
+                ((value * 31) >> 6) as u16
+            } else if value < 0 {
+                0x8000 | (((-value) * 31) >> 5) as u16
+            } else {
+                ((value * 31) >> 5) as u16
+            };
+
+            half_to_f32(half)
+        })
+    })
+}
+
+// Bit `i` gives the subset of texel `i`, BC6H uses the first 32
+const PARTITIONS_2: [u16; 64] = [
+    0xCCCC, 0x8888, 0xEEEE, 0xECC8, 0xC880, 0xFEEC, 0xFEC8, 0xEC80,
+    0xC800, 0xFFEC, 0xFE80, 0xE800, 0xFFE8, 0xFF00, 0xFFF0, 0xF000,
+    0xF710, 0x008E, 0x7100, 0x08CE, 0x008C, 0x7310, 0x3100, 0x8CCE,
+    0x088C, 0x3110, 0x6666, 0x366C, 0x17E8, 0x0FF0, 0x718E, 0x399C,
+    0xAAAA, 0xF0F0, 0x5A5A, 0x33CC, 0x3C3C, 0x55AA, 0x9696, 0xA55A,
+    0x73CE, 0x13C8, 0x324C, 0x3BDC, 0x6996, 0xC33C, 0x9966, 0x0660,
+    0x0272, 0x04E4, 0x4E40, 0x2720, 0xC936, 0x936C, 0x39C6, 0x639C,
+    0x9336, 0x9CC6, 0x817E, 0xE718, 0xCCF0, 0x0FCC, 0x7744, 0xEE22,
+];
+
+const PARTITIONS_3: [[u8; 16]; 64] = [
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 1, 2, 2, 2, 2],
+    [0, 0, 0, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 2, 0, 0, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2],
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2],
+    [0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+    [0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0, 2, 2, 2, 0],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2],
+    [0, 1, 1, 1, 0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0],
+    [0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2, 0, 2, 2, 2],
+    [0, 0, 0, 1, 0, 0, 0, 1, 2, 2, 2, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 0, 0, 1, 1, 0, 0, 2, 2, 1, 0, 2, 2, 1, 0],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1, 0, 0, 0, 0],
+    [0, 0, 1, 2, 0, 0, 1, 2, 1, 1, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1, 0, 1, 1, 0],
+    [0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1],
+    [0, 0, 2, 2, 1, 1, 0, 2, 1, 1, 0, 2, 0, 0, 2, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 0, 0, 2, 2, 2, 2, 2],
+    [0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 0, 0, 2, 0, 0, 0, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+    [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 2, 2, 0, 2, 2, 2],
+    [0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0],
+    [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0],
+    [0, 1, 2, 0, 2, 0, 1, 2, 1, 2, 0, 1, 0, 1, 2, 0],
+    [0, 0, 1, 1, 2, 2, 0, 0, 1, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 1, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 0, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 1, 1],
+    [0, 2, 2, 0, 1, 2, 2, 1, 0, 2, 2, 0, 1, 2, 2, 1],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 0, 1, 0, 1],
+    [0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 2, 2, 2, 0, 1, 1, 1],
+    [0, 0, 0, 2, 1, 1, 1, 2, 0, 0, 0, 2, 1, 1, 1, 2],
+    [0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2],
+    [0, 0, 0, 2, 1, 1, 1, 2, 1, 1, 1, 2, 0, 0, 0, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2],
+    [0, 0, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2],
+    [0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1],
+    [0, 2, 2, 2, 1, 2, 2, 2, 0, 2, 2, 2, 1, 2, 2, 2],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 1, 2, 0, 1, 1, 2, 2, 0, 1, 2, 2, 2, 0],
+];
+
+// Anchor texel of the second subset for two subsets, and of the second and third for three
+const ANCHORS_2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15, 2, 8, 2, 2, 8, 8, 15, 2, 8, 2, 2, 8, 8, 2, 2,
+    15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6,
+    6, 2, 6, 8, 15, 15, 2, 2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+
+const ANCHORS_3_SECOND: [u8; 64] = [
+    3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3,
+    3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5, 15, 15,
+    8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15,
+    3, 15, 5, 5, 5, 8, 5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+];
+
+const ANCHORS_3_THIRD: [u8; 64] = [
+    15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8,
+    15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6, 10, 15, 15, 10, 8,
+    15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8,
+    15, 3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Packs fields from the least significant bit on, like the decoders read them
+    #[derive(Default)]
+    struct BitWriter
+    {
+        bits: u128,
+        position: u32,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u32, count: u32) -> &mut Self
+        {
+            self.bits |= (value as u128) << self.position;
+            self.position += count;
+            self
+        }
+
+        fn block(&self) -> [u8; 16]
+        {
+            assert_eq!(self.position, 128);
+            self.bits.to_le_bytes()
+        }
+    }
+
+    #[test]
+    fn anchors_belong_to_their_subsets()
+    {
+        for partition in 0..64 {
+            assert_eq!(get_subset(2, partition, 0), 0);
+            assert_eq!(get_subset(2, partition, ANCHORS_2[partition] as usize), 1);
+
+            assert_eq!(get_subset(3, partition, 0), 0);
+            assert_eq!(get_subset(3, partition, ANCHORS_3_SECOND[partition] as usize), 1);
+            assert_eq!(get_subset(3, partition, ANCHORS_3_THIRD[partition] as usize), 2);
+        }
+    }
+
+    #[test]
+    fn bc1_switches_to_three_colours_when_the_endpoints_are_swapped()
+    {
+        let four = [0x00, 0xF8, 0x1F, 0x00, 0b11_10_01_00, 0, 0, 0];
+        let texels = decompress_block(BlockFormat::Bc1Rgba, &four, false);
+        assert_eq!(&texels[..4], &[[255, 0, 0, 255], [0, 0, 255, 255], [170, 0, 85, 255], [85, 0, 170, 255]]);
+
+        let three = [0x1F, 0x00, 0x00, 0xF8, 0b11_10_01_00, 0, 0, 0];
+        let texels = decompress_block(BlockFormat::Bc1Rgba, &three, false);
+        assert_eq!(&texels[..4], &[[0, 0, 255, 255], [255, 0, 0, 255], [127, 0, 127, 255], [0, 0, 0, 0]]);
+
+        let texels = decompress_block(BlockFormat::Bc1Rgb, &three, false);
+        assert_eq!(texels[3], [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn bc4_interpolates_six_values_between_ordered_endpoints()
+    {
+        let texels = decompress_block(BlockFormat::Bc4, &[255, 0, 0b01_010_001, 0, 0, 0, 0, 0], false);
+        assert_eq!(texels[0], [0, 0, 0, 255]);
+        assert_eq!(texels[1], [218, 0, 0, 255]);
+
+        let texels = decompress_block(BlockFormat::Bc4, &[0, 255, 0b00_111_110, 0, 0, 0, 0, 0], false);
+        assert_eq!(texels[0], [0, 0, 0, 255]);
+        assert_eq!(texels[1], [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn bc7_mode_6_interpolates_between_its_endpoints()
+    {
+        let mut writer = BitWriter::default();
+        writer.write(1 << 6, 7);
+
+        for _ in 0..4 {
+            writer.write(0, 7).write(127, 7);
+        }
+
+        // The p-bits make the endpoints 0 and 255, every texel but the anchor uses its own position as index
+        writer.write(0, 1).write(1, 1).write(0, 3);
+        for texel in 1..16 {
+            writer.write(texel, 4);
+        }
+
+        let texels = decompress_block(BlockFormat::Bc7, &writer.block(), false);
+        for (texel, value) in texels.iter().enumerate() {
+            let expected = interpolate(0, 255, WEIGHTS_4[texel]) as u8;
+            assert_eq!(*value, [expected; 4]);
+        }
+    }
+
+    #[test]
+    fn bc7_mode_5_rotates_alpha_into_a_colour_channel()
+    {
+        let mut writer = BitWriter::default();
+        writer.write(1 << 5, 6).write(1, 2);
+
+        // Red spans the whole range, green and blue stay black, alpha is constant
+        writer.write(0, 7).write(127, 7);
+        writer.write(0, 14).write(0, 14);
+        writer.write(200, 8).write(200, 8);
+
+        // Colour indices go from the first endpoint to the second, alpha indices are all zero
+        writer.write(0, 1);
+        for _ in 1..16 {
+            writer.write(3, 2);
+        }
+
+        writer.write(0, 1).write(0, 30);
+
+        let texels = decompress_block(BlockFormat::Bc7, &writer.block(), false);
+        assert_eq!(texels[0], [200, 0, 0, 0]);
+        assert_eq!(texels[15], [200, 0, 0, 255]);
+    }
+
+    #[test]
+    fn bc6h_mode_11_covers_the_half_float_range()
+    {
+        let mut writer = BitWriter::default();
+        writer.write(0x03, 5).write(0, 30).write(0x3FF, 10).write(0x3FF, 10).write(0x3FF, 10);
+
+        writer.write(0, 3);
+        for _ in 1..16 {
+            writer.write(15, 4);
+        }
+
+        let texels = decode_bc6h(&writer.block(), false);
+        assert_eq!(texels[0], [0.0; 3]);
+        assert_eq!(texels[1], [65504.0; 3]);
+    }
+
+    #[test]
+    fn reserved_modes_decode_to_black()
+    {
+        assert_eq!(decompress_block(BlockFormat::Bc7, &[0; 16], false), [[0; 4]; 16]);
+
+        let mut writer = BitWriter::default();
+        writer.write(0x13, 5).write(u32::MAX, 32).write(u32::MAX, 32).write(u32::MAX, 32).write(0x7FFFFFF, 27);
+        assert_eq!(decode_bc6h(&writer.block(), false), [[0.0; 3]; 16]);
+    }
+
+    #[test]
+    fn every_level_is_decompressed_and_cropped()
+    {
+        let image = DecodedImage {width: 5, height: 5, mip_levels: 2, compression: Some(BlockFormat::Bc1Rgb), pixels: vec![0; 8 * 5]};
+        let decompressed = decompress_image(&image, true);
+
+        assert_eq!(decompressed.compression, None);
+        assert_eq!(decompressed.pixels.len(), 4 * (5 * 5 + 2 * 2));
+        assert_eq!(decompressed.level_offsets(), vec![0, 4 * 5 * 5]);
+    }
+
+    fn max_error(a: &[[u8; 4]; 16], b: &[[u8; 4]; 16]) -> i32
+    {
+        a.iter().flatten().zip(b.iter().flatten()).map(|(a, b)| (*a as i32 - *b as i32).abs()).max().unwrap()
+    }
+
+    #[test]
+    fn bc1_keeps_two_colour_blocks()
+    {
+        let texels = std::array::from_fn(|texel| if texel % 3 == 0 {[200, 40, 10, 255]} else {[20, 90, 250, 255]});
+        let block = compress_block(BlockFormat::Bc1Rgb, &texels).unwrap();
+
+        // Only the 565 rounding of the endpoints is lost
+        assert!(max_error(&texels, &decompress_block(BlockFormat::Bc1Rgb, &block, false)) <= 4);
+
+        let flat = [[128, 128, 128, 255]; 16];
+        let block = compress_block(BlockFormat::Bc1Rgb, &flat).unwrap();
+        assert!(max_error(&flat, &decompress_block(BlockFormat::Bc1Rgb, &block, false)) <= 4);
+    }
+
+    #[test]
+    fn bc3_alpha_stays_within_one_palette_step()
+    {
+        let texels = std::array::from_fn(|texel| [255, 255, 255, 17 * texel as u8]);
+        let block = compress_block(BlockFormat::Bc3, &texels).unwrap();
+        let decoded = decompress_block(BlockFormat::Bc3, &block, false);
+
+        for (texel, value) in decoded.iter().enumerate() {
+            assert!((value[3] as i32 - 17 * texel as i32).abs() <= 19, "alpha {} for texel {}", value[3], texel);
+        }
+
+        let mut opaque = texels;
+        opaque.iter_mut().for_each(|t| t[3] = 255);
+        let block = compress_block(BlockFormat::Bc3, &opaque).unwrap();
+        assert!(decompress_block(BlockFormat::Bc3, &block, false).iter().all(|t| t[3] == 255));
+    }
+
+    #[test]
+    fn every_level_is_compressed_with_edges_repeated()
+    {
+        let image = DecodedImage {width: 6, height: 5, mip_levels: 2, compression: None, pixels: [10, 200, 30, 255].repeat(6 * 5 + 3 * 2)};
+        let compressed = compress_image(&image, BlockFormat::Bc1Rgb).unwrap();
+
+        assert_eq!(compressed.compression, Some(BlockFormat::Bc1Rgb));
+        assert_eq!(compressed.pixels.len(), compressed.level_size(0) + compressed.level_size(1));
+
+        let decompressed = decompress_image(&compressed, false);
+        for (a, b) in decompressed.pixels.iter().zip(&image.pixels) {
+            assert!((*a as i32 - *b as i32).abs() <= 4);
+        }
+
+        assert!(compress_image(&compressed, BlockFormat::Bc1Rgb).is_err());
+        assert!(compress_image(&image, BlockFormat::Bc7).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+use flate2::read::ZlibDecoder;
+use vulkanalia::vk;
+
+use super::{
+    image::{get_mip_levels, DecodedImage},
+    mesh_cache::Reader,
+    texture_compression::BlockFormat,
+};
+
+const KTX2_IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+
+// Identifier, nine fields describing the image and the index of the data format, key/value and supercompression sections
+const KTX2_HEADER_SIZE: usize = 12 + 9 * 4 + 4 * 4 + 2 * 8;
+
+const KTX2_SUPERCOMPRESSION_NONE: u32 = 0;
+const KTX2_SUPERCOMPRESSION_ZLIB: u32 = 3;
+
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+// Magic and the header following it, an extended header comes right after when the pixel format says `DX10`
+const DDS_HEADER_SIZE: usize = 4 + 124;
+
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDSCAPS2_VOLUME: u32 = 0x200000;
+const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+const DDS_DIMENSION_TEXTURE2D: u32 = 3;
+
+/*
+    Reads a single 2D texture with its mip levels from a KTX2 file. Block compressed and eight
+    bit RGBA formats are supported, uncompressed or compressed with zlib. Whether the texels
+    are sRGB is left to how the texture is used, like with PNGs. A file without levels gets
+    its chain generated when uploading, like an image with only the first level.
+ */
+pub fn decode_ktx2(bytes: &[u8]) -> Result<DecodedImage>
+{
+    let mut reader = Reader::new(bytes);
+
+    if reader.take(KTX2_IDENTIFIER.len())? != KTX2_IDENTIFIER {
+        return Err(anyhow!("Not a KTX2 file."));
+    }
+
+    let format = reader.u32()?;
+    let _type_size = reader.u32()?;
+    let width = reader.u32()?;
+    let height = reader.u32()?;
+    let depth = reader.u32()?;
+    let layer_count = reader.u32()?;
+    let face_count = reader.u32()?;
+    let level_count = reader.u32()?.max(1);
+    let supercompression = reader.u32()?;
+    reader.take(KTX2_HEADER_SIZE - KTX2_IDENTIFIER.len() - 9 * 4)?;
+
+    if depth > 0 || layer_count > 1 || face_count != 1 {
+        return Err(anyhow!("Only single 2D textures are supported, not volumes, arrays or cubemaps."));
+    }
+
+    let compression = match vk::Format::from_raw(format as i32) {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => None,
+        vk::Format::BC1_RGB_UNORM_BLOCK | vk::Format::BC1_RGB_SRGB_BLOCK => Some(BlockFormat::Bc1Rgb),
+        vk::Format::BC1_RGBA_UNORM_BLOCK | vk::Format::BC1_RGBA_SRGB_BLOCK => Some(BlockFormat::Bc1Rgba),
+        vk::Format::BC2_UNORM_BLOCK | vk::Format::BC2_SRGB_BLOCK => Some(BlockFormat::Bc2),
+        vk::Format::BC3_UNORM_BLOCK | vk::Format::BC3_SRGB_BLOCK => Some(BlockFormat::Bc3),
+        vk::Format::BC4_UNORM_BLOCK => Some(BlockFormat::Bc4),
+        vk::Format::BC5_UNORM_BLOCK => Some(BlockFormat::Bc5),
+        vk::Format::BC6H_UFLOAT_BLOCK => Some(BlockFormat::Bc6h),
+        vk::Format::BC6H_SFLOAT_BLOCK => Some(BlockFormat::Bc6hSigned),
+        vk::Format::BC7_UNORM_BLOCK | vk::Format::BC7_SRGB_BLOCK => Some(BlockFormat::Bc7),
+        vk::Format::UNDEFINED => return Err(anyhow!("Basis Universal textures are not supported.")),
+        other => return Err(anyhow!("Texture format {:?} is not supported.", other)),
+    };
+
+    let mut image = DecodedImage {width, height, mip_levels: level_count, compression, pixels: vec![]};
+    validate_size(&image)?;
+
+    // Level 0 comes first in the index even though the data is stored smallest level first
+    for level in 0..level_count {
+        let offset = reader.u64()? as usize;
+        let size = reader.u64()? as usize;
+        let _uncompressed_size = reader.u64()?;
+
+        let expected = image.level_size(level);
+        let data = offset
+            .checked_add(size)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or_else(|| anyhow!("Level {} out of range.", level))?;
+
+        match supercompression {
+            KTX2_SUPERCOMPRESSION_NONE if data.len() == expected => image.pixels.extend(data),
+            KTX2_SUPERCOMPRESSION_ZLIB => {
+                let start = image.pixels.len();
+                ZlibDecoder::new(data).take(expected as u64 + 1).read_to_end(&mut image.pixels)?;
+
+                if image.pixels.len() - start != expected {
+                    return Err(anyhow!("Level {} inflates to the wrong size.", level));
+                }
+            },
+            KTX2_SUPERCOMPRESSION_NONE => return Err(anyhow!("Level {} has the wrong size.", level)),
+            other => return Err(anyhow!("Supercompression scheme {} is not supported.", other)),
+        }
+    }
+
+    Ok(image)
+}
+
+/*
+    Reads a single 2D texture with its mip levels from a DDS file, either block compressed by
+    its four character code or extended header, or 32 bit RGB(A) described by channel masks.
+ */
+pub fn decode_dds(bytes: &[u8]) -> Result<DecodedImage>
+{
+    let mut reader = Reader::new(bytes);
+
+    if reader.take(4)? != DDS_MAGIC {
+        return Err(anyhow!("Not a DDS file."));
+    }
+
+    let _size = reader.u32()?;
+    let flags = reader.u32()?;
+    let height = reader.u32()?;
+    let width = reader.u32()?;
+    let _pitch = reader.u32()?;
+    let _depth = reader.u32()?;
+    let mip_count = reader.u32()?;
+    let mip_levels = if flags & DDSD_MIPMAPCOUNT != 0 {mip_count.max(1)} else {1};
+    reader.take(11 * 4)?;
+
+    let _format_size = reader.u32()?;
+    let format_flags = reader.u32()?;
+    let four_cc = reader.take(4)?;
+    let bit_count = reader.u32()?;
+    let masks = [reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?];
+
+    let _caps = reader.u32()?;
+    let caps2 = reader.u32()?;
+    reader.take(3 * 4)?;
+
+    if caps2 & (DDSCAPS2_CUBEMAP | DDSCAPS2_VOLUME) != 0 {
+        return Err(anyhow!("Only single 2D textures are supported, not volumes or cubemaps."));
+    }
+
+    // `None` for masked RGB, which is converted to RGBA below
+    let compression = if format_flags & DDPF_FOURCC == 0 {
+        if format_flags & DDPF_RGB == 0 || bit_count != 32 {
+            return Err(anyhow!("Only block compressed and 32 bit RGB textures are supported."));
+        }
+
+        None
+    } else {
+        match four_cc {
+            b"DXT1" => Some(BlockFormat::Bc1Rgba),
+            b"DXT2" | b"DXT3" => Some(BlockFormat::Bc2),
+            b"DXT4" | b"DXT5" => Some(BlockFormat::Bc3),
+            b"ATI1" | b"BC4U" => Some(BlockFormat::Bc4),
+            b"ATI2" | b"BC5U" => Some(BlockFormat::Bc5),
+            b"DX10" => {
+                let format = reader.u32()?;
+                let dimension = reader.u32()?;
+                let misc_flags = reader.u32()?;
+                let array_size = reader.u32()?;
+                let _misc_flags2 = reader.u32()?;
+
+                if dimension != DDS_DIMENSION_TEXTURE2D || array_size > 1 || misc_flags & DDS_RESOURCE_MISC_TEXTURECUBE != 0 {
+                    return Err(anyhow!("Only single 2D textures are supported, not arrays or cubemaps."));
+                }
+
+                get_dxgi_compression(format)?
+            },
+            other => return Err(anyhow!("Four character code `{}` is not supported.", String::from_utf8_lossy(other))),
+        }
+    };
+
+    let mut image = DecodedImage {width, height, mip_levels, compression, pixels: vec![]};
+    validate_size(&image)?;
+
+    let size = (0..mip_levels).map(|level| image.level_size(level)).sum();
+    let data = reader.take(size)?;
+
+    // Channels missing from the masks read as 255, like alpha from a format without it
+    image.pixels = if format_flags & DDPF_FOURCC == 0 {
+        data.chunks_exact(4)
+            .flat_map(|texel| {
+                let texel = u32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]]);
+                masks.map(|mask| match mask {
+                    0 => 255,
+                    _ => (((texel & mask) >> mask.trailing_zeros()) as u64 * 255 / (mask >> mask.trailing_zeros()) as u64) as u8,
+                })
+            })
+            .collect()
+    } else {
+        data.to_vec()
+    };
+
+    Ok(image)
+}
+
+// Typeless, UNORM and sRGB variants are read alike, see `decode_ktx2`
+fn get_dxgi_compression(format: u32) -> Result<Option<BlockFormat>>
+{
+    match format {
+        27..=29 => Ok(None),
+        70..=72 => Ok(Some(BlockFormat::Bc1Rgba)),
+        73..=75 => Ok(Some(BlockFormat::Bc2)),
+        76..=78 => Ok(Some(BlockFormat::Bc3)),
+        79 | 80 => Ok(Some(BlockFormat::Bc4)),
+        82 | 83 => Ok(Some(BlockFormat::Bc5)),
+        94 | 95 => Ok(Some(BlockFormat::Bc6h)),
+        96 => Ok(Some(BlockFormat::Bc6hSigned)),
+        97..=99 => Ok(Some(BlockFormat::Bc7)),
+        _ => Err(anyhow!("DXGI format {} is not supported.", format)),
+    }
+}
+
+fn validate_size(image: &DecodedImage) -> Result<()>
+{
+    if image.width == 0 || image.height == 0 || image.mip_levels > get_mip_levels(image.width, image.height) {
+        return Err(anyhow!("Texture has an invalid size."));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    fn ktx2(format: vk::Format, width: u32, height: u32, supercompression: u32, levels: &[Vec<u8>]) -> Vec<u8>
+    {
+        let mut bytes = KTX2_IDENTIFIER.to_vec();
+        for value in [format.as_raw() as u32, 1, width, height, 0, 0, 1, levels.len() as u32, supercompression] {
+            bytes.extend(value.to_le_bytes());
+        }
+
+        bytes.resize(KTX2_HEADER_SIZE, 0);
+
+        // Stored smallest level first like real files
+        let mut offset = KTX2_HEADER_SIZE + 3 * 8 * levels.len();
+        let mut data: Vec<u8> = vec![];
+        let mut index = vec![(0, 0); levels.len()];
+
+        for (level, bytes) in levels.iter().enumerate().rev() {
+            index[level] = (offset, bytes.len());
+            offset += bytes.len();
+            data.extend(bytes);
+        }
+
+        for (offset, size) in index {
+            for value in [offset as u64, size as u64, size as u64] {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+
+        bytes.extend(data);
+        bytes
+    }
+
+    fn dds(four_cc: &[u8; 4], flags: u32, width: u32, height: u32, mip_levels: u32, masks: [u32; 4], data: &[u8]) -> Vec<u8>
+    {
+        let mut bytes = DDS_MAGIC.to_vec();
+        for value in [124, DDSD_MIPMAPCOUNT, height, width, 0, 0, mip_levels] {
+            bytes.extend(u32::to_le_bytes(value));
+        }
+
+        bytes.resize(4 + 72, 0);
+        bytes.extend(32u32.to_le_bytes());
+        bytes.extend(flags.to_le_bytes());
+        bytes.extend(four_cc);
+        bytes.extend(32u32.to_le_bytes());
+        masks.iter().for_each(|m| bytes.extend(m.to_le_bytes()));
+
+        bytes.resize(DDS_HEADER_SIZE, 0);
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn ktx2_levels_are_read_largest_first()
+    {
+        let levels = vec![vec![1; 64], vec![2; 16], vec![3; 16]];
+        let image = decode_ktx2(&ktx2(vk::Format::BC7_SRGB_BLOCK, 8, 8, KTX2_SUPERCOMPRESSION_NONE, &levels)).unwrap();
+
+        assert_eq!((image.width, image.height, image.mip_levels), (8, 8, 3));
+        assert_eq!(image.compression, Some(BlockFormat::Bc7));
+        assert_eq!(image.pixels, levels.concat());
+    }
+
+    #[test]
+    fn zlib_compressed_ktx2_levels_are_inflated()
+    {
+        let level = (0..4 * 5 * 3).map(|i| i as u8).collect::<Vec<_>>();
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&level).unwrap();
+
+        let bytes = ktx2(vk::Format::R8G8B8A8_UNORM, 5, 3, KTX2_SUPERCOMPRESSION_ZLIB, &[encoder.finish().unwrap()]);
+        let image = decode_ktx2(&bytes).unwrap();
+
+        assert_eq!(image.compression, None);
+        assert_eq!(image.pixels, level);
+    }
+
+    #[test]
+    fn ktx2_levels_of_the_wrong_size_are_rejected()
+    {
+        let bytes = ktx2(vk::Format::BC1_RGB_UNORM_BLOCK, 8, 8, KTX2_SUPERCOMPRESSION_NONE, &[vec![0; 24]]);
+        assert!(decode_ktx2(&bytes).is_err());
+    }
+
+    #[test]
+    fn dds_blocks_are_read_by_four_character_code()
+    {
+        // 6x6 has 2x2 blocks, then 3x3 and 1x1 have one each
+        let data = (0..8 * 6).map(|i| i as u8).collect::<Vec<_>>();
+        let image = decode_dds(&dds(b"DXT1", DDPF_FOURCC, 6, 6, 3, [0; 4], &data)).unwrap();
+
+        assert_eq!((image.width, image.height, image.mip_levels), (6, 6, 3));
+        assert_eq!(image.compression, Some(BlockFormat::Bc1Rgba));
+        assert_eq!(image.pixels, data);
+    }
+
+    #[test]
+    fn masked_dds_texels_are_converted_to_rgba()
+    {
+        // BGRA in memory without alpha
+        let masks = [0x00FF0000, 0x0000FF00, 0x000000FF, 0];
+        let image = decode_dds(&dds(&[0; 4], DDPF_RGB, 1, 1, 1, masks, &[10, 20, 30, 0])).unwrap();
+
+        assert_eq!(image.pixels, [30, 20, 10, 255]);
+    }
+
+    #[test]
+    fn truncated_dds_files_are_rejected()
+    {
+        assert!(decode_dds(&dds(b"DXT5", DDPF_FOURCC, 4, 4, 1, [0; 4], &[0; 8])).is_err());
+    }
+}
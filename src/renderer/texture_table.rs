@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0, KhrGetPhysicalDeviceProperties2Extension}, Device, Instance};
+
+use super::{image::Texture, RenderData};
+
+// Upper bound of the texture array with descriptor indexing, devices may allow fewer
+const MAX_BINDLESS_TEXTURES: u32 = 4096;
+
+// Without descriptor indexing every element has to stay valid, so the array is kept small
+const MAX_ARRAY_TEXTURES: u32 = 128;
+
+// Samplers the scene shaders bind outside of the array, the shadow map and the environment
+const RESERVED_SAMPLERS: u32 = 2;
+
+pub const DESCRIPTOR_INDEXING_EXTENSIONS: &[vk::ExtensionName] = &[
+    vk::EXT_DESCRIPTOR_INDEXING_EXTENSION.name,
+    vk::KHR_MAINTENANCE3_EXTENSION.name,
+];
+
+// Written once when the table is created and shared by every material that lacks the map
+pub const WHITE_TEXTURE: u32 = 0;
+pub const FLAT_NORMAL_TEXTURE: u32 = 1;
+
+/*
+    Slots of the texture array in the material descriptor set, materials look their maps up by index.
+    With descriptor indexing the array is large and partially bound. Without it the array is an
+    ordinary one, which is why released slots are pointed back at the white texture.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct TextureTable
+{
+    pub capacity: u32,
+    len: u32,
+    free: Vec<u32>,
+}
+
+impl TextureTable {
+    pub fn new(capacity: u32) -> Self
+    {
+        Self {capacity, len: 0, free: vec![]}
+    }
+
+    // Released slots are reused first so that the used part of the array stays compact
+    pub fn allocate(&mut self) -> Option<u32>
+    {
+        if let Some(index) = self.free.pop() {
+            return Some(index);
+        }
+
+        if self.len < self.capacity {
+            self.len += 1;
+            return Some(self.len - 1);
+        }
+
+        None
+    }
+
+    pub fn release(&mut self, index: u32)
+    {
+        debug_assert!(index < self.len && !self.free.contains(&index));
+        self.free.push(index);
+    }
+}
+
+/*
+    Descriptor indexing is only used for partially bound arrays updated after binding. The material
+    index is uniform across a draw, so neither non-uniform indexing nor runtime arrays are needed.
+ */
+pub unsafe fn get_descriptor_indexing_support(instance: &Instance, data: &RenderData) -> bool
+{
+    if !data.properties2_supported {
+        return false;
+    }
+
+    let extensions = match instance.enumerate_device_extension_properties(data.physical_device, None) {
+        Ok(extensions) => extensions.iter().map(|e| e.extension_name).collect::<HashSet<_>>(),
+        Err(_) => return false,
+    };
+
+    if !DESCRIPTOR_INDEXING_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
+        return false;
+    }
+
+    let mut indexing = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut indexing);
+    instance.get_physical_device_features2_khr(data.physical_device, &mut features);
+
+    indexing.descriptor_binding_partially_bound == vk::TRUE
+        && indexing.descriptor_binding_sampled_image_update_after_bind == vk::TRUE
+}
+
+// Largest texture array the material set can hold next to the samplers of the frame's set
+pub unsafe fn get_texture_capacity(instance: &Instance, data: &RenderData) -> u32
+{
+    let limits = instance.get_physical_device_properties(data.physical_device).limits;
+
+    let (max_textures, per_stage, per_set) = if data.descriptor_indexing {
+        let mut indexing = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut indexing);
+        instance.get_physical_device_properties2_khr(data.physical_device, &mut properties);
+
+        (
+            MAX_BINDLESS_TEXTURES,
+            indexing.max_per_stage_descriptor_update_after_bind_samplers
+                .min(indexing.max_per_stage_descriptor_update_after_bind_sampled_images),
+            indexing.max_descriptor_set_update_after_bind_samplers
+                .min(indexing.max_descriptor_set_update_after_bind_sampled_images),
+        )
+    } else {
+        (
+            MAX_ARRAY_TEXTURES,
+            limits.max_per_stage_descriptor_samplers.min(limits.max_per_stage_descriptor_sampled_images),
+            limits.max_descriptor_set_samplers.min(limits.max_descriptor_set_sampled_images),
+        )
+    };
+
+    // The white and flat normal textures always take up the first two slots
+    max_textures
+        .min(per_stage.saturating_sub(RESERVED_SAMPLERS))
+        .min(per_set)
+        .max(FLAT_NORMAL_TEXTURE + 1)
+}
+
+// Expects the material descriptor set and the default textures to exist
+pub unsafe fn create_texture_table(device: &Device, data: &mut RenderData)
+{
+    data.texture_table = TextureTable::new(data.texture_table.capacity);
+
+    let white = data.texture_table.allocate().unwrap();
+    let flat_normal = data.texture_table.allocate().unwrap();
+    debug_assert_eq!((white, flat_normal), (WHITE_TEXTURE, FLAT_NORMAL_TEXTURE));
+
+    write_texture_descriptor(device, data, WHITE_TEXTURE, data.white_texture.view, data.texture_sampler);
+    write_texture_descriptor(device, data, FLAT_NORMAL_TEXTURE, data.flat_normal_texture.view, data.texture_sampler);
+
+    if !data.descriptor_indexing {
+        for index in FLAT_NORMAL_TEXTURE + 1..data.texture_table.capacity {
+            write_texture_descriptor(device, data, index, data.white_texture.view, data.texture_sampler);
+        }
+    }
+}
+
+// Without descriptor indexing the device has to be idle, the array is read by frames in flight
+pub unsafe fn add_texture(device: &Device, data: &mut RenderData, texture: &Texture, sampler: vk::Sampler) -> Result<u32>
+{
+    let index = data.texture_table
+        .allocate()
+        .ok_or_else(|| anyhow!("Texture array is full ({} textures).", data.texture_table.capacity))?;
+
+    write_texture_descriptor(device, data, index, texture.view, sampler);
+
+    Ok(index)
+}
+
+// Expects the device to be idle, the texture in the slot is usually destroyed right after
+pub unsafe fn remove_texture(device: &Device, data: &mut RenderData, index: u32)
+{
+    if index <= FLAT_NORMAL_TEXTURE {
+        return;
+    }
+
+    if !data.descriptor_indexing {
+        write_texture_descriptor(device, data, index, data.white_texture.view, data.texture_sampler);
+    }
+
+    data.texture_table.release(index);
+}
+
+unsafe fn write_texture_descriptor(device: &Device, data: &RenderData, index: u32, view: vk::ImageView, sampler: vk::Sampler)
+{
+    let info = vk::DescriptorImageInfo::builder()
+        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .image_view(view)
+        .sampler(sampler);
+
+    let image_info = &[info];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(data.material_descriptor_set)
+        .dst_binding(1)
+        .dst_array_element(index)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(image_info);
+
+    device.update_descriptor_sets(&[write], &[] as &[vk::CopyDescriptorSet]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_slots_are_reused()
+    {
+        let mut table = TextureTable::new(4);
+
+        assert_eq!(table.allocate(), Some(0));
+        assert_eq!(table.allocate(), Some(1));
+        assert_eq!(table.allocate(), Some(2));
+
+        table.release(1);
+        assert_eq!(table.allocate(), Some(1));
+        assert_eq!(table.allocate(), Some(3));
+        assert_eq!(table.allocate(), None);
+
+        table.release(0);
+        assert_eq!(table.allocate(), Some(0));
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::HashSet;
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::{anyhow, Result};
+use vulkanalia::{vk::{self, DeviceV1_0, Handle, HasBuilder}, Device, Instance};
+
+use super::{
+    allocator::{Allocation, Strategy},
+    barrier::{record_image_barriers, ImageBarrier},
+    buffer::create_buffer,
+    command::{begin_single_time_commands, end_single_time_commands},
+    device::QueueFamilyIndices,
+    image::{copy_buffer_to_image, generate_mip_chain, generate_mipmaps, supports_linear_blit, transition_image_layout, DecodedImage},
+    RenderData,
+};
+
+// 0 is never handed out, resources that weren't uploaded count as complete
+pub type UploadId = u64;
+
+// Work left for the graphics queue once a copy has finished on the transfer queue
+#[derive(Copy, Clone, Debug)]
+enum Acquire
+{
+    Buffer {
+        buffer: vk::Buffer,
+        dst_stage_mask: vk::PipelineStageFlags,
+        dst_access_mask: vk::AccessFlags,
+    },
+    // Either only level 0 was copied and the other levels are blitted on the graphics queue, or every level was
+    Image {
+        image: vk::Image,
+        format: vk::Format,
+        width: u32,
+        height: u32,
+        mip_levels: u32,
+        layer_count: u32,
+        generate_mipmaps: bool,
+    },
+}
+
+#[derive(Clone, Debug, Default)]
+struct UploadBatch
+{
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    staging: Vec<(vk::Buffer, Allocation)>,
+    acquires: Vec<(UploadId, Acquire)>,
+}
+
+/*
+    Batches staging copies into one command buffer per frame and submits them to the transfer
+    queue. Finished batches are found by polling their fences, after which the graphics queue
+    acquires the resources at the start of the next frame. Waiting on the fence from the host
+    orders the release before the acquire, so no semaphore is needed between the queues.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct UploadManager
+{
+    graphics_family: u32,
+    transfer_family: u32,
+    command_pool: vk::CommandPool,
+    // Batch commands are recorded into until the next flush
+    recording: Option<UploadBatch>,
+    submitted: Vec<UploadBatch>,
+    finished: Vec<(UploadId, Acquire)>,
+    pending: HashSet<UploadId>,
+    next_id: UploadId,
+}
+
+impl UploadManager {
+    pub fn is_complete(&self, id: UploadId) -> bool
+    {
+        !self.pending.contains(&id)
+    }
+
+    pub fn is_idle(&self) -> bool
+    {
+        self.pending.is_empty()
+    }
+
+    // Ownership only has to be transferred between different queue families
+    fn queue_families(&self) -> Option<(u32, u32)>
+    {
+        if self.transfer_family == self.graphics_family {
+            None
+        } else {
+            Some((self.transfer_family, self.graphics_family))
+        }
+    }
+
+    fn push(&mut self, acquire: Acquire) -> UploadId
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.pending.insert(id);
+        self.recording
+            .get_or_insert_with(UploadBatch::default)
+            .acquires
+            .push((id, acquire));
+
+        id
+    }
+}
+
+pub unsafe fn create_upload_manager(instance: &Instance, device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
+
+    let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(indices.transfer);
+
+    data.uploads = UploadManager {
+        graphics_family: indices.graphics,
+        transfer_family: indices.transfer,
+        command_pool: device.create_command_pool(&info, None)?,
+        next_id: 1,
+        ..Default::default()
+    };
+
+    Ok(())
+}
+
+// Copies `values` into `buffer`, which becomes usable at the given stages once the upload is complete
+pub unsafe fn upload_buffer<T: Copy>(instance: &Instance, device: &Device, data: &mut RenderData, values: &[T], buffer: vk::Buffer, dst_stage_mask: vk::PipelineStageFlags, dst_access_mask: vk::AccessFlags) -> Result<UploadId>
+{
+    let size = size_of_val(values) as vk::DeviceSize;
+    let staging_buffer = create_staging_buffer(instance, device, data, values)?;
+    let command_buffer = get_upload_command_buffer(device, data)?;
+
+    let region = vk::BufferCopy::builder().size(size);
+    device.cmd_copy_buffer(command_buffer, staging_buffer, buffer, &[region]);
+
+    if let Some((src_family, dst_family)) = data.uploads.queue_families() {
+        let release = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .src_queue_family_index(src_family)
+            .dst_queue_family_index(dst_family)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE as vk::DeviceSize);
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[release],
+            &[] as &[vk::ImageMemoryBarrier]
+        );
+    }
+
+    Ok(data.uploads.push(Acquire::Buffer {buffer, dst_stage_mask, dst_access_mask}))
+}
+
+/*
+    Fills level 0 of every layer from `pixels`, the image ends up in SHADER_READ_ONLY_OPTIMAL
+    with its mip chain generated. Formats that can't be blitted linearly have the chain of
+    their eight bit texels averaged on the CPU instead, so the graphics side never fails.
+ */
+pub unsafe fn upload_image(instance: &Instance, device: &Device, data: &mut RenderData, pixels: &[u8], image: vk::Image, format: vk::Format, width: u32, height: u32, mip_levels: u32, layer_count: u32) -> Result<UploadId>
+{
+    if mip_levels > 1 && !supports_linear_blit(instance, data, format) {
+        let srgb = match format {
+            vk::Format::R8G8B8A8_SRGB => true,
+            vk::Format::R8G8B8A8_UNORM => false,
+            _ => return Err(anyhow!("Mipmaps for {:?} can neither be blitted nor generated on the CPU.", format)),
+        };
+
+        if layer_count != 1 {
+            return Err(anyhow!("Mipmaps for layered {:?} images can't be blitted.", format));
+        }
+
+        let image_data = DecodedImage {width, height, mip_levels: 1, compression: None, pixels: pixels.to_vec()};
+        let chain = generate_mip_chain(&image_data, srgb);
+
+        return upload_image_levels(instance, device, data, &chain.pixels, &chain.level_offsets(), image, format, width, height);
+    }
+
+    let staging_buffer = create_staging_buffer(instance, device, data, pixels)?;
+    let command_buffer = get_upload_command_buffer(device, data)?;
+
+    transition_image_layout(
+        device,
+        command_buffer,
+        image,
+        format,
+        vk::ImageLayout::UNDEFINED,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        mip_levels,
+        layer_count
+    );
+
+    copy_buffer_to_image(device, command_buffer, staging_buffer, image, width, height, layer_count);
+    release_image(device, data, command_buffer, image, format, mip_levels, layer_count);
+
+    Ok(data.uploads.push(Acquire::Image {image, format, width, height, mip_levels, layer_count, generate_mipmaps: true}))
+}
+
+// Fills every level of a single layer image from `pixels`, `level_offsets` gives where each level starts in it
+pub unsafe fn upload_image_levels(instance: &Instance, device: &Device, data: &mut RenderData, pixels: &[u8], level_offsets: &[vk::DeviceSize], image: vk::Image, format: vk::Format, width: u32, height: u32) -> Result<UploadId>
+{
+    let mip_levels = level_offsets.len() as u32;
+    let staging_buffer = create_staging_buffer(instance, device, data, pixels)?;
+    let command_buffer = get_upload_command_buffer(device, data)?;
+
+    transition_image_layout(
+        device,
+        command_buffer,
+        image,
+        format,
+        vk::ImageLayout::UNDEFINED,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        mip_levels,
+        1
+    );
+
+    let regions = level_offsets
+        .iter()
+        .enumerate()
+        .map(|(level, &offset)| {
+            let subresource = vk::ImageSubresourceLayers::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(level as u32)
+                .base_array_layer(0)
+                .layer_count(1);
+
+            vk::BufferImageCopy::builder()
+                .buffer_offset(offset)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(subresource)
+                .image_offset(vk::Offset3D {x: 0, y: 0, z: 0})
+                .image_extent(vk::Extent3D {width: (width >> level).max(1), height: (height >> level).max(1), depth: 1})
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    device.cmd_copy_buffer_to_image(command_buffer, staging_buffer, image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &regions);
+    release_image(device, data, command_buffer, image, format, mip_levels, 1);
+
+    Ok(data.uploads.push(Acquire::Image {image, format, width, height, mip_levels, layer_count: 1, generate_mipmaps: false}))
+}
+
+unsafe fn release_image(device: &Device, data: &RenderData, command_buffer: vk::CommandBuffer, image: vk::Image, format: vk::Format, mip_levels: u32, layer_count: u32)
+{
+    if let Some((src_family, dst_family)) = data.uploads.queue_families() {
+        let release = ImageBarrier::new(image, format, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .with_mip_levels(0, mip_levels)
+            .with_layers(0, layer_count)
+            .with_dst(vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::AccessFlags::empty())
+            .with_queue_families(src_family, dst_family);
+
+        record_image_barriers(device, command_buffer, &[release]);
+    }
+}
+
+unsafe fn create_staging_buffer<T: Copy>(instance: &Instance, device: &Device, data: &mut RenderData, values: &[T]) -> Result<vk::Buffer>
+{
+    let (staging_buffer, staging_buffer_memory) = create_buffer(
+        instance,
+        device,
+        data,
+        size_of_val(values) as vk::DeviceSize,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        // Batches finish in the order they were submitted and free their staging in the order it was created
+        Strategy::Linear,
+    )?;
+
+    let memory = data.allocator.lock().unwrap().map(device, &staging_buffer_memory)?;
+    memcpy(values.as_ptr(), memory.cast(), values.len());
+
+    data.uploads.recording
+        .get_or_insert_with(UploadBatch::default)
+        .staging
+        .push((staging_buffer, staging_buffer_memory));
+
+    Ok(staging_buffer)
+}
+
+unsafe fn get_upload_command_buffer(device: &Device, data: &mut RenderData) -> Result<vk::CommandBuffer>
+{
+    let command_pool = data.uploads.command_pool;
+    let batch = data.uploads.recording.get_or_insert_with(UploadBatch::default);
+
+    if batch.command_buffer.is_null() {
+        let info = vk::CommandBufferAllocateInfo::builder()
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_pool(command_pool)
+            .command_buffer_count(1);
+
+        batch.command_buffer = device.allocate_command_buffers(&info)?[0];
+
+        let info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        device.begin_command_buffer(batch.command_buffer, &info)?;
+    }
+
+    Ok(batch.command_buffer)
+}
+
+// Submits everything recorded since the last flush to the transfer queue
+pub unsafe fn flush_uploads(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let Some(mut batch) = data.uploads.recording.take() else {
+        return Ok(());
+    };
+
+    device.end_command_buffer(batch.command_buffer)?;
+    batch.fence = device.create_fence(&vk::FenceCreateInfo::builder(), None)?;
+
+    let command_buffers = &[batch.command_buffer];
+    let info = vk::SubmitInfo::builder()
+        .command_buffers(command_buffers);
+
+    device.queue_submit(data.transfer_queue, &[info], batch.fence)?;
+    data.uploads.submitted.push(batch);
+
+    Ok(())
+}
+
+// Releases the staging memory of finished batches and queues their resources to be acquired
+pub unsafe fn poll_uploads(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    let mut index = 0;
+    while index < data.uploads.submitted.len() {
+        if device.get_fence_status(data.uploads.submitted[index].fence)? == vk::SuccessCode::NOT_READY {
+            index += 1;
+            continue;
+        }
+
+        let batch = data.uploads.submitted.remove(index);
+        destroy_batch(device, data, &batch);
+        data.uploads.finished.extend(batch.acquires);
+    }
+
+    Ok(())
+}
+
+// Records the graphics side of every finished upload, the resources can be used by the rest of the command buffer
+pub unsafe fn record_upload_acquires(device: &Device, data: &mut RenderData, command_buffer: vk::CommandBuffer)
+{
+    let finished = std::mem::take(&mut data.uploads.finished);
+    let queue_families = data.uploads.queue_families();
+    let (src_family, dst_family) = queue_families.unwrap_or((vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED));
+
+    let mut buffer_barriers = vec![];
+    let mut buffer_stage_mask = vk::PipelineStageFlags::empty();
+
+    for (_, acquire) in &finished {
+        match *acquire {
+            Acquire::Buffer {buffer, dst_stage_mask, dst_access_mask} => {
+                buffer_barriers.push(
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                        .dst_access_mask(dst_access_mask)
+                        .src_queue_family_index(src_family)
+                        .dst_queue_family_index(dst_family)
+                        .buffer(buffer)
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE as vk::DeviceSize)
+                        .build()
+                );
+
+                buffer_stage_mask |= dst_stage_mask;
+            },
+            Acquire::Image {image, format, width, height, mip_levels, layer_count, generate_mipmaps: generate} => {
+                let mut acquire = ImageBarrier::new(image, format, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .with_mip_levels(0, mip_levels)
+                    .with_layers(0, layer_count);
+
+                if let Some((src_family, dst_family)) = queue_families {
+                    acquire = acquire.with_queue_families(src_family, dst_family);
+                }
+
+                record_image_barriers(device, command_buffer, &[acquire]);
+
+                if generate {
+                    generate_mipmaps(device, command_buffer, image, format, width, height, mip_levels, layer_count);
+                } else {
+                    let ready = ImageBarrier::new(image, format, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                        .with_mip_levels(0, mip_levels)
+                        .with_layers(0, layer_count);
+
+                    record_image_barriers(device, command_buffer, &[ready]);
+                }
+            },
+        }
+    }
+
+    if !buffer_barriers.is_empty() {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            buffer_stage_mask,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &buffer_barriers,
+            &[] as &[vk::ImageMemoryBarrier]
+        );
+    }
+
+    for (id, _) in &finished {
+        data.uploads.pending.remove(id);
+    }
+}
+
+// Blocks until every upload so far is complete, for resources that have to be ready before the next frame
+pub unsafe fn wait_for_uploads(device: &Device, data: &mut RenderData) -> Result<()>
+{
+    flush_uploads(device, data)?;
+
+    let fences = data.uploads.submitted
+        .iter()
+        .map(|b| b.fence)
+        .collect::<Vec<_>>();
+
+    if !fences.is_empty() {
+        device.wait_for_fences(&fences, true, u64::MAX)?;
+    }
+
+    poll_uploads(device, data)?;
+
+    if !data.uploads.finished.is_empty() {
+        let command_buffer = begin_single_time_commands(device, data)?;
+        record_upload_acquires(device, data, command_buffer);
+        end_single_time_commands(device, data, command_buffer)?;
+    }
+
+    Ok(())
+}
+
+unsafe fn destroy_batch(device: &Device, data: &RenderData, batch: &UploadBatch)
+{
+    for (buffer, memory) in &batch.staging {
+        device.destroy_buffer(*buffer, None);
+        data.allocator.lock().unwrap().free(device, memory);
+    }
+
+    if !batch.command_buffer.is_null() {
+        device.free_command_buffers(data.uploads.command_pool, &[batch.command_buffer]);
+    }
+
+    device.destroy_fence(batch.fence, None);
+}
+
+// Expects the device to be idle
+pub unsafe fn destroy_upload_manager(device: &Device, data: &RenderData)
+{
+    data.uploads.recording
+        .iter()
+        .chain(data.uploads.submitted.iter())
+        .for_each(|b| destroy_batch(device, data, b));
+
+    device.destroy_command_pool(data.uploads.command_pool, None);
+}
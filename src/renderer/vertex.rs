@@ -1,9 +1,15 @@
 use std::hash::{Hash, Hasher};
 
+use cgmath::InnerSpace;
 use vulkanalia::vk::{self, HasBuilder};
 
 use crate::math::vector::Vector3;
 use crate::math::vector::Vector2;
+use crate::math::vector::Vector4;
+
+use super::{descriptor::Mat4, instancing::Prop, light::Vec4};
+
+type Vec3 = cgmath::Vector3<f32>;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -11,13 +17,18 @@ pub struct Vertex {
     pub position : Vector3,
     pub color: Vector3,
     pub tex_coord: Vector2,
+    pub normal: Vector3,
+    // xyz = tangent, w = handedness of the bitangent
+    pub tangent: Vector4,
 }
 
 impl PartialEq for Vertex {
     fn eq(&self, other: &Self) -> bool {
         self.position == other.position &&
         self.color == other.color &&
-        self.tex_coord == other.tex_coord
+        self.tex_coord == other.tex_coord &&
+        self.normal == other.normal &&
+        self.tangent == other.tangent
     }
 }
 
@@ -35,12 +46,19 @@ impl Hash for Vertex {
         self.color.z.to_bits().hash(state);
         self.tex_coord.x.to_bits().hash(state);
         self.tex_coord.y.to_bits().hash(state);
+        self.normal.x.to_bits().hash(state);
+        self.normal.y.to_bits().hash(state);
+        self.normal.z.to_bits().hash(state);
+        self.tangent.x.to_bits().hash(state);
+        self.tangent.y.to_bits().hash(state);
+        self.tangent.z.to_bits().hash(state);
+        self.tangent.w.to_bits().hash(state);
     }
 }
 
 impl Vertex {
-    pub const fn new(position: Vector3, color: Vector3, tex_coord: Vector2) -> Self {
-        Self {position, color, tex_coord}
+    pub const fn new(position: Vector3, color: Vector3, tex_coord: Vector2, normal: Vector3, tangent: Vector4) -> Self {
+        Self {position, color, tex_coord, normal, tangent}
     }
 
     pub fn binding_description() -> vk::VertexInputBindingDescription {
@@ -51,7 +69,7 @@ impl Vertex {
             .build()
     }
 
-    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
         let position = vk::VertexInputAttributeDescription::builder()
             .binding(0)
             .location(0)
@@ -73,6 +91,151 @@ impl Vertex {
             .offset((size_of::<Vector3>() + size_of::<Vector3>()) as u32)
             .build();
 
-        [position, color, tex_coord]
+        let normal = vk::VertexInputAttributeDescription::builder()
+            .binding(0)
+            .location(3)
+            .format(vk::Format::R32G32B32_SFLOAT)
+            .offset((size_of::<Vector3>() + size_of::<Vector3>() + size_of::<Vector2>()) as u32)
+            .build();
+
+        let tangent = vk::VertexInputAttributeDescription::builder()
+            .binding(0)
+            .location(4)
+            .format(vk::Format::R32G32B32A32_SFLOAT)
+            .offset((size_of::<Vector3>() + size_of::<Vector3>() + size_of::<Vector2>() + size_of::<Vector3>()) as u32)
+            .build();
+
+        [position, color, tex_coord, normal, tangent]
+    }
+}
+
+// Flat grid of `size` by `size` quads, each split into two triangles, shared by the mesh processing tests
+#[cfg(test)]
+pub fn grid(size: u32) -> (Vec<Vertex>, Vec<u32>)
+{
+    let vertices = (0..=size)
+        .flat_map(|y| (0..=size).map(move |x| (x, y)))
+        .map(|(x, y)| Vertex::new(
+            Vector3::new(x as f32, y as f32, 0.0),
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector2::new(x as f32 / size as f32, y as f32 / size as f32),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector4::new(1.0, 0.0, 0.0, 1.0),
+        ))
+        .collect::<Vec<_>>();
+
+    let corner = |x: u32, y: u32| y * (size + 1) + x;
+    let indices = (0..size)
+        .flat_map(|y| (0..size).map(move |x| (x, y)))
+        .flat_map(|(x, y)| [
+            corner(x, y), corner(x + 1, y), corner(x + 1, y + 1),
+            corner(x, y), corner(x + 1, y + 1), corner(x, y + 1),
+        ])
+        .collect::<Vec<_>>();
+
+    (vertices, indices)
+}
+
+/*
+    Per-instance attributes read from a second vertex buffer binding, the model matrix is
+    split into four column attributes since a vertex attribute holds at most a vec4.
+ */
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceData {
+    pub model: Mat4,
+    // x = opacity
+    pub parameters: Vec4,
+}
+
+impl InstanceData {
+    pub fn new(prop: &Prop) -> Self {
+        Self {
+            model: prop.transform,
+            parameters: Vec4::new(prop.opacity, 0.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription::builder()
+            .binding(1)
+            .stride(size_of::<InstanceData>() as u32)
+            .input_rate(vk::VertexInputRate::INSTANCE)
+            .build()
+    }
+
+    // Locations follow the ones of `Vertex`
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
+        let column = |i: u32| vk::VertexInputAttributeDescription::builder()
+            .binding(1)
+            .location(5 + i)
+            .format(vk::Format::R32G32B32A32_SFLOAT)
+            .offset(i * size_of::<Vec4>() as u32)
+            .build();
+
+        let parameters = vk::VertexInputAttributeDescription::builder()
+            .binding(1)
+            .location(9)
+            .format(vk::Format::R32G32B32A32_SFLOAT)
+            .offset(size_of::<Mat4>() as u32)
+            .build();
+
+        [column(0), column(1), column(2), column(3), parameters]
+    }
+}
+
+/*
+    Per-vertex tangents for normal mapping, derived from the UV gradients of each triangle.
+    Triangle tangents are accumulated per vertex, then orthogonalized against the normal (Gram-Schmidt).
+    The handedness is stored in w so the shader can rebuild the bitangent as cross(N, T) * w.
+ */
+pub fn generate_tangents(vertices: &mut [Vertex], indices: &[u32])
+{
+    let to_vec3 = |v: Vector3| Vec3::new(v.x, v.y, v.z);
+
+    let mut tangents = vec![Vec3::new(0.0, 0.0, 0.0); vertices.len()];
+    let mut bitangents = vec![Vec3::new(0.0, 0.0, 0.0); vertices.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let (i0, i1, i2) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+        let (v0, v1, v2) = (vertices[i0], vertices[i1], vertices[i2]);
+
+        let edge1 = to_vec3(v1.position) - to_vec3(v0.position);
+        let edge2 = to_vec3(v2.position) - to_vec3(v0.position);
+
+        let du1 = v1.tex_coord.x - v0.tex_coord.x;
+        let dv1 = v1.tex_coord.y - v0.tex_coord.y;
+        let du2 = v2.tex_coord.x - v0.tex_coord.x;
+        let dv2 = v2.tex_coord.y - v0.tex_coord.y;
+
+        let determinant = du1 * dv2 - du2 * dv1;
+        if determinant.abs() < f32::EPSILON {
+            continue;
+        }
+
+        let r = 1.0 / determinant;
+        let tangent = (edge1 * dv2 - edge2 * dv1) * r;
+        let bitangent = (edge2 * du1 - edge1 * du2) * r;
+
+        for i in [i0, i1, i2] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let normal = to_vec3(vertex.normal);
+        let mut tangent = tangents[i] - normal * normal.dot(tangents[i]);
+
+        // Degenerate UVs leave no usable tangent, so pick any vector perpendicular to the normal
+        if tangent.magnitude2() < f32::EPSILON {
+            let axis = if normal.x.abs() < 0.9 { Vec3::unit_x() } else { Vec3::unit_y() };
+            tangent = normal.cross(axis);
+        }
+
+        let tangent = tangent.normalize();
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+
+        vertex.tangent = Vector4::new(tangent.x, tangent.y, tangent.z, handedness);
     }
 }
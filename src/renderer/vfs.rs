@@ -0,0 +1,594 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File},
+    io::{Read, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use log::{info, warn};
+use memmap2::Mmap;
+
+use super::mesh_cache::Reader;
+
+// Mounted from every root it is found in, just below the root directory itself
+pub const ARCHIVE_NAME: &str = "assets.pak";
+
+// Separated like `PATH`, mounted above every other root
+pub const ASSET_ROOTS_VARIABLE: &str = "ASSET_ROOTS";
+
+const ARCHIVE_MAGIC: [u8; 4] = *b"SRGP";
+const ARCHIVE_VERSION: u32 = 1;
+
+// Magic, version, entry count and the offset of the index
+const ARCHIVE_HEADER_SIZE: usize = 4 + 4 + 4 + 8;
+
+// Priorities of the default mounts, a mount hides the files of every mount below it
+const CONFIGURED_ROOT_PRIORITY: i32 = 300;
+const WORKING_DIRECTORY_PRIORITY: i32 = 200;
+const EXECUTABLE_DIRECTORY_PRIORITY: i32 = 100;
+
+// Directories above the executable searched for assets, covers `target/<profile>/` during development
+const EXECUTABLE_ANCESTOR_COUNT: usize = 3;
+
+// Most reserved up front for an inflated file, so that a corrupt size in the index can't exhaust memory
+const MAX_INFLATE_RESERVE: usize = 64 << 20;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression
+{
+    None = 0,
+    Deflate = 1,
+}
+
+impl Compression {
+    fn from_raw(value: u32) -> Result<Self>
+    {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Deflate),
+            _ => Err(anyhow!("Unknown compression {}.", value)),
+        }
+    }
+}
+
+// Contents of a file, mapped when it is stored uncompressed and decompressed into memory otherwise
+#[derive(Debug)]
+pub enum FileData
+{
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl AsRef<[u8]> for FileData {
+    fn as_ref(&self) -> &[u8]
+    {
+        self
+    }
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8]
+    {
+        match self {
+            Self::Mapped(map) => map,
+            Self::Owned(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct ArchiveEntry
+{
+    offset: usize,
+    stored_size: usize,
+    size: usize,
+    compression: Compression,
+}
+
+/*
+    Files packed into one, each optionally deflated. The header is followed by the file
+    contents and then by an index from each path to where its contents are stored. Paths are
+    relative and use forward slashes, the same way the game refers to assets.
+ */
+#[derive(Debug)]
+pub struct Archive
+{
+    path: PathBuf,
+    map: Mmap,
+    entries: HashMap<String, ArchiveEntry>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self>
+    {
+        let file = File::open(path)?;
+
+        // Archives are only replaced by the packing tool, never while the game is running
+        let map = unsafe { Mmap::map(&file)? };
+        let mut reader = Reader::new(&map);
+
+        if reader.take(4)? != ARCHIVE_MAGIC {
+            return Err(anyhow!("`{}` is not an asset archive.", path.display()));
+        }
+
+        let version = reader.u32()?;
+        if version != ARCHIVE_VERSION {
+            return Err(anyhow!("`{}` has version {}, expected {}.", path.display(), version, ARCHIVE_VERSION));
+        }
+
+        let entry_count = reader.u32()?;
+        let index_offset = reader.u64()? as usize;
+
+        let mut index = Reader::new(map.get(index_offset..).ok_or_else(|| anyhow!("Archive index out of range."))?);
+        let mut entries = HashMap::new();
+
+        for _ in 0..entry_count {
+            let compression = Compression::from_raw(index.u32()?)?;
+            let path_length = index.u32()? as usize;
+            let offset = index.u64()? as usize;
+            let stored_size = index.u64()? as usize;
+            let size = index.u64()? as usize;
+            let name = std::str::from_utf8(index.take(path_length)?)?.to_string();
+
+            if offset.checked_add(stored_size).is_none_or(|end| end > index_offset) {
+                return Err(anyhow!("Contents of `{}` out of range.", name));
+            }
+
+            entries.insert(name, ArchiveEntry {offset, stored_size, size, compression});
+        }
+
+        Ok(Self {path: path.to_path_buf(), map, entries})
+    }
+
+    pub fn contains(&self, path: &str) -> bool
+    {
+        self.entries.contains_key(path)
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &str>
+    {
+        self.entries.keys().map(|p| p.as_str())
+    }
+
+    // `None` if the archive doesn't have the file
+    pub fn read(&self, path: &str) -> Option<Result<FileData>>
+    {
+        let entry = self.entries.get(path)?;
+        let stored = &self.map[entry.offset..entry.offset + entry.stored_size];
+
+        Some(match entry.compression {
+            // Copied since handing out part of the mapping would tie the data to the archive's lifetime
+            Compression::None => Ok(FileData::Owned(stored.to_vec())),
+            Compression::Deflate => {
+                // Reading one byte past the size is enough for the size check to notice too much data
+                let mut bytes = Vec::with_capacity(entry.size.min(MAX_INFLATE_RESERVE));
+                DeflateDecoder::new(stored)
+                    .take(entry.size as u64 + 1)
+                    .read_to_end(&mut bytes)
+                    .map_err(|e| anyhow!("Failed to inflate `{}` from `{}`: {}", path, self.path.display(), e))
+                    .and_then(|_| match bytes.len() == entry.size {
+                        true => Ok(FileData::Owned(bytes)),
+                        false => Err(anyhow!("`{}` in `{}` has the wrong size.", path, self.path.display())),
+                    })
+            },
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveWriter
+{
+    entries: Vec<(String, Compression, usize, Vec<u8>)>,
+}
+
+impl ArchiveWriter {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    // Deflated files that don't get smaller are stored as they are
+    pub fn add_file(&mut self, path: &str, bytes: &[u8], compression: Compression) -> Result<()>
+    {
+        let path = normalize_path(path)?;
+
+        let (compression, stored) = match compression {
+            Compression::None => (Compression::None, bytes.to_vec()),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(bytes)?;
+                let deflated = encoder.finish()?;
+
+                if deflated.len() < bytes.len() {
+                    (Compression::Deflate, deflated)
+                } else {
+                    (Compression::None, bytes.to_vec())
+                }
+            },
+        };
+
+        self.entries.retain(|(p, ..)| *p != path);
+        self.entries.push((path, compression, bytes.len(), stored));
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+
+    // Sizes of the files as given and as stored
+    pub fn sizes(&self) -> (usize, usize)
+    {
+        self.entries
+            .iter()
+            .fold((0, 0), |(size, stored), (_, _, s, bytes)| (size + s, stored + bytes.len()))
+    }
+
+    // Written to a temporary file first so that a running game never maps a half written archive
+    pub fn write(&self, path: &Path) -> Result<()>
+    {
+        let mut bytes = vec![0; ARCHIVE_HEADER_SIZE];
+        let mut offsets = Vec::with_capacity(self.entries.len());
+
+        for (_, _, _, stored) in &self.entries {
+            offsets.push(bytes.len());
+            bytes.extend(stored);
+        }
+
+        let index_offset = bytes.len();
+        for ((name, compression, size, stored), offset) in self.entries.iter().zip(offsets) {
+            bytes.extend((*compression as u32).to_le_bytes());
+            bytes.extend((name.len() as u32).to_le_bytes());
+            bytes.extend((offset as u64).to_le_bytes());
+            bytes.extend((stored.len() as u64).to_le_bytes());
+            bytes.extend((*size as u64).to_le_bytes());
+            bytes.extend(name.as_bytes());
+        }
+
+        bytes[0..4].copy_from_slice(&ARCHIVE_MAGIC);
+        bytes[4..8].copy_from_slice(&ARCHIVE_VERSION.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        bytes[12..20].copy_from_slice(&(index_offset as u64).to_le_bytes());
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Mount
+{
+    Directory(PathBuf),
+    Archive(Arc<Archive>),
+}
+
+/*
+    Resolves the relative asset paths used throughout the renderer against mounted directories
+    and archives. Mounts are searched from the highest priority down, so a loose file can
+    override the one packed in an archive. Absolute paths bypass the mounts.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Vfs
+{
+    // Sorted by descending priority, mounts with the same priority are searched in the order they were added
+    mounts: Vec<(i32, Mount)>,
+}
+
+impl Vfs {
+    /*
+        Mounts the roots listed in `ASSET_ROOTS`, the working directory, then the directory
+        of the executable and a few above it, so that the game finds its assets no matter
+        where it is launched from. Each root's `assets.pak` is mounted just below it.
+     */
+    pub fn with_default_mounts() -> Self
+    {
+        let mut roots = vec![];
+
+        if let Some(configured) = env::var_os(ASSET_ROOTS_VARIABLE) {
+            roots.extend(env::split_paths(&configured).map(|p| (CONFIGURED_ROOT_PRIORITY, p)));
+        }
+
+        if let Ok(directory) = env::current_dir() {
+            roots.push((WORKING_DIRECTORY_PRIORITY, directory));
+        }
+
+        if let Some(directory) = env::current_exe().ok().and_then(|e| e.parent().map(Path::to_path_buf)) {
+            let ancestors = directory.ancestors().take(EXECUTABLE_ANCESTOR_COUNT + 1);
+            roots.extend(ancestors.enumerate().map(|(i, p)| (EXECUTABLE_DIRECTORY_PRIORITY - 2 * i as i32, p.to_path_buf())));
+        }
+
+        let mut vfs = Self::default();
+        let mut mounted = vec![];
+
+        for (priority, root) in roots {
+            // The working directory usually is one of the executable's ancestors
+            let Ok(root) = root.canonicalize() else {
+                continue;
+            };
+
+            if mounted.contains(&root) {
+                continue;
+            }
+
+            let archive = root.join(ARCHIVE_NAME);
+            if archive.is_file() {
+                if let Err(e) = vfs.mount_archive(&archive, priority - 1) {
+                    warn!("Failed to mount `{}`: {:#}", archive.display(), e);
+                }
+            }
+
+            vfs.mount_directory(&root, priority);
+            mounted.push(root);
+        }
+
+        vfs
+    }
+
+    pub fn mount_directory(&mut self, path: &Path, priority: i32)
+    {
+        info!("Mounted directory `{}` with priority {}.", path.display(), priority);
+        self.insert(priority, Mount::Directory(path.to_path_buf()));
+    }
+
+    pub fn mount_archive(&mut self, path: &Path, priority: i32) -> Result<()>
+    {
+        let archive = Archive::open(path)?;
+        info!("Mounted archive `{}` with {} files and priority {}.", path.display(), archive.entries.len(), priority);
+
+        self.insert(priority, Mount::Archive(Arc::new(archive)));
+        Ok(())
+    }
+
+    fn insert(&mut self, priority: i32, mount: Mount)
+    {
+        let index = self.mounts.partition_point(|(p, _)| *p >= priority);
+        self.mounts.insert(index, (priority, mount));
+    }
+
+    pub fn exists(&self, path: &str) -> bool
+    {
+        self.find(path).is_ok_and(|m| m.is_some())
+    }
+
+    pub fn read(&self, path: &str) -> Result<FileData>
+    {
+        match self.find(path)? {
+            Some(Found::File(file)) => map_file(&file),
+            Some(Found::Archive(archive, name)) => archive
+                .read(&name)
+                .ok_or_else(|| anyhow!("`{}` is missing from `{}`.", name, archive.path.display()))?,
+            None => Err(anyhow!("`{}` was not found in any mounted directory or archive.", path)),
+        }
+    }
+
+    // Where the file is on disk, `None` if it only exists inside an archive
+    pub fn real_path(&self, path: &str) -> Option<PathBuf>
+    {
+        match self.find(path) {
+            Ok(Some(Found::File(file))) => Some(file),
+            _ => None,
+        }
+    }
+
+    fn find(&self, path: &str) -> Result<Option<Found>>
+    {
+        if Path::new(path).is_absolute() {
+            return Ok(Path::new(path).is_file().then(|| Found::File(PathBuf::from(path))));
+        }
+
+        let name = normalize_path(path)?;
+
+        for (_, mount) in &self.mounts {
+            match mount {
+                Mount::Directory(directory) => {
+                    let file = directory.join(&name);
+                    if file.is_file() {
+                        return Ok(Some(Found::File(file)));
+                    }
+                },
+                Mount::Archive(archive) => {
+                    if archive.contains(&name) {
+                        return Ok(Some(Found::Archive(archive.clone(), name)));
+                    }
+                },
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+enum Found
+{
+    File(PathBuf),
+    Archive(Arc<Archive>, String),
+}
+
+fn map_file(path: &Path) -> Result<FileData>
+{
+    let file = File::open(path)?;
+
+    // Empty files can't be mapped
+    if file.metadata()?.len() == 0 {
+        return Ok(FileData::Owned(vec![]));
+    }
+
+    // Asset files aren't expected to change while they are being loaded
+    Ok(FileData::Mapped(unsafe { Mmap::map(&file)? }))
+}
+
+// Forward slashes without `.` components, paths leaving the root are rejected
+pub fn normalize_path(path: &str) -> Result<String>
+{
+    let mut components = vec![];
+
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {},
+            ".." => return Err(anyhow!("Asset path `{}` leaves the asset root.", path)),
+            _ => components.push(component),
+        }
+    }
+
+    if components.is_empty() {
+        return Err(anyhow!("Asset path `{}` is empty.", path));
+    }
+
+    Ok(components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Removed again when dropped, even if the test fails
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self
+        {
+            let path = env::temp_dir().join(format!("vfs_test_{}_{}", name, std::process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, path: &str, bytes: &[u8]) -> &Self
+        {
+            let file = self.0.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, bytes).unwrap();
+            self
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self)
+        {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_archive(directory: &TestDirectory, files: &[(&str, &[u8])], compression: Compression) -> PathBuf
+    {
+        let mut writer = ArchiveWriter::new();
+        for (path, bytes) in files {
+            writer.add_file(path, bytes, compression).unwrap();
+        }
+
+        let path = directory.0.join(ARCHIVE_NAME);
+        writer.write(&path).unwrap();
+        path
+    }
+
+    fn read_string(vfs: &Vfs, path: &str) -> String
+    {
+        String::from_utf8(vfs.read(path).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn files_survive_a_round_trip_through_an_archive()
+    {
+        let directory = TestDirectory::new("round_trip");
+        let repeated = b"abcd".repeat(1000);
+        let files: [(&str, &[u8]); 3] = [("a.txt", b"first"), ("nested/b.bin", &repeated), ("empty", b"")];
+
+        for compression in [Compression::None, Compression::Deflate] {
+            let archive = Archive::open(&write_archive(&directory, &files, compression)).unwrap();
+
+            for (path, bytes) in files {
+                assert_eq!(&archive.read(path).unwrap().unwrap()[..], bytes);
+            }
+
+            assert!(archive.read("missing").is_none());
+
+            // Only what shrinks is deflated
+            let stored = archive.entries["nested/b.bin"];
+            assert_eq!(stored.compression, compression);
+            assert_eq!(archive.entries["a.txt"].compression, Compression::None);
+        }
+    }
+
+    #[test]
+    fn inflating_to_the_wrong_size_fails()
+    {
+        let directory = TestDirectory::new("wrong_size");
+        let repeated = b"abcd".repeat(1000);
+        let path = write_archive(&directory, &[("b.bin", &repeated)], Compression::Deflate);
+
+        let mut archive = Archive::open(&path).unwrap();
+        archive.entries.get_mut("b.bin").unwrap().size = 10;
+        assert!(archive.read("b.bin").unwrap().is_err());
+
+        archive.entries.get_mut("b.bin").unwrap().size = usize::MAX / 2;
+        assert!(archive.read("b.bin").unwrap().is_err());
+    }
+
+    #[test]
+    fn higher_mounts_hide_lower_ones()
+    {
+        let (high, low) = (TestDirectory::new("high"), TestDirectory::new("low"));
+        high.write("shared.txt", b"high");
+        low.write("shared.txt", b"low").write("low.txt", b"low only");
+
+        // Mounted in the opposite order of their priorities
+        let mut vfs = Vfs::default();
+        vfs.mount_directory(&low.0, 1);
+        vfs.mount_directory(&high.0, 2);
+
+        assert_eq!(read_string(&vfs, "shared.txt"), "high");
+        assert_eq!(read_string(&vfs, "low.txt"), "low only");
+        assert_eq!(vfs.real_path("shared.txt"), Some(high.0.join("shared.txt")));
+        assert!(vfs.read("missing.txt").is_err());
+    }
+
+    #[test]
+    fn mounts_with_the_same_priority_are_searched_in_order()
+    {
+        let (first, second) = (TestDirectory::new("first"), TestDirectory::new("second"));
+        first.write("shared.txt", b"first");
+        second.write("shared.txt", b"second");
+
+        let mut vfs = Vfs::default();
+        vfs.mount_directory(&first.0, 0);
+        vfs.mount_directory(&second.0, 0);
+
+        assert_eq!(read_string(&vfs, "shared.txt"), "first");
+    }
+
+    #[test]
+    fn loose_files_override_archived_ones()
+    {
+        let (loose, packed) = (TestDirectory::new("loose"), TestDirectory::new("packed"));
+        loose.write("resources/shared.txt", b"loose");
+        let archive = write_archive(&packed, &[("resources/shared.txt", b"packed"), ("resources/packed.txt", b"packed only")], Compression::None);
+
+        let mut vfs = Vfs::default();
+        vfs.mount_archive(&archive, 1).unwrap();
+        vfs.mount_directory(&loose.0, 2);
+
+        assert_eq!(read_string(&vfs, "resources/shared.txt"), "loose");
+        assert_eq!(read_string(&vfs, "./resources//packed.txt"), "packed only");
+
+        // Archived files have no path on disk
+        assert_eq!(vfs.real_path("resources/packed.txt"), None);
+        assert!(vfs.exists("resources/packed.txt"));
+        assert!(vfs.read("../resources/packed.txt").is_err());
+    }
+}